@@ -1,14 +1,32 @@
+use crate::cpu_tracer::{collision_grid_size, LineLayout, LineMesh, LineVertex, SeedingMode};
+use crate::evenly_spaced::evenly_spaced_mesh;
+use crate::images::RgbaImage;
+use crate::noise::{FractalMode, NoiseType};
+use crate::seeding::generate_seeds;
 use crate::utilities::*;
 use crate::*;
 use bevy::{
     ecs::query::QueryItem,
     render::{
+        extract_resource::ExtractResource,
         render_graph::{NodeRunError, RenderGraphContext, ViewNode},
         renderer::{RenderContext, RenderDevice, RenderQueue},
         view::{ViewUniform, ViewUniformOffset, ViewUniforms},
     },
+    tasks::{AsyncComputeTaskPool, Task},
+    utils::HashMap,
+};
+use std::{
+    borrow::Cow,
+    future::Future,
+    mem::size_of,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
 };
-use std::{borrow::Cow, mem::size_of};
 
 #[derive(Resource, Default, ShaderType, Clone, Copy)]
 pub struct CurrentIterationCount {
@@ -39,6 +57,12 @@ impl ViewNode for FlowFieldComputeNode {
                     iteration_count.value = 0;
                 }
 
+                if SeedingMode::from_u32(globals.seeding_mode) == SeedingMode::EvenlySpaced {
+                    // The lines were traced on the CPU and uploaded by `create_line_mesh_buffers`.
+                    *state = FlowFieldComputeState::Finished;
+                    return;
+                }
+
                 if !world.resource::<ShouldUpdateFlowField>().0 {
                     return;
                 }
@@ -140,6 +164,11 @@ impl ViewNode for FlowFieldComputeNode {
                 return Ok(());
             }
         };
+        // The lines wrote their segment indices
+        world
+            .resource::<FlowFieldLineMeshBuffers>()
+            .compacted
+            .store(false, Ordering::Relaxed);
 
         let device = world.resource::<RenderDevice>();
         let queue = world.resource::<RenderQueue>();
@@ -160,6 +189,22 @@ impl FromWorld for FlowFieldComputeNode {
 pub struct FlowFieldLineMeshBuffers {
     pub vertex_buffer: Option<Buffer>,
     pub index_buffer: Option<Buffer>,
+    // See cpu_tracer::LineState
+    pub line_state_buffer: Option<Buffer>,
+    // See cpu_tracer::CollisionGrid
+    pub collision_grid_buffer: Option<Buffer>,
+    // The number of indices in `index_buffer`, which may lag behind the globals while a CPU mesh
+    // is being retraced
+    pub num_indices: u32,
+    // The triangles of `index_buffer` that are drawn and the draw_indexed_indirect arguments for
+    // them, see flow_field_compact.wgsl
+    pub compacted_index_buffer: Option<Buffer>,
+    pub chunk_offset_buffer: Option<Buffer>,
+    pub block_offset_buffer: Option<Buffer>,
+    pub draw_args_buffer: Option<Buffer>,
+    // Whether `compacted_index_buffer` is up to date with `index_buffer`. Cleared when the compute
+    // node or a CPU mesh upload writes the index buffer, set once the render node compacted it.
+    pub compacted: AtomicBool,
 }
 
 impl Default for FlowFieldLineMeshBuffers {
@@ -167,37 +212,538 @@ impl Default for FlowFieldLineMeshBuffers {
         Self {
             vertex_buffer: None,
             index_buffer: None,
+            line_state_buffer: None,
+            collision_grid_buffer: None,
+            num_indices: 0,
+            compacted_index_buffer: None,
+            chunk_offset_buffer: None,
+            block_offset_buffer: None,
+            draw_args_buffer: None,
+            compacted: AtomicBool::new(false),
         }
     }
 }
 
+// Lines traced on the CPU for seeding modes the compute shader can't do, in the layout of the
+// line mesh buffers.
+#[derive(Resource, Clone, ExtractResource, Default)]
+pub struct CpuLineMesh(pub Option<Arc<LineMesh>>);
+
+// The evenly spaced tracing in flight, see `update_cpu_line_mesh`.
+#[derive(Resource, Default)]
+pub struct CpuLineMeshTask(Option<Task<LineMesh>>);
+
+// Retraces the CPU lines on reset. Runs after the UI so the lines match the reset it requests.
+// Tracing can take a while, so it runs on the async compute pool and the last mesh stays on
+// screen until it's done. A newer reset replaces the task in flight.
+pub fn update_cpu_line_mesh(
+    globals: Res<FlowFieldGlobals>,
+    inputs: Res<FlowFieldInputs>,
+    mut cpu_line_mesh: ResMut<CpuLineMesh>,
+    mut task: ResMut<CpuLineMeshTask>,
+) {
+    if SeedingMode::from_u32(globals.seeding_mode) != SeedingMode::EvenlySpaced {
+        if cpu_line_mesh.0.is_some() {
+            cpu_line_mesh.0 = None;
+        }
+        task.0 = None;
+        return;
+    }
+
+    if (cpu_line_mesh.0.is_none() && task.0.is_none()) || globals.should_reset == 1 {
+        let (globals, inputs) = (*globals, inputs.clone());
+        task.0 = Some(
+            AsyncComputeTaskPool::get().spawn(async move { evenly_spaced_mesh(&globals, &inputs) }),
+        );
+    }
+
+    // Polled once a frame, nothing needs waking
+    if let Some(tracing) = task.0.as_mut() {
+        if let Poll::Ready(mesh) = Pin::new(tracing).poll(&mut Context::from_waker(Waker::noop())) {
+            cpu_line_mesh.0 = Some(Arc::new(mesh));
+            task.0 = None;
+        }
+    }
+}
+
+// Start positions generated on the CPU, see seeding.rs.
+#[derive(Resource, Clone, ExtractResource, Default)]
+pub struct LineSeeds(pub Arc<Vec<Vec2>>);
+
+pub fn update_line_seeds(globals: Res<FlowFieldGlobals>, mut line_seeds: ResMut<LineSeeds>) {
+    if globals.should_reset == 1 {
+        let seeds = generate_seeds(&globals);
+        // Avoids marking the resource as changed and reuploading when there were no seeds before
+        if !seeds.is_empty() || !line_seeds.0.is_empty() {
+            line_seeds.0 = Arc::new(seeds);
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct LineSeedBuffer(pub Option<Buffer>);
+
+pub fn create_line_seed_buffer(
+    mut seed_buffer: ResMut<LineSeedBuffer>,
+    line_seeds: Res<LineSeeds>,
+    device: Res<RenderDevice>,
+) {
+    if seed_buffer.0.is_none() || line_seeds.is_changed() {
+        // Storage buffers can't be empty
+        let seeds: &[Vec2] = if line_seeds.0.is_empty() {
+            &[Vec2::ZERO]
+        } else {
+            &line_seeds.0
+        };
+        seed_buffer.0 = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("line_seed_buffer"),
+            contents: bytemuck::cast_slice(seeds),
+            usage: BufferUsages::STORAGE,
+        }));
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct DensityMapBuffer(pub Option<Buffer>);
+
+pub fn create_density_map_buffer(
+    mut density_map_buffer: ResMut<DensityMapBuffer>,
+    inputs: Res<FlowFieldInputs>,
+    device: Res<RenderDevice>,
+) {
+    if density_map_buffer.0.is_none() || inputs.is_changed() {
+        // The size of the map followed by its distributions. A width of 0 tells the shader there
+        // is no map, the last word is there because storage buffers can't be empty.
+        let contents: Vec<u32> = match &inputs.density_map {
+            Some(map) => [map.width, map.height]
+                .into_iter()
+                .chain(map.cdf.iter().map(|c| c.to_bits()))
+                .collect(),
+            None => vec![0, 0, 0],
+        };
+        density_map_buffer.0 = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("density_map_buffer"),
+            contents: bytemuck::cast_slice(&contents),
+            usage: BufferUsages::STORAGE,
+        }));
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct FieldImageBuffer(pub Option<Buffer>);
+
+pub fn create_field_image_buffer(
+    mut field_image_buffer: ResMut<FieldImageBuffer>,
+    inputs: Res<FlowFieldInputs>,
+    device: Res<RenderDevice>,
+) {
+    if field_image_buffer.0.is_none() || inputs.is_changed() {
+        let contents = image_buffer_contents(inputs.field_image.as_deref());
+        field_image_buffer.0 = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("field_image_buffer"),
+            contents: bytemuck::cast_slice(&contents),
+            usage: BufferUsages::STORAGE,
+        }));
+    }
+}
+
+// The size of the image followed by its RGBA8 pixels. A width of 0 tells the shader there is no
+// image.
+fn image_buffer_contents(image: Option<&RgbaImage>) -> Vec<u32> {
+    match image {
+        Some(image) => [image.width, image.height]
+            .into_iter()
+            .chain(image.pixels.iter().map(|p| u32::from_le_bytes(*p)))
+            .collect(),
+        None => vec![0, 0, 0],
+    }
+}
+
+// The colour image as a texture, so its sampling is filtered in hardware and it doesn't take up
+// one of the storage buffers the compute shader is limited to.
+#[derive(Resource, Default)]
+pub struct ColorImageTexture {
+    pub view: Option<TextureView>,
+    pub sampler: Option<Sampler>,
+}
+
+pub fn create_color_image_texture(
+    mut color_image_texture: ResMut<ColorImageTexture>,
+    inputs: Res<FlowFieldInputs>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    if color_image_texture.sampler.is_none() {
+        color_image_texture.sampler = Some(device.create_sampler(&SamplerDescriptor {
+            label: Some("color_image_sampler"),
+            address_mode_u: AddressMode::ClampToEdge,
+            address_mode_v: AddressMode::ClampToEdge,
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        }));
+    }
+
+    if color_image_texture.view.is_none() || inputs.is_changed() {
+        // Textures can't be empty, the shader ignores this one without COLOR_IMAGE
+        let (width, height, pixels) = match inputs.color_image.as_deref() {
+            Some(image) => (image.width, image.height, image.pixels.as_slice()),
+            None => (1, 1, &[[0; 4]][..]),
+        };
+        // Not sRGB, the shader filters the encoded values like the CPU does
+        let texture = device.create_texture_with_data(
+            &queue,
+            &TextureDescriptor {
+                label: Some("color_image_texture"),
+                size: Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rgba8Unorm,
+                usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                view_formats: &[],
+            },
+            bytemuck::cast_slice(pixels),
+        );
+        color_image_texture.view = Some(texture.create_view(&TextureViewDescriptor::default()));
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct VectorGridBuffer(pub Option<Buffer>);
+
+pub fn create_vector_grid_buffer(
+    mut vector_grid_buffer: ResMut<VectorGridBuffer>,
+    inputs: Res<FlowFieldInputs>,
+    device: Res<RenderDevice>,
+) {
+    if vector_grid_buffer.0.is_none() || inputs.is_changed() {
+        // The size and bounds of the grid and its longest vector, padded to the 8 byte alignment of
+        // the vectors that follow. A width of 0 tells the shader there is no grid.
+        let contents: Vec<u32> = match &inputs.vector_grid {
+            Some(grid) => [grid.width, grid.height]
+                .into_iter()
+                .chain(
+                    [
+                        grid.min.x,
+                        grid.min.y,
+                        grid.max.x,
+                        grid.max.y,
+                        grid.max_magnitude,
+                    ]
+                    .map(f32::to_bits),
+                )
+                .chain([0])
+                .chain(
+                    grid.vectors
+                        .iter()
+                        .flat_map(|v| [v.x.to_bits(), v.y.to_bits()]),
+                )
+                .collect(),
+            None => vec![0; 10],
+        };
+        vector_grid_buffer.0 = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("vector_grid_buffer"),
+            contents: bytemuck::cast_slice(&contents),
+            usage: BufferUsages::STORAGE,
+        }));
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct InfluenceBuffer(pub Option<Buffer>);
+
+pub fn create_influence_buffer(
+    mut influence_buffer: ResMut<InfluenceBuffer>,
+    inputs: Res<FlowFieldInputs>,
+    device: Res<RenderDevice>,
+) {
+    if influence_buffer.0.is_none() || inputs.is_changed() {
+        // The number of influences, padded to the 8 byte alignment of the influences that follow.
+        // Every influence is padded to 6 words for the same reason.
+        let mut contents: Vec<u32> = vec![inputs.influences.len() as u32, 0];
+        for influence in inputs.influences.iter() {
+            contents.extend([
+                influence.position.x.to_bits(),
+                influence.position.y.to_bits(),
+                influence.strength.to_bits(),
+                influence.radius.to_bits(),
+                influence.kind as u32,
+                0,
+            ]);
+        }
+        if inputs.influences.is_empty() {
+            contents.extend([0; 6]);
+        }
+        influence_buffer.0 = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("influence_buffer"),
+            contents: bytemuck::cast_slice(&contents),
+            usage: BufferUsages::STORAGE,
+        }));
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ObstacleBuffers {
+    pub obstacles: Option<Buffer>,
+    pub points: Option<Buffer>,
+}
+
+pub fn create_obstacle_buffers(
+    mut obstacle_buffers: ResMut<ObstacleBuffers>,
+    inputs: Res<FlowFieldInputs>,
+    device: Res<RenderDevice>,
+) {
+    if obstacle_buffers.obstacles.is_none() || inputs.is_changed() {
+        // The number of obstacles, padded to the 8 byte alignment of the obstacles that follow.
+        // The corners of all polygons go in a separate buffer.
+        let mut contents: Vec<u32> = vec![inputs.obstacles.len() as u32, 0];
+        let mut points: Vec<Vec2> = Vec::new();
+        for obstacle in inputs.obstacles.iter() {
+            contents.extend([
+                obstacle.shape as u32,
+                obstacle.behaviour as u32,
+                points.len() as u32,
+                obstacle.points.len() as u32,
+                obstacle.centre.x.to_bits(),
+                obstacle.centre.y.to_bits(),
+                obstacle.size.x.to_bits(),
+                obstacle.size.y.to_bits(),
+                obstacle.rounding.to_bits(),
+                obstacle.margin.to_bits(),
+            ]);
+            points.extend(&obstacle.points);
+        }
+        if inputs.obstacles.is_empty() {
+            contents.extend([0; 10]);
+        }
+        if points.is_empty() {
+            points.push(Vec2::ZERO);
+        }
+        obstacle_buffers.obstacles = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("obstacle_buffer"),
+            contents: bytemuck::cast_slice(&contents),
+            usage: BufferUsages::STORAGE,
+        }));
+        obstacle_buffers.points = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("obstacle_point_buffer"),
+            contents: bytemuck::cast_slice(&points),
+            usage: BufferUsages::STORAGE,
+        }));
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct GradientBuffer(pub Option<Buffer>);
+
+pub fn create_gradient_buffer(
+    mut gradient_buffer: ResMut<GradientBuffer>,
+    inputs: Res<FlowFieldInputs>,
+    device: Res<RenderDevice>,
+) {
+    if gradient_buffer.0.is_none() || inputs.is_changed() {
+        // The number of colours in the ramp, padded to the 16 byte alignment of the colours that
+        // follow. 0 tells the shader to blend line_color_start and line_color_end instead.
+        let contents: Vec<u32> = match &inputs.gradient {
+            Some(gradient) => [gradient.ramp().len() as u32, 0, 0, 0]
+                .into_iter()
+                .chain(
+                    gradient
+                        .ramp()
+                        .iter()
+                        .flat_map(|color| color.to_array().map(f32::to_bits)),
+                )
+                .collect(),
+            None => vec![0; 8],
+        };
+        gradient_buffer.0 = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("gradient_buffer"),
+            contents: bytemuck::cast_slice(&contents),
+            usage: BufferUsages::STORAGE,
+        }));
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct WidthCurveBuffer(pub Option<Buffer>);
+
+pub fn create_width_curve_buffer(
+    mut width_curve_buffer: ResMut<WidthCurveBuffer>,
+    inputs: Res<FlowFieldInputs>,
+    device: Res<RenderDevice>,
+) {
+    if width_curve_buffer.0.is_none() || inputs.is_changed() {
+        // The number of widths followed by the widths
+        let samples = inputs.width_curve.samples();
+        let contents: Vec<u32> = std::iter::once(samples.len() as u32)
+            .chain(samples.iter().map(|width| width.to_bits()))
+            .collect();
+        width_curve_buffer.0 = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("width_curve_buffer"),
+            contents: bytemuck::cast_slice(&contents),
+            usage: BufferUsages::STORAGE,
+        }));
+    }
+}
+
 pub fn create_line_mesh_buffers(
     mut mesh_data: ResMut<FlowFieldLineMeshBuffers>,
     globals: Res<FlowFieldGlobals>,
+    cpu_line_mesh: Res<CpuLineMesh>,
     device: Res<RenderDevice>,
 ) {
+    if cpu_line_mesh.is_changed() {
+        if let Some(mesh) = &cpu_line_mesh.0 {
+            mesh_data.vertex_buffer = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("compute_vertex_buffer"),
+                contents: bytemuck::cast_slice(&mesh.vertices),
+                usage: BufferUsages::VERTEX | BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            }));
+            mesh_data.index_buffer = Some(device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("compute_index_buffer"),
+                contents: bytemuck::cast_slice(&mesh.indices),
+                usage: BufferUsages::INDEX | BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            }));
+            mesh_data.num_indices = mesh.indices.len() as u32;
+            create_compacted_index_buffers(&mut mesh_data, &device);
+            return;
+        }
+    }
+
     if mesh_data.vertex_buffer.is_none()
         || mesh_data.index_buffer.is_none()
-        || globals.should_reset == 1
+        || (globals.should_reset == 1 && cpu_line_mesh.0.is_none())
     {
+        // Joins take a lot more memory, see `max_num_lines`
+        let layout = LineLayout::new(&globals, globals.max_iterations);
         let vertex_buffer = device.create_buffer(&BufferDescriptor {
             label: Some("compute_vertex_buffer"),
-            size: (size_of::<f32>() as u32 * 16 * globals.num_lines * globals.max_iterations)
-                .into(),
+            size: size_of::<LineVertex>() as u64
+                * layout.num_vertices() as u64
+                * globals.num_lines as u64,
             usage: BufferUsages::VERTEX | BufferUsages::STORAGE | BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
 
         let index_buffer = device.create_buffer(&BufferDescriptor {
             label: Some("compute_index_buffer"),
-            size: (size_of::<u32>() as u32 * 6 * globals.num_lines * (globals.max_iterations - 1))
-                .into(),
+            size: size_of::<u32>() as u64 * layout.num_indices() as u64 * globals.num_lines as u64,
             usage: BufferUsages::INDEX | BufferUsages::STORAGE | BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
 
+        // Written by init before update reads it
+        let line_state_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("line_state_buffer"),
+            size: (size_of::<u32>() as u32 * 5 * globals.num_lines).into(),
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        // New buffers are zeroed, which is the empty grid. Storage buffers can't be empty.
+        let num_cells = if globals.collision_distance > 0.0 {
+            let size = collision_grid_size(&globals);
+            size.x * size.y
+        } else {
+            1
+        };
+        let collision_grid_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("collision_grid_buffer"),
+            size: (size_of::<u32>() as u32 * num_cells).into(),
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
         mesh_data.vertex_buffer = Some(vertex_buffer);
         mesh_data.index_buffer = Some(index_buffer);
+        mesh_data.line_state_buffer = Some(line_state_buffer);
+        mesh_data.collision_grid_buffer = Some(collision_grid_buffer);
+        mesh_data.num_indices = layout.num_indices() * globals.num_lines;
+        create_compacted_index_buffers(&mut mesh_data, &device);
+    }
+}
+
+// The most lines whose vertex, index and compacted index buffers each fit in a storage buffer
+// binding on the GPU. Joins reserve their vertices at every joint whether or not one is drawn
+// there, which takes several times the memory of lines without joins.
+pub fn max_num_lines(globals: &FlowFieldGlobals, limits: &wgpu::Limits) -> u32 {
+    let layout = LineLayout::new(globals, globals.max_iterations);
+    let largest_buffer = limits
+        .max_buffer_size
+        .min(limits.max_storage_buffer_binding_size as u64);
+    let index_buffer_per_line = size_of::<u32>() as u64 * layout.num_indices() as u64;
+    let per_line = [
+        size_of::<LineVertex>() as u64 * layout.num_vertices() as u64,
+        index_buffer_per_line,
+        // Has room for every index, see create_compacted_index_buffers
+        index_buffer_per_line,
+    ];
+    let largest_per_line = per_line.into_iter().max().unwrap_or(1);
+    (largest_buffer / largest_per_line).min(u32::MAX as u64) as u32
+}
+
+// The buffers flow_field_compact.wgsl fills from the index buffer.
+fn create_compacted_index_buffers(mesh_data: &mut FlowFieldLineMeshBuffers, device: &RenderDevice) {
+    mesh_data.compacted_index_buffer = Some(device.create_buffer(&BufferDescriptor {
+        label: Some("compacted_index_buffer"),
+        // Storage buffers can't be empty
+        size: size_of::<u32>() as u64 * mesh_data.num_indices.max(1) as u64,
+        usage: BufferUsages::INDEX | BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    }));
+    mesh_data.chunk_offset_buffer = Some(device.create_buffer(&BufferDescriptor {
+        label: Some("chunk_offset_buffer"),
+        size: size_of::<u32>() as u64
+            * crate::render::num_compact_chunks(mesh_data.num_indices).max(1) as u64,
+        usage: BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    }));
+    mesh_data.block_offset_buffer = Some(device.create_buffer(&BufferDescriptor {
+        label: Some("block_offset_buffer"),
+        size: size_of::<u32>() as u64
+            * crate::render::num_compact_blocks(mesh_data.num_indices).max(1) as u64,
+        usage: BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    }));
+    mesh_data.draw_args_buffer = Some(device.create_buffer(&BufferDescriptor {
+        label: Some("draw_args_buffer"),
+        size: size_of::<wgpu::util::DrawIndexedIndirect>() as u64,
+        usage: BufferUsages::INDIRECT | BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    }));
+    mesh_data.compacted.store(false, Ordering::Relaxed);
+}
+
+// The settings that are compiled into the compute shader through shader defs instead of being
+// read from the globals uniform.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FlowFieldPipelineKey {
+    pub noise_type: NoiseType,
+    pub fractal_mode: FractalMode,
+    // Whether there is a colour image, which the placeholder texture can't tell the shader
+    pub color_image: bool,
+}
+
+impl FlowFieldPipelineKey {
+    pub fn new(globals: &FlowFieldGlobals, inputs: &FlowFieldInputs) -> Self {
+        Self {
+            noise_type: NoiseType::from_u32(globals.noise_type),
+            fractal_mode: FractalMode::from_u32(globals.noise_fractal_mode),
+            color_image: inputs.color_image.is_some(),
+        }
+    }
+
+    pub fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        let mut shader_defs = vec![self.noise_type.shader_def()];
+        shader_defs.extend(self.fractal_mode.shader_def());
+        if self.color_image {
+            shader_defs.push("COLOR_IMAGE".into());
+        }
+        shader_defs
     }
 }
 
@@ -206,6 +752,11 @@ pub struct FlowFieldComputeResources {
     pub init_pipeline_id: CachedComputePipelineId,
     pub update_pipeline_id: CachedComputePipelineId,
     pub bind_group_layout: BindGroupLayout,
+    // Key of the pipelines above
+    pub pipeline_key: FlowFieldPipelineKey,
+    // Every specialization queued so far, so switching back doesn't recompile the shader
+    pub pipelines:
+        HashMap<FlowFieldPipelineKey, (CachedComputePipelineId, CachedComputePipelineId)>,
 }
 
 impl FromWorld for FlowFieldComputeResources {
@@ -273,35 +824,214 @@ impl FromWorld for FlowFieldComputeResources {
                         },
                         count: None,
                     },
+                    // Seed buffer
+                    BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Density map
+                    BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Field image
+                    BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Vector grid
+                    BindGroupLayoutEntry {
+                        binding: 8,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Influences
+                    BindGroupLayoutEntry {
+                        binding: 9,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Obstacles
+                    BindGroupLayoutEntry {
+                        binding: 10,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Obstacle polygon corners
+                    BindGroupLayoutEntry {
+                        binding: 11,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Line states
+                    BindGroupLayoutEntry {
+                        binding: 12,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Collision grid
+                    BindGroupLayoutEntry {
+                        binding: 13,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Gradient colour ramp
+                    BindGroupLayoutEntry {
+                        binding: 14,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Colour image
+                    BindGroupLayoutEntry {
+                        binding: 15,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Texture {
+                            sample_type: TextureSampleType::Float { filterable: true },
+                            view_dimension: TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    // Width curve
+                    BindGroupLayoutEntry {
+                        binding: 16,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Colour image sampler
+                    BindGroupLayoutEntry {
+                        binding: 17,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                        count: None,
+                    },
                 ],
             });
 
-        let init_pipeline_id = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("flow_field_init_pipeline")),
-            layout: vec![bind_group_layout.clone()],
-            push_constant_ranges: vec![],
-            shader: FLOW_FIELD_COMPUTE_SHADER.typed(),
-            shader_defs: vec![],
-            entry_point: Cow::from("init"),
-        });
-
-        let update_pipeline_id = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-            label: Some(Cow::from("flow_field_update_pipeline")),
-            layout: vec![bind_group_layout.clone()],
-            push_constant_ranges: vec![],
-            shader: FLOW_FIELD_COMPUTE_SHADER.typed(),
-            shader_defs: vec![],
-            entry_point: Cow::from("update"),
-        });
+        // The globals haven't been extracted yet, `specialize_compute_pipelines` switches to the
+        // right pipelines once they are.
+        let pipeline_key = FlowFieldPipelineKey::default();
+        let (init_pipeline_id, update_pipeline_id) =
+            queue_compute_pipelines(pipeline_cache, &bind_group_layout, pipeline_key);
 
         Self {
             init_pipeline_id,
             update_pipeline_id,
             bind_group_layout,
+            pipeline_key,
+            pipelines: HashMap::from([(pipeline_key, (init_pipeline_id, update_pipeline_id))]),
         }
     }
 }
 
+// Queues the init and update pipelines for the given key.
+fn queue_compute_pipelines(
+    pipeline_cache: &PipelineCache,
+    bind_group_layout: &BindGroupLayout,
+    key: FlowFieldPipelineKey,
+) -> (CachedComputePipelineId, CachedComputePipelineId) {
+    let init_pipeline_id = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        label: Some(Cow::from("flow_field_init_pipeline")),
+        layout: vec![bind_group_layout.clone()],
+        push_constant_ranges: vec![],
+        shader: FLOW_FIELD_COMPUTE_SHADER.typed(),
+        shader_defs: key.shader_defs(),
+        entry_point: Cow::from("init"),
+    });
+
+    let update_pipeline_id = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        label: Some(Cow::from("flow_field_update_pipeline")),
+        layout: vec![bind_group_layout.clone()],
+        push_constant_ranges: vec![],
+        shader: FLOW_FIELD_COMPUTE_SHADER.typed(),
+        shader_defs: key.shader_defs(),
+        entry_point: Cow::from("update"),
+    });
+
+    (init_pipeline_id, update_pipeline_id)
+}
+
+// Switches to the pipelines matching the current globals. Changing them resets the flow field, so
+// the compute node waits in `Loading` until the new pipelines are compiled.
+pub fn specialize_compute_pipelines(
+    mut compute_resources: ResMut<FlowFieldComputeResources>,
+    pipeline_cache: Res<PipelineCache>,
+    globals: Res<FlowFieldGlobals>,
+    inputs: Res<FlowFieldInputs>,
+) {
+    let key = FlowFieldPipelineKey::new(&globals, &inputs);
+    if key == compute_resources.pipeline_key {
+        return;
+    }
+
+    let compute_resources = &mut *compute_resources;
+    let (init_pipeline_id, update_pipeline_id) =
+        *compute_resources.pipelines.entry(key).or_insert_with(|| {
+            queue_compute_pipelines(&pipeline_cache, &compute_resources.bind_group_layout, key)
+        });
+    compute_resources.init_pipeline_id = init_pipeline_id;
+    compute_resources.update_pipeline_id = update_pipeline_id;
+    compute_resources.pipeline_key = key;
+}
+
 #[derive(Resource, Default)]
 pub struct FlowFieldComputeBindGroup(pub Option<BindGroup>);
 
@@ -311,6 +1041,18 @@ pub fn queue_compute_bind_group(
     render_queue: Res<RenderQueue>,
     compute_resources: Res<FlowFieldComputeResources>,
     mesh_buffers: Res<FlowFieldLineMeshBuffers>,
+    seed_buffer: Res<LineSeedBuffer>,
+    density_map_buffer: Res<DensityMapBuffer>,
+    field_image_buffer: Res<FieldImageBuffer>,
+    vector_grid_buffer: Res<VectorGridBuffer>,
+    influence_buffer: Res<InfluenceBuffer>,
+    obstacle_buffers: Res<ObstacleBuffers>,
+    // Grouped to stay within the number of parameters a system can have
+    (gradient_buffer, color_image_texture, width_curve_buffer): (
+        Res<GradientBuffer>,
+        Res<ColorImageTexture>,
+        Res<WidthCurveBuffer>,
+    ),
     view_uniforms: Res<ViewUniforms>,
     globals: Res<FlowFieldGlobals>,
     iteration_count: Res<CurrentIterationCount>,
@@ -320,10 +1062,40 @@ pub fn queue_compute_bind_group(
     let iteration_buffer = struct_to_buffer(*iteration_count, &*render_device, &*render_queue);
     // info!("{}", iteration_count.value);
 
-    if let (Some(view_uniforms), Some(vertex_buffer), Some(index_buffer)) = (
+    if let (
+        Some(view_uniforms),
+        Some(vertex_buffer),
+        Some(index_buffer),
+        Some(seed_buffer),
+        Some(density_map_buffer),
+        Some(field_image_buffer),
+        Some(vector_grid_buffer),
+        Some(influence_buffer),
+        Some(obstacle_buffer),
+        Some(obstacle_point_buffer),
+        Some(line_state_buffer),
+        Some(collision_grid_buffer),
+        Some(gradient_buffer),
+        Some(color_image_view),
+        Some(color_image_sampler),
+        Some(width_curve_buffer),
+    ) = (
         view_uniforms.uniforms.binding(),
         mesh_buffers.vertex_buffer.clone(),
         mesh_buffers.index_buffer.clone(),
+        seed_buffer.0.clone(),
+        density_map_buffer.0.clone(),
+        field_image_buffer.0.clone(),
+        vector_grid_buffer.0.clone(),
+        influence_buffer.0.clone(),
+        obstacle_buffers.obstacles.clone(),
+        obstacle_buffers.points.clone(),
+        mesh_buffers.line_state_buffer.clone(),
+        mesh_buffers.collision_grid_buffer.clone(),
+        gradient_buffer.0.clone(),
+        color_image_texture.view.clone(),
+        color_image_texture.sampler.clone(),
+        width_curve_buffer.0.clone(),
     ) {
         let entries = &[
             BindGroupEntry {
@@ -346,6 +1118,58 @@ pub fn queue_compute_bind_group(
                 binding: 4,
                 resource: index_buffer.as_entire_binding(),
             },
+            BindGroupEntry {
+                binding: 5,
+                resource: seed_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: density_map_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: field_image_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 8,
+                resource: vector_grid_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 9,
+                resource: influence_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 10,
+                resource: obstacle_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 11,
+                resource: obstacle_point_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 12,
+                resource: line_state_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 13,
+                resource: collision_grid_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 14,
+                resource: gradient_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 15,
+                resource: BindingResource::TextureView(&color_image_view),
+            },
+            BindGroupEntry {
+                binding: 16,
+                resource: width_curve_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 17,
+                resource: BindingResource::Sampler(&color_image_sampler),
+            },
         ];
 
         let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
@@ -357,3 +1181,30 @@ pub fn queue_compute_bind_group(
         *compute_bind_group = FlowFieldComputeBindGroup(Some(bind_group));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu_tracer::LineJoin;
+
+    #[test]
+    fn line_mesh_buffers_fit_the_limits() {
+        let limits = wgpu::Limits::default();
+        let max_lines = |line_join: LineJoin| {
+            let globals = FlowFieldGlobals {
+                line_join: line_join as u32,
+                ..default()
+            };
+            let num_lines = max_num_lines(&globals, &limits);
+            let layout = LineLayout::new(&globals, globals.max_iterations);
+            let vertex_buffer =
+                size_of::<LineVertex>() as u64 * layout.num_vertices() as u64 * num_lines as u64;
+            assert!(vertex_buffer <= limits.max_storage_buffer_binding_size as u64);
+            num_lines
+        };
+        let none = max_lines(LineJoin::None);
+        assert!(none > 0);
+        assert!(max_lines(LineJoin::Miter) < none);
+        assert!(max_lines(LineJoin::Round) < max_lines(LineJoin::Bevel));
+    }
+}
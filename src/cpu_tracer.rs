@@ -0,0 +1,2611 @@
+// CPU reference implementation of the line tracer in flow_field_compute.wgsl.
+//
+// Every function here mirrors the shader function of the same name so that the geometry produced
+// by the `init` and `update` entry points can be reproduced (and tested) on machines without a GPU.
+// When changing the compute shader, change this file in the same way.
+
+use bevy::prelude::*;
+
+use crate::{
+    evenly_spaced::*, gradients::srgb_to_linear, images::*, influences::apply_influences, noise::*,
+    obstacles::*, seeding::*, vector_grid::VectorGrid, FlowFieldGlobals, FlowFieldInputs,
+};
+
+// Must match `padding` in the `init` entry point.
+pub const SEED_PADDING: f32 = 100.0;
+
+// Must match the constants of the same name in the compute shader. Set in position.z of both
+// vertices of a joint inside a mask, and in position.w of both vertices of the joint where a line
+// stopped and every joint after it.
+pub const JOINT_MASKED: f32 = 1.0;
+pub const LINE_STOPPED: f32 = 1.0;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LineVertex {
+    pub position: Vec4,
+    pub color: Vec4,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LineVertexPair {
+    pub first: LineVertex,
+    pub second: LineVertex,
+}
+
+// Same layout as the vertex and index buffers filled by the compute shader.
+// Line `i` owns the `num_vertices` vertices starting at `num_vertices * i` and the `num_indices`
+// indices starting at `num_indices * i` of `LineLayout::new(globals, max_iterations)`. Without
+// bevel or round joins and square or round caps that's two vertices per joint and two triangles
+// per segment.
+#[derive(Clone, Debug, Default)]
+pub struct LineMesh {
+    pub vertices: Vec<LineVertex>,
+    pub indices: Vec<u32>,
+}
+
+// Traces all lines for all `max_iterations`, i.e. the state of the GPU buffers once the compute
+// node has reached `FlowFieldComputeState::Finished`.
+pub fn trace_lines(globals: &FlowFieldGlobals, inputs: &FlowFieldInputs) -> LineMesh {
+    if SeedingMode::from_u32(globals.seeding_mode) == SeedingMode::EvenlySpaced {
+        return evenly_spaced_mesh(globals, inputs);
+    }
+
+    let vertices_per_line =
+        LineLayout::new(globals, globals.max_iterations).num_vertices() as usize;
+    let mut mesh = LineMesh {
+        vertices: vec![LineVertex::default(); vertices_per_line * globals.num_lines as usize],
+        indices: line_indices(globals),
+    };
+
+    // Every line takes a step before any line takes the next, like the dispatches of the compute
+    // shader, so lines run into each other the same way.
+    let seeds = generate_seeds(globals);
+    let mut collision_grid = CollisionGrid::new(globals);
+    let mut states: Vec<LineState> = mesh
+        .vertices
+        .chunks_exact_mut(vertices_per_line)
+        .enumerate()
+        .map(|(line_index, vertices)| {
+            let grid = collision_grid.as_mut();
+            init(globals, inputs, line_index as u32, &seeds, grid, vertices)
+        })
+        .collect();
+    for iteration in 2..globals.max_iterations {
+        for ((line_index, vertices), state) in mesh
+            .vertices
+            .chunks_exact_mut(vertices_per_line)
+            .enumerate()
+            .zip(&mut states)
+        {
+            let grid = collision_grid.as_mut();
+            update(
+                globals,
+                inputs,
+                line_index as u32,
+                iteration,
+                state,
+                grid,
+                vertices,
+            );
+        }
+    }
+    skip_stopped_segments(globals, &mut mesh.indices, &mesh.vertices);
+
+    mesh
+}
+
+// The index buffer, which doesn't depend on the traced positions.
+pub fn line_indices(globals: &FlowFieldGlobals) -> Vec<u32> {
+    let layout = LineLayout::new(globals, globals.max_iterations);
+    let indices_per_line = layout.num_indices() as usize;
+    let mut indices = vec![0; indices_per_line * globals.num_lines as usize];
+
+    for (line_index, indices) in indices.chunks_exact_mut(indices_per_line).enumerate() {
+        let first_vertex_index = layout.num_vertices() * line_index as u32;
+        write_line_indices(layout, first_vertex_index, indices);
+    }
+
+    indices
+}
+
+// The indices of a single line whose vertices start at `first_vertex_index`. Joins and caps that
+// weren't written are all zero like the rest of the vertex buffer, so their fans have no area.
+pub fn write_line_indices(layout: LineLayout, first_vertex_index: u32, indices: &mut [u32]) {
+    for (segment, triangles) in indices[..layout.join_index(0) as usize]
+        .chunks_exact_mut(6)
+        .enumerate()
+    {
+        let (start, end) = segment_vertices(layout, segment as u32);
+        triangles.copy_from_slice(&segment_indices(
+            first_vertex_index + start,
+            first_vertex_index + end,
+        ));
+    }
+    for joint in 0..layout.num_joints {
+        // The fan after the pairs of vertices of the segments
+        let centre = first_vertex_index + layout.join_vertex(joint) + 4;
+        let i = layout.join_index(joint) as usize;
+        write_fan_indices(
+            centre,
+            &mut indices[i..i + 3 * layout.join_triangles as usize],
+        );
+    }
+    for end in 0..2 {
+        let centre = first_vertex_index + layout.cap_vertex(end);
+        let i = layout.cap_index(end) as usize;
+        write_fan_indices(
+            centre,
+            &mut indices[i..i + 3 * layout.cap_triangles as usize],
+        );
+    }
+}
+
+// Mirrors `write_fan_indices`. Triangles from the vertex at `centre` to each pair of the vertices
+// after it.
+fn write_fan_indices(centre: u32, indices: &mut [u32]) {
+    for (k, triangle) in indices.chunks_exact_mut(3).enumerate() {
+        let k = k as u32;
+        triangle.copy_from_slice(&[centre, centre + 1 + k, centre + 2 + k]);
+    }
+}
+
+// Collapses the triangles of every segment with a joint where its line had stopped or that is
+// inside a mask to a single vertex, like `write_segment_indices`.
+pub fn skip_stopped_segments(
+    globals: &FlowFieldGlobals,
+    indices: &mut [u32],
+    vertices: &[LineVertex],
+) {
+    let layout = LineLayout::new(globals, globals.max_iterations);
+    for (line_index, (indices, vertices)) in indices
+        .chunks_exact_mut(layout.num_indices() as usize)
+        .zip(vertices.chunks_exact(layout.num_vertices() as usize))
+        .enumerate()
+    {
+        let first_vertex_index = layout.num_vertices() * line_index as u32;
+        skip_stopped_line_segments(layout, first_vertex_index, indices, vertices);
+    }
+}
+
+// `skip_stopped_segments` for the indices and vertices of a single line.
+pub fn skip_stopped_line_segments(
+    layout: LineLayout,
+    first_vertex_index: u32,
+    indices: &mut [u32],
+    vertices: &[LineVertex],
+) {
+    for (segment, triangles) in indices[..layout.join_index(0) as usize]
+        .chunks_exact_mut(6)
+        .enumerate()
+    {
+        let v = 2 * segment;
+        if [&vertices[v], &vertices[v + 2]]
+            .iter()
+            .any(|vertex| vertex.position.w == LINE_STOPPED || vertex.position.z == JOINT_MASKED)
+        {
+            triangles.fill(first_vertex_index + v as u32 + 2);
+        }
+    }
+}
+
+// Traces a single line into `vertices`, which holds the `num_vertices` vertices of that line (see
+// `LineMesh`).
+// Lines only depend on each other through collisions, which this ignores, so without them this
+// gives the same result as running `init` and then `update` for every iteration on all lines at
+// once. `seeds` are the ones from `generate_seeds`.
+pub fn trace_line(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    line_index: u32,
+    seeds: &[Vec2],
+    vertices: &mut [LineVertex],
+) -> LineState {
+    // Joins and caps that aren't written are left empty, like in the new vertex buffer
+    vertices.fill(LineVertex::default());
+    let mut state = init(globals, inputs, line_index, seeds, None, vertices);
+    for iteration in 2..globals.max_iterations {
+        update(
+            globals, inputs, line_index, iteration, &mut state, None, vertices,
+        );
+    }
+    state
+}
+
+// Traces the lines one at a time in draw order without keeping the whole mesh in memory. Evenly
+// spaced lines are all traced up front and passed with only the joints they have (see
+// `LineLayout::of_vertices`), and so are lines that can run into each other.
+pub fn for_each_line(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    mut f: impl FnMut(u32, &[LineVertex]),
+) {
+    if SeedingMode::from_u32(globals.seeding_mode) == SeedingMode::EvenlySpaced {
+        for (line_index, line) in trace_evenly_spaced_lines(globals, inputs)
+            .iter()
+            .enumerate()
+        {
+            let line_index = line_index as u32;
+            f(
+                line_index,
+                &line_vertices(globals, inputs, line_index, line),
+            );
+        }
+        return;
+    }
+    if globals.collision_distance > 0.0 {
+        let mesh = trace_lines(globals, inputs);
+        let layout = LineLayout::new(globals, globals.max_iterations);
+        for (line_index, vertices) in mesh
+            .vertices
+            .chunks_exact(layout.num_vertices() as usize)
+            .enumerate()
+        {
+            f(line_index as u32, vertices);
+        }
+        return;
+    }
+
+    let seeds = generate_seeds(globals);
+    let layout = LineLayout::new(globals, globals.max_iterations);
+    let mut vertices = vec![LineVertex::default(); layout.num_vertices() as usize];
+    for line_index in 0..globals.num_lines {
+        trace_line(globals, inputs, line_index, &seeds, &mut vertices);
+        f(line_index, &vertices);
+    }
+}
+
+// The centre of every joint of a line, i.e. the polyline the ribbon of quads is built around. Takes
+// the two vertices per joint, see `LineLayout::joint_vertices`.
+pub fn joint_positions(vertices: &[LineVertex]) -> impl Iterator<Item = Vec2> + '_ {
+    vertices.chunks_exact(2).map(|pair| {
+        let v1 = pair[0].position.truncate().truncate();
+        let v2 = pair[1].position.truncate().truncate();
+        v1 + 0.5 * (v2 - v1)
+    })
+}
+
+// The parts of a line that are drawn: masked joints split it and joints where it had stopped are
+// left out. Takes the two vertices per joint like `joint_positions`.
+pub fn drawn_polylines(vertices: &[LineVertex]) -> Vec<Vec<Vec2>> {
+    let joints: Vec<Vec2> = joint_positions(vertices).collect();
+    drawn_joints(vertices)
+        .into_iter()
+        .map(|part| part.into_iter().map(|joint| joints[joint]).collect())
+        .collect()
+}
+
+// The indices of the joints in each part of `drawn_polylines`.
+pub fn drawn_joints(vertices: &[LineVertex]) -> Vec<Vec<usize>> {
+    let mut parts = Vec::new();
+    let mut current = Vec::new();
+    for (joint, pair) in vertices.chunks_exact(2).enumerate() {
+        // Stopped joints repeat the last one that was traced in their direction
+        if pair[0].position.w == LINE_STOPPED {
+            continue;
+        }
+        if pair[0].position.z == JOINT_MASKED {
+            if current.len() > 1 {
+                parts.push(std::mem::take(&mut current));
+            }
+            current.clear();
+        } else {
+            current.push(joint);
+        }
+    }
+    if current.len() > 1 {
+        parts.push(current);
+    }
+    parts
+}
+
+// Where lines start.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SeedingMode {
+    // Uniformly distributed over the padded viewport by `init`
+    #[default]
+    Random,
+    // Lines are traced one after another on the CPU, see evenly_spaced.rs
+    EvenlySpaced,
+    // Centres of a grid of `num_lines` cells
+    Grid,
+    // A random position in every grid cell
+    JitteredGrid,
+    // Generated on the CPU and read from the seed buffer, see seeding.rs
+    PoissonDisk,
+    BlueNoise,
+    // Sampled from `FlowFieldInputs::density_map` by `init`, denser where the image is darker
+    ImageDensity,
+}
+
+impl SeedingMode {
+    pub const ALL: [SeedingMode; 7] = [
+        SeedingMode::Random,
+        SeedingMode::EvenlySpaced,
+        SeedingMode::Grid,
+        SeedingMode::JitteredGrid,
+        SeedingMode::PoissonDisk,
+        SeedingMode::BlueNoise,
+        SeedingMode::ImageDensity,
+    ];
+
+    pub fn from_u32(value: u32) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SeedingMode::Random => "Random",
+            SeedingMode::EvenlySpaced => "Evenly spaced",
+            SeedingMode::Grid => "Grid",
+            SeedingMode::JitteredGrid => "Jittered grid",
+            SeedingMode::PoissonDisk => "Poisson disk",
+            SeedingMode::BlueNoise => "Blue noise",
+            SeedingMode::ImageDensity => "Image density",
+        }
+    }
+}
+
+// Mirrors `seed_position` in the compute shader. Where line `line_index` starts, in [0, 1] x [0, 1]
+// over the padded viewport. Lines without a generated seed or density map start at a random
+// position.
+pub fn seed_position(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    line_index: u32,
+    seeds: &[Vec2],
+) -> Vec2 {
+    let seed_1 = line_index.wrapping_mul(2);
+    let seed_2 = seed_1.wrapping_add(1);
+    let random = Vec2::new(
+        random_f32(seeded(globals, seed_1)),
+        random_f32(seeded(globals, seed_2)),
+    );
+
+    match SeedingMode::from_u32(globals.seeding_mode) {
+        mode @ (SeedingMode::Grid | SeedingMode::JitteredGrid) => {
+            let grid = grid_size(globals);
+            let cell = Vec2::new((line_index % grid.x) as f32, (line_index / grid.x) as f32);
+            let offset = if mode == SeedingMode::Grid {
+                Vec2::splat(0.5)
+            } else {
+                random
+            };
+            (cell + offset) / grid.as_vec2()
+        }
+        SeedingMode::PoissonDisk | SeedingMode::BlueNoise
+            if (line_index as usize) < seeds.len() =>
+        {
+            seeds[line_index as usize]
+        }
+        SeedingMode::ImageDensity => match &inputs.density_map {
+            Some(map) => {
+                let viewport = Vec2::new(globals.viewport_width, globals.viewport_height);
+                let image_size = Vec2::new(map.width as f32, map.height as f32);
+                let p = image_to_world(viewport, image_size, map.sample(random));
+                p / (viewport + SEED_PADDING) + 0.5
+            }
+            None => random,
+        },
+        _ => random,
+    }
+}
+
+// Mirrors `LineState` in the compute shader.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LineState {
+    pub alive: bool,
+    pub num_joints: u32,
+    // Distance travelled forwards from the seed in pixels
+    pub length: f32,
+    // False once a bidirectional line stopped growing backwards, always false for other lines
+    pub backward_alive: bool,
+    // Distance travelled backwards from the seed in pixels
+    pub backward_length: f32,
+}
+
+// Mirrors `seed_slot`. The slot of the seed in the joints of a line.
+pub fn seed_slot(globals: &FlowFieldGlobals) -> u32 {
+    if globals.bidirectional == 1 {
+        (globals.max_iterations - 1) / 2
+    } else {
+        0
+    }
+}
+
+// Mirrors `TraceStep`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceStep {
+    // The slot `update` writes
+    pub new_joint: u32,
+    // The slot of the joint it continues from
+    pub prev_joint: u32,
+    // 1 forwards, -1 backwards
+    pub sign: f32,
+}
+
+// Mirrors `trace_step`. Bidirectional lines alternately grow backwards and forwards from the seed,
+// starting with backwards, which splits the iterations between both directions with the extra one
+// going forwards.
+pub fn trace_step(globals: &FlowFieldGlobals, iteration: u32) -> TraceStep {
+    if globals.bidirectional != 1 {
+        return TraceStep {
+            new_joint: iteration,
+            prev_joint: iteration - 1,
+            sign: 1.0,
+        };
+    }
+    let step = iteration - 2;
+    if step.is_multiple_of(2) {
+        let new_joint = seed_slot(globals) - 1 - step / 2;
+        TraceStep {
+            new_joint,
+            prev_joint: new_joint + 1,
+            sign: -1.0,
+        }
+    } else {
+        let new_joint = seed_slot(globals) + 2 + step / 2;
+        TraceStep {
+            new_joint,
+            prev_joint: new_joint - 1,
+            sign: 1.0,
+        }
+    }
+}
+
+// Mirrors `get_trace_direction`. The field direction followed forwards (sign 1) or backwards
+// (sign -1). Backwards it's steered again so it doesn't point into obstacles either.
+pub fn get_trace_direction(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    pos: Vec2,
+    iteration: u32,
+    sign: f32,
+) -> Vec2 {
+    let direction = get_field_direction(globals, inputs, pos, iteration);
+    if sign > 0.0 {
+        direction
+    } else {
+        steer_around_obstacles(&inputs.obstacles, -direction, pos)
+    }
+}
+
+// Mirrors the `init` entry point. The shader runs `init` while the iteration count uniform is
+// still 0, so the first two joints are coloured and modulated as iteration 0.
+fn init(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    line_index: u32,
+    seeds: &[Vec2],
+    mut collision_grid: Option<&mut CollisionGrid>,
+    vertices: &mut [LineVertex],
+) -> LineState {
+    let iteration = 0;
+
+    let viewport = viewport(globals);
+    let viewport_bottom_left = Vec2::new(
+        viewport.x - (viewport.z + SEED_PADDING) / 2.0,
+        viewport.y - (viewport.w + SEED_PADDING) / 2.0,
+    );
+    let seed = seed_position(globals, inputs, line_index, seeds);
+    let joint_1 = Vec2::new(
+        viewport_bottom_left.x + seed.x * (viewport.z + SEED_PADDING),
+        viewport_bottom_left.y + seed.y * (viewport.w + SEED_PADDING),
+    );
+
+    let field_direction = get_field_direction(globals, inputs, joint_1, iteration);
+    let joint_2 = integrate(globals, joint_1, field_direction, |p| {
+        get_field_direction(globals, inputs, p, iteration)
+    });
+
+    let obstacles = &inputs.obstacles;
+    let seed_slot = seed_slot(globals);
+    // Lines that only grow forwards colour both joints as iteration 0
+    let joint_2_slot = seed_slot + globals.bidirectional;
+    let length = joint_1.distance(joint_2);
+    let joint_1_info = JointInfo {
+        line_index,
+        joint_index: seed_slot,
+        along: along_line(globals, line_index, seed_slot),
+        seed: joint_1,
+        distance: 0.0,
+        curvature: 0.0,
+        field_strength: field_magnitude(globals, inputs, joint_1, iteration),
+    };
+    let joint_2_info = JointInfo {
+        joint_index: joint_2_slot,
+        along: along_line(globals, line_index, seed_slot + 1),
+        distance: length,
+        field_strength: field_magnitude(globals, inputs, joint_2, iteration),
+        ..joint_1_info
+    };
+    let direction = joint_direction(globals, joint_1, joint_2, field_direction);
+    let mut joint_1_vertices = hide_masked_joint(
+        obstacles,
+        joint_1,
+        create_vertices_for_line_joint(globals, inputs, joint_1, direction, joint_1_info),
+    );
+    let mut joint_2_vertices = hide_masked_joint(
+        obstacles,
+        joint_2,
+        create_vertices_for_line_joint(globals, inputs, joint_2, direction, joint_2_info),
+    );
+    let bidirectional = globals.bidirectional == 1;
+    let mut state = LineState {
+        alive: true,
+        num_joints: 2,
+        length,
+        backward_alive: bidirectional,
+        backward_length: 0.0,
+    };
+    let mut stops_before = |num_joints, joint| {
+        let grid = collision_grid.as_deref_mut();
+        stops_before(
+            globals, inputs, grid, line_index, num_joints, joint, iteration,
+        )
+    };
+    // Lines that stop at their seed don't move
+    if stops_before(0, joint_1) {
+        joint_1_vertices = stop_line(joint_1_vertices);
+        joint_2_vertices = joint_1_vertices;
+        state = LineState::default();
+    } else if stops_before(1, joint_2) {
+        joint_2_vertices = stop_line(joint_1_vertices);
+        state = LineState {
+            num_joints: 1,
+            backward_alive: bidirectional,
+            ..default()
+        };
+    }
+
+    let v = 2 * seed_slot as usize;
+    vertices[v] = joint_1_vertices.first;
+    vertices[v + 1] = joint_1_vertices.second;
+    vertices[v + 2] = joint_2_vertices.first;
+    vertices[v + 3] = joint_2_vertices.second;
+
+    // Without area for lines that stopped before joint_2
+    let layout = LineLayout::new(globals, globals.max_iterations);
+    write_join(globals, layout, vertices, seed_slot, joint_1, joint_1);
+    write_join(globals, layout, vertices, seed_slot + 1, joint_2, joint_2);
+    write_cap(globals, layout, vertices, 0, seed_slot, joint_2);
+    write_cap(globals, layout, vertices, 1, seed_slot + 1, joint_1);
+
+    state
+}
+
+// Mirrors the `update` entry point for a single line at the given iteration count.
+fn update(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    line_index: u32,
+    iteration: u32,
+    state: &mut LineState,
+    collision_grid: Option<&mut CollisionGrid>,
+    vertices: &mut [LineVertex],
+) {
+    let step = trace_step(globals, iteration);
+    let v = 2 * step.new_joint as usize;
+    let prev_v = 2 * step.prev_joint as usize;
+    let prev_joint_vertices = LineVertexPair {
+        first: vertices[prev_v],
+        second: vertices[prev_v + 1],
+    };
+
+    // Stopped lines repeat the joint where they stopped
+    let alive = if step.sign < 0.0 {
+        &mut state.backward_alive
+    } else {
+        &mut state.alive
+    };
+    let mut new_joint_vertices = prev_joint_vertices;
+    // The joints before and after prev_joint in the order of the slots if a joint was added
+    let mut join = None;
+    if *alive {
+        let prev_joint_v1_pos = prev_joint_vertices.first.position.truncate().truncate();
+        let prev_joint_v2_pos = prev_joint_vertices.second.position.truncate().truncate();
+
+        let prev_joint = prev_joint_v1_pos + 0.5 * (prev_joint_v2_pos - prev_joint_v1_pos);
+
+        let direction = |p| get_trace_direction(globals, inputs, p, iteration, step.sign);
+        let field_direction = direction(prev_joint);
+
+        let new_joint = integrate(globals, prev_joint, field_direction, direction);
+        let num_joints = state.num_joints;
+        if stops_before(
+            globals,
+            inputs,
+            collision_grid,
+            line_index,
+            num_joints,
+            new_joint,
+            iteration,
+        ) {
+            new_joint_vertices = stop_line(prev_joint_vertices);
+            *alive = false;
+        } else {
+            // The joint before prev_joint in the direction of the step
+            let before_v = (prev_v as isize - 2 * step.sign as isize) as usize;
+            let before_joint = joint_positions(&vertices[before_v..before_v + 2])
+                .next()
+                .unwrap();
+            let seed_v = 2 * seed_slot(globals) as usize;
+            let seed = joint_positions(&vertices[seed_v..seed_v + 2])
+                .next()
+                .unwrap();
+
+            state.num_joints += 1;
+            let distance = if step.sign < 0.0 {
+                state.backward_length += prev_joint.distance(new_joint);
+                state.backward_length
+            } else {
+                state.length += prev_joint.distance(new_joint);
+                state.length
+            };
+            let info = JointInfo {
+                line_index,
+                joint_index: step.new_joint,
+                along: along_line(globals, line_index, step.new_joint),
+                seed,
+                distance,
+                curvature: line_curvature(before_joint, prev_joint, new_joint),
+                field_strength: field_magnitude(globals, inputs, new_joint, iteration),
+            };
+            // Normals point the same way along the whole line
+            let direction =
+                step.sign * joint_direction(globals, prev_joint, new_joint, field_direction);
+            new_joint_vertices = hide_masked_joint(
+                &inputs.obstacles,
+                new_joint,
+                create_vertices_for_line_joint(globals, inputs, new_joint, direction, info),
+            );
+            join = Some(if step.sign < 0.0 {
+                (new_joint, before_joint)
+            } else {
+                (before_joint, new_joint)
+            });
+        }
+    }
+
+    vertices[v] = new_joint_vertices.first;
+    vertices[v + 1] = new_joint_vertices.second;
+
+    if let Some((a, c)) = join {
+        let layout = LineLayout::new(globals, globals.max_iterations);
+        let prev_joint = joint_positions(&vertices[prev_v..prev_v + 2])
+            .next()
+            .unwrap();
+        let new_joint = joint_positions(&vertices[v..v + 2]).next().unwrap();
+        write_join(
+            globals,
+            layout,
+            vertices,
+            step.new_joint,
+            new_joint,
+            new_joint,
+        );
+        write_join(globals, layout, vertices, step.prev_joint, a, c);
+        let end = u32::from(step.sign > 0.0);
+        write_cap(globals, layout, vertices, end, step.new_joint, prev_joint);
+    }
+}
+
+// Mirrors `stops_before`. Whether a line that has `num_joints` joints so far ends before reaching
+// `joint`. Marks the collision grid if it doesn't.
+pub fn stops_before(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    collision_grid: Option<&mut CollisionGrid>,
+    line_index: u32,
+    num_joints: u32,
+    joint: Vec2,
+    iteration: u32,
+) -> bool {
+    let viewport = viewport(globals);
+    let half_size = (Vec2::new(viewport.z, viewport.w) + SEED_PADDING) / 2.0;
+    let outside = (joint - Vec2::new(viewport.x, viewport.y))
+        .abs()
+        .cmpgt(half_size)
+        .any();
+    if globals.stop_outside_bounds == 1 && outside {
+        return true;
+    }
+    if num_joints >= max_line_joints(globals, line_index) {
+        return true;
+    }
+    let obstacles = &inputs.obstacles;
+    if is_inside_obstacle(obstacles, ObstacleBehaviour::Stop, joint) {
+        return true;
+    }
+    if globals.stop_in_masks == 1 && is_inside_obstacle(obstacles, ObstacleBehaviour::Mask, joint) {
+        return true;
+    }
+    if field_magnitude(globals, inputs, joint, iteration) < globals.min_field_magnitude {
+        return true;
+    }
+    // Last, so only joints that are drawn take up their cell
+    collision_grid.is_some_and(|grid| grid.collides(line_index, joint))
+}
+
+// Mirrors `max_line_joints`. How many joints a line has at most, randomly shortened by up to
+// `length_variation`.
+pub fn max_line_joints(globals: &FlowFieldGlobals, line_index: u32) -> u32 {
+    // Past the two random numbers per line of `seed_position`
+    let random = random_f32(seeded(
+        globals,
+        (2 * globals.num_lines).wrapping_add(line_index),
+    ));
+    (globals.max_iterations as f32 * (1.0 - globals.length_variation * random)).ceil() as u32
+}
+
+// Mirrors `along_line`. How far along its line the joint in `slot` is, from 0 at the first joint
+// to 1 at the last one the line has once it reaches `max_line_joints`. Bidirectional lines split
+// their joints between both directions like `trace_step`.
+pub fn along_line(globals: &FlowFieldGlobals, line_index: u32, slot: u32) -> f32 {
+    let num_joints = max_line_joints(globals, line_index).max(2);
+    let backward_joints = globals.bidirectional * (num_joints - 1) / 2;
+    let first_slot = seed_slot(globals).saturating_sub(backward_joints);
+    // Lines that stopped early in one direction can get further than that in the other
+    ((slot as f32 - first_slot as f32) / (num_joints - 1) as f32).clamp(0.0, 1.0)
+}
+
+// Must match the constant of the same name in the compute shader. The collision grid is made
+// coarser than `collision_distance` where it would have more cells.
+pub const MAX_COLLISION_CELLS: f32 = 16777216.0;
+
+// Mirrors `collision_grid`, the index + 1 of the highest line that passed through every cell, 0
+// for none. Cells are square and cover the viewport padded like the seeds.
+pub struct CollisionGrid {
+    cell_size: f32,
+    size: UVec2,
+    half_size: Vec2,
+    cells: Vec<u32>,
+}
+
+impl CollisionGrid {
+    // None if collisions are disabled.
+    pub fn new(globals: &FlowFieldGlobals) -> Option<Self> {
+        if globals.collision_distance <= 0.0 {
+            return None;
+        }
+        let size = collision_grid_size(globals);
+        Some(Self {
+            cell_size: collision_cell_size(globals),
+            size,
+            half_size: (Vec2::new(globals.viewport_width, globals.viewport_height) + SEED_PADDING)
+                / 2.0,
+            cells: vec![0; (size.x * size.y) as usize],
+        })
+    }
+
+    // Mirrors `collides`. Marks the cell of `p` as passed through by the line and returns whether
+    // another line passed through it before.
+    pub fn collides(&mut self, line_index: u32, p: Vec2) -> bool {
+        let cell = ((p + self.half_size) / self.cell_size).floor();
+        if cell.cmplt(Vec2::ZERO).any() || cell.cmpge(self.size.as_vec2()).any() {
+            return false;
+        }
+        let i = cell.y as usize * self.size.x as usize + cell.x as usize;
+        let Some(value) = self.cells.get_mut(i) else {
+            return false;
+        };
+        let previous = *value;
+        *value = previous.max(line_index + 1);
+        previous != 0 && previous != line_index + 1
+    }
+}
+
+pub fn collision_cell_size(globals: &FlowFieldGlobals) -> f32 {
+    let area = (globals.viewport_width + SEED_PADDING) * (globals.viewport_height + SEED_PADDING);
+    globals
+        .collision_distance
+        .max((area / MAX_COLLISION_CELLS).sqrt())
+}
+
+pub fn collision_grid_size(globals: &FlowFieldGlobals) -> UVec2 {
+    let size = Vec2::new(globals.viewport_width, globals.viewport_height) + SEED_PADDING;
+    (size / collision_cell_size(globals)).ceil().as_uvec2()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Integrator {
+    #[default]
+    Euler,
+    Midpoint,
+    Rk4,
+    // Runge-Kutta-Fehlberg 4(5), splits a step into substeps until the error estimate of each is
+    // below `integrator_tolerance`
+    Rk45,
+}
+
+impl Integrator {
+    pub const ALL: [Integrator; 4] = [
+        Integrator::Euler,
+        Integrator::Midpoint,
+        Integrator::Rk4,
+        Integrator::Rk45,
+    ];
+
+    pub fn from_u32(value: u32) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Integrator::Euler => "Euler",
+            Integrator::Midpoint => "Midpoint",
+            Integrator::Rk4 => "RK4",
+            Integrator::Rk45 => "Adaptive RK45",
+        }
+    }
+}
+
+// Must match the constants of the same name in the compute shader.
+pub const RK45_MAX_SUBSTEPS: u32 = 32;
+// Substeps aren't made smaller than step_size / RK45_MIN_SUBSTEP_FRACTION.
+pub const RK45_MIN_SUBSTEP_FRACTION: f32 = 64.0;
+
+// Advances `p` by `step_size` along the field given by `direction`. `k1` is the direction at `p`,
+// which the caller already has.
+pub fn integrate(
+    globals: &FlowFieldGlobals,
+    p: Vec2,
+    k1: Vec2,
+    direction: impl Fn(Vec2) -> Vec2,
+) -> Vec2 {
+    let h = globals.step_size;
+    match Integrator::from_u32(globals.integrator) {
+        Integrator::Euler => p + k1 * h,
+        Integrator::Midpoint => {
+            let k2 = direction(p + k1 * (h / 2.0));
+            p + k2 * h
+        }
+        Integrator::Rk4 => {
+            let k2 = direction(p + k1 * (h / 2.0));
+            let k3 = direction(p + k2 * (h / 2.0));
+            let k4 = direction(p + k3 * h);
+            p + (k1 + 2.0 * k2 + 2.0 * k3 + k4) * (h / 6.0)
+        }
+        Integrator::Rk45 => integrate_rk45(globals, p, k1, direction),
+    }
+}
+
+fn integrate_rk45(
+    globals: &FlowFieldGlobals,
+    mut p: Vec2,
+    mut k1: Vec2,
+    direction: impl Fn(Vec2) -> Vec2,
+) -> Vec2 {
+    let min_h = globals.step_size / RK45_MIN_SUBSTEP_FRACTION;
+    let mut remaining = globals.step_size;
+    let mut h = globals.step_size;
+    for _ in 0..RK45_MAX_SUBSTEPS {
+        if remaining <= 0.0 {
+            break;
+        }
+        h = h.min(remaining);
+        let (y4, y5) = rk45_substep(p, k1, h, &direction);
+
+        let error = (y5 - y4).length();
+        // Standard step size control with a safety factor of 0.9, growing at most 4x per substep
+        let factor = if error > 0.0 {
+            (0.9 * (globals.integrator_tolerance / error).powf(0.2)).clamp(0.1, 4.0)
+        } else {
+            4.0
+        };
+        if error <= globals.integrator_tolerance || h <= min_h {
+            p = y5;
+            remaining -= h;
+            k1 = direction(p);
+        }
+        h = (h * factor).max(min_h);
+    }
+    // Whatever the substeps didn't cover is taken in one go, so joints stay step_size apart
+    if remaining > 0.0 {
+        p = rk45_substep(p, k1, remaining, &direction).1;
+    }
+    p
+}
+
+// Mirrors `rk45_substep`. The 4th and 5th order Runge-Kutta-Fehlberg estimates of `p` moved by `h`.
+fn rk45_substep(p: Vec2, k1: Vec2, h: f32, direction: &impl Fn(Vec2) -> Vec2) -> (Vec2, Vec2) {
+    let k2 = direction(p + h * (k1 / 4.0));
+    let k3 = direction(p + h * (3.0 / 32.0 * k1 + 9.0 / 32.0 * k2));
+    let k4 =
+        direction(p + h * (1932.0 / 2197.0 * k1 - 7200.0 / 2197.0 * k2 + 7296.0 / 2197.0 * k3));
+    let k5 = direction(
+        p + h * (439.0 / 216.0 * k1 - 8.0 * k2 + 3680.0 / 513.0 * k3 - 845.0 / 4104.0 * k4),
+    );
+    let k6 = direction(
+        p + h
+            * (-8.0 / 27.0 * k1 + 2.0 * k2 - 3544.0 / 2565.0 * k3 + 1859.0 / 4104.0 * k4
+                - 11.0 / 40.0 * k5),
+    );
+    let y4 = p + h * (25.0 / 216.0 * k1 + 1408.0 / 2565.0 * k3 + 2197.0 / 4104.0 * k4 - k5 / 5.0);
+    let y5 = p + h
+        * (16.0 / 135.0 * k1 + 6656.0 / 12825.0 * k3 + 28561.0 / 56430.0 * k4 - 9.0 / 50.0 * k5
+            + 2.0 / 55.0 * k6);
+    (y4, y5)
+}
+
+// The two triangles between the pairs of vertices starting at `start` and `end`, see
+// `segment_vertices`.
+pub fn segment_indices(start: u32, end: u32) -> [u32; 6] {
+    [start, start + 1, end + 1, start, end + 1, end]
+}
+
+// The `view.viewport` the shader sees for the default 2d camera: origin at 0 with the size of the window.
+fn viewport(globals: &FlowFieldGlobals) -> Vec4 {
+    Vec4::new(0.0, 0.0, globals.viewport_width, globals.viewport_height)
+}
+
+// What lines are coloured by, from `line_color_start` to `line_color_end` or along the gradient.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ColorMode {
+    // Along the line, by the iteration that traced the joint
+    #[default]
+    Iteration,
+    // By the length of the vector grid vector at the joint relative to the longest one. Lines
+    // are coloured by iteration without a vector grid.
+    Magnitude,
+    // By the direction of the line at the joint, once around the colours per turn
+    FieldAngle,
+    // Every line gets a random colour
+    LineRandom,
+    // By where the line was seeded, from the left of the viewport to the right
+    SeedPosition,
+    // Straight lines get the start colour, bends with `color_curvature_radius` or tighter the end
+    Curvature,
+    // By the distance from the seed along the line, relative to the longest a line can get
+    Distance,
+    // By the luminance of the field image at the joint. Lines are coloured by iteration without
+    // an image.
+    ImageLuminance,
+}
+
+impl ColorMode {
+    pub const ALL: [ColorMode; 8] = [
+        ColorMode::Iteration,
+        ColorMode::Magnitude,
+        ColorMode::FieldAngle,
+        ColorMode::LineRandom,
+        ColorMode::SeedPosition,
+        ColorMode::Curvature,
+        ColorMode::Distance,
+        ColorMode::ImageLuminance,
+    ];
+
+    pub fn from_u32(value: u32) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorMode::Iteration => "Iteration",
+            ColorMode::Magnitude => "Vector magnitude",
+            ColorMode::FieldAngle => "Field angle",
+            ColorMode::LineRandom => "Random per line",
+            ColorMode::SeedPosition => "Seed position",
+            ColorMode::Curvature => "Curvature",
+            ColorMode::Distance => "Distance travelled",
+            ColorMode::ImageLuminance => "Image luminance",
+        }
+    }
+}
+
+// Whether lines take their colour from `FlowFieldInputs::color_image` instead of the line colours.
+// The opacity still comes from the line colours.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ImageColorMode {
+    #[default]
+    Off,
+    // The colour of the image at the seed of the line
+    PerLine,
+    // The colour of the image at every joint
+    PerJoint,
+}
+
+impl ImageColorMode {
+    pub const ALL: [ImageColorMode; 3] = [
+        ImageColorMode::Off,
+        ImageColorMode::PerLine,
+        ImageColorMode::PerJoint,
+    ];
+
+    pub fn from_u32(value: u32) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageColorMode::Off => "Off",
+            ImageColorMode::PerLine => "Per line",
+            ImageColorMode::PerJoint => "Per joint",
+        }
+    }
+}
+
+// How the width of lines changes along them, as a factor of `line_width`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum WidthMode {
+    #[default]
+    Constant,
+    // From `min_width` at both ends to full width over `width_taper` of the line's own
+    // `max_line_joints`. Bidirectional lines split those between both directions from the seed.
+    // Lines that stop early, like at collisions, end at the width they had.
+    Taper,
+    // Swells `width_pulses` times between `min_width` and full width along the line
+    SinePulse,
+    // Along `FlowFieldInputs::width_curve`
+    Curve,
+    // Every line gets a random width between `min_width` and full width
+    LineRandom,
+    // Full width where `field_magnitude` is `width_field_scale` or more
+    FieldStrength,
+}
+
+impl WidthMode {
+    pub const ALL: [WidthMode; 6] = [
+        WidthMode::Constant,
+        WidthMode::Taper,
+        WidthMode::SinePulse,
+        WidthMode::Curve,
+        WidthMode::LineRandom,
+        WidthMode::FieldStrength,
+    ];
+
+    pub fn from_u32(value: u32) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WidthMode::Constant => "Constant",
+            WidthMode::Taper => "Taper",
+            WidthMode::SinePulse => "Sine pulse",
+            WidthMode::Curve => "Curve",
+            WidthMode::LineRandom => "Random per line",
+            WidthMode::FieldStrength => "Field strength",
+        }
+    }
+}
+
+// Mirrors `JointInfo`. Where a joint is in its line, for colouring it and setting its width.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JointInfo {
+    pub line_index: u32,
+    // The slot of the joint in its line
+    pub joint_index: u32,
+    // From 0 at the first joint of the line to 1 at the last, see `along_line`
+    pub along: f32,
+    // The first joint the line was traced from
+    pub seed: Vec2,
+    // Along the line from the seed in pixels
+    pub distance: f32,
+    // Of the line at the joint in 1/pixels
+    pub curvature: f32,
+    // `field_magnitude` at the joint
+    pub field_strength: f32,
+}
+
+// Mirrors `line_curvature`. How much a line turns at joint `b` on its way from `a` to `c`.
+pub fn line_curvature(a: Vec2, b: Vec2, c: Vec2) -> f32 {
+    let (d1, d2) = (b - a, c - b);
+    let step = d2.length();
+    if d1.length() == 0.0 || step == 0.0 {
+        return 0.0;
+    }
+    d1.perp_dot(d2).atan2(d1.dot(d2)).abs() / step
+}
+
+// Mirrors `color_position`. The position of a joint along the line colours in [0, 1].
+// `field_direction` points towards the end of the line.
+pub fn color_position(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    joint: Vec2,
+    field_direction: Vec2,
+    info: JointInfo,
+) -> f32 {
+    let viewport = Vec2::new(globals.viewport_width, globals.viewport_height);
+    match ColorMode::from_u32(globals.color_mode) {
+        ColorMode::Magnitude => {
+            if let Some(grid) = &inputs.vector_grid {
+                return grid.sample(viewport, joint).length()
+                    / grid.max_magnitude.max(f32::MIN_POSITIVE);
+            }
+        }
+        ColorMode::FieldAngle => {
+            return field_direction.y.atan2(field_direction.x) / std::f32::consts::TAU + 0.5;
+        }
+        ColorMode::LineRandom => {
+            return random_f32(seeded(globals, 3 * globals.num_lines + info.line_index));
+        }
+        ColorMode::SeedPosition => return info.seed.x / globals.viewport_width + 0.5,
+        ColorMode::Curvature => return info.curvature * globals.color_curvature_radius,
+        ColorMode::Distance => {
+            // The longest a line can get in one direction
+            let max_distance =
+                (globals.max_iterations - 1 - seed_slot(globals)) as f32 * globals.step_size;
+            return info.distance / max_distance.max(f32::MIN_POSITIVE);
+        }
+        ColorMode::ImageLuminance => {
+            if let Some(image) = &inputs.field_image {
+                return luma(image.sample(viewport, joint));
+            }
+        }
+        ColorMode::Iteration => {}
+    }
+    info.joint_index as f32 / globals.max_iterations as f32
+}
+
+// Mirrors `width_factor`. The width of a joint relative to `line_width`.
+pub fn width_factor(globals: &FlowFieldGlobals, inputs: &FlowFieldInputs, info: JointInfo) -> f32 {
+    let min_width = globals.min_width;
+    let t = info.along;
+    let f = match WidthMode::from_u32(globals.width_mode) {
+        WidthMode::Constant => return 1.0,
+        WidthMode::Taper => {
+            let x = (t.min(1.0 - t) / globals.width_taper.max(f32::MIN_POSITIVE)).clamp(0.0, 1.0);
+            x * x * (3.0 - 2.0 * x)
+        }
+        WidthMode::SinePulse => {
+            0.5 - 0.5 * (std::f32::consts::TAU * globals.width_pulses * t).cos()
+        }
+        WidthMode::Curve => return inputs.width_curve.sample(t),
+        WidthMode::LineRandom => {
+            random_f32(seeded(globals, 4 * globals.num_lines + info.line_index))
+        }
+        WidthMode::FieldStrength => {
+            (info.field_strength / globals.width_field_scale.max(f32::MIN_POSITIVE)).min(1.0)
+        }
+    };
+    min_width + (1.0 - min_width) * f
+}
+
+pub fn create_vertices_for_line_joint(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    joint: Vec2,
+    field_direction: Vec2,
+    info: JointInfo,
+) -> LineVertexPair {
+    let line_width = globals.line_width * width_factor(globals, inputs, info);
+    let line_normal = Vec2::new(field_direction.y, -field_direction.x).normalize();
+    let p_1 = joint - line_normal * line_width / 2.0;
+    let p_2 = joint + line_normal * line_width / 2.0;
+
+    let f = color_position(globals, inputs, joint, field_direction, info);
+    let mut c = match &inputs.gradient {
+        Some(gradient) => gradient.sample(f),
+        None => globals.line_color_start * (1.0 - f) + globals.line_color_end * f,
+    };
+    if let Some(image) = &inputs.color_image {
+        let viewport = Vec2::new(globals.viewport_width, globals.viewport_height);
+        let sample = match ImageColorMode::from_u32(globals.image_color_mode) {
+            ImageColorMode::Off => None,
+            ImageColorMode::PerLine => Some(image.sample(viewport, info.seed)),
+            ImageColorMode::PerJoint => Some(image.sample(viewport, joint)),
+        };
+        if let Some(sample) = sample {
+            // The image is sRGB, the line colours linear
+            let rgb = sample.truncate().to_array().map(srgb_to_linear);
+            c = Vec3::from_array(rgb).extend(c.w * sample.w);
+        }
+    }
+
+    LineVertexPair {
+        first: LineVertex {
+            position: Vec4::new(p_1.x, p_1.y, 0.0, 0.0),
+            color: c,
+        },
+        second: LineVertex {
+            position: Vec4::new(p_2.x, p_2.y, 0.0, 0.0),
+            color: c,
+        },
+    }
+}
+
+// Mirrors `hide_masked_joint`. Collapses the vertices of a joint inside a mask onto the joint and
+// marks it as masked.
+pub fn hide_masked_joint(
+    obstacles: &[Obstacle],
+    joint: Vec2,
+    vertices: LineVertexPair,
+) -> LineVertexPair {
+    if !is_inside_obstacle(obstacles, ObstacleBehaviour::Mask, joint) {
+        return vertices;
+    }
+    let position = Vec4::new(joint.x, joint.y, JOINT_MASKED, 0.0);
+    LineVertexPair {
+        first: LineVertex {
+            position,
+            ..vertices.first
+        },
+        second: LineVertex {
+            position,
+            ..vertices.second
+        },
+    }
+}
+
+// Mirrors `stop_line`. The joint marked as the one where its line stopped.
+pub fn stop_line(mut vertices: LineVertexPair) -> LineVertexPair {
+    vertices.first.position.w = LINE_STOPPED;
+    vertices.second.position.w = LINE_STOPPED;
+    vertices
+}
+
+// How the segments of a line meet at its joints. The vertices of a joint are moved out along the
+// bisector of its segments so the quads on either side share an edge without overlapping.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LineJoin {
+    // The two vertices of every joint are across the field direction there, as before there were
+    // joins. Takes no extra vertices, but sharp turns twist or overlap their quads.
+    #[default]
+    None,
+    // To a point, or cut off straight like a bevel where that is more than `miter_limit` times
+    // half the line width from the joint
+    Miter,
+    // Sharp corners are cut off straight
+    Bevel,
+    // Sharp corners are rounded off
+    Round,
+}
+
+impl LineJoin {
+    pub const ALL: [LineJoin; 4] = [
+        LineJoin::None,
+        LineJoin::Miter,
+        LineJoin::Bevel,
+        LineJoin::Round,
+    ];
+
+    pub fn from_u32(value: u32) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineJoin::None => "None",
+            LineJoin::Miter => "Miter",
+            LineJoin::Bevel => "Bevel",
+            LineJoin::Round => "Round",
+        }
+    }
+}
+
+// What the ends of lines look like.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LineCap {
+    // Square at the last joint
+    #[default]
+    Butt,
+    // Square, half the line width past the last joint
+    Square,
+    Round,
+}
+
+impl LineCap {
+    pub const ALL: [LineCap; 3] = [LineCap::Butt, LineCap::Square, LineCap::Round];
+
+    pub fn from_u32(value: u32) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineCap::Butt => "Butt",
+            LineCap::Square => "Square",
+            LineCap::Round => "Round",
+        }
+    }
+}
+
+// Must match the constants of the same name in the compute shader. The number of triangles
+// rounding off a join or cap.
+pub const ROUND_JOIN_TRIANGLES: u32 = 4;
+pub const ROUND_CAP_TRIANGLES: u32 = 8;
+
+// Must match the constant of the same name in the compute shader. Bevel and round joins are only
+// drawn where they are further than this many pixels from a miter.
+pub const JOIN_TOLERANCE: f32 = 0.25;
+
+// Mirrors `LineLayout`. Where the parts of a line are in its vertices and indices, for a line of
+// `num_joints` joints.
+//
+// The vertices start with two per joint, followed by the joins of all joints and then the caps of
+// the start and end of the line. The two vertices of a joint are always centred on it, so they
+// give the traced line. A join is the pair of vertices the segment before the joint ends at, the
+// pair the segment after it starts at, and a fan of triangles filling the outside of the corner
+// between them. A cap is the last joint and the fan around it. Joins are only there for bevel and
+// round joins, caps for square and round caps.
+//
+// The indices start with two triangles per segment, followed by the fans of the joins and caps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LineLayout {
+    pub num_joints: u32,
+    pub join_triangles: u32,
+    pub cap_triangles: u32,
+}
+
+impl LineLayout {
+    pub fn new(globals: &FlowFieldGlobals, num_joints: u32) -> Self {
+        Self {
+            num_joints,
+            join_triangles: match LineJoin::from_u32(globals.line_join) {
+                LineJoin::None => 0,
+                // For the bevel past the miter limit
+                LineJoin::Miter | LineJoin::Bevel => 1,
+                LineJoin::Round => ROUND_JOIN_TRIANGLES,
+            },
+            cap_triangles: match LineCap::from_u32(globals.line_cap) {
+                LineCap::Butt => 0,
+                LineCap::Square => 3,
+                LineCap::Round => ROUND_CAP_TRIANGLES,
+            },
+        }
+    }
+
+    // The layout of a line with as many joints as fit into `num_vertices`.
+    pub fn of_vertices(globals: &FlowFieldGlobals, num_vertices: usize) -> Self {
+        let layout = Self::new(globals, 0);
+        let num_joints =
+            (num_vertices as u32 - 2 * layout.cap_vertices()) / (2 + layout.join_vertices());
+        Self {
+            num_joints,
+            ..layout
+        }
+    }
+
+    pub fn join_vertices(self) -> u32 {
+        if self.join_triangles == 0 {
+            0
+        } else {
+            self.join_triangles + 6
+        }
+    }
+
+    pub fn cap_vertices(self) -> u32 {
+        if self.cap_triangles == 0 {
+            0
+        } else {
+            self.cap_triangles + 2
+        }
+    }
+
+    pub fn num_vertices(self) -> u32 {
+        self.num_joints * (2 + self.join_vertices()) + 2 * self.cap_vertices()
+    }
+
+    pub fn num_indices(self) -> u32 {
+        6 * (self.num_joints - 1)
+            + 3 * self.num_joints * self.join_triangles
+            + 6 * self.cap_triangles
+    }
+
+    pub fn join_vertex(self, joint: u32) -> u32 {
+        2 * self.num_joints + self.join_vertices() * joint
+    }
+
+    // End 0 is the start of the line, 1 the end.
+    pub fn cap_vertex(self, end: u32) -> u32 {
+        self.num_joints * (2 + self.join_vertices()) + self.cap_vertices() * end
+    }
+
+    pub fn join_index(self, joint: u32) -> u32 {
+        6 * (self.num_joints - 1) + 3 * self.join_triangles * joint
+    }
+
+    pub fn cap_index(self, end: u32) -> u32 {
+        6 * (self.num_joints - 1)
+            + 3 * self.num_joints * self.join_triangles
+            + 3 * self.cap_triangles * end
+    }
+
+    // The two vertices per joint, i.e. without joins and caps.
+    pub fn joint_vertices(self, vertices: &[LineVertex]) -> &[LineVertex] {
+        &vertices[..2 * self.num_joints as usize]
+    }
+}
+
+// Mirrors `segment_vertices`. The first vertex of the pair at either end of the segment from
+// joint `segment` to the next.
+pub fn segment_vertices(layout: LineLayout, segment: u32) -> (u32, u32) {
+    if layout.join_triangles == 0 {
+        (2 * segment, 2 * (segment + 1))
+    } else {
+        (
+            layout.join_vertex(segment) + 2,
+            layout.join_vertex(segment + 1),
+        )
+    }
+}
+
+// Mirrors `segment_direction`. The direction from `a` to `b`, or `fallback` where they are the
+// same.
+pub fn segment_direction(a: Vec2, b: Vec2, fallback: Vec2) -> Vec2 {
+    let d = b - a;
+    if d.length() == 0.0 {
+        fallback
+    } else {
+        d.normalize()
+    }
+}
+
+// Mirrors `joint_direction`. The direction the vertices of a new joint `b` traced from `a` are put
+// across. Joins need it to be the segment between them, without joins it's the field direction
+// like before there were any.
+pub fn joint_direction(
+    globals: &FlowFieldGlobals,
+    a: Vec2,
+    b: Vec2,
+    field_direction: Vec2,
+) -> Vec2 {
+    if LineJoin::from_u32(globals.line_join) == LineJoin::None {
+        field_direction
+    } else {
+        segment_direction(a, b, field_direction)
+    }
+}
+
+// Mirrors `write_join`. Joins the segment from `a` to the joint in slot `joint` with the one
+// from the joint to `c`, where `a` comes before `c` in the slots. The ends of a line pass the
+// joint itself for the neighbour they don't have. The vertices of the joint have to be the ones
+// made for it, which are at right angles to a segment and give its width. Does nothing without
+// joins.
+pub fn write_join(
+    globals: &FlowFieldGlobals,
+    layout: LineLayout,
+    vertices: &mut [LineVertex],
+    joint: u32,
+    a: Vec2,
+    c: Vec2,
+) {
+    let line_join = LineJoin::from_u32(globals.line_join);
+    if line_join == LineJoin::None {
+        return;
+    }
+    let v = 2 * joint as usize;
+    let mut pair = LineVertexPair {
+        first: vertices[v],
+        second: vertices[v + 1],
+    };
+    let (flags, color) = (pair.first.position, pair.first.color);
+    let at = |p: Vec2| LineVertex {
+        position: p.extend(flags.z).extend(flags.w),
+        color,
+    };
+    let b = joint_positions(&vertices[v..v + 2]).next().unwrap();
+    let half_width = pair.first.position.distance(pair.second.position) / 2.0;
+    let (mut pair_in, mut pair_out) = (pair, pair);
+    let mut apex = b;
+    let mut fan = [b; ROUND_JOIN_TRIANGLES as usize + 1];
+
+    let (d_in, d_out) = (b - a, c - b);
+    if half_width > 0.0 && d_in.length() > 0.0 && d_out.length() > 0.0 {
+        let n_in = Vec2::new(d_in.y, -d_in.x).normalize();
+        let n_out = Vec2::new(d_out.y, -d_out.x).normalize();
+        let bisector = (n_in + n_out).normalize_or_zero();
+        // Of half the angle the line turns by
+        let cos_half_turn = bisector.dot(n_in);
+        let miter = half_width / cos_half_turn.max(f32::MIN_POSITIVE);
+        let error = match line_join {
+            // Past the limit the join is a bevel, which keeps the ribbon its full width
+            LineJoin::Miter if miter > globals.miter_limit * half_width => f32::INFINITY,
+            LineJoin::None | LineJoin::Miter => 0.0,
+            LineJoin::Bevel => miter - half_width * cos_half_turn,
+            LineJoin::Round => miter - half_width,
+        };
+        // Lines that turn right around have no bisector
+        if bisector != Vec2::ZERO && error <= JOIN_TOLERANCE {
+            pair = LineVertexPair {
+                first: at(b - bisector * miter),
+                second: at(b + bisector * miter),
+            };
+            (pair_in, pair_out) = (pair, pair);
+        } else {
+            // 1 where the second vertices are on the outside of the turn
+            let side = if d_in.perp_dot(d_out) > 0.0 {
+                1.0
+            } else {
+                -1.0
+            };
+            // The segments meet on the inside where their edges cross, but no further back than
+            // half way to the next joints so they don't cut into their joins. Lines that turn
+            // right around overlap instead.
+            if bisector != Vec2::ZERO {
+                let sin_half_turn = (1.0 - cos_half_turn * cos_half_turn).sqrt();
+                let reach = 0.5 * d_in.length().min(d_out.length()) / sin_half_turn;
+                apex = b - side * bisector * miter.min(reach);
+            }
+            let segment_pair = |normal: Vec2| {
+                let inner = if apex == b {
+                    b - side * normal * half_width
+                } else {
+                    apex
+                };
+                let outer = b + side * normal * half_width;
+                let (first, second) = if side > 0.0 {
+                    (inner, outer)
+                } else {
+                    (outer, inner)
+                };
+                LineVertexPair {
+                    first: at(first),
+                    second: at(second),
+                }
+            };
+            (pair_in, pair_out) = (segment_pair(n_in), segment_pair(n_out));
+            // Around the outside of the turn, from one pair to the other
+            let arc_start = side * half_width * n_in;
+            let arc_end = side * half_width * n_out;
+            let angle = arc_start.perp_dot(arc_end).atan2(arc_start.dot(arc_end));
+            let num_triangles = layout.join_triangles.max(1);
+            for (k, point) in fan.iter_mut().take(num_triangles as usize + 1).enumerate() {
+                let f = k as f32 / num_triangles as f32;
+                *point = b + Vec2::from_angle(angle * f).rotate(arc_start);
+            }
+        }
+    }
+
+    vertices[v] = pair.first;
+    vertices[v + 1] = pair.second;
+    if layout.join_triangles == 0 {
+        return;
+    }
+    let j = layout.join_vertex(joint) as usize;
+    vertices[j] = pair_in.first;
+    vertices[j + 1] = pair_in.second;
+    vertices[j + 2] = pair_out.first;
+    vertices[j + 3] = pair_out.second;
+    vertices[j + 4] = at(apex);
+    for k in 0..=layout.join_triangles as usize {
+        vertices[j + 5 + k] = at(fan[k]);
+    }
+}
+
+// Mirrors `write_cap`. The cap of end `end` of a line (see `LineLayout::cap_vertex`) at the joint
+// in slot `joint`, pointing away from `neighbour`, the joint next to it in the line.
+pub fn write_cap(
+    globals: &FlowFieldGlobals,
+    layout: LineLayout,
+    vertices: &mut [LineVertex],
+    end: u32,
+    joint: u32,
+    neighbour: Vec2,
+) {
+    if layout.cap_triangles == 0 {
+        return;
+    }
+    let v = 2 * joint as usize;
+    let (flags, color) = (vertices[v].position, vertices[v].color);
+    let at = |p: Vec2| LineVertex {
+        position: p.extend(flags.z).extend(flags.w),
+        color,
+    };
+    let first = vertices[v].position.truncate().truncate();
+    let second = vertices[v + 1].position.truncate().truncate();
+    let b = first + 0.5 * (second - first);
+    let half_width = first.distance(second) / 2.0;
+    let outwards = segment_direction(neighbour, b, Vec2::ZERO);
+
+    let c = layout.cap_vertex(end) as usize;
+    vertices[c] = at(b);
+    for k in 0..=layout.cap_triangles {
+        let mut point = b;
+        if half_width > 0.0 && outwards != Vec2::ZERO {
+            point = match LineCap::from_u32(globals.line_cap) {
+                LineCap::Square => [
+                    first,
+                    first + outwards * half_width,
+                    second + outwards * half_width,
+                    second,
+                ][k.min(3) as usize],
+                _ => {
+                    // Half a circle from the first vertex to the second
+                    let normal = (second - first) / (2.0 * half_width);
+                    let angle = std::f32::consts::PI * k as f32 / layout.cap_triangles as f32;
+                    b + half_width * (outwards * angle.sin() - normal * angle.cos())
+                }
+            };
+        }
+        vertices[c + 1 + k as usize] = at(point);
+    }
+}
+
+// How the noise is turned into the direction of the field.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum FieldMode {
+    // The noise value is the angle
+    #[default]
+    Angle,
+    // The noise is a stream function and the field follows its curl, which is divergence-free
+    Curl,
+    // The luminance of `FlowFieldInputs::field_image` is the angle
+    ImageLuminance,
+    // Perpendicular to the luminance gradient of the image, so lines follow its contours
+    ImageContour,
+    // Red and green are the x and y of the direction (green up), as in normal and flow maps
+    ImageVector,
+    // Follows `FlowFieldInputs::vector_grid`, and the noise where it has no flow
+    VectorGrid,
+    // Follows `FlowFieldInputs::field_expression`, and the noise where it is zero
+    Expression,
+}
+
+impl FieldMode {
+    pub const ALL: [FieldMode; 7] = [
+        FieldMode::Angle,
+        FieldMode::Curl,
+        FieldMode::ImageLuminance,
+        FieldMode::ImageContour,
+        FieldMode::ImageVector,
+        FieldMode::VectorGrid,
+        FieldMode::Expression,
+    ];
+
+    pub fn from_u32(value: u32) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FieldMode::Angle => "Noise angle",
+            FieldMode::Curl => "Curl noise",
+            FieldMode::ImageLuminance => "Image luminance",
+            FieldMode::ImageContour => "Image contours",
+            FieldMode::ImageVector => "Normal/flow map",
+            FieldMode::VectorGrid => "Vector data",
+            FieldMode::Expression => "Expression",
+        }
+    }
+}
+
+// Must match `CURL_EPSILON` in the compute shader. In noise space, where features are about 1 apart.
+pub const CURL_EPSILON: f32 = 0.01;
+
+// Must match `IMAGE_FIELD_EPSILON` in the compute shader. Image field vectors up to two steps of 8
+// bit colour long have no direction, which covers flat areas and the neutral (128, 128) of normal
+// maps. The field uses the noise angle there, and everywhere if no image is loaded.
+pub const IMAGE_FIELD_EPSILON: f32 = 2.0 / 255.0;
+
+// Uses the same truncated constants as the shader rather than TAU and PI.
+#[allow(clippy::approx_constant)]
+pub fn get_field_angle(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    pos: Vec2,
+    iteration: u32,
+) -> f32 {
+    let offset = Vec2::new(globals.field_offset_x, globals.field_offset_y);
+    let p = warp(
+        globals,
+        (pos + offset) * globals.noise_scale + seed_noise_offset(globals),
+    );
+    let noise_angle = match FieldMode::from_u32(globals.field_mode) {
+        FieldMode::Angle => 6.2832 * fractal_noise(globals, p),
+        FieldMode::Curl => {
+            let curl = get_curl(globals, p);
+            curl.y.atan2(curl.x)
+        }
+        FieldMode::ImageLuminance | FieldMode::ImageContour | FieldMode::ImageVector => {
+            let v = image_field_vector(globals, inputs, pos);
+            if v.length() > IMAGE_FIELD_EPSILON {
+                v.y.atan2(v.x)
+            } else {
+                6.2832 * fractal_noise(globals, p)
+            }
+        }
+        FieldMode::VectorGrid => {
+            let v = vector_grid_vector(globals, inputs.vector_grid.as_deref(), pos);
+            if v != Vec2::ZERO {
+                v.y.atan2(v.x)
+            } else {
+                6.2832 * fractal_noise(globals, p)
+            }
+        }
+        FieldMode::Expression => {
+            let v = inputs
+                .field_expression
+                .as_ref()
+                .map_or(Vec2::ZERO, |e| e.evaluate(globals, pos, iteration as f32));
+            // Also false for NaN
+            if v.length() > 0.0 {
+                v.y.atan2(v.x)
+            } else {
+                6.2832 * fractal_noise(globals, p)
+            }
+        }
+    };
+    let field_angle = noise_angle
+        + 3.1415
+            * globals.angle_modulation_strength
+            * (iteration as f32 * globals.angle_modulation_frequency).sin();
+
+    if globals.num_angles_allowed > 0 {
+        let angle_multiple = 6.2832 / globals.num_angles_allowed as f32;
+        // WGSL `round` rounds halfway cases to even.
+        let n = (field_angle / angle_multiple).round_ties_even();
+        n * angle_multiple
+    } else {
+        field_angle
+    }
+}
+
+// Curl of the noise as a stream function, (dn/dy, -dn/dx), by central differences.
+pub fn get_curl(globals: &FlowFieldGlobals, p: Vec2) -> Vec2 {
+    let dx = Vec2::new(CURL_EPSILON, 0.0);
+    let dy = Vec2::new(0.0, CURL_EPSILON);
+    let dn_dx = fractal_noise(globals, p + dx) - fractal_noise(globals, p - dx);
+    let dn_dy = fractal_noise(globals, p + dy) - fractal_noise(globals, p - dy);
+    Vec2::new(dn_dy, -dn_dx) / (2.0 * CURL_EPSILON)
+}
+
+// Mirrors `image_field_vector` in the compute shader. The direction of the image field modes at
+// world position `pos`, zero without an image.
+#[allow(clippy::approx_constant)]
+pub fn image_field_vector(globals: &FlowFieldGlobals, inputs: &FlowFieldInputs, pos: Vec2) -> Vec2 {
+    let Some(image) = &inputs.field_image else {
+        return Vec2::ZERO;
+    };
+    let viewport = Vec2::new(globals.viewport_width, globals.viewport_height);
+    let sample_luma = |p: Vec2| luma(image.sample(viewport, p));
+    match FieldMode::from_u32(globals.field_mode) {
+        FieldMode::ImageLuminance => {
+            let angle = 6.2832 * sample_luma(pos);
+            Vec2::new(angle.cos(), angle.sin())
+        }
+        FieldMode::ImageContour => {
+            // Luminance differences across `2 * image_gradient_step` pixels
+            let dx = Vec2::new(globals.image_gradient_step, 0.0);
+            let dy = Vec2::new(0.0, globals.image_gradient_step);
+            let gx = sample_luma(pos + dx) - sample_luma(pos - dx);
+            let gy = sample_luma(pos + dy) - sample_luma(pos - dy);
+            Vec2::new(-gy, gx)
+        }
+        _ => {
+            let color = image.sample(viewport, pos);
+            Vec2::new(color.x, color.y) * 2.0 - 1.0
+        }
+    }
+}
+
+// The interpolated vector grid at world position `pos`, zero without a grid.
+pub fn vector_grid_vector(
+    globals: &FlowFieldGlobals,
+    grid: Option<&VectorGrid>,
+    pos: Vec2,
+) -> Vec2 {
+    let viewport = Vec2::new(globals.viewport_width, globals.viewport_height);
+    grid.map_or(Vec2::ZERO, |grid| grid.sample(viewport, pos))
+}
+
+pub fn get_field_direction(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    pos: Vec2,
+    iteration: u32,
+) -> Vec2 {
+    let field_angle = get_field_angle(globals, inputs, pos, iteration);
+    let field_direction = Vec2::new(field_angle.cos(), field_angle.sin()).normalize();
+    steer_around_obstacles(
+        &inputs.obstacles,
+        apply_influences(&inputs.influences, field_direction, pos),
+        pos,
+    )
+}
+
+// Mirrors `field_magnitude`. Length of the vector the field direction is taken from, 1 where the
+// direction is a noise angle.
+pub fn field_magnitude(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    pos: Vec2,
+    iteration: u32,
+) -> f32 {
+    match FieldMode::from_u32(globals.field_mode) {
+        FieldMode::Curl => {
+            let offset = Vec2::new(globals.field_offset_x, globals.field_offset_y);
+            let p = warp(
+                globals,
+                (pos + offset) * globals.noise_scale + seed_noise_offset(globals),
+            );
+            get_curl(globals, p).length()
+        }
+        FieldMode::ImageLuminance | FieldMode::ImageContour | FieldMode::ImageVector
+            if inputs.field_image.is_some() =>
+        {
+            image_field_vector(globals, inputs, pos).length()
+        }
+        FieldMode::VectorGrid if inputs.vector_grid.is_some() => {
+            vector_grid_vector(globals, inputs.vector_grid.as_deref(), pos).length()
+        }
+        FieldMode::Expression => inputs
+            .field_expression
+            .as_ref()
+            .map_or(0.0, |e| e.evaluate(globals, pos, iteration as f32).length()),
+        _ => 1.0,
+    }
+}
+
+pub fn hash(value: u32) -> u32 {
+    let mut state = value;
+    state ^= 2747636419;
+    state = state.wrapping_mul(2654435769);
+    state ^= state >> 16;
+    state = state.wrapping_mul(2654435769);
+    state ^= state >> 16;
+    state = state.wrapping_mul(2654435769);
+    state
+}
+
+pub fn random_f32(value: u32) -> f32 {
+    hash(value) as f32 / 4294967295.0
+}
+
+pub fn seeded(globals: &FlowFieldGlobals, value: u32) -> u32 {
+    if globals.seed == 0 {
+        return value;
+    }
+    value ^ hash(globals.seed)
+}
+
+pub fn seed_noise_offset(globals: &FlowFieldGlobals) -> Vec2 {
+    if globals.seed == 0 {
+        return Vec2::ZERO;
+    }
+    let seed_hash = hash(globals.seed);
+    Vec2::new(random_f32(seed_hash), random_f32(seed_hash.wrapping_add(1))) * 289.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_globals() -> FlowFieldGlobals {
+        FlowFieldGlobals {
+            viewport_width: 200.0,
+            viewport_height: 100.0,
+            num_lines: 8,
+            max_iterations: 20,
+            step_size: 2.0,
+            line_width: 1.5,
+            line_color_start: Vec4::new(1.0, 0.0, 0.0, 1.0),
+            line_color_end: Vec4::new(0.0, 0.0, 1.0, 0.5),
+            ..default()
+        }
+    }
+
+    fn joint(mesh: &LineMesh, globals: &FlowFieldGlobals, line: u32, k: u32) -> Vec2 {
+        let v = (2 * globals.max_iterations * line + 2 * k) as usize;
+        joint_positions(&mesh.vertices[v..v + 2]).next().unwrap()
+    }
+
+    // Across the line at every joint, at right angles to the segment before it. Miters make the
+    // vertices of a joint further apart than that.
+    fn joint_widths(vertices: &[LineVertex]) -> Vec<f32> {
+        let joints: Vec<Vec2> = joint_positions(vertices).collect();
+        vertices
+            .chunks_exact(2)
+            .enumerate()
+            .map(|(k, pair)| {
+                let segment = joints[k.max(1)] - joints[k.max(1) - 1];
+                let across = (pair[1].position - pair[0].position).truncate().truncate();
+                across.dot(segment.perp().normalize()).abs()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hash_matches_shader() {
+        assert_eq!(hash(0), 1_739_749_167);
+        assert_eq!(hash(1), 150_776_505);
+        assert_eq!(hash(12345), 3_826_328_255);
+        assert_eq!(random_f32(0), 1_739_749_167.0 / 4294967295.0);
+    }
+
+    #[test]
+    fn buffer_layout_matches_shader() {
+        let globals = small_globals();
+        let mesh = trace_lines(&globals, &FlowFieldInputs::default());
+        let n = globals.num_lines;
+        let m = globals.max_iterations;
+
+        assert_eq!(mesh.vertices.len() as u32, 2 * n * m);
+        assert_eq!(mesh.indices.len() as u32, 6 * n * (m - 1));
+
+        for line in 0..n {
+            for segment in 0..m - 1 {
+                let t = (6 * (m - 1) * line + 6 * segment) as usize;
+                let v = 2 * m * line + 2 * segment;
+                assert_eq!(
+                    mesh.indices[t..t + 6],
+                    [v, v + 1, v + 3, v, v + 3, v + 2],
+                    "line {line} segment {segment}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn joints_are_step_size_apart_and_line_width_wide() {
+        let globals = small_globals();
+        let mesh = trace_lines(&globals, &FlowFieldInputs::default());
+
+        for line in 0..globals.num_lines {
+            for k in 1..globals.max_iterations {
+                let d =
+                    joint(&mesh, &globals, line, k).distance(joint(&mesh, &globals, line, k - 1));
+                assert!(
+                    (d - globals.step_size).abs() < 1e-3,
+                    "line {line} joint {k}: {d}"
+                );
+            }
+            let v = (2 * globals.max_iterations * line) as usize;
+            let vertices = &mesh.vertices[v..v + 2 * globals.max_iterations as usize];
+            for w in joint_widths(vertices) {
+                assert!((w - globals.line_width).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn seeds_are_inside_padded_viewport() {
+        let globals = FlowFieldGlobals {
+            num_lines: 1000,
+            max_iterations: 2,
+            ..small_globals()
+        };
+        let mesh = trace_lines(&globals, &FlowFieldInputs::default());
+        let half_w = (globals.viewport_width + SEED_PADDING) / 2.0;
+        let half_h = (globals.viewport_height + SEED_PADDING) / 2.0;
+
+        for line in 0..globals.num_lines {
+            let p = joint(&mesh, &globals, line, 0);
+            assert!(p.x >= -half_w && p.x <= half_w && p.y >= -half_h && p.y <= half_h);
+        }
+    }
+
+    #[test]
+    fn colors_follow_iteration() {
+        let globals = small_globals();
+        let mesh = trace_lines(&globals, &FlowFieldInputs::default());
+        let m = globals.max_iterations;
+
+        // init writes the first two joints at iteration 0
+        assert_eq!(mesh.vertices[0].color, globals.line_color_start);
+        assert_eq!(mesh.vertices[3].color, globals.line_color_start);
+
+        let last = mesh.vertices[(2 * m - 1) as usize].color;
+        let f = (m - 1) as f32 / m as f32;
+        let expected = globals.line_color_start * (1.0 - f) + globals.line_color_end * f;
+        assert!(last.abs_diff_eq(expected, 1e-6));
+    }
+
+    #[test]
+    fn color_modes() {
+        let positions = |color_mode: ColorMode, bidirectional| {
+            let globals = FlowFieldGlobals {
+                color_mode: color_mode as u32,
+                bidirectional,
+                line_color_start: Vec4::ZERO,
+                line_color_end: Vec4::ONE,
+                ..small_globals()
+            };
+            let mesh = trace_lines(&globals, &FlowFieldInputs::default());
+            let lines: Vec<Vec<f32>> = mesh
+                .vertices
+                .chunks_exact(2 * globals.max_iterations as usize)
+                .map(|line| line.iter().step_by(2).map(|v| v.color.x).collect())
+                .collect();
+            lines
+        };
+
+        let random = positions(ColorMode::LineRandom, 0);
+        assert!(random.iter().all(|line| line.iter().all(|&f| f == line[0])));
+        assert_ne!(random[0][0], random[1][0]);
+
+        // Grows with the number of steps from the seed in both directions
+        let distance = positions(ColorMode::Distance, 1);
+        let seed = (small_globals().max_iterations as usize - 1) / 2;
+        for line in &distance {
+            assert_eq!(line[seed], 0.0);
+            assert!(line[..seed].windows(2).all(|pair| pair[0] > pair[1]));
+            assert!(line[seed..].windows(2).all(|pair| pair[0] < pair[1]));
+        }
+
+        let seed_position = positions(ColorMode::SeedPosition, 0);
+        for (line, mesh_line) in seed_position.iter().zip(positions(ColorMode::Iteration, 0)) {
+            assert!(line.iter().all(|&f| f == line[0]));
+            assert_ne!(line, &mesh_line);
+        }
+
+        let (a, b) = (Vec2::new(10.0, 0.0), Vec2::new(0.0, 10.0));
+        assert_eq!(line_curvature(-a, Vec2::ZERO, a), 0.0);
+        // A quarter turn on a circle of radius 10 through 4 joints
+        let curvature = line_curvature(a, Vec2::ZERO, b);
+        assert!((curvature - std::f32::consts::FRAC_PI_2 / 10.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn width_modes() {
+        let traced_widths = |globals: FlowFieldGlobals| {
+            let mesh = trace_lines(&globals, &FlowFieldInputs::default());
+            let lines: Vec<Vec<f32>> = mesh
+                .vertices
+                .chunks_exact(2 * globals.max_iterations as usize)
+                .map(|line| {
+                    // Only the joints that were traced, stopped ones on either side repeat the
+                    // last in their direction
+                    let start = line
+                        .iter()
+                        .position(|v| v.position.w != LINE_STOPPED)
+                        .unwrap();
+                    let end = line
+                        .iter()
+                        .rposition(|v| v.position.w != LINE_STOPPED)
+                        .unwrap();
+                    joint_widths(&line[start..=end])
+                        .into_iter()
+                        .map(|width| width / globals.line_width)
+                        .collect()
+                })
+                .collect();
+            lines
+        };
+        let widths = |width_mode: WidthMode| {
+            traced_widths(FlowFieldGlobals {
+                width_mode: width_mode as u32,
+                min_width: 0.25,
+                width_taper: 0.5,
+                ..small_globals()
+            })
+        };
+
+        for line in widths(WidthMode::Taper) {
+            let last = line.len() - 1;
+            assert!((line[0] - 0.25).abs() < 1e-4 && (line[last] - 0.25).abs() < 1e-4);
+            assert!(line[..last / 2].windows(2).all(|pair| pair[0] < pair[1]));
+            assert!(line[last / 2] > 0.9);
+        }
+
+        // Shortened lines taper over their own length, in both directions from the seed
+        for bidirectional in [0, 1] {
+            let globals = FlowFieldGlobals {
+                width_mode: WidthMode::Taper as u32,
+                min_width: 0.25,
+                width_taper: 0.5,
+                length_variation: 0.6,
+                bidirectional,
+                ..small_globals()
+            };
+            let lines = traced_widths(globals);
+            assert!(lines
+                .iter()
+                .any(|line| line.len() < globals.max_iterations as usize));
+            for (line_index, line) in lines.iter().enumerate() {
+                let last = line.len() - 1;
+                assert_eq!(
+                    line.len() as u32,
+                    max_line_joints(&globals, line_index as u32)
+                );
+                assert!((line[0] - 0.25).abs() < 1e-4 && (line[last] - 0.25).abs() < 1e-4);
+                assert!(line[last / 2] > 0.9);
+            }
+        }
+
+        let random = widths(WidthMode::LineRandom);
+        for line in &random {
+            assert!(line.iter().all(|&w| (w - line[0]).abs() < 1e-4));
+            assert!((0.25..=1.0).contains(&line[0]));
+        }
+        assert_ne!(random[0][0], random[1][0]);
+
+        // Noise angles have a magnitude of 1
+        for line in widths(WidthMode::FieldStrength) {
+            assert!(line.iter().all(|&w| (w - 1.0).abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn joins_and_caps() {
+        // Snapped lines only turn at right angles
+        let none = FlowFieldGlobals {
+            num_angles_allowed: 4,
+            step_size: 8.0,
+            line_width: 4.0,
+            ..small_globals()
+        };
+        let miter = FlowFieldGlobals {
+            line_join: LineJoin::Miter as u32,
+            ..none
+        };
+        // A right angle's miter is longer than that
+        let limited = FlowFieldGlobals {
+            miter_limit: 1.2,
+            ..miter
+        };
+        let bevel = FlowFieldGlobals {
+            line_join: LineJoin::Bevel as u32,
+            ..miter
+        };
+        let round = FlowFieldGlobals {
+            line_join: LineJoin::Round as u32,
+            line_cap: LineCap::Round as u32,
+            ..miter
+        };
+        let a = trace_lines(&miter, &FlowFieldInputs::default());
+        let b = trace_lines(&round, &FlowFieldInputs::default());
+        let c = trace_lines(&limited, &FlowFieldInputs::default());
+        let d = trace_lines(&bevel, &FlowFieldInputs::default());
+        let layout = LineLayout::new(&round, round.max_iterations);
+        let miter_layout = LineLayout::new(&miter, miter.max_iterations);
+        assert_eq!(
+            LineJoin::from_u32(FlowFieldGlobals::default().line_join),
+            LineJoin::None
+        );
+
+        // Without joins every joint is just its pair across the field direction at the joint
+        let e = trace_lines(&none, &FlowFieldInputs::default());
+        let m = none.max_iterations as usize;
+        assert_eq!(e.vertices.len(), none.num_lines as usize * 2 * m);
+        for pair in e.vertices.chunks_exact(2) {
+            let across = (pair[1].position - pair[0].position).truncate().truncate();
+            if across != Vec2::ZERO {
+                assert!((across.length() - none.line_width).abs() < 1e-3);
+            }
+        }
+
+        assert_eq!(
+            b.vertices.len() as u32,
+            round.num_lines * layout.num_vertices()
+        );
+        assert_eq!(
+            b.indices.len() as u32,
+            round.num_lines * layout.num_indices()
+        );
+        assert!(b.indices.iter().all(|&i| (i as usize) < b.vertices.len()));
+
+        let half_width = miter.line_width / 2.0;
+        let mut num_corners = 0;
+        let miter_lines = a
+            .vertices
+            .chunks_exact(miter_layout.num_vertices() as usize)
+            .zip(
+                c.vertices
+                    .chunks_exact(miter_layout.num_vertices() as usize),
+            )
+            .zip(
+                d.vertices
+                    .chunks_exact(miter_layout.num_vertices() as usize),
+            );
+        for (((a, c), d), vertices) in
+            miter_lines.zip(b.vertices.chunks_exact(layout.num_vertices() as usize))
+        {
+            let joints = miter_layout.joint_vertices(a);
+            let points: Vec<Vec2> = joint_positions(joints).collect();
+            assert!(points
+                .iter()
+                .zip(joint_positions(layout.joint_vertices(vertices)))
+                .all(|(p, q)| p.distance(q) < 1e-4));
+
+            for k in 1..m - 1 {
+                let (d_in, d_out) = (points[k] - points[k - 1], points[k + 1] - points[k]);
+                if d_in.length() < 1.0 || d_out.length() < 1.0 || d_in.dot(d_out).abs() > 1e-3 {
+                    continue;
+                }
+                num_corners += 1;
+                // Miters reach the corners of the outlines
+                let corner = joints[2 * k].position.distance(joints[2 * k + 1].position);
+                assert!((corner - 2.0 * std::f32::consts::SQRT_2 * half_width).abs() < 1e-3);
+                // Past the limit they are bevels, so the segments keep their width
+                let j = miter_layout.join_vertex(k as u32) as usize;
+                assert!(c[j..j + miter_layout.join_vertices() as usize]
+                    .iter()
+                    .zip(&d[j..])
+                    .all(|(p, q)| p.position.distance(q.position) < 1e-4));
+                let n_in = d_in.perp().normalize();
+                for vertex in &c[j..j + 2] {
+                    let offset = vertex.position.truncate().truncate() - points[k];
+                    assert!((offset.dot(n_in).abs() - half_width).abs() < 1e-3);
+                }
+                // Round joins meet at the inner corner and fan out around the outside, the next
+                // segment starts at the second pair
+                let j = layout.join_vertex(k as u32) as usize;
+                let distance =
+                    |vertex: &LineVertex| vertex.position.truncate().truncate().distance(points[k]);
+                let apex = distance(&vertices[j + 4]);
+                assert!((apex - std::f32::consts::SQRT_2 * half_width).abs() < 1e-3);
+                for vertex in &vertices[j + 5..j + 6 + ROUND_JOIN_TRIANGLES as usize] {
+                    assert!((distance(vertex) - half_width).abs() < 1e-3);
+                }
+                assert_eq!(segment_vertices(layout, k as u32).0 as usize, j + 2);
+            }
+
+            // Half a circle past the end
+            let end = points.len() - 1;
+            let c = layout.cap_vertex(1) as usize;
+            let outwards = (points[end] - points[end - 1]).normalize();
+            for vertex in &vertices[c + 1..c + 2 + ROUND_CAP_TRIANGLES as usize] {
+                let offset = vertex.position.truncate().truncate() - points[end];
+                assert!((offset.length() - half_width).abs() < 1e-3);
+                assert!(offset.dot(outwards) > -1e-3);
+            }
+        }
+        assert!(num_corners > 10, "{num_corners} corners");
+    }
+
+    #[test]
+    fn colors_from_image() {
+        let inputs = FlowFieldInputs {
+            color_image: Some(std::sync::Arc::new(RgbaImage {
+                width: 2,
+                height: 1,
+                pixels: vec![[255, 0, 0, 255], [0, 0, 255, 255]],
+            })),
+            ..default()
+        };
+        let globals = FlowFieldGlobals {
+            image_color_mode: ImageColorMode::PerLine as u32,
+            line_color_start: Vec4::new(1.0, 1.0, 1.0, 0.5),
+            line_color_end: Vec4::new(1.0, 1.0, 1.0, 0.5),
+            ..small_globals()
+        };
+        let mesh = trace_lines(&globals, &inputs);
+        for line in mesh
+            .vertices
+            .chunks_exact(2 * globals.max_iterations as usize)
+        {
+            // Red on the left, blue on the right, never green
+            let color = line[0].color;
+            assert!(line.iter().all(|v| v.color == color));
+            assert_eq!((color.y, color.w), (0.0, 0.5));
+            assert!((color.x + color.z - 1.0).abs() < 0.5, "{color}");
+        }
+
+        let per_joint = FlowFieldGlobals {
+            image_color_mode: ImageColorMode::PerJoint as u32,
+            ..globals
+        };
+        let mesh = trace_lines(&per_joint, &inputs);
+        let joints = joint_positions(&mesh.vertices);
+        for (pair, joint) in mesh.vertices.chunks_exact(2).zip(joints) {
+            let color = pair[0].color;
+            assert_eq!(color.x > color.z, joint.x < 0.0, "{color} at {joint}");
+        }
+    }
+
+    #[test]
+    fn snapped_angles_are_multiples() {
+        let globals = FlowFieldGlobals {
+            num_angles_allowed: 4,
+            ..small_globals()
+        };
+        let mesh = trace_lines(&globals, &FlowFieldInputs::default());
+
+        for line in 0..globals.num_lines {
+            for k in 1..globals.max_iterations {
+                let d = joint(&mesh, &globals, line, k) - joint(&mesh, &globals, line, k - 1);
+                assert!(
+                    d.x.abs() < 1e-3 || d.y.abs() < 1e-3,
+                    "line {line} joint {k}: {d}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn obstacles_stop_and_mask_lines() {
+        let globals = FlowFieldGlobals {
+            num_lines: 50,
+            ..small_globals()
+        };
+        // Stops lines on the right, hides them at the top
+        let inputs = FlowFieldInputs {
+            obstacles: std::sync::Arc::new(vec![
+                parse_obstacle("box,stop,100,0,100,400").unwrap(),
+                parse_obstacle("box,mask,0,100,400,100").unwrap(),
+            ]),
+            ..default()
+        };
+        let mesh = trace_lines(&globals, &inputs);
+
+        for (line, vertices) in mesh
+            .vertices
+            .chunks_exact(2 * globals.max_iterations as usize)
+            .enumerate()
+        {
+            let mut stopped = false;
+            for (pair, joint) in vertices.chunks_exact(2).zip(joint_positions(vertices)) {
+                let w = pair[0].position.w;
+                assert!(
+                    !stopped || w == LINE_STOPPED,
+                    "line {line} moved after stopping"
+                );
+                stopped = w == LINE_STOPPED;
+                assert!(stopped || joint.x <= 50.0, "line {line} entered at {joint}");
+                // Stopped joints repeat the last traced one, masked or not
+                if !stopped {
+                    assert_eq!(pair[0].position.z == JOINT_MASKED, joint.y > 50.0);
+                }
+            }
+            for polyline in drawn_polylines(vertices) {
+                assert!(polyline.iter().all(|p| p.x <= 50.0 && p.y <= 50.0));
+            }
+        }
+
+        // Segments reaching into the mask are left out entirely instead of tapering into it
+        let layout = LineLayout::new(&globals, globals.max_iterations);
+        for (line, indices) in mesh
+            .indices
+            .chunks_exact(layout.num_indices() as usize)
+            .enumerate()
+        {
+            for triangles in indices[..layout.join_index(0) as usize].chunks_exact(6) {
+                if triangles.iter().all(|&i| i == triangles[0]) {
+                    continue;
+                }
+                assert!(
+                    triangles
+                        .iter()
+                        .all(|&i| mesh.vertices[i as usize].position.z != JOINT_MASKED),
+                    "line {line} draws a segment with a masked joint"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lines_terminate() {
+        let globals = FlowFieldGlobals {
+            num_lines: 200,
+            max_iterations: 60,
+            stop_outside_bounds: 1,
+            collision_distance: 4.0,
+            length_variation: 1.0,
+            ..small_globals()
+        };
+        let mesh = trace_lines(&globals, &FlowFieldInputs::default());
+        let half_size =
+            (Vec2::new(globals.viewport_width, globals.viewport_height) + SEED_PADDING) / 2.0;
+
+        let mut cells = std::collections::HashMap::new();
+        let mut lengths = std::collections::HashSet::new();
+        for (line, vertices) in mesh
+            .vertices
+            .chunks_exact(2 * globals.max_iterations as usize)
+            .enumerate()
+        {
+            let joints: Vec<Vec2> = vertices
+                .chunks_exact(2)
+                .zip(joint_positions(vertices))
+                .take_while(|(pair, _)| pair[0].position.w != LINE_STOPPED)
+                .map(|(_, joint)| joint)
+                .collect();
+            assert!(joints.len() as u32 <= max_line_joints(&globals, line as u32));
+            lengths.insert(joints.len());
+            for joint in joints {
+                assert!(joint.abs().cmple(half_size).all());
+                // Only one line is drawn through every cell
+                let cell = ((joint + half_size) / globals.collision_distance).floor();
+                let owner = *cells.entry(cell.as_ivec2().to_array()).or_insert(line);
+                assert_eq!(owner, line, "line {line} ran into line {owner} at {joint}");
+            }
+        }
+        assert!(lengths.len() > 10);
+
+        // Collapsed segments are after the line stopped
+        for triangles in mesh.indices.chunks_exact(6) {
+            let stopped = mesh.vertices[triangles[5] as usize].position.w == LINE_STOPPED;
+            assert_eq!(stopped, triangles[0] == triangles[5]);
+        }
+    }
+
+    #[test]
+    fn bidirectional_lines_extend_both_ways() {
+        let forward = small_globals();
+        let both = FlowFieldGlobals {
+            bidirectional: 1,
+            ..forward
+        };
+        let a = trace_lines(&forward, &FlowFieldInputs::default());
+        let b = trace_lines(&both, &FlowFieldInputs::default());
+
+        let seed = seed_slot(&both);
+        assert_eq!(seed, 9);
+        for line in 0..forward.num_lines {
+            // The forward half is the start of the unidirectional line
+            for k in 0..forward.max_iterations - seed {
+                let expected = joint(&a, &forward, line, k);
+                assert!(joint(&b, &both, line, seed + k).distance(expected) < 1e-3);
+            }
+            for k in 1..both.max_iterations {
+                let step = joint(&b, &both, line, k - 1).distance(joint(&b, &both, line, k));
+                assert!(
+                    (step - both.step_size).abs() < 1e-3,
+                    "line {line} joint {k}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn seed_changes_placement_and_field() {
+        let unseeded = small_globals();
+        let seeded = FlowFieldGlobals {
+            seed: 7,
+            ..unseeded
+        };
+        let a = trace_lines(&unseeded, &FlowFieldInputs::default());
+        let b = trace_lines(&seeded, &FlowFieldInputs::default());
+        let c = trace_lines(&seeded, &FlowFieldInputs::default());
+
+        assert_eq!(b.vertices, c.vertices);
+        for line in 0..unseeded.num_lines {
+            assert_ne!(joint(&a, &unseeded, line, 0), joint(&b, &seeded, line, 0));
+        }
+        assert_ne!(
+            get_field_angle(
+                &unseeded,
+                &FlowFieldInputs::default(),
+                Vec2::new(10.0, 20.0),
+                0
+            ),
+            get_field_angle(
+                &seeded,
+                &FlowFieldInputs::default(),
+                Vec2::new(10.0, 20.0),
+                0
+            )
+        );
+    }
+
+    #[test]
+    fn curl_is_divergence_free() {
+        let globals = FlowFieldGlobals {
+            field_mode: FieldMode::Curl as u32,
+            noise_octaves: 3,
+            ..small_globals()
+        };
+        // The net flux out of a square is zero for a divergence-free field, while the flux through
+        // its edges in one direction isn't.
+        let n = 400;
+        for i in 0..10 {
+            let corner = Vec2::new(random_f32(2 * i), random_f32(2 * i + 1)) * 20.0;
+            let (mut net_flux, mut total_flux) = (0.0, 0.0);
+            for j in 0..n {
+                let t = (j as f32 + 0.5) / n as f32;
+                let edges = [
+                    (Vec2::new(t, 0.0), -Vec2::Y),
+                    (Vec2::new(1.0, t), Vec2::X),
+                    (Vec2::new(t, 1.0), Vec2::Y),
+                    (Vec2::new(0.0, t), -Vec2::X),
+                ];
+                for (offset, normal) in edges {
+                    let flux = get_curl(&globals, corner + offset).dot(normal) / n as f32;
+                    net_flux += flux;
+                    total_flux += flux.abs();
+                }
+            }
+            assert!(
+                net_flux.abs() < 0.01 * total_flux,
+                "{corner}: {net_flux} of {total_flux}"
+            );
+        }
+    }
+
+    #[test]
+    fn image_contours_go_around_radial_gradient() {
+        // Brighter with the distance from the centre
+        let size = 64;
+        let pixels = (0..size * size)
+            .map(|i| {
+                let p = Vec2::new((i % size) as f32, (i / size) as f32) + 0.5 - size as f32 / 2.0;
+                let l = (p.length() * 6.0).min(255.0) as u8;
+                [l, l, l, 255]
+            })
+            .collect();
+        let inputs = FlowFieldInputs {
+            field_image: Some(std::sync::Arc::new(RgbaImage {
+                width: size as u32,
+                height: size as u32,
+                pixels,
+            })),
+            ..default()
+        };
+        let globals = FlowFieldGlobals {
+            viewport_width: 200.0,
+            viewport_height: 200.0,
+            field_mode: FieldMode::ImageContour as u32,
+            image_gradient_step: 4.0,
+            ..default()
+        };
+
+        for i in 0..20 {
+            let p = Vec2::from_angle(i as f32 * 0.3) * (20.0 + 3.0 * i as f32);
+            let direction = get_field_direction(&globals, &inputs, p, 0);
+            assert!(direction.dot(p.normalize()).abs() < 0.1, "{p}: {direction}");
+        }
+    }
+
+    // Traces the unit speed circular field around the origin and returns the largest distance from
+    // the circle the line starts on.
+    fn circle_error(integrator: Integrator) -> f32 {
+        let globals = FlowFieldGlobals {
+            integrator: integrator as u32,
+            integrator_tolerance: 1e-4,
+            step_size: 10.0,
+            ..default()
+        };
+        let circle = |p: Vec2| Vec2::new(-p.y, p.x).normalize();
+        let radius = 50.0;
+        let mut p = Vec2::new(radius, 0.0);
+        let mut max_error: f32 = 0.0;
+        for _ in 0..100 {
+            p = integrate(&globals, p, circle(p), circle);
+            max_error = max_error.max((p.length() - radius).abs());
+        }
+        max_error
+    }
+
+    #[test]
+    fn rk45_covers_whole_step_at_tiny_tolerance() {
+        let globals = FlowFieldGlobals {
+            integrator: Integrator::Rk45 as u32,
+            integrator_tolerance: 1e-6,
+            step_size: 10.0,
+            ..default()
+        };
+        // Stripes turning the line up and down, which the substeps never get the error of down
+        // to the tolerance where they cross. The smallest substeps alone only cover half a step.
+        assert!(RK45_MAX_SUBSTEPS as f32 / RK45_MIN_SUBSTEP_FRACTION < 1.0);
+        let angle: f32 = 0.5;
+        let stripes = |p: Vec2| {
+            let up = (p.y * 4.0).floor().rem_euclid(2.0) == 0.0;
+            Vec2::from_angle(if up { angle } else { -angle })
+        };
+        // Every direction moves the same distance along x, however the substeps combine them
+        let mut p = Vec2::ZERO;
+        for _ in 0..20 {
+            let next = integrate(&globals, p, stripes(p), stripes);
+            let length = (next.x - p.x) / angle.cos();
+            assert!((length - globals.step_size).abs() < 1e-3, "{length}");
+            p = next;
+        }
+    }
+
+    #[test]
+    fn higher_order_integrators_follow_circle() {
+        let euler = circle_error(Integrator::Euler);
+        let midpoint = circle_error(Integrator::Midpoint);
+        let rk4 = circle_error(Integrator::Rk4);
+        let rk45 = circle_error(Integrator::Rk45);
+        // Euler spirals outwards by several step sizes over a few revolutions.
+        assert!(euler > 50.0, "euler: {euler}");
+        assert!(
+            midpoint < euler / 10.0,
+            "midpoint: {midpoint}, euler: {euler}"
+        );
+        assert!(rk4 < 0.1, "rk4: {rk4}");
+        assert!(rk4 < euler / 1000.0, "rk4: {rk4}, euler: {euler}");
+        assert!(rk45 < 0.1, "rk45: {rk45}");
+    }
+
+    #[test]
+    fn first_line_is_pinned() {
+        let globals = small_globals();
+        let mesh = trace_lines(&globals, &FlowFieldInputs::default());
+
+        let expected = [
+            Vec2::new(-28.479_927, -92.978_92),
+            Vec2::new(-26.566_98, -104.743_385),
+            Vec2::new(-20.865_118, -115.264_48),
+            Vec2::new(-13.195_516, -124.481_05),
+        ];
+        for (k, expected) in expected.iter().enumerate() {
+            let p = joint(&mesh, &globals, 0, k as u32 * 6);
+            assert!(p.abs_diff_eq(*expected, 1e-3), "joint {}: {p}", k * 6);
+        }
+    }
+}
@@ -0,0 +1,421 @@
+// Evenly-spaced streamlines after Jobard and Lefer, "Creating Evenly-Spaced Streamlines of
+// Arbitrary Density" (1997).
+//
+// New lines are seeded `line_separation` away from the joints of the lines traced so far and stop
+// when they come closer than `line_separation * separation_test_ratio` to another line. Every line
+// depends on all lines before it, so unlike the compute shader this runs on the CPU and the result
+// is uploaded to the line mesh buffers.
+
+use bevy::prelude::*;
+
+use crate::{cpu_tracer::*, obstacles::*, FlowFieldGlobals, FlowFieldInputs};
+
+// Random seeds tried once no line has room for a neighbour left, to reach areas the lines traced
+// so far don't border.
+pub const MAX_RANDOM_SEEDS: u32 = 1000;
+
+// A joint of a line, `index` counts joints from the seed of the line, negative going backwards.
+struct GridPoint {
+    position: Vec2,
+    line: u32,
+    index: i32,
+}
+
+// Joints of all lines bucketed into square cells of `line_separation`, so only the 3x3 cells
+// around a position need to be searched.
+struct OccupancyGrid {
+    cell_size: f32,
+    min: Vec2,
+    width: usize,
+    height: usize,
+    cells: Vec<Vec<GridPoint>>,
+}
+
+impl OccupancyGrid {
+    fn new(min: Vec2, max: Vec2, cell_size: f32) -> Self {
+        let width = ((max.x - min.x) / cell_size).ceil().max(1.0) as usize;
+        let height = ((max.y - min.y) / cell_size).ceil().max(1.0) as usize;
+        Self {
+            cell_size,
+            min,
+            width,
+            height,
+            cells: (0..width * height).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    fn cell(&self, p: Vec2) -> (usize, usize) {
+        let c = ((p - self.min) / self.cell_size).floor().max(Vec2::ZERO);
+        (
+            (c.x as usize).min(self.width - 1),
+            (c.y as usize).min(self.height - 1),
+        )
+    }
+
+    fn insert(&mut self, position: Vec2, line: u32, index: i32) {
+        let (x, y) = self.cell(position);
+        self.cells[y * self.width + x].push(GridPoint {
+            position,
+            line,
+            index,
+        });
+    }
+
+    // Whether no joint is closer than `radius` (at most the cell size) to `p`. Joints of `own_line`
+    // less than `lag` joints away from `index` along the line don't count.
+    fn is_free(&self, p: Vec2, radius: f32, own_line: Option<(u32, i32)>, lag: i32) -> bool {
+        let (cx, cy) = self.cell(p);
+        for y in cy.saturating_sub(1)..=(cy + 1).min(self.height - 1) {
+            for x in cx.saturating_sub(1)..=(cx + 1).min(self.width - 1) {
+                for point in &self.cells[y * self.width + x] {
+                    if point.position.distance_squared(p) >= radius * radius {
+                        continue;
+                    }
+                    match own_line {
+                        Some((line, index))
+                            if point.line == line && (point.index - index).abs() < lag => {}
+                        _ => return false,
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+// The joints of a line from its backward end to its forward end.
+pub struct EvenlySpacedLine {
+    pub joints: Vec<Vec2>,
+    // Index of the joint the line was traced from
+    pub seed: usize,
+}
+
+struct Tracer<'a> {
+    globals: &'a FlowFieldGlobals,
+    inputs: &'a FlowFieldInputs,
+    grid: OccupancyGrid,
+    min: Vec2,
+    max: Vec2,
+    separation: f32,
+    test_distance: f32,
+    // Joints of the same line closer than this along the line never stop it, larger loops do
+    self_lag: i32,
+    next_line: u32,
+}
+
+impl Tracer<'_> {
+    // Inside the bounds and outside the obstacles that stop lines.
+    fn is_inside(&self, p: Vec2) -> bool {
+        p.cmpge(self.min).all()
+            && p.cmple(self.max).all()
+            && !is_inside_obstacle(&self.inputs.obstacles, ObstacleBehaviour::Stop, p)
+    }
+
+    fn is_valid_seed(&self, p: Vec2) -> bool {
+        self.is_inside(p) && self.grid.is_free(p, self.separation, None, 0)
+    }
+
+    // Traces a line through `seed` in both directions. The backward half gets half of the
+    // `max_iterations - 1` steps, the forward half the rest. The joints are added to the grid even
+    // if the line turns out too short to be kept, so the same spot isn't tried again.
+    fn trace(&mut self, seed: Vec2) -> EvenlySpacedLine {
+        let line = self.next_line;
+        self.next_line += 1;
+        self.grid.insert(seed, line, 0);
+
+        let max_steps = self.globals.max_iterations.saturating_sub(1);
+        let backward = self.follow(seed, line, -1, max_steps / 2);
+        let forward = self.follow(seed, line, 1, max_steps - backward.len() as u32);
+
+        let seed_index = backward.len();
+        let mut joints = backward;
+        joints.reverse();
+        joints.push(seed);
+        joints.extend(forward);
+        EvenlySpacedLine {
+            joints,
+            seed: seed_index,
+        }
+    }
+
+    fn follow(&mut self, seed: Vec2, line: u32, sign: i32, max_steps: u32) -> Vec<Vec2> {
+        let (globals, inputs) = (self.globals, self.inputs);
+        let mut points = Vec::new();
+        let mut p = seed;
+        for step in 1..=max_steps {
+            // Steered again so that going backwards doesn't point into obstacles either
+            let direction = |q: Vec2| {
+                let backwards = sign as f32 * get_field_direction(globals, inputs, q, step);
+                steer_around_obstacles(&inputs.obstacles, backwards, q)
+            };
+            let next = integrate(globals, p, direction(p), direction);
+            let index = sign * step as i32;
+            if !next.is_finite()
+                || !self.is_inside(next)
+                || field_magnitude(globals, inputs, next, step) < globals.min_field_magnitude
+                || (globals.stop_in_masks == 1
+                    && is_inside_obstacle(&inputs.obstacles, ObstacleBehaviour::Mask, next))
+                || !self
+                    .grid
+                    .is_free(next, self.test_distance, Some((line, index)), self.self_lag)
+            {
+                break;
+            }
+            self.grid.insert(next, line, index);
+            points.push(next);
+            p = next;
+        }
+        points
+    }
+}
+
+// The joints of every line, at most `num_lines` lines of at most `max_iterations` joints.
+pub fn trace_evenly_spaced_lines(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+) -> Vec<EvenlySpacedLine> {
+    let size = Vec2::new(globals.viewport_width, globals.viewport_height);
+    let separation = globals.line_separation.max(0.5);
+    let mut tracer = Tracer {
+        globals,
+        inputs,
+        grid: OccupancyGrid::new(-size / 2.0, size / 2.0, separation),
+        min: -size / 2.0,
+        max: size / 2.0,
+        separation,
+        test_distance: separation * globals.separation_test_ratio.clamp(0.0, 1.0),
+        self_lag: (2.0 * separation / globals.step_size.max(0.001)).ceil() as i32 + 1,
+        next_line: 0,
+    };
+
+    let mut lines: Vec<EvenlySpacedLine> = Vec::new();
+    let mut num_processed = 0;
+    let mut num_random_seeds = 0;
+    while lines.len() < globals.num_lines as usize {
+        let mut seeds = Vec::new();
+        if num_processed < lines.len() {
+            // Seeds on both sides of every joint of the oldest line that hasn't been visited yet
+            let line = &lines[num_processed].joints;
+            num_processed += 1;
+            for (i, p) in line.iter().enumerate() {
+                let tangent = line[(i + 1).min(line.len() - 1)] - line[i.saturating_sub(1)];
+                let normal = Vec2::new(-tangent.y, tangent.x).normalize_or_zero();
+                seeds.push(*p + normal * separation);
+                seeds.push(*p - normal * separation);
+            }
+        } else if num_random_seeds < MAX_RANDOM_SEEDS {
+            seeds.push(
+                tracer.min
+                    + Vec2::new(
+                        random_f32(seeded(globals, 2 * num_random_seeds)),
+                        random_f32(seeded(globals, 2 * num_random_seeds + 1)),
+                    ) * size,
+            );
+            num_random_seeds += 1;
+        } else {
+            break;
+        }
+
+        for seed in seeds {
+            if lines.len() >= globals.num_lines as usize {
+                break;
+            }
+            if tracer.is_valid_seed(seed) {
+                let line = tracer.trace(seed);
+                if line.joints.len() >= 2 {
+                    lines.push(line);
+                }
+            }
+        }
+    }
+
+    lines
+}
+
+// The vertices of a line in the layout written by `trace_line`, for the joints that were traced.
+pub fn line_vertices(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    line_index: u32,
+    line: &EvenlySpacedLine,
+) -> Vec<LineVertex> {
+    let joints = &line.joints;
+    // Distances from the seed, growing away from it in both directions
+    let mut distances = vec![0.0; joints.len()];
+    for i in (0..line.seed).rev() {
+        distances[i] = distances[i + 1] + joints[i].distance(joints[i + 1]);
+    }
+    for i in line.seed + 1..joints.len() {
+        distances[i] = distances[i - 1] + joints[i].distance(joints[i - 1]);
+    }
+
+    let layout = LineLayout::new(globals, joints.len() as u32);
+    let mut vertices = vec![LineVertex::default(); layout.num_vertices() as usize];
+    for (i, joint) in joints.iter().enumerate() {
+        let (prev, next) = (i.saturating_sub(1), (i + 1).min(joints.len() - 1));
+        let direction = joints[next] - joints[prev];
+        let info = JointInfo {
+            line_index,
+            joint_index: i as u32,
+            // Evenly spaced lines are traced up front, so their length is known
+            along: i as f32 / (joints.len() - 1).max(1) as f32,
+            seed: joints[line.seed],
+            distance: distances[i],
+            curvature: line_curvature(joints[prev], *joint, joints[next]),
+            // Traced with the step count from the seed as iteration
+            field_strength: field_magnitude(globals, inputs, *joint, i.abs_diff(line.seed) as u32),
+        };
+        let pair = hide_masked_joint(
+            &inputs.obstacles,
+            *joint,
+            create_vertices_for_line_joint(globals, inputs, *joint, direction, info),
+        );
+        vertices[2 * i] = pair.first;
+        vertices[2 * i + 1] = pair.second;
+    }
+    let last = joints.len() - 1;
+    for i in 0..=last {
+        write_join(
+            globals,
+            layout,
+            &mut vertices,
+            i as u32,
+            joints[i.saturating_sub(1)],
+            joints[(i + 1).min(last)],
+        );
+    }
+    write_cap(globals, layout, &mut vertices, 0, 0, joints[1]);
+    write_cap(
+        globals,
+        layout,
+        &mut vertices,
+        1,
+        last as u32,
+        joints[last - 1],
+    );
+    vertices
+}
+
+// The same layout as `trace_lines`, for uploading to the GPU. Lines shorter than `max_iterations`
+// repeat their last joint as stopped lines do, and lines that weren't traced are all zero, which
+// gives triangles without area.
+pub fn evenly_spaced_mesh(globals: &FlowFieldGlobals, inputs: &FlowFieldInputs) -> LineMesh {
+    let layout = LineLayout::new(globals, globals.max_iterations);
+    let vertices_per_line = layout.num_vertices() as usize;
+    let mut mesh = LineMesh {
+        vertices: vec![LineVertex::default(); vertices_per_line * globals.num_lines as usize],
+        indices: line_indices(globals),
+    };
+
+    for (line_index, (line, vertices)) in trace_evenly_spaced_lines(globals, inputs)
+        .iter()
+        .zip(mesh.vertices.chunks_exact_mut(vertices_per_line))
+        .enumerate()
+    {
+        let line_vertices = line_vertices(globals, inputs, line_index as u32, line);
+        let line_layout = LineLayout::of_vertices(globals, line_vertices.len());
+        let joint_vertices = line_layout.joint_vertices(&line_vertices);
+        let (traced, rest) =
+            vertices[..2 * globals.max_iterations as usize].split_at_mut(joint_vertices.len());
+        traced.copy_from_slice(joint_vertices);
+        let last = stop_line(LineVertexPair {
+            first: joint_vertices[joint_vertices.len() - 2],
+            second: joint_vertices[joint_vertices.len() - 1],
+        });
+        for pair in rest.chunks_exact_mut(2) {
+            pair.copy_from_slice(&[last.first, last.second]);
+        }
+        // The joins of the joints there are and both caps
+        let joins = line_layout.join_vertex(0) as usize..line_layout.cap_vertex(0) as usize;
+        let start = layout.join_vertex(0) as usize;
+        vertices[start..start + joins.len()].copy_from_slice(&line_vertices[joins]);
+        let caps = line_layout.cap_vertex(0) as usize..line_vertices.len();
+        let start = layout.cap_vertex(0) as usize;
+        vertices[start..start + caps.len()].copy_from_slice(&line_vertices[caps]);
+    }
+    skip_stopped_segments(globals, &mut mesh.indices, &mesh.vertices);
+
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evenly_spaced_globals() -> FlowFieldGlobals {
+        FlowFieldGlobals {
+            viewport_width: 200.0,
+            viewport_height: 100.0,
+            num_lines: 500,
+            max_iterations: 100,
+            step_size: 2.0,
+            noise_scale: 0.02,
+            seeding_mode: SeedingMode::EvenlySpaced as u32,
+            line_separation: 8.0,
+            separation_test_ratio: 0.5,
+            ..default()
+        }
+    }
+
+    #[test]
+    fn lines_keep_their_distance() {
+        let globals = evenly_spaced_globals();
+        let lines = trace_evenly_spaced_lines(&globals, &FlowFieldInputs::default());
+        assert!(lines.len() > 10, "{} lines", lines.len());
+
+        let test_distance = globals.line_separation * globals.separation_test_ratio;
+        for (i, a) in lines.iter().enumerate() {
+            let a = &a.joints;
+            assert!(a.len() >= 2 && a.len() <= globals.max_iterations as usize);
+            for b in &lines[i + 1..] {
+                for p in a {
+                    for q in &b.joints {
+                        // Seeds are only checked against earlier lines, so allow some rounding.
+                        assert!(p.distance(*q) >= test_distance - 1e-3, "{p} {q}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lines_cover_the_viewport() {
+        let globals = evenly_spaced_globals();
+        let lines = trace_evenly_spaced_lines(&globals, &FlowFieldInputs::default());
+
+        // Every point of the viewport is near a line, apart from the ends of lines.
+        let mut num_far = 0;
+        for y in 0..10 {
+            for x in 0..20 {
+                let p = Vec2::new(x as f32 * 10.0 - 95.0, y as f32 * 10.0 - 45.0);
+                let distance = lines
+                    .iter()
+                    .flat_map(|line| &line.joints)
+                    .map(|q| p.distance(*q))
+                    .fold(f32::MAX, f32::min);
+                if distance > 2.0 * globals.line_separation {
+                    num_far += 1;
+                }
+            }
+        }
+        assert!(num_far < 10, "{num_far} of 200 samples far from lines");
+    }
+
+    #[test]
+    fn mesh_has_gpu_layout() {
+        let globals = evenly_spaced_globals();
+        let mesh = evenly_spaced_mesh(&globals, &FlowFieldInputs::default());
+        assert_eq!(
+            mesh.vertices.len(),
+            2 * (globals.num_lines * globals.max_iterations) as usize
+        );
+        // Segments past the end of a line collapse to their last vertex
+        for (triangles, full) in mesh
+            .indices
+            .chunks_exact(6)
+            .zip(line_indices(&globals).chunks_exact(6))
+        {
+            assert!(triangles == full || triangles == [full[5]; 6]);
+        }
+    }
+}
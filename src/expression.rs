@@ -0,0 +1,701 @@
+// Vector fields written as expressions, for cpu_tracer::FieldMode::Expression.
+//
+// An expression is a list of assignments separated by commas, semicolons or new lines, e.g.
+// `vx = sin(y*0.01), vy = cos(x*0.01)`. `vx` and `vy` are required, other names are local
+// variables that can be used by the assignments after them. The inputs are the world position
+// `x`, `y` and the iteration `t`, and `pi` is a constant.
+//
+// Expressions are checked when they are parsed, so the WGSL they compile to always compiles. The
+// CPU tracer evaluates the parsed expression directly.
+
+use bevy::prelude::*;
+
+use crate::{
+    cpu_tracer::seed_noise_offset,
+    noise::{fractal_noise, perlin_noise_2, simplex_noise_2, value_noise_2, worley_noise_2},
+    FlowFieldGlobals, FlowFieldInputs, FLOW_FIELD_COMPUTE_SHADER,
+};
+
+// Must match `field_expression` in the compute shader, which is replaced by the compiled
+// expression.
+pub const SHADER_STUB: &str = "\
+fn field_expression(x: f32, y: f32, t: f32) -> vec2<f32> {
+    return vec2<f32>(0.0);
+}";
+
+const COMPUTE_SHADER_SOURCE: &str = include_str!("flow_field_compute.wgsl");
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Variable {
+    X,
+    Y,
+    T,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Power,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Function {
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Atan2,
+    Sinh,
+    Cosh,
+    Tanh,
+    Sqrt,
+    Abs,
+    Sign,
+    Exp,
+    Log,
+    Pow,
+    Min,
+    Max,
+    Floor,
+    Ceil,
+    Fract,
+    Clamp,
+    Mix,
+    Smoothstep,
+    // Fractal noise with the noise settings of the field
+    Noise,
+    Perlin,
+    Simplex,
+    Value,
+    Worley,
+}
+
+impl Function {
+    pub const ALL: [Function; 29] = [
+        Function::Sin,
+        Function::Cos,
+        Function::Tan,
+        Function::Asin,
+        Function::Acos,
+        Function::Atan,
+        Function::Atan2,
+        Function::Sinh,
+        Function::Cosh,
+        Function::Tanh,
+        Function::Sqrt,
+        Function::Abs,
+        Function::Sign,
+        Function::Exp,
+        Function::Log,
+        Function::Pow,
+        Function::Min,
+        Function::Max,
+        Function::Floor,
+        Function::Ceil,
+        Function::Fract,
+        Function::Clamp,
+        Function::Mix,
+        Function::Smoothstep,
+        Function::Noise,
+        Function::Perlin,
+        Function::Simplex,
+        Function::Value,
+        Function::Worley,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Function::Sin => "sin",
+            Function::Cos => "cos",
+            Function::Tan => "tan",
+            Function::Asin => "asin",
+            Function::Acos => "acos",
+            Function::Atan => "atan",
+            Function::Atan2 => "atan2",
+            Function::Sinh => "sinh",
+            Function::Cosh => "cosh",
+            Function::Tanh => "tanh",
+            Function::Sqrt => "sqrt",
+            Function::Abs => "abs",
+            Function::Sign => "sign",
+            Function::Exp => "exp",
+            Function::Log => "log",
+            Function::Pow => "pow",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Floor => "floor",
+            Function::Ceil => "ceil",
+            Function::Fract => "fract",
+            Function::Clamp => "clamp",
+            Function::Mix => "mix",
+            Function::Smoothstep => "smoothstep",
+            Function::Noise => "noise",
+            Function::Perlin => "perlin",
+            Function::Simplex => "simplex",
+            Function::Value => "value",
+            Function::Worley => "worley",
+        }
+    }
+
+    pub fn num_arguments(self) -> usize {
+        match self {
+            Function::Atan2
+            | Function::Pow
+            | Function::Min
+            | Function::Max
+            | Function::Noise
+            | Function::Perlin
+            | Function::Simplex
+            | Function::Value
+            | Function::Worley => 2,
+            Function::Clamp | Function::Mix | Function::Smoothstep => 3,
+            _ => 1,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.name() == name)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Expr {
+    Number(f32),
+    Variable(Variable),
+    // Index of an earlier assignment
+    Local(usize),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+// A parsed and checked expression.
+pub struct FieldExpression {
+    pub source: String,
+    // In order, every one may use the ones before it
+    pub assignments: Vec<(String, Expr)>,
+    pub vx: usize,
+    pub vy: usize,
+}
+
+impl FieldExpression {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            source,
+            tokens: &tokens,
+            next: 0,
+            assignments: Vec::new(),
+        };
+        parser.parse_assignments()?;
+
+        let assignments = parser.assignments;
+        let find = |name: &str| {
+            assignments
+                .iter()
+                .position(|(n, _)| n == name)
+                .ok_or_else(|| format!("missing `{name} = ...`"))
+        };
+        let (vx, vy) = (find("vx")?, find("vy")?);
+        Ok(Self {
+            source: source.to_string(),
+            assignments,
+            vx,
+            vy,
+        })
+    }
+
+    // The field vector at world position `pos` and iteration `t`. Mirrors the compiled WGSL.
+    pub fn evaluate(&self, globals: &FlowFieldGlobals, pos: Vec2, t: f32) -> Vec2 {
+        let mut locals = Vec::with_capacity(self.assignments.len());
+        for (_, expr) in &self.assignments {
+            let value = evaluate(globals, expr, pos, t, &locals);
+            locals.push(value);
+        }
+        Vec2::new(locals[self.vx], locals[self.vy])
+    }
+
+    // The `field_expression` function of the compute shader.
+    pub fn to_wgsl(&self) -> String {
+        let mut wgsl = String::from("fn field_expression(x: f32, y: f32, t: f32) -> vec2<f32> {\n");
+        for (name, expr) in &self.assignments {
+            wgsl += &format!("    let e_{name} = {};\n", self.expr_to_wgsl(expr));
+        }
+        wgsl += "    return vec2<f32>(e_vx, e_vy);\n}";
+        wgsl
+    }
+
+    fn expr_to_wgsl(&self, expr: &Expr) -> String {
+        match expr {
+            // Debug formatting always includes a `.` or an exponent, so the literal is a float
+            Expr::Number(value) => format!("{value:?}"),
+            Expr::Variable(Variable::X) => "x".to_string(),
+            Expr::Variable(Variable::Y) => "y".to_string(),
+            Expr::Variable(Variable::T) => "t".to_string(),
+            Expr::Local(index) => format!("e_{}", self.assignments[*index].0),
+            Expr::Negate(a) => format!("(-{})", self.expr_to_wgsl(a)),
+            Expr::Binary(BinaryOp::Power, a, b) => format!(
+                "expression_pow({}, {})",
+                self.expr_to_wgsl(a),
+                self.expr_to_wgsl(b)
+            ),
+            Expr::Binary(op, a, b) => {
+                let op = match op {
+                    BinaryOp::Add => "+",
+                    BinaryOp::Subtract => "-",
+                    BinaryOp::Multiply => "*",
+                    BinaryOp::Divide => "/",
+                    BinaryOp::Remainder => "%",
+                    BinaryOp::Power => unreachable!(),
+                };
+                format!("({} {op} {})", self.expr_to_wgsl(a), self.expr_to_wgsl(b))
+            }
+            Expr::Call(function, arguments) => {
+                let arguments: Vec<String> =
+                    arguments.iter().map(|a| self.expr_to_wgsl(a)).collect();
+                let arguments = arguments.join(", ");
+                match function {
+                    Function::Pow => format!("expression_pow({arguments})"),
+                    Function::Noise => format!("expression_noise({arguments})"),
+                    Function::Perlin => {
+                        format!("perlinNoise2(expression_noise_position({arguments}))")
+                    }
+                    Function::Simplex => {
+                        format!("simplexNoise2(expression_noise_position({arguments}))")
+                    }
+                    Function::Value => {
+                        format!("valueNoise2(expression_noise_position({arguments}))")
+                    }
+                    Function::Worley => {
+                        format!("worleyNoise2(expression_noise_position({arguments}))")
+                    }
+                    _ => format!("{}({arguments})", function.name()),
+                }
+            }
+        }
+    }
+}
+
+// Mirrors `expression_pow` in the compute shader. WGSL's pow is undefined for negative bases, so
+// the sign is taken from odd integer exponents and the magnitude from the absolute base.
+pub fn pow(a: f32, b: f32) -> f32 {
+    let magnitude = a.abs().powf(b);
+    if a < 0.0 && b.fract() == 0.0 && (b * 0.5).fract() != 0.0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+fn evaluate(globals: &FlowFieldGlobals, expr: &Expr, pos: Vec2, t: f32, locals: &[f32]) -> f32 {
+    let eval = |e: &Expr| evaluate(globals, e, pos, t, locals);
+    match expr {
+        Expr::Number(value) => *value,
+        Expr::Variable(Variable::X) => pos.x,
+        Expr::Variable(Variable::Y) => pos.y,
+        Expr::Variable(Variable::T) => t,
+        Expr::Local(index) => locals[*index],
+        Expr::Negate(a) => -eval(a),
+        Expr::Binary(op, a, b) => {
+            let (a, b) = (eval(a), eval(b));
+            match op {
+                BinaryOp::Add => a + b,
+                BinaryOp::Subtract => a - b,
+                BinaryOp::Multiply => a * b,
+                BinaryOp::Divide => a / b,
+                BinaryOp::Remainder => a % b,
+                BinaryOp::Power => pow(a, b),
+            }
+        }
+        Expr::Call(function, arguments) => {
+            let a: Vec<f32> = arguments.iter().map(eval).collect();
+            let noise_position = || Vec2::new(a[0], a[1]) + seed_noise_offset(globals);
+            match function {
+                Function::Sin => a[0].sin(),
+                Function::Cos => a[0].cos(),
+                Function::Tan => a[0].tan(),
+                Function::Asin => a[0].asin(),
+                Function::Acos => a[0].acos(),
+                Function::Atan => a[0].atan(),
+                Function::Atan2 => a[0].atan2(a[1]),
+                Function::Sinh => a[0].sinh(),
+                Function::Cosh => a[0].cosh(),
+                Function::Tanh => a[0].tanh(),
+                Function::Sqrt => a[0].sqrt(),
+                Function::Abs => a[0].abs(),
+                // WGSL's sign is 0 at 0
+                Function::Sign => {
+                    if a[0] == 0.0 {
+                        0.0
+                    } else {
+                        a[0].signum()
+                    }
+                }
+                Function::Exp => a[0].exp(),
+                Function::Log => a[0].ln(),
+                Function::Pow => pow(a[0], a[1]),
+                Function::Min => a[0].min(a[1]),
+                Function::Max => a[0].max(a[1]),
+                Function::Floor => a[0].floor(),
+                Function::Ceil => a[0].ceil(),
+                Function::Fract => a[0] - a[0].floor(),
+                // Not f32::clamp, which panics when the bounds are the wrong way around
+                Function::Clamp => a[0].max(a[1]).min(a[2]),
+                Function::Mix => a[0] * (1.0 - a[2]) + a[1] * a[2],
+                Function::Smoothstep => {
+                    let s = ((a[2] - a[0]) / (a[1] - a[0])).clamp(0.0, 1.0);
+                    s * s * (3.0 - 2.0 * s)
+                }
+                Function::Noise => fractal_noise(globals, noise_position()),
+                Function::Perlin => perlin_noise_2(noise_position()),
+                Function::Simplex => simplex_noise_2(noise_position()),
+                Function::Value => value_noise_2(noise_position()),
+                Function::Worley => worley_noise_2(noise_position()),
+            }
+        }
+    }
+}
+
+// The compute shader source with `field_expression` replaced by `expression`.
+pub fn splice_into_shader(expression: &FieldExpression) -> String {
+    COMPUTE_SHADER_SOURCE.replacen(SHADER_STUB, &expression.to_wgsl(), 1)
+}
+
+// Recompiles the compute shader when the expression changes. The pipeline cache picks up the
+// modified shader and the compute node waits for the new pipelines in `Loading`.
+pub fn update_expression_shader(
+    inputs: Res<FlowFieldInputs>,
+    mut shaders: ResMut<Assets<Shader>>,
+    mut current: Local<Option<std::sync::Arc<FieldExpression>>>,
+) {
+    let is_current = match (&inputs.field_expression, &*current) {
+        (Some(a), Some(b)) => std::sync::Arc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    };
+    if is_current {
+        return;
+    }
+
+    let source = match &inputs.field_expression {
+        Some(expression) => splice_into_shader(expression),
+        None => COMPUTE_SHADER_SOURCE.to_string(),
+    };
+    shaders.set_untracked(
+        FLOW_FIELD_COMPUTE_SHADER,
+        Shader::from_wgsl(source, "flow_field_compute.wgsl"),
+    );
+    *current = inputs.field_expression.clone();
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Number(f32),
+    Name(String),
+    // One of + - * / % ^ ( ) , =
+    Symbol(char),
+    // Comma, semicolon or new line between assignments
+    Separator,
+}
+
+// Tokens with their byte offset in the source.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    // New lines and commas inside parentheses don't separate assignments
+    let mut depth = 0;
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_ascii_digit() || c == '.' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                let is_exponent_sign =
+                    (c == '-' || c == '+') && matches!(source[..i].chars().last(), Some('e' | 'E'));
+                if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || is_exponent_sign {
+                    end = i + c.len_utf8();
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let text = &source[start..end];
+            let value: f32 = text
+                .parse()
+                .ok()
+                .filter(|v: &f32| v.is_finite())
+                .ok_or_else(|| error(source, start, &format!("invalid number `{text}`")))?;
+            tokens.push((Token::Number(value), start));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    end = i + c.len_utf8();
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            tokens.push((Token::Name(source[start..end].to_string()), start));
+        } else {
+            chars.next();
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            match c {
+                '\n' | ',' | ';' if depth <= 0 => tokens.push((Token::Separator, start)),
+                _ if c.is_whitespace() => {}
+                '+' | '-' | '*' | '/' | '%' | '^' | '(' | ')' | ',' | '=' => {
+                    tokens.push((Token::Symbol(c), start))
+                }
+                _ => return Err(error(source, start, &format!("unexpected `{c}`"))),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// "line 2, column 5: message", counting from 1.
+fn error(source: &str, offset: usize, message: &str) -> String {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    format!("line {line}, column {column}: {message}")
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: &'a [(Token, usize)],
+    next: usize,
+    assignments: Vec<(String, Expr)>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(token, _)| token)
+    }
+
+    fn error(&self, message: &str) -> String {
+        let offset = self
+            .tokens
+            .get(self.next)
+            .map_or(self.source.len(), |(_, offset)| *offset);
+        error(self.source, offset, message)
+    }
+
+    fn eat(&mut self, symbol: char) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.next += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), String> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{symbol}`")))
+        }
+    }
+
+    fn parse_assignments(&mut self) -> Result<(), String> {
+        loop {
+            while self.peek() == Some(&Token::Separator) {
+                self.next += 1;
+            }
+            let name = match self.peek() {
+                None => return Ok(()),
+                Some(Token::Name(name)) => name.clone(),
+                Some(_) => return Err(self.error("expected a name to assign to")),
+            };
+            if matches!(name.as_str(), "x" | "y" | "t" | "pi")
+                || Function::from_name(&name).is_some()
+            {
+                return Err(self.error(&format!("`{name}` can't be assigned to")));
+            }
+            if self.assignments.iter().any(|(n, _)| *n == name) {
+                return Err(self.error(&format!("`{name}` is already assigned")));
+            }
+            self.next += 1;
+            self.expect('=')?;
+            let expr = self.parse_sum()?;
+            match self.peek() {
+                None | Some(Token::Separator) => {}
+                Some(_) => return Err(self.error("expected an operator or the end of the line")),
+            }
+            self.assignments.push((name, expr));
+        }
+    }
+
+    fn parse_sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_product()?;
+        loop {
+            let op = if self.eat('+') {
+                BinaryOp::Add
+            } else if self.eat('-') {
+                BinaryOp::Subtract
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.parse_product()?));
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_unary()?;
+        loop {
+            let op = if self.eat('*') {
+                BinaryOp::Multiply
+            } else if self.eat('/') {
+                BinaryOp::Divide
+            } else if self.eat('%') {
+                BinaryOp::Remainder
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Binary(op, Box::new(expr), Box::new(self.parse_unary()?));
+        }
+    }
+
+    // `-x^2` is `-(x^2)`, and `^` is right associative.
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.eat('-') {
+            return Ok(Expr::Negate(Box::new(self.parse_unary()?)));
+        }
+        if self.eat('+') {
+            return self.parse_unary();
+        }
+        let base = self.parse_primary()?;
+        if self.eat('^') {
+            let exponent = self.parse_unary()?;
+            return Ok(Expr::Binary(
+                BinaryOp::Power,
+                Box::new(base),
+                Box::new(exponent),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.error("unexpected end of the expression"));
+        };
+        match token {
+            Token::Number(value) => {
+                self.next += 1;
+                Ok(Expr::Number(value))
+            }
+            Token::Symbol('(') => {
+                self.next += 1;
+                let expr = self.parse_sum()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Token::Name(name) => {
+                let start = self.next;
+                self.next += 1;
+                if self.eat('(') {
+                    let function = Function::from_name(&name).ok_or_else(|| {
+                        self.next = start;
+                        self.error(&format!("unknown function `{name}`"))
+                    })?;
+                    let mut arguments = Vec::new();
+                    if !self.eat(')') {
+                        loop {
+                            arguments.push(self.parse_sum()?);
+                            if self.eat(')') {
+                                break;
+                            }
+                            self.expect(',')?;
+                        }
+                    }
+                    if arguments.len() != function.num_arguments() {
+                        self.next = start;
+                        return Err(self.error(&format!(
+                            "`{name}` takes {} arguments, not {}",
+                            function.num_arguments(),
+                            arguments.len()
+                        )));
+                    }
+                    return Ok(Expr::Call(function, arguments));
+                }
+                match name.as_str() {
+                    "x" => Ok(Expr::Variable(Variable::X)),
+                    "y" => Ok(Expr::Variable(Variable::Y)),
+                    "t" => Ok(Expr::Variable(Variable::T)),
+                    "pi" => Ok(Expr::Number(std::f32::consts::PI)),
+                    _ => match self.assignments.iter().position(|(n, _)| *n == name) {
+                        Some(index) => Ok(Expr::Local(index)),
+                        None => {
+                            self.next = start;
+                            Err(self.error(&format!("unknown variable `{name}`")))
+                        }
+                    },
+                }
+            }
+            Token::Symbol(_) | Token::Separator => Err(self.error("expected a value")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_evaluates() {
+        let expression =
+            FieldExpression::parse("r = sqrt(x*x + y^2)\nvx = -y / r, vy = x/r * 2^-1 + t % 2")
+                .unwrap();
+        let globals = FlowFieldGlobals::default();
+        let v = expression.evaluate(&globals, Vec2::new(3.0, 4.0), 5.0);
+        assert_eq!(v, Vec2::new(-0.8, 0.3 + 1.0));
+        assert_eq!(pow(-2.0, 3.0), -8.0);
+        assert_eq!(pow(-2.0, 2.0), 4.0);
+
+        // Commas inside calls don't separate assignments
+        let expression = FieldExpression::parse("vx = max(x, 1), vy = atan2(y, x)").unwrap();
+        assert_eq!(expression.assignments.len(), 2);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let message = |source| FieldExpression::parse(source).err().unwrap();
+        assert_eq!(
+            message("vx = 1\nvy = foo(x)"),
+            "line 2, column 6: unknown function `foo`"
+        );
+        assert_eq!(
+            message("vx = sin(x, y), vy = 0"),
+            "line 1, column 6: `sin` takes 1 arguments, not 2"
+        );
+        assert_eq!(message("vx = 1"), "missing `vy = ...`");
+        assert_eq!(
+            message("vx = r, vy = 0"),
+            "line 1, column 6: unknown variable `r`"
+        );
+        assert_eq!(message("vx = (1"), "line 1, column 8: expected `)`");
+        assert_eq!(
+            message("vx = 1 2, vy = 0"),
+            "line 1, column 8: expected an operator or the end of the line"
+        );
+    }
+
+    #[test]
+    fn splices_into_compute_shader() {
+        assert!(COMPUTE_SHADER_SOURCE.contains(SHADER_STUB));
+        let expression = FieldExpression::parse("a = 1e3, vx = -a * pi, vy = noise(x, y)").unwrap();
+        let source = splice_into_shader(&expression);
+        assert!(!source.contains(SHADER_STUB));
+        assert!(source.contains("    let e_a = 1000.0;\n    let e_vx = ((-e_a) * 3.1415927);\n"));
+    }
+}
@@ -0,0 +1,148 @@
+// Copies the triangles of the index buffer that are drawn into a second index buffer that is drawn
+// with draw_indexed_indirect, so the segments of lines that stopped or are masked, which
+// write_segment_indices collapses onto a single vertex, aren't submitted at all.
+// Works on chunks of triangles: count the triangles each chunk keeps, turn the counts into offsets
+// with a prefix sum over blocks of chunks and another over the block totals, then copy. The
+// triangles keep their order, so lines still overlap in the order they are drawn in.
+
+struct CompactParams {
+    num_triangles: u32,
+}
+
+// Matches wgpu's DrawIndexedIndirect
+struct DrawIndexedIndirect {
+    index_count: u32,
+    instance_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: CompactParams;
+@group(0) @binding(1) var<storage, read> indices: array<u32>;
+@group(0) @binding(2) var<storage, read_write> compacted_indices: array<u32>;
+// The number of triangles each chunk keeps, then where its first one goes in its block
+@group(0) @binding(3) var<storage, read_write> chunk_offsets: array<u32>;
+// The number of triangles each block of chunks keeps, then where its first one goes
+@group(0) @binding(4) var<storage, read_write> block_offsets: array<u32>;
+@group(0) @binding(5) var<storage, read_write> draw_args: DrawIndexedIndirect;
+
+// Must match COMPACT_CHUNK_TRIANGLES in render.rs
+const CHUNK_TRIANGLES: u32 = 64u;
+// The number of chunks in a block, and the workgroup size of the prefix sums. Must match
+// COMPACT_SCAN_BLOCK in render.rs.
+const SCAN_BLOCK: u32 = 256u;
+
+var<workgroup> scan: array<u32, SCAN_BLOCK>;
+
+fn num_chunks() -> u32 {
+    return (params.num_triangles + CHUNK_TRIANGLES - 1u) / CHUNK_TRIANGLES;
+}
+
+fn num_blocks() -> u32 {
+    return (num_chunks() + SCAN_BLOCK - 1u) / SCAN_BLOCK;
+}
+
+// Triangles with all corners on the same vertex have no area
+fn is_drawn(triangle: u32) -> bool {
+    let i = 3u * triangle;
+    return indices[i] != indices[i + 1u] || indices[i] != indices[i + 2u];
+}
+
+// The sum of the values of the invocations before this one in the workgroup. The whole workgroup
+// has to call it.
+fn exclusive_scan(local_index: u32, value: u32) -> u32 {
+    scan[local_index] = value;
+    workgroupBarrier();
+    for (var offset = 1u; offset < SCAN_BLOCK; offset *= 2u) {
+        var before = 0u;
+        if local_index >= offset {
+            before = scan[local_index - offset];
+        }
+        workgroupBarrier();
+        scan[local_index] += before;
+        workgroupBarrier();
+    }
+    return scan[local_index] - value;
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn count_chunks(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let chunk = invocation_id.x;
+    if chunk >= num_chunks() {
+        return;
+    }
+    let start = chunk * CHUNK_TRIANGLES;
+    let end = min(start + CHUNK_TRIANGLES, params.num_triangles);
+    var kept = 0u;
+    for (var triangle = start; triangle < end; triangle++) {
+        if is_drawn(triangle) {
+            kept += 1u;
+        }
+    }
+    chunk_offsets[chunk] = kept;
+}
+
+// One workgroup per block of chunks
+@compute @workgroup_size(256, 1, 1)
+fn scan_blocks(
+    @builtin(local_invocation_index) local_index: u32,
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+) {
+    let chunk = workgroup_id.x * SCAN_BLOCK + local_index;
+    var kept = 0u;
+    if chunk < num_chunks() {
+        kept = chunk_offsets[chunk];
+    }
+    let offset = exclusive_scan(local_index, kept);
+    if chunk < num_chunks() {
+        chunk_offsets[chunk] = offset;
+    }
+    if local_index == SCAN_BLOCK - 1u {
+        block_offsets[workgroup_id.x] = offset + kept;
+    }
+}
+
+// A single workgroup. Each invocation sums a run of consecutive blocks, the runs are summed
+// together, then each invocation turns its run into offsets. Even at the largest index buffers the
+// runs are a few hundred blocks long.
+@compute @workgroup_size(256, 1, 1)
+fn scan_block_totals(@builtin(local_invocation_index) local_index: u32) {
+    let blocks = num_blocks();
+    let run = (blocks + SCAN_BLOCK - 1u) / SCAN_BLOCK;
+    let start = min(local_index * run, blocks);
+    let end = min(start + run, blocks);
+    var run_kept = 0u;
+    for (var block = start; block < end; block++) {
+        run_kept += block_offsets[block];
+    }
+    var offset = exclusive_scan(local_index, run_kept);
+    for (var block = start; block < end; block++) {
+        let kept = block_offsets[block];
+        block_offsets[block] = offset;
+        offset += kept;
+    }
+    // Past the last block, so the total
+    if local_index == SCAN_BLOCK - 1u {
+        draw_args = DrawIndexedIndirect(3u * offset, 1u, 0u, 0, 0u);
+    }
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn compact_chunks(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let chunk = invocation_id.x;
+    if chunk >= num_chunks() {
+        return;
+    }
+    let start = chunk * CHUNK_TRIANGLES;
+    let end = min(start + CHUNK_TRIANGLES, params.num_triangles);
+    var next = 3u * (block_offsets[chunk / SCAN_BLOCK] + chunk_offsets[chunk]);
+    for (var triangle = start; triangle < end; triangle++) {
+        if is_drawn(triangle) {
+            for (var k = 0u; k < 3u; k++) {
+                compacted_indices[next + k] = indices[3u * triangle + k];
+            }
+            next += 3u;
+        }
+    }
+}
@@ -25,6 +25,76 @@ struct Globals {
     noise_scale: f32,
     field_offset_x: f32,
     field_offset_y: f32,
+    // Mixed into the start position hashes and the noise lattice. 0 gives the unseeded output.
+    seed: u32,
+    // The noise type and fractal mode are selected with shader defs, see noise.rs.
+    noise_type: u32,
+    noise_octaves: u32,
+    noise_lacunarity: f32,
+    noise_gain: f32,
+    noise_fractal_mode: u32,
+    // 0 maps the noise to an angle, 1 follows the curl of the noise, 2 to 4 sample the field image,
+    // see image_field_vector, 5 follows the vector grid, 6 follows field_expression.
+    field_mode: u32,
+    // Number of warp_layers the noise position is run through, 0 disables domain warping.
+    num_warp_layers: u32,
+    @align(16) warp_layers: array<WarpLayer, 4>,
+    // 0 Euler, 1 midpoint, 2 RK4, 3 adaptive RK45
+    integrator: u32,
+    // Largest error per substep of the adaptive integrator, in pixels
+    integrator_tolerance: f32,
+    // 0 random, 2 grid, 3 jittered grid, 4 Poisson disk, 5 blue noise, 6 image density. Evenly spaced lines
+    // (seeding_mode 1) are traced on the CPU, this shader isn't run for them.
+    seeding_mode: u32,
+    line_separation: f32,
+    separation_test_ratio: f32,
+    // Distance in pixels across which the luminance gradient of the contour field mode is taken
+    image_gradient_step: f32,
+    // 0 by iteration, 1 by vector grid magnitude, 2 by field angle, 3 random per line, 4 by seed
+    // position, 5 by curvature, 6 by distance, 7 by image luminance, see color_position
+    color_mode: u32,
+    // Lines end once they leave the viewport padded like the seeds if 1
+    stop_outside_bounds: u32,
+    // Lines end where field_magnitude is below this, 0 disables
+    min_field_magnitude: f32,
+    // Lines end on entering a mask instead of continuing hidden if 1
+    stop_in_masks: u32,
+    // Size of the collision_grid cells, 0 disables collisions
+    collision_distance: f32,
+    // Largest fraction of max_iterations a line is randomly shortened by
+    length_variation: f32,
+    // Lines grow backwards and forwards from a seed in their middle joint if 1, see trace_step
+    bidirectional: u32,
+    // Radius of curvature in pixels at which lines reach the end of the colours in the curvature
+    // colour mode, tighter bends are clamped
+    color_curvature_radius: f32,
+    // Lines take their colour from color_image at their seed if 1 or at every joint if 2
+    image_color_mode: u32,
+    // How the width changes along lines, see width_factor
+    width_mode: u32,
+    // The width of the thinnest joints relative to line_width
+    min_width: f32,
+    // Fraction of its own max_line_joints a line tapers over at each end, see along_line
+    width_taper: f32,
+    // Number of times lines swell along their length in the pulse width mode
+    width_pulses: f32,
+    // Field magnitude at which lines are full width in the field strength width mode
+    width_field_scale: f32,
+    // 0 none, 1 miter, 2 bevel, 3 round, see LineLayout and cpu_tracer::LineJoin
+    line_join: u32,
+    // 0 butt, 1 square, 2 round
+    line_cap: u32,
+    // Longest a miter join gets relative to the line width, longer ones are cut off like a bevel
+    miter_limit: f32,
+}
+
+// Must match MAX_WARP_LAYERS in main.rs.
+const MAX_WARP_LAYERS: u32 = 4u;
+
+struct WarpLayer {
+    strength: f32,
+    scale: f32,
+    offset: vec2<f32>,
 }
 
 struct CurrentIterationCount {
@@ -36,6 +106,100 @@ struct CurrentIterationCount {
 @group(0) @binding(2) var<uniform> iteration_count: CurrentIterationCount;
 @group(0) @binding(3) var<storage, read_write> vertex_buffer: array<LineVertex>;
 @group(0) @binding(4) var<storage, read_write> index_buffer: array<u32>;
+// Start positions generated on the CPU in [0, 1] x [0, 1] over the padded viewport.
+@group(0) @binding(5) var<storage, read> seed_buffer: array<vec2<f32>>;
+@group(0) @binding(6) var<storage, read> density_map: DensityMap;
+@group(0) @binding(7) var<storage, read> field_image: FieldImage;
+@group(0) @binding(8) var<storage, read> vector_grid: VectorGrid;
+@group(0) @binding(9) var<storage, read> influences: Influences;
+@group(0) @binding(10) var<storage, read> obstacles: Obstacles;
+@group(0) @binding(11) var<storage, read> obstacle_points: array<vec2<f32>>;
+@group(0) @binding(12) var<storage, read_write> line_states: array<LineState>;
+// The index + 1 of the highest line that passed through every cell, 0 for none
+@group(0) @binding(13) var<storage, read_write> collision_grid: array<atomic<u32>>;
+@group(0) @binding(14) var<storage, read> color_ramp: ColorRamp;
+// 1x1 without an image, COLOR_IMAGE is defined when there is one. Filtered by a linear sampler
+// that clamps to the edge.
+@group(0) @binding(15) var color_image: texture_2d<f32>;
+@group(0) @binding(16) var<storage, read> width_curve: WidthCurve;
+@group(0) @binding(17) var color_image_sampler: sampler;
+
+// Seed density from an image, see seeding::DensityMap. A width of 0 means no image is loaded.
+struct DensityMap {
+    width: u32,
+    height: u32,
+    // The cumulative distribution over the rows, followed by the one within every row
+    cdf: array<f32>,
+}
+
+// An image for the field to follow, placed like the density map. A width of 0 means no image is
+// loaded.
+struct FieldImage {
+    width: u32,
+    height: u32,
+    // RGBA8, row by row from the top
+    pixels: array<u32>,
+}
+
+// Gridded vector data, see vector_grid::VectorGrid. A width of 0 means no data is loaded.
+struct VectorGrid {
+    width: u32,
+    height: u32,
+    // Longitude and latitude of the first and last grid point
+    min: vec2<f32>,
+    max: vec2<f32>,
+    max_magnitude: f32,
+    // Row by row from the south
+    vectors: array<vec2<f32>>,
+}
+
+// Attractors, repulsors and vortices, see influences::Influence.
+struct Influence {
+    position: vec2<f32>,
+    strength: f32,
+    radius: f32,
+    // 0 attractor, 1 repulsor, 2 vortex
+    kind: u32,
+}
+
+struct Influences {
+    count: u32,
+    influences: array<Influence>,
+}
+
+// Shapes that lines stop at, steer around or are hidden inside, see obstacles::Obstacle.
+struct Obstacle {
+    // 0 circle, 1 box, 2 polygon
+    shape: u32,
+    // 0 stop, 1 steer, 2 mask
+    behaviour: u32,
+    // The corners of polygons in obstacle_points, relative to the centre
+    first_point: u32,
+    num_points: u32,
+    centre: vec2<f32>,
+    // The radius of circles in x, half the width and height of boxes
+    size: vec2<f32>,
+    rounding: f32,
+    margin: f32,
+}
+
+struct Obstacles {
+    count: u32,
+    obstacles: array<Obstacle>,
+}
+
+// Written by init and updated by every update, see cpu_tracer::LineState.
+struct LineState {
+    // 0 once the line stopped
+    alive: u32,
+    num_joints: u32,
+    // Distance travelled forwards from the seed in pixels
+    length: f32,
+    // 0 once a bidirectional line stopped growing backwards, always 0 for other lines
+    backward_alive: u32,
+    // Distance travelled backwards from the seed in pixels
+    backward_length: f32,
+}
 
 struct LineVertex {
     position: vec4<f32>,
@@ -47,13 +211,149 @@ struct LineVertexPair {
     second: LineVertex,
 }
 
-fn create_vertices_for_line_joint(joint: vec2<f32>, field_direction: vec2<f32>, line_width: f32) -> LineVertexPair {
+// A gradient baked into evenly spaced colours, see gradients::Gradient. Without colours lines are
+// coloured from line_color_start to line_color_end.
+struct ColorRamp {
+    num_colors: u32,
+    colors: array<vec4<f32>>,
+}
+
+fn sample_color_ramp(f: f32) -> vec4<f32> {
+    let last = color_ramp.num_colors - 1u;
+    let x = clamp(f, 0.0, 1.0) * f32(last);
+    let i = min(u32(x), last);
+    let j = min(i + 1u, last);
+    return mix(color_ramp.colors[i], color_ramp.colors[j], x - f32(i));
+}
+
+// Widths relative to line_width evenly spaced along a line, see width_curve::WidthCurve.
+struct WidthCurve {
+    num_values: u32,
+    values: array<f32>,
+}
+
+fn sample_width_curve(t: f32) -> f32 {
+    let last = width_curve.num_values - 1u;
+    let x = clamp(t, 0.0, 1.0) * f32(last);
+    let i = min(u32(x), last);
+    let j = min(i + 1u, last);
+    return mix(width_curve.values[i], width_curve.values[j], x - f32(i));
+}
+
+// Where a joint is in its line, for colouring it and setting its width.
+struct JointInfo {
+    line_index: u32,
+    // The slot of the joint in its line
+    joint_index: u32,
+    // From 0 at the first joint of the line to 1 at the last, see along_line
+    along: f32,
+    // The first joint the line was traced from
+    seed: vec2<f32>,
+    // Along the line from the seed in pixels
+    distance: f32,
+    // Of the line at the joint in 1/pixels
+    curvature: f32,
+    // field_magnitude at the joint
+    field_strength: f32,
+}
+
+// How much a line turns at joint b on its way from a to c.
+fn line_curvature(a: vec2<f32>, b: vec2<f32>, c: vec2<f32>) -> f32 {
+    let d1 = b - a;
+    let d2 = c - b;
+    let step = length(d2);
+    if length(d1) == 0.0 || step == 0.0 {
+        return 0.0;
+    }
+    let turn = atan2(d1.x * d2.y - d1.y * d2.x, dot(d1, d2));
+    return abs(turn) / step;
+}
+
+// The position of a joint along the line colours in [0, 1], see cpu_tracer::ColorMode.
+// field_direction points towards the end of the line.
+fn color_position(joint: vec2<f32>, field_direction: vec2<f32>, info: JointInfo) -> f32 {
+    switch globals.color_mode {
+        case 1u: {
+            if vector_grid.width > 0u {
+                return length(sample_vector_grid(joint)) / max(vector_grid.max_magnitude, 1.17549435e-38);
+            }
+        }
+        case 2u: {
+            return atan2(field_direction.y, field_direction.x) / 6.2831855 + 0.5;
+        }
+        case 3u: {
+            return random_f32(seeded(3u * globals.num_lines + info.line_index));
+        }
+        case 4u: {
+            return info.seed.x / globals.viewport_width + 0.5;
+        }
+        case 5u: {
+            return info.curvature * globals.color_curvature_radius;
+        }
+        case 6u: {
+            // The longest a line can get in one direction
+            let max_distance = f32(globals.max_iterations - 1u - seed_slot()) * globals.step_size;
+            return info.distance / max(max_distance, 1.17549435e-38);
+        }
+        case 7u: {
+            if field_image.width > 0u {
+                return luma(sample_field_image(joint));
+            }
+        }
+        default: {}
+    }
+    return f32(info.joint_index) / f32(globals.max_iterations);
+}
+
+// The width of a joint relative to line_width, see cpu_tracer::WidthMode.
+fn width_factor(info: JointInfo) -> f32 {
+    let t = info.along;
+    var f = 1.0;
+    switch globals.width_mode {
+        case 1u: {
+            f = smoothstep(0.0, 1.0, min(t, 1.0 - t) / max(globals.width_taper, 1.17549435e-38));
+        }
+        case 2u: {
+            f = 0.5 - 0.5 * cos(6.2831855 * globals.width_pulses * t);
+        }
+        case 3u: {
+            return sample_width_curve(t);
+        }
+        case 4u: {
+            f = random_f32(seeded(4u * globals.num_lines + info.line_index));
+        }
+        case 5u: {
+            f = min(info.field_strength / max(globals.width_field_scale, 1.17549435e-38), 1.0);
+        }
+        default: {
+            return 1.0;
+        }
+    }
+    return mix(globals.min_width, 1.0, f);
+}
+
+fn create_vertices_for_line_joint(joint: vec2<f32>, field_direction: vec2<f32>, base_line_width: f32, info: JointInfo) -> LineVertexPair {
+    let line_width = base_line_width * width_factor(info);
     let line_normal = normalize(vec2<f32>(field_direction.y, -field_direction.x));
     let p_1 = joint - line_normal * line_width / 2.0;
     let p_2 = joint + line_normal * line_width / 2.0;
 
-    let f = f32(iteration_count.value) / f32(globals.max_iterations);
-    let c = globals.line_color_start * (1.0 - f) + globals.line_color_end * f;
+    let f = color_position(joint, field_direction, info);
+    var c = globals.line_color_start * (1.0 - f) + globals.line_color_end * f;
+    if color_ramp.num_colors > 0u {
+        c = sample_color_ramp(f);
+    }
+#ifdef COLOR_IMAGE
+    if globals.image_color_mode != 0u {
+        var p = joint;
+        if globals.image_color_mode == 1u {
+            p = info.seed;
+        }
+        let sample = sample_color_image(p);
+        // The image is sRGB, the line colours linear
+        c = vec4<f32>(srgb_to_linear(sample.xyz), c.w * sample.w);
+    }
+#endif
 
     return LineVertexPair(
         LineVertex(vec4<f32>(p_1, 0.0, 0.0), c), 
@@ -61,10 +361,174 @@ fn create_vertices_for_line_joint(joint: vec2<f32>, field_direction: vec2<f32>,
     );
 }
 
+// In noise space, where features are about 1 apart.
+const CURL_EPSILON: f32 = 0.01;
+
+// Curl of the noise as a stream function, (dn/dy, -dn/dx), by central differences.
+fn get_curl(p: vec2<f32>) -> vec2<f32> {
+    let dx = vec2<f32>(CURL_EPSILON, 0.0);
+    let dy = vec2<f32>(0.0, CURL_EPSILON);
+    let dn_dx = fractal_noise(p + dx) - fractal_noise(p - dx);
+    let dn_dy = fractal_noise(p + dy) - fractal_noise(p - dy);
+    return vec2<f32>(dn_dy, -dn_dx) / (2.0 * CURL_EPSILON);
+}
+
+// Image field vectors up to two steps of 8 bit colour long have no direction, which covers flat
+// areas and the neutral (128, 128) of normal maps. The noise angle is used there instead.
+const IMAGE_FIELD_EPSILON: f32 = 0.007843138;
+
+// Rec. 709 luma, composited over white.
+fn luma(color: vec4<f32>) -> f32 {
+    let luma = 0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z;
+    return luma * color.w + (1.0 - color.w);
+}
+
+// Clamped to the edge of the image.
+fn field_image_texel(x: i32, y: i32) -> vec4<f32> {
+    let clamped_x = clamp(x, 0, i32(field_image.width) - 1);
+    let clamped_y = clamp(y, 0, i32(field_image.height) - 1);
+    return unpack4x8unorm(field_image.pixels[clamped_y * i32(field_image.width) + clamped_x]);
+}
+
+// Bilinear filtering at a world position.
+fn sample_field_image(pos: vec2<f32>) -> vec4<f32> {
+    let viewport = vec2<f32>(globals.viewport_width, globals.viewport_height);
+    let size = vec2<f32>(f32(field_image.width), f32(field_image.height));
+    let p = world_to_image(viewport, size, pos) * size - 0.5;
+    let p0 = floor(p);
+    let t = p - p0;
+    let x = i32(p0.x);
+    let y = i32(p0.y);
+    let top = field_image_texel(x, y) * (1.0 - t.x) + field_image_texel(x + 1, y) * t.x;
+    let bottom = field_image_texel(x, y + 1) * (1.0 - t.x) + field_image_texel(x + 1, y + 1) * t.x;
+    return top * (1.0 - t.y) + bottom * t.y;
+}
+
+// Like sample_field_image for color_image. The sampler filters the encoded values like
+// sample_field_image does, texel centres are at half texels.
+fn sample_color_image(pos: vec2<f32>) -> vec4<f32> {
+    let viewport = vec2<f32>(globals.viewport_width, globals.viewport_height);
+    let size = vec2<f32>(textureDimensions(color_image));
+    return textureSampleLevel(color_image, color_image_sampler, world_to_image(viewport, size, pos), 0.0);
+}
+
+fn srgb_to_linear(c: vec3<f32>) -> vec3<f32> {
+    return select(pow((c + 0.055) / 1.055, vec3<f32>(2.4)), c / 12.92, c <= vec3<f32>(0.04045));
+}
+
+// Clamped to the edge of the grid.
+fn vector_grid_point(x: i32, y: i32) -> vec2<f32> {
+    let clamped_x = clamp(x, 0, i32(vector_grid.width) - 1);
+    let clamped_y = clamp(y, 0, i32(vector_grid.height) - 1);
+    return vector_grid.vectors[clamped_y * i32(vector_grid.width) + clamped_x];
+}
+
+// Bilinear interpolation at a world position, with grid points at the corners of the area.
+fn sample_vector_grid(pos: vec2<f32>) -> vec2<f32> {
+    let viewport = vec2<f32>(globals.viewport_width, globals.viewport_height);
+    let uv = world_to_image(viewport, vector_grid.max - vector_grid.min, pos);
+    let p = vec2<f32>(uv.x, 1.0 - uv.y) * vec2<f32>(f32(vector_grid.width) - 1.0, f32(vector_grid.height) - 1.0);
+    let p0 = floor(p);
+    let t = p - p0;
+    let x = i32(p0.x);
+    let y = i32(p0.y);
+    let bottom = vector_grid_point(x, y) * (1.0 - t.x) + vector_grid_point(x + 1, y) * t.x;
+    let top = vector_grid_point(x, y + 1) * (1.0 - t.x) + vector_grid_point(x + 1, y + 1) * t.x;
+    return bottom * (1.0 - t.y) + top * t.y;
+}
+
+// The direction of the image field modes, zero without an image.
+fn image_field_vector(pos: vec2<f32>) -> vec2<f32> {
+    if field_image.width == 0u {
+        return vec2<f32>(0.0);
+    }
+    switch globals.field_mode {
+        // The luminance is the angle
+        case 2u: {
+            let angle = 6.2832 * luma(sample_field_image(pos));
+            return vec2<f32>(cos(angle), sin(angle));
+        }
+        // Perpendicular to the luminance gradient, along the contours of the image
+        case 3u: {
+            let dx = vec2<f32>(globals.image_gradient_step, 0.0);
+            let dy = vec2<f32>(0.0, globals.image_gradient_step);
+            let gx = luma(sample_field_image(pos + dx)) - luma(sample_field_image(pos - dx));
+            let gy = luma(sample_field_image(pos + dy)) - luma(sample_field_image(pos - dy));
+            return vec2<f32>(-gy, gx);
+        }
+        // Normal or flow map, green up
+        default: {
+            let color = sample_field_image(pos);
+            return color.xy * 2.0 - 1.0;
+        }
+    }
+}
+
+// Replaced by the expression typed in the UI when the shader is compiled, see
+// expression::splice_into_shader. x and y are the world position and t the iteration.
+fn field_expression(x: f32, y: f32, t: f32) -> vec2<f32> {
+    return vec2<f32>(0.0);
+}
+
+// pow with the sign of odd integer exponents, as pow is undefined for negative bases.
+fn expression_pow(a: f32, b: f32) -> f32 {
+    let magnitude = pow(abs(a), b);
+    if a < 0.0 && fract(b) == 0.0 && fract(b * 0.5) != 0.0 {
+        return -magnitude;
+    }
+    return magnitude;
+}
+
+fn expression_noise_position(x: f32, y: f32) -> vec2<f32> {
+    return vec2<f32>(x, y) + seed_noise_offset();
+}
+
+fn expression_noise(x: f32, y: f32) -> f32 {
+    return fractal_noise(expression_noise_position(x, y));
+}
+
 fn get_field_angle(pos: vec2<f32>) -> f32 {
     let offset = vec2<f32>(globals.field_offset_x, globals.field_offset_y);
-    let noise = perlinNoise2((pos + offset) * globals.noise_scale);
-    let field_angle = 6.2832 * noise + 3.1415 * globals.angle_modulation_strength* sin(f32(iteration_count.value) * globals.angle_modulation_frequency);
+    let p = warp((pos + offset) * globals.noise_scale + seed_noise_offset());
+    var noise_angle: f32;
+    switch globals.field_mode {
+        case 1u: {
+            let curl = get_curl(p);
+            noise_angle = atan2(curl.y, curl.x);
+        }
+        case 2u, 3u, 4u: {
+            let v = image_field_vector(pos);
+            if length(v) > IMAGE_FIELD_EPSILON {
+                noise_angle = atan2(v.y, v.x);
+            } else {
+                noise_angle = 6.2832 * fractal_noise(p);
+            }
+        }
+        case 5u: {
+            var v = vec2<f32>(0.0);
+            if vector_grid.width > 0u {
+                v = sample_vector_grid(pos);
+            }
+            if any(v != vec2<f32>(0.0)) {
+                noise_angle = atan2(v.y, v.x);
+            } else {
+                noise_angle = 6.2832 * fractal_noise(p);
+            }
+        }
+        case 6u: {
+            let v = field_expression(pos.x, pos.y, f32(iteration_count.value));
+            // Also false for NaN
+            if length(v) > 0.0 {
+                noise_angle = atan2(v.y, v.x);
+            } else {
+                noise_angle = 6.2832 * fractal_noise(p);
+            }
+        }
+        default: {
+            noise_angle = 6.2832 * fractal_noise(p);
+        }
+    }
+    let field_angle = noise_angle + 3.1415 * globals.angle_modulation_strength* sin(f32(iteration_count.value) * globals.angle_modulation_frequency);
 
     if globals.num_angles_allowed > 0u {
         let angle_multiple = 6.2832 / f32(globals.num_angles_allowed);
@@ -80,7 +544,786 @@ fn get_field_direction(pos: vec2<f32>) -> vec2<f32> {
     let field_angle = get_field_angle(pos);
     let field_direction = normalize(vec2<f32>(cos(field_angle), sin(field_angle)));
        
-    return field_direction;
+    return steer_around_obstacles(apply_influences(field_direction, pos), pos);
+}
+
+// Falls off quadratically to zero at the radius of the influence.
+fn influence_vector(influence: Influence, p: vec2<f32>) -> vec2<f32> {
+    let d = influence.position - p;
+    let distance = length(d);
+    if distance >= influence.radius || distance == 0.0 {
+        return vec2<f32>(0.0);
+    }
+    let falloff = 1.0 - distance / influence.radius;
+    let towards = d / distance;
+    var direction: vec2<f32>;
+    switch influence.kind {
+        case 1u: {
+            direction = -towards;
+        }
+        case 2u: {
+            direction = vec2<f32>(towards.y, -towards.x);
+        }
+        default: {
+            direction = towards;
+        }
+    }
+    return direction * influence.strength * falloff * falloff;
+}
+
+// The direction turned towards the sum of the influences, or left as it is where they cancel it out.
+fn apply_influences(direction: vec2<f32>, p: vec2<f32>) -> vec2<f32> {
+    var combined = direction;
+    for (var i = 0u; i < influences.count; i++) {
+        combined += influence_vector(influences.influences[i], p);
+    }
+    if length(combined) > 0.0 {
+        return normalize(combined);
+    }
+    return direction;
+}
+
+const OBSTACLE_STOP: u32 = 0u;
+const OBSTACLE_STEER: u32 = 1u;
+const OBSTACLE_MASK: u32 = 2u;
+// Distance in pixels across which the gradient of the signed distance is taken.
+const OBSTACLE_GRADIENT_STEP: f32 = 0.5;
+// Set in position.z of both vertices of a joint inside a mask, which are collapsed onto the joint
+// so the segments on either side aren't drawn.
+const JOINT_MASKED: f32 = 1.0;
+// Set in position.w of both vertices of the joint where a line stopped and of every joint after it,
+// which repeat that joint.
+const LINE_STOPPED: f32 = 1.0;
+
+// Signed distance to a closed polygon, after Inigo Quilez. Negative inside.
+fn polygon_distance(obstacle: Obstacle, p: vec2<f32>) -> f32 {
+    if obstacle.num_points == 0u {
+        return 3.40282347e+38;
+    }
+    let first = obstacle_points[obstacle.first_point];
+    var d = dot(p - first, p - first);
+    var s = 1.0;
+    var j = obstacle.num_points - 1u;
+    for (var i = 0u; i < obstacle.num_points; i++) {
+        let v_i = obstacle_points[obstacle.first_point + i];
+        let v_j = obstacle_points[obstacle.first_point + j];
+        let e = v_j - v_i;
+        let w = p - v_i;
+        let b = w - e * clamp(dot(w, e) / max(dot(e, e), 1.17549435e-38), 0.0, 1.0);
+        d = min(d, dot(b, b));
+        // Crossings of a ray towards +x flip the sign
+        let c = vec3<bool>(p.y >= v_i.y, p.y < v_j.y, e.x * w.y > e.y * w.x);
+        if all(c) || !any(c) {
+            s = -s;
+        }
+        j = i;
+    }
+    return s * sqrt(d);
+}
+
+fn obstacle_distance(obstacle: Obstacle, pos: vec2<f32>) -> f32 {
+    let p = pos - obstacle.centre;
+    var distance: f32;
+    switch obstacle.shape {
+        case 1u: {
+            let q = abs(p) - obstacle.size;
+            distance = length(max(q, vec2<f32>(0.0))) + min(max(q.x, q.y), 0.0);
+        }
+        case 2u: {
+            distance = polygon_distance(obstacle, p);
+        }
+        default: {
+            distance = length(p) - obstacle.size.x;
+        }
+    }
+    return distance - obstacle.rounding;
+}
+
+// Points away from the obstacle. Not normalized.
+fn obstacle_gradient(obstacle: Obstacle, p: vec2<f32>) -> vec2<f32> {
+    let dx = vec2<f32>(OBSTACLE_GRADIENT_STEP, 0.0);
+    let dy = vec2<f32>(0.0, OBSTACLE_GRADIENT_STEP);
+    return vec2<f32>(
+        obstacle_distance(obstacle, p + dx) - obstacle_distance(obstacle, p - dx),
+        obstacle_distance(obstacle, p + dy) - obstacle_distance(obstacle, p - dy),
+    );
+}
+
+// The direction turned along the surface of the steering obstacles it points into, fully at the
+// surface and not at all `margin` away. Inside it also points out.
+fn steer_around_obstacles(direction: vec2<f32>, p: vec2<f32>) -> vec2<f32> {
+    var steered = direction;
+    for (var i = 0u; i < obstacles.count; i++) {
+        let obstacle = obstacles.obstacles[i];
+        if obstacle.behaviour != OBSTACLE_STEER {
+            continue;
+        }
+        let d = obstacle_distance(obstacle, p);
+        if d >= obstacle.margin {
+            continue;
+        }
+        let gradient = obstacle_gradient(obstacle, p);
+        var n = vec2<f32>(0.0);
+        if length(gradient) > 0.0 {
+            n = normalize(gradient);
+        }
+        let w = clamp(1.0 - d / max(obstacle.margin, 1.17549435e-38), 0.0, 1.0);
+        let inward = dot(steered, n);
+        if inward < 0.0 {
+            steered -= n * inward * w;
+        }
+        if d < 0.0 {
+            steered += n;
+        }
+    }
+    if length(steered) > 0.0 {
+        return normalize(steered);
+    }
+    return direction;
+}
+
+fn is_inside_obstacle(behaviour: u32, p: vec2<f32>) -> bool {
+    for (var i = 0u; i < obstacles.count; i++) {
+        let obstacle = obstacles.obstacles[i];
+        if obstacle.behaviour == behaviour && obstacle_distance(obstacle, p) < 0.0 {
+            return true;
+        }
+    }
+    return false;
+}
+
+// Collapses the vertices of a joint inside a mask onto the joint and marks it as masked.
+fn hide_masked_joint(joint: vec2<f32>, vertices: LineVertexPair) -> LineVertexPair {
+    if !is_inside_obstacle(OBSTACLE_MASK, joint) {
+        return vertices;
+    }
+    var hidden = vertices;
+    hidden.first.position = vec4<f32>(joint, JOINT_MASKED, 0.0);
+    hidden.second.position = vec4<f32>(joint, JOINT_MASKED, 0.0);
+    return hidden;
+}
+
+// The joint marked as the one where its line stopped.
+fn stop_line(vertices: LineVertexPair) -> LineVertexPair {
+    var stopped = vertices;
+    stopped.first.position.w = LINE_STOPPED;
+    stopped.second.position.w = LINE_STOPPED;
+    return stopped;
+}
+
+// Where the parts of a line are in its vertices and indices, see cpu_tracer::LineLayout.
+struct LineLayout {
+    num_joints: u32,
+    join_triangles: u32,
+    cap_triangles: u32,
+}
+
+// The number of triangles rounding off a join or cap.
+const ROUND_JOIN_TRIANGLES: u32 = 4u;
+const ROUND_CAP_TRIANGLES: u32 = 8u;
+
+// Bevel and round joins are only drawn where they are further than this many pixels from a miter.
+const JOIN_TOLERANCE: f32 = 0.25;
+
+fn line_layout() -> LineLayout {
+    var parts = LineLayout(globals.max_iterations, 0u, 0u);
+    // Miters take the triangle for the bevel past the miter limit
+    if globals.line_join == 1u || globals.line_join == 2u {
+        parts.join_triangles = 1u;
+    } else if globals.line_join == 3u {
+        parts.join_triangles = ROUND_JOIN_TRIANGLES;
+    }
+    if globals.line_cap == 1u {
+        parts.cap_triangles = 3u;
+    } else if globals.line_cap == 2u {
+        parts.cap_triangles = ROUND_CAP_TRIANGLES;
+    }
+    return parts;
+}
+
+fn join_vertices(parts: LineLayout) -> u32 {
+    return select(parts.join_triangles + 6u, 0u, parts.join_triangles == 0u);
+}
+
+fn cap_vertices(parts: LineLayout) -> u32 {
+    return select(parts.cap_triangles + 2u, 0u, parts.cap_triangles == 0u);
+}
+
+fn line_num_vertices(parts: LineLayout) -> u32 {
+    return parts.num_joints * (2u + join_vertices(parts)) + 2u * cap_vertices(parts);
+}
+
+fn line_num_indices(parts: LineLayout) -> u32 {
+    return 6u * (parts.num_joints - 1u) + 3u * parts.num_joints * parts.join_triangles + 6u * parts.cap_triangles;
+}
+
+fn join_vertex(parts: LineLayout, joint: u32) -> u32 {
+    return 2u * parts.num_joints + join_vertices(parts) * joint;
+}
+
+// End 0 is the start of the line, 1 the end.
+fn cap_vertex(parts: LineLayout, end: u32) -> u32 {
+    return parts.num_joints * (2u + join_vertices(parts)) + cap_vertices(parts) * end;
+}
+
+fn join_index(parts: LineLayout, joint: u32) -> u32 {
+    return 6u * (parts.num_joints - 1u) + 3u * parts.join_triangles * joint;
+}
+
+fn cap_index(parts: LineLayout, end: u32) -> u32 {
+    return 6u * (parts.num_joints - 1u) + 3u * parts.num_joints * parts.join_triangles + 3u * parts.cap_triangles * end;
+}
+
+// The first vertex of the pair at either end of the segment from joint segment to the next.
+// With joins, segments end at the first pair of the join and start at the second.
+fn segment_vertices(parts: LineLayout, segment: u32) -> vec2<u32> {
+    if parts.join_triangles == 0u {
+        return vec2<u32>(2u * segment, 2u * (segment + 1u));
+    }
+    return vec2<u32>(join_vertex(parts, segment) + 2u, join_vertex(parts, segment + 1u));
+}
+
+// The direction from a to b, or fallback where they are the same.
+fn segment_direction(a: vec2<f32>, b: vec2<f32>, fallback: vec2<f32>) -> vec2<f32> {
+    let d = b - a;
+    if length(d) == 0.0 {
+        return fallback;
+    }
+    return normalize(d);
+}
+
+// The direction the vertices of a new joint b traced from a are put across. Joins need it to be
+// the segment between them, without joins it's the field direction like before there were any.
+fn joint_direction(a: vec2<f32>, b: vec2<f32>, field_direction: vec2<f32>) -> vec2<f32> {
+    if globals.line_join == 0u {
+        return field_direction;
+    }
+    return segment_direction(a, b, field_direction);
+}
+
+fn rotate(v: vec2<f32>, angle: f32) -> vec2<f32> {
+    let c = cos(angle);
+    let s = sin(angle);
+    return vec2<f32>(c * v.x - s * v.y, s * v.x + c * v.y);
+}
+
+// Joins the segment from a to the joint in slot joint with the one from the joint to c, where a
+// comes before c in the slots. The ends of a line pass the joint itself for the neighbour they
+// don't have. The vertices of the joint have to be the ones made for it, which are at right
+// angles to a segment and give its width. Writes the fan indices of the join as well. Does nothing
+// without joins.
+fn write_join(parts: LineLayout, first_vertex_index: u32, first_triangle_index: u32, joint: u32, a: vec2<f32>, c: vec2<f32>) {
+    if globals.line_join == 0u {
+        return;
+    }
+    let v = first_vertex_index + 2u * joint;
+    var pair = LineVertexPair(vertex_buffer[v], vertex_buffer[v + 1u]);
+    var centre = pair.first;
+    let b = mix(pair.first.position.xy, pair.second.position.xy, 0.5);
+    centre.position = vec4<f32>(b, pair.first.position.zw);
+    let half_width = distance(pair.first.position.xy, pair.second.position.xy) / 2.0;
+    var pair_in = pair;
+    var pair_out = pair;
+    var apex = b;
+    var arc_start = vec2<f32>(0.0);
+    var angle = 0.0;
+
+    let d_in = b - a;
+    let d_out = c - b;
+    if half_width > 0.0 && length(d_in) > 0.0 && length(d_out) > 0.0 {
+        let n_in = normalize(vec2<f32>(d_in.y, -d_in.x));
+        let n_out = normalize(vec2<f32>(d_out.y, -d_out.x));
+        var bisector = vec2<f32>(0.0);
+        if length(n_in + n_out) > 0.0 {
+            bisector = normalize(n_in + n_out);
+        }
+        // Of half the angle the line turns by
+        let cos_half_turn = dot(bisector, n_in);
+        let miter = half_width / max(cos_half_turn, 1.17549435e-38);
+        var error = 0.0;
+        if globals.line_join == 1u {
+            // Past the limit the join is a bevel, which keeps the ribbon its full width
+            if miter > globals.miter_limit * half_width {
+                error = JOIN_TOLERANCE + 1.0;
+            }
+        } else if globals.line_join == 2u {
+            error = miter - half_width * cos_half_turn;
+        } else if globals.line_join == 3u {
+            error = miter - half_width;
+        }
+        // Lines that turn right around have no bisector
+        if length(bisector) > 0.0 && error <= JOIN_TOLERANCE {
+            pair.first.position = vec4<f32>(b - bisector * miter, centre.position.zw);
+            pair.second.position = vec4<f32>(b + bisector * miter, centre.position.zw);
+            pair_in = pair;
+            pair_out = pair;
+        } else {
+            // 1 where the second vertices are on the outside of the turn
+            var side = -1.0;
+            if d_in.x * d_out.y - d_in.y * d_out.x > 0.0 {
+                side = 1.0;
+            }
+            // The segments meet on the inside where their edges cross, but no further back than
+            // half way to the next joints so they don't cut into their joins. Lines that turn
+            // right around overlap instead.
+            let meet = length(bisector) > 0.0;
+            if meet {
+                let sin_half_turn = sqrt(1.0 - cos_half_turn * cos_half_turn);
+                let reach = 0.5 * min(length(d_in), length(d_out)) / sin_half_turn;
+                apex = b - side * bisector * min(miter, reach);
+            }
+            var inner_in = b - side * n_in * half_width;
+            var inner_out = b - side * n_out * half_width;
+            if meet {
+                inner_in = apex;
+                inner_out = apex;
+            }
+            let outer_in = b + side * n_in * half_width;
+            let outer_out = b + side * n_out * half_width;
+            if side > 0.0 {
+                pair_in.first.position = vec4<f32>(inner_in, centre.position.zw);
+                pair_in.second.position = vec4<f32>(outer_in, centre.position.zw);
+                pair_out.first.position = vec4<f32>(inner_out, centre.position.zw);
+                pair_out.second.position = vec4<f32>(outer_out, centre.position.zw);
+            } else {
+                pair_in.first.position = vec4<f32>(outer_in, centre.position.zw);
+                pair_in.second.position = vec4<f32>(inner_in, centre.position.zw);
+                pair_out.first.position = vec4<f32>(outer_out, centre.position.zw);
+                pair_out.second.position = vec4<f32>(inner_out, centre.position.zw);
+            }
+            // Around the outside of the turn, from one pair to the other
+            arc_start = side * half_width * n_in;
+            let arc_end = side * half_width * n_out;
+            angle = atan2(arc_start.x * arc_end.y - arc_start.y * arc_end.x, dot(arc_start, arc_end));
+        }
+    }
+
+    vertex_buffer[v] = pair.first;
+    vertex_buffer[v + 1u] = pair.second;
+    if parts.join_triangles == 0u {
+        return;
+    }
+    let j = first_vertex_index + join_vertex(parts, joint);
+    vertex_buffer[j] = pair_in.first;
+    vertex_buffer[j + 1u] = pair_in.second;
+    vertex_buffer[j + 2u] = pair_out.first;
+    vertex_buffer[j + 3u] = pair_out.second;
+    var apex_vertex = centre;
+    apex_vertex.position = vec4<f32>(apex, centre.position.zw);
+    vertex_buffer[j + 4u] = apex_vertex;
+    for (var k = 0u; k <= parts.join_triangles; k++) {
+        var point = centre;
+        point.position = vec4<f32>(b + rotate(arc_start, angle * f32(k) / f32(parts.join_triangles)), centre.position.zw);
+        vertex_buffer[j + 5u + k] = point;
+    }
+    write_fan_indices(j + 4u, first_triangle_index + join_index(parts, joint), parts.join_triangles);
+}
+
+// The cap of end end of a line (see cap_vertex) at the joint in slot joint, pointing away from
+// neighbour, the joint next to it in the line. Writes the fan indices of the cap as well.
+fn write_cap(parts: LineLayout, first_vertex_index: u32, first_triangle_index: u32, end: u32, joint: u32, neighbour: vec2<f32>) {
+    if parts.cap_triangles == 0u {
+        return;
+    }
+    let v = first_vertex_index + 2u * joint;
+    var centre = vertex_buffer[v];
+    let first = vertex_buffer[v].position.xy;
+    let second = vertex_buffer[v + 1u].position.xy;
+    let b = mix(first, second, 0.5);
+    centre.position = vec4<f32>(b, centre.position.zw);
+    let half_width = distance(first, second) / 2.0;
+    let outwards = segment_direction(neighbour, b, vec2<f32>(0.0));
+
+    let c = first_vertex_index + cap_vertex(parts, end);
+    vertex_buffer[c] = centre;
+    for (var k = 0u; k <= parts.cap_triangles; k++) {
+        var point = b;
+        if half_width > 0.0 && length(outwards) > 0.0 {
+            if globals.line_cap == 1u {
+                var corners = array<vec2<f32>, 4>(
+                    first,
+                    first + outwards * half_width,
+                    second + outwards * half_width,
+                    second
+                );
+                point = corners[min(k, 3u)];
+            } else {
+                // Half a circle from the first vertex to the second
+                let normal = (second - first) / (2.0 * half_width);
+                let angle = 3.14159265 * f32(k) / f32(parts.cap_triangles);
+                point = b + half_width * (outwards * sin(angle) - normal * cos(angle));
+            }
+        }
+        var vertex = centre;
+        vertex.position = vec4<f32>(point, centre.position.zw);
+        vertex_buffer[c + 1u + k] = vertex;
+    }
+    write_fan_indices(c, first_triangle_index + cap_index(parts, end), parts.cap_triangles);
+}
+
+// Triangles from the vertex at centre to each pair of the vertices after it.
+fn write_fan_indices(centre: u32, base_triangle_index: u32, num_triangles: u32) {
+    for (var k = 0u; k < num_triangles; k++) {
+        index_buffer[base_triangle_index + 3u * k] = centre;
+        index_buffer[base_triangle_index + 3u * k + 1u] = centre + 1u + k;
+        index_buffer[base_triangle_index + 3u * k + 2u] = centre + 2u + k;
+    }
+}
+
+// The field direction followed forwards (sign 1) or backwards (sign -1). Backwards it's steered
+// again so it doesn't point into obstacles either.
+fn get_trace_direction(pos: vec2<f32>, sign: f32) -> vec2<f32> {
+    let direction = get_field_direction(pos);
+    if sign > 0.0 {
+        return direction;
+    }
+    return steer_around_obstacles(-direction, pos);
+}
+
+// Length of the vector the field direction is taken from, 1 where the direction is a noise angle.
+fn field_magnitude(pos: vec2<f32>) -> f32 {
+    switch globals.field_mode {
+        case 1u: {
+            let offset = vec2<f32>(globals.field_offset_x, globals.field_offset_y);
+            return length(get_curl(warp((pos + offset) * globals.noise_scale + seed_noise_offset())));
+        }
+        case 2u, 3u, 4u: {
+            if field_image.width > 0u {
+                return length(image_field_vector(pos));
+            }
+        }
+        case 5u: {
+            if vector_grid.width > 0u {
+                return length(sample_vector_grid(pos));
+            }
+        }
+        case 6u: {
+            return length(field_expression(pos.x, pos.y, f32(iteration_count.value)));
+        }
+        default: {}
+    }
+    return 1.0;
+}
+
+// The collision grid is made coarser than collision_distance where it would have more cells.
+const MAX_COLLISION_CELLS: f32 = 16777216.0;
+
+// Cells of the collision grid are square and cover the viewport padded like the seeds.
+fn collision_cell_size() -> f32 {
+    let padding = 100.0;
+    let area = (globals.viewport_width + padding) * (globals.viewport_height + padding);
+    return max(globals.collision_distance, sqrt(area / MAX_COLLISION_CELLS));
+}
+
+fn collision_grid_size() -> vec2<u32> {
+    let padding = 100.0;
+    let size = vec2<f32>(globals.viewport_width, globals.viewport_height) + padding;
+    return vec2<u32>(ceil(size / collision_cell_size()));
+}
+
+// Marks the cell of p as passed through by the line and returns whether another line passed
+// through it before.
+fn collides(line_index: u32, p: vec2<f32>) -> bool {
+    if globals.collision_distance <= 0.0 {
+        return false;
+    }
+    let padding = 100.0;
+    let size = collision_grid_size();
+    let half_size = (vec2<f32>(globals.viewport_width, globals.viewport_height) + padding) / 2.0;
+    let cell = floor((p + half_size) / collision_cell_size());
+    if any(cell < vec2<f32>(0.0)) || any(cell >= vec2<f32>(size)) {
+        return false;
+    }
+    let i = u32(cell.y) * size.x + u32(cell.x);
+    if i >= arrayLength(&collision_grid) {
+        return false;
+    }
+    let previous = atomicMax(&collision_grid[i], line_index + 1u);
+    return previous != 0u && previous != line_index + 1u;
+}
+
+// How many joints a line has at most, randomly shortened by up to length_variation.
+fn max_line_joints(line_index: u32) -> u32 {
+    // Past the two random numbers per line of seed_position
+    let random = random_f32(seeded(2u * globals.num_lines + line_index));
+    return u32(ceil(f32(globals.max_iterations) * (1.0 - globals.length_variation * random)));
+}
+
+// How far along its line the joint in slot is, from 0 at the first joint to 1 at the last one the
+// line has once it reaches max_line_joints. Bidirectional lines split their joints between both
+// directions like trace_step.
+fn along_line(line_index: u32, slot: u32) -> f32 {
+    let num_joints = max(max_line_joints(line_index), 2u);
+    let backward_joints = globals.bidirectional * (num_joints - 1u) / 2u;
+    let first_slot = seed_slot() - min(seed_slot(), backward_joints);
+    // Lines that stopped early in one direction can get further than that in the other
+    return clamp((f32(slot) - f32(first_slot)) / f32(num_joints - 1u), 0.0, 1.0);
+}
+
+// Whether a line that has num_joints joints so far ends before reaching `joint`.
+fn stops_before(line_index: u32, num_joints: u32, joint: vec2<f32>) -> bool {
+    let padding = 100.0;
+    let half_size = (view.viewport.zw + padding) / 2.0;
+    if globals.stop_outside_bounds == 1u && any(abs(joint - view.viewport.xy) > half_size) {
+        return true;
+    }
+    if num_joints >= max_line_joints(line_index) {
+        return true;
+    }
+    if is_inside_obstacle(OBSTACLE_STOP, joint) {
+        return true;
+    }
+    if globals.stop_in_masks == 1u && is_inside_obstacle(OBSTACLE_MASK, joint) {
+        return true;
+    }
+    if field_magnitude(joint) < globals.min_field_magnitude {
+        return true;
+    }
+    // Last, so only joints that are drawn take up their cell
+    return collides(line_index, joint);
+}
+
+// The slot of the seed in the joints of a line.
+fn seed_slot() -> u32 {
+    if globals.bidirectional == 1u {
+        return (globals.max_iterations - 1u) / 2u;
+    }
+    return 0u;
+}
+
+struct TraceStep {
+    // The slot update writes
+    new_joint: u32,
+    // The slot of the joint it continues from
+    prev_joint: u32,
+    // 1 forwards, -1 backwards
+    sign: f32,
+}
+
+// Bidirectional lines alternately grow backwards and forwards from the seed, starting with
+// backwards, which splits the iterations between both directions with the extra one going forwards.
+fn trace_step(iteration: u32) -> TraceStep {
+    if globals.bidirectional != 1u {
+        return TraceStep(iteration, iteration - 1u, 1.0);
+    }
+    let step = iteration - 2u;
+    if step % 2u == 0u {
+        let new_joint = seed_slot() - 1u - step / 2u;
+        return TraceStep(new_joint, new_joint + 1u, -1.0);
+    }
+    let new_joint = seed_slot() + 2u + step / 2u;
+    return TraceStep(new_joint, new_joint - 1u, 1.0);
+}
+
+// The two triangles of the segment from joint segment to the next, collapsed to the first vertex
+// of the next joint once the line has stopped so nothing is drawn. The same goes for segments with
+// a joint inside a mask, which would otherwise taper into it. Both joints have to be written.
+fn write_segment_indices(parts: LineLayout, first_vertex_index: u32, first_triangle_index: u32, segment: u32, alive: bool) {
+    let base_triangle_index = first_triangle_index + 6u * segment;
+    let start = first_vertex_index + 2u * segment;
+    let masked = vertex_buffer[start].position.z == JOINT_MASKED || vertex_buffer[start + 2u].position.z == JOINT_MASKED;
+    if !alive || masked {
+        for (var i = 0u; i < 6u; i++) {
+            index_buffer[base_triangle_index + i] = first_vertex_index + 2u * (segment + 1u);
+        }
+        return;
+    }
+    let vertices = first_vertex_index + segment_vertices(parts, segment);
+    index_buffer[base_triangle_index] = vertices.x;
+    index_buffer[base_triangle_index + 1u] = vertices.x + 1u;
+    index_buffer[base_triangle_index + 2u] = vertices.y + 1u;
+    index_buffer[base_triangle_index + 3u] = vertices.x;
+    index_buffer[base_triangle_index + 4u] = vertices.y + 1u;
+    index_buffer[base_triangle_index + 5u] = vertices.y;
+}
+
+const RK45_MAX_SUBSTEPS: u32 = 32u;
+// Substeps aren't made smaller than step_size / RK45_MIN_SUBSTEP_FRACTION.
+const RK45_MIN_SUBSTEP_FRACTION: f32 = 64.0;
+
+// Advances p by step_size along the field. k1 is the field direction at p, which the caller
+// already has.
+fn integrate(p: vec2<f32>, k1: vec2<f32>, sign: f32) -> vec2<f32> {
+    let h = globals.step_size;
+    switch globals.integrator {
+        // Midpoint
+        case 1u: {
+            let k2 = get_trace_direction(p + k1 * (h / 2.0), sign);
+            return p + k2 * h;
+        }
+        // RK4
+        case 2u: {
+            let k2 = get_trace_direction(p + k1 * (h / 2.0), sign);
+            let k3 = get_trace_direction(p + k2 * (h / 2.0), sign);
+            let k4 = get_trace_direction(p + k3 * h, sign);
+            return p + (k1 + 2.0 * k2 + 2.0 * k3 + k4) * (h / 6.0);
+        }
+        case 3u: {
+            return integrate_rk45(p, k1, sign);
+        }
+        // Euler
+        default: {
+            return p + k1 * h;
+        }
+    }
+}
+
+// Runge-Kutta-Fehlberg 4(5), splits the step into substeps until the error estimate of each is
+// below globals.integrator_tolerance.
+fn integrate_rk45(start: vec2<f32>, start_direction: vec2<f32>, sign: f32) -> vec2<f32> {
+    let min_h = globals.step_size / RK45_MIN_SUBSTEP_FRACTION;
+    var p = start;
+    var k1 = start_direction;
+    var remaining = globals.step_size;
+    var h = globals.step_size;
+    for (var i = 0u; i < RK45_MAX_SUBSTEPS; i++) {
+        if remaining <= 0.0 {
+            break;
+        }
+        h = min(h, remaining);
+        let substep = rk45_substep(p, k1, h, sign);
+
+        let error = length(substep.y5 - substep.y4);
+        // Standard step size control with a safety factor of 0.9, growing at most 4x per substep
+        var factor = 4.0;
+        if error > 0.0 {
+            factor = clamp(0.9 * pow(globals.integrator_tolerance / error, 0.2), 0.1, 4.0);
+        }
+        if error <= globals.integrator_tolerance || h <= min_h {
+            p = substep.y5;
+            remaining -= h;
+            k1 = get_trace_direction(p, sign);
+        }
+        h = max(h * factor, min_h);
+    }
+    // Whatever the substeps didn't cover is taken in one go, so joints stay step_size apart
+    if remaining > 0.0 {
+        p = rk45_substep(p, k1, remaining, sign).y5;
+    }
+    return p;
+}
+
+struct Rk45Substep {
+    y4: vec2<f32>,
+    y5: vec2<f32>,
+}
+
+// The 4th and 5th order Runge-Kutta-Fehlberg estimates of p moved by h.
+fn rk45_substep(p: vec2<f32>, k1: vec2<f32>, h: f32, sign: f32) -> Rk45Substep {
+    let k2 = get_trace_direction(p + h * (k1 / 4.0), sign);
+    let k3 = get_trace_direction(p + h * (3.0 / 32.0 * k1 + 9.0 / 32.0 * k2), sign);
+    let k4 = get_trace_direction(p + h * (1932.0 / 2197.0 * k1 - 7200.0 / 2197.0 * k2 + 7296.0 / 2197.0 * k3), sign);
+    let k5 = get_trace_direction(p + h * (439.0 / 216.0 * k1 - 8.0 * k2 + 3680.0 / 513.0 * k3 - 845.0 / 4104.0 * k4), sign);
+    let k6 = get_trace_direction(p + h * (-8.0 / 27.0 * k1 + 2.0 * k2 - 3544.0 / 2565.0 * k3 + 1859.0 / 4104.0 * k4 - 11.0 / 40.0 * k5), sign);
+    let y4 = p + h * (25.0 / 216.0 * k1 + 1408.0 / 2565.0 * k3 + 2197.0 / 4104.0 * k4 - k5 / 5.0);
+    let y5 = p + h * (16.0 / 135.0 * k1 + 6656.0 / 12825.0 * k3 + 28561.0 / 56430.0 * k4 - 9.0 / 50.0 * k5 + 2.0 / 55.0 * k6);
+    return Rk45Substep(y4, y5);
+}
+
+// The columns and rows of the grid seeding modes, with cells as close to square as possible.
+fn grid_size() -> vec2<u32> {
+    let padding = 100.0;
+    let aspect = (globals.viewport_width + padding) / (globals.viewport_height + padding);
+    let columns = max(u32(ceil(sqrt(f32(globals.num_lines) * aspect))), 1u);
+    let rows = max((globals.num_lines + columns - 1u) / columns, 1u);
+    return vec2<u32>(columns, rows);
+}
+
+// The first of the n entries of the density map cdf starting at offset that is larger than u.
+fn cdf_index(offset: u32, n: u32, u: f32) -> u32 {
+    var low = 0u;
+    var high = n;
+    while low < high {
+        let middle = (low + high) / 2u;
+        if density_map.cdf[offset + middle] <= u {
+            low = middle + 1u;
+        } else {
+            high = middle;
+        }
+    }
+    return min(low, n - 1u);
+}
+
+// Where u falls within entry i, in [0, 1] over all n entries.
+fn cdf_position(offset: u32, n: u32, i: u32, u: f32) -> f32 {
+    var low = 0.0;
+    if i > 0u {
+        low = density_map.cdf[offset + i - 1u];
+    }
+    let high = density_map.cdf[offset + i];
+    var t = 0.5;
+    if high > low {
+        t = clamp((u - low) / (high - low), 0.0, 1.0);
+    }
+    return (f32(i) + t) / f32(n);
+}
+
+// Maps two uniform random numbers to a position in the density image, y down.
+fn sample_density_map(random: vec2<f32>) -> vec2<f32> {
+    let row = cdf_index(0u, density_map.height, random.y);
+    let row_offset = density_map.height + row * density_map.width;
+    let column = cdf_index(row_offset, density_map.width, random.x);
+    return vec2<f32>(
+        cdf_position(row_offset, density_map.width, column, random.x),
+        cdf_position(0u, density_map.height, row, random.y),
+    );
+}
+
+// The world position of image coordinates in [0, 1] x [0, 1], for an image that is scaled to fit
+// inside the viewport and centred.
+fn image_to_world(viewport: vec2<f32>, image_size: vec2<f32>, uv: vec2<f32>) -> vec2<f32> {
+    let scale = min(viewport.x / image_size.x, viewport.y / image_size.y);
+    return vec2<f32>(uv.x - 0.5, 0.5 - uv.y) * image_size * scale;
+}
+
+// The inverse of image_to_world.
+fn world_to_image(viewport: vec2<f32>, image_size: vec2<f32>, p: vec2<f32>) -> vec2<f32> {
+    let scale = min(viewport.x / image_size.x, viewport.y / image_size.y);
+    let q = p / (image_size * scale);
+    return vec2<f32>(q.x + 0.5, 0.5 - q.y);
+}
+
+// Where a line starts, in [0, 1] x [0, 1] over the padded viewport. Lines without a generated seed
+// or density map start at a random position.
+fn seed_position(line_index: u32) -> vec2<f32> {
+    // Multiplying by two ensures there's room for exactly two unique seeds per invocation
+    let seed_1 = line_index * 2u;
+    let seed_2 = seed_1 + 1u;
+    let random = vec2<f32>(random_f32(seeded(seed_1)), random_f32(seeded(seed_2)));
+
+    switch globals.seeding_mode {
+        // Grid, jittered grid
+        case 2u, 3u: {
+            let grid = grid_size();
+            let cell = vec2<f32>(f32(line_index % grid.x), f32(line_index / grid.x));
+            var offset = random;
+            if globals.seeding_mode == 2u {
+                offset = vec2<f32>(0.5);
+            }
+            return (cell + offset) / vec2<f32>(grid);
+        }
+        // Poisson disk, blue noise
+        case 4u, 5u: {
+            if line_index < arrayLength(&seed_buffer) {
+                return seed_buffer[line_index];
+            }
+            return random;
+        }
+        // Image density
+        case 6u: {
+            if density_map.width == 0u {
+                return random;
+            }
+            let padding = 100.0;
+            let viewport = vec2<f32>(globals.viewport_width, globals.viewport_height);
+            let image_size = vec2<f32>(f32(density_map.width), f32(density_map.height));
+            let p = image_to_world(viewport, image_size, sample_density_map(random));
+            return p / (viewport + padding) + 0.5;
+        }
+        default: {
+            return random;
+        }
+    }
 }
 
 // Create an initial line segment of 4 vertices.
@@ -91,38 +1334,56 @@ fn init(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(num_wo
         return;
     }
 
-    // Multiplying by two ensures there's room for exactly two unique seeds per invocation
-    let seed_1 = invocation_id.x * 2u; 
-    let seed_2 = seed_1 + 1u;
-
     // view.viewport is vec4<f32>(x_orig, y_orig, width, height)
     let padding = 100.0;
     let viewport_bottom_left = vec2<f32>(view.viewport.x - (view.viewport.z + padding) / 2.0, view.viewport.y - (view.viewport.w + padding) / 2.0);
-    let joint_1 = vec2<f32>(viewport_bottom_left.x + random_f32(seed_1) * (view.viewport.z + padding),  viewport_bottom_left.y + random_f32(seed_2) * (view.viewport.w + padding));
+    let seed = seed_position(invocation_id.x);
+    let joint_1 = vec2<f32>(viewport_bottom_left.x + seed.x * (view.viewport.z + padding),  viewport_bottom_left.y + seed.y * (view.viewport.w + padding));
 
     let field_direction = get_field_direction(joint_1);
-    let joint_2 = vec2<f32>(joint_1.x + field_direction.x * globals.step_size, joint_1.y + field_direction.y * globals.step_size);
+    let joint_2 = integrate(joint_1, field_direction, 1.0);
 
     // let joint_1 = vec2<f32>(100.0, 100.0);
     // let joint_2 = vec2<f32>(150.0, 100.0);
 
-    let joint_1_vertices = create_vertices_for_line_joint(joint_1, field_direction, globals.line_width);
-    let joint_2_vertices = create_vertices_for_line_joint(joint_2, field_direction, globals.line_width);
+    let line_index = invocation_id.x;
+    let seed_slot = seed_slot();
+    // Lines that only grow forwards colour both joints as iteration 0
+    let joint_2_slot = seed_slot + globals.bidirectional;
+    let length = distance(joint_1, joint_2);
+    let joint_1_info = JointInfo(line_index, seed_slot, along_line(line_index, seed_slot), joint_1, 0.0, 0.0, field_magnitude(joint_1));
+    let joint_2_info = JointInfo(line_index, joint_2_slot, along_line(line_index, seed_slot + 1u), joint_1, length, 0.0, field_magnitude(joint_2));
+    let direction = joint_direction(joint_1, joint_2, field_direction);
+    var joint_1_vertices = hide_masked_joint(joint_1, create_vertices_for_line_joint(joint_1, direction, globals.line_width, joint_1_info));
+    var joint_2_vertices = hide_masked_joint(joint_2, create_vertices_for_line_joint(joint_2, direction, globals.line_width, joint_2_info));
+    var state = LineState(1u, 2u, length, globals.bidirectional, 0.0);
+    // Lines that stop at their seed don't move
+    if stops_before(line_index, 0u, joint_1) {
+        joint_1_vertices = stop_line(joint_1_vertices);
+        joint_2_vertices = joint_1_vertices;
+        state = LineState(0u, 0u, 0.0, 0u, 0.0);
+    } else if stops_before(line_index, 1u, joint_2) {
+        joint_2_vertices = stop_line(joint_1_vertices);
+        state = LineState(0u, 1u, 0.0, globals.bidirectional, 0.0);
+    }
+    line_states[line_index] = state;
+
+    let parts = line_layout();
+    let first_vertex_index = line_num_vertices(parts) * line_index;
+    let first_triangle_index = line_num_indices(parts) * line_index;
+    let seed_vertex_index = first_vertex_index + 2u * seed_slot;
 
-    let first_vertex_index = 2u * globals.max_iterations * invocation_id.x;
-    let first_triangle_index = 6u * (globals.max_iterations - 1u) * invocation_id.x;
+    vertex_buffer[seed_vertex_index] = joint_1_vertices.first;
+    vertex_buffer[seed_vertex_index+1u] = joint_1_vertices.second;
+    vertex_buffer[seed_vertex_index+2u] = joint_2_vertices.first;
+    vertex_buffer[seed_vertex_index+3u] = joint_2_vertices.second;
 
-    vertex_buffer[first_vertex_index] = joint_1_vertices.first;
-    vertex_buffer[first_vertex_index+1u] = joint_1_vertices.second;
-    vertex_buffer[first_vertex_index+2u] = joint_2_vertices.first;
-    vertex_buffer[first_vertex_index+3u] = joint_2_vertices.second;
-    
-    index_buffer[first_triangle_index] = first_vertex_index;
-    index_buffer[first_triangle_index+1u] = first_vertex_index+1u;
-    index_buffer[first_triangle_index+2u] = first_vertex_index+3u;
-    index_buffer[first_triangle_index+3u] = first_vertex_index;
-    index_buffer[first_triangle_index+4u] = first_vertex_index+3u;
-    index_buffer[first_triangle_index+5u] = first_vertex_index+2u;
+    write_join(parts, first_vertex_index, first_triangle_index, seed_slot, joint_1, joint_1);
+    write_join(parts, first_vertex_index, first_triangle_index, seed_slot + 1u, joint_2, joint_2);
+    write_segment_indices(parts, first_vertex_index, first_triangle_index, seed_slot, state.alive == 1u);
+    // Without area for lines that stopped before joint_2
+    write_cap(parts, first_vertex_index, first_triangle_index, 0u, seed_slot, joint_2);
+    write_cap(parts, first_vertex_index, first_triangle_index, 1u, seed_slot + 1u, joint_1);
 
     // Debug
     // index_buffer[0] = u32(iteration_count.value);
@@ -134,35 +1395,144 @@ fn update(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         return;
     }
 
-    let base_vertex_index = 2u * iteration_count.value + 2u * globals.max_iterations * invocation_id.x;
-    let base_triangle_index = 6u * (iteration_count.value - 1u) + 6u * (globals.max_iterations - 1u) * invocation_id.x;
+    let line_index = invocation_id.x;
+    let parts = line_layout();
+    let first_vertex_index = line_num_vertices(parts) * line_index;
+    let first_triangle_index = line_num_indices(parts) * line_index;
+    let step = trace_step(iteration_count.value);
+    let new_vertex_index = first_vertex_index + 2u * step.new_joint;
+    let prev_vertex_index = first_vertex_index + 2u * step.prev_joint;
 
-    // Vertex position for previous line joint
-    let prev_joint_v1_pos = vertex_buffer[base_vertex_index-u32(2)].position.xy;
-    let prev_joint_v2_pos = vertex_buffer[base_vertex_index-u32(1)].position.xy;
+    let prev_joint_vertices = LineVertexPair(vertex_buffer[prev_vertex_index], vertex_buffer[prev_vertex_index + 1u]);
 
-    let prev_joint = prev_joint_v1_pos + 0.5 * (prev_joint_v2_pos - prev_joint_v1_pos);
+    // Stopped lines repeat the joint where they stopped
+    var state = line_states[line_index];
+    var alive = state.alive == 1u;
+    if step.sign < 0.0 {
+        alive = state.backward_alive == 1u;
+    }
+    var new_joint_vertices = prev_joint_vertices;
+    // The joints before and after prev_joint in the order of the slots if a joint was added
+    var joined = false;
+    var join_a = vec2<f32>(0.0);
+    var join_c = vec2<f32>(0.0);
+    if alive {
+        // Vertex position for previous line joint
+        let prev_joint_v1_pos = prev_joint_vertices.first.position.xy;
+        let prev_joint_v2_pos = prev_joint_vertices.second.position.xy;
+
+        let prev_joint = prev_joint_v1_pos + 0.5 * (prev_joint_v2_pos - prev_joint_v1_pos);
 
-    let field_direction = get_field_direction(prev_joint);
+        let field_direction = get_trace_direction(prev_joint, step.sign);
 
-    let new_joint = vec2<f32>(prev_joint.x + field_direction.x * globals.step_size, prev_joint.y + field_direction.y * globals.step_size);
-    let new_joint_vertices = create_vertices_for_line_joint(new_joint, field_direction, globals.line_width);
+        let new_joint = integrate(prev_joint, field_direction, step.sign);
+        if stops_before(line_index, state.num_joints, new_joint) {
+            new_joint_vertices = stop_line(prev_joint_vertices);
+            alive = false;
+        } else {
+            // Normals point the same way along the whole line
+            // The joint before prev_joint in the direction of the step
+            let before_vertex_index = u32(i32(prev_vertex_index) - 2 * i32(step.sign));
+            let before_joint = mix(vertex_buffer[before_vertex_index].position.xy, vertex_buffer[before_vertex_index + 1u].position.xy, 0.5);
+            let seed_vertex_index = first_vertex_index + 2u * seed_slot();
+            let seed = mix(vertex_buffer[seed_vertex_index].position.xy, vertex_buffer[seed_vertex_index + 1u].position.xy, 0.5);
 
-    vertex_buffer[base_vertex_index] = new_joint_vertices.first;
-    vertex_buffer[base_vertex_index+1u] = new_joint_vertices.second;
-    
-    index_buffer[base_triangle_index] = base_vertex_index-u32(2u);
-    index_buffer[base_triangle_index+1u] = base_vertex_index-u32(1u);
-    index_buffer[base_triangle_index+2u] = base_vertex_index+u32(1u);
-    index_buffer[base_triangle_index+3u] = base_vertex_index-u32(2u);
-    index_buffer[base_triangle_index+4u] = base_vertex_index+u32(1u);
-    index_buffer[base_triangle_index+5u] = base_vertex_index;
+            state.num_joints += 1u;
+            var distance_from_seed = 0.0;
+            if step.sign < 0.0 {
+                state.backward_length += distance(prev_joint, new_joint);
+                distance_from_seed = state.backward_length;
+            } else {
+                state.length += distance(prev_joint, new_joint);
+                distance_from_seed = state.length;
+            }
+            let info = JointInfo(line_index, step.new_joint, along_line(line_index, step.new_joint), seed, distance_from_seed, line_curvature(before_joint, prev_joint, new_joint), field_magnitude(new_joint));
+            let direction = step.sign * joint_direction(prev_joint, new_joint, field_direction);
+            new_joint_vertices = hide_masked_joint(new_joint, create_vertices_for_line_joint(new_joint, direction, globals.line_width, info));
+            joined = true;
+            join_a = before_joint;
+            join_c = new_joint;
+            if step.sign < 0.0 {
+                join_a = new_joint;
+                join_c = before_joint;
+            }
+        }
+        if step.sign < 0.0 {
+            state.backward_alive = u32(alive);
+        } else {
+            state.alive = u32(alive);
+        }
+        line_states[line_index] = state;
+    }
+
+    vertex_buffer[new_vertex_index] = new_joint_vertices.first;
+    vertex_buffer[new_vertex_index+1u] = new_joint_vertices.second;
+
+    if joined {
+        let prev_joint = mix(prev_joint_vertices.first.position.xy, prev_joint_vertices.second.position.xy, 0.5);
+        let new_joint = mix(new_joint_vertices.first.position.xy, new_joint_vertices.second.position.xy, 0.5);
+        write_join(parts, first_vertex_index, first_triangle_index, step.new_joint, new_joint, new_joint);
+        write_join(parts, first_vertex_index, first_triangle_index, step.prev_joint, join_a, join_c);
+        write_cap(parts, first_vertex_index, first_triangle_index, u32(step.sign > 0.0), step.new_joint, prev_joint);
+    }
+
+    write_segment_indices(parts, first_vertex_index, first_triangle_index, min(step.new_joint, step.prev_joint), alive);
 
     // Debug
     // index_buffer[0] = u32(iteration_count.value);
 
 }
 
+fn base_noise(p: vec2<f32>) -> f32 {
+#ifdef NOISE_SIMPLEX
+    return simplexNoise2(p);
+#else ifdef NOISE_VALUE
+    return valueNoise2(p);
+#else ifdef NOISE_WORLEY
+    return worleyNoise2(p);
+#else
+    return perlinNoise2(p);
+#endif
+}
+
+// Sum of globals.noise_octaves octaves, normalized by the total amplitude. A single octave gives
+// the base noise unchanged.
+fn fractal_noise(p: vec2<f32>) -> f32 {
+    var sum = 0.0;
+    var total_amplitude = 0.0;
+    var amplitude = 1.0;
+    var frequency = 1.0;
+    for (var octave = 0u; octave < max(globals.noise_octaves, 1u); octave++) {
+        var n = base_noise(p * frequency);
+#ifdef FRACTAL_RIDGED
+        n = 1.0 - abs(n);
+        n = n * n;
+#else ifdef FRACTAL_TURBULENCE
+        n = abs(n);
+#endif
+        sum += amplitude * n;
+        total_amplitude += amplitude;
+        amplitude *= globals.noise_gain;
+        frequency *= globals.noise_lacunarity;
+    }
+    return sum / total_amplitude;
+}
+
+// Decorrelates the x and y of a warp.
+const WARP_COMPONENT_OFFSET: vec2<f32> = vec2<f32>(5.2, 1.3);
+
+// Domain warping in the style of f(p + g(p + h(p))): every layer displaces p by noise sampled at
+// the position displaced by the previous layers.
+fn warp(p: vec2<f32>) -> vec2<f32> {
+    var displacement = vec2<f32>(0.0);
+    for (var i = 0u; i < min(globals.num_warp_layers, MAX_WARP_LAYERS); i++) {
+        let layer = globals.warp_layers[i];
+        let q = (p + displacement) * layer.scale + layer.offset;
+        displacement = layer.strength * vec2<f32>(fractal_noise(q), fractal_noise(q + WARP_COMPONENT_OFFSET));
+    }
+    return p + displacement;
+}
+
 // MIT License. © Stefan Gustavson, Munrocket
 //
 fn permute4(x: vec4f) -> vec4f { return ((x * 34. + 1.) * x) % vec4f(289.); }
@@ -201,6 +1571,71 @@ fn perlinNoise2(P: vec2f) -> f32 {
     return 2.3 * n_xy;
 }
 
+// MIT License. © Ian McEwan, Stefan Gustavson, Munrocket
+//
+fn mod289_2(x: vec2f) -> vec2f { return x - floor(x * (1. / 289.)) * 289.; }
+fn mod289_3(x: vec3f) -> vec3f { return x - floor(x * (1. / 289.)) * 289.; }
+fn permute3(x: vec3f) -> vec3f { return mod289_3(((x * 34.) + 1.) * x); }
+
+fn simplexNoise2(v: vec2f) -> f32 {
+    let C = vec4(
+        0.211324865405187, // (3.0-sqrt(3.0))/6.0
+        0.366025403784439, // 0.5*(sqrt(3.0)-1.0)
+        -0.577350269189626, // -1.0 + 2.0 * C.x
+        0.024390243902439 // 1.0 / 41.0
+    );
+    var i = floor(v + dot(v, C.yy));
+    let x0 = v - i + dot(i, C.xx);
+    let i1 = select(vec2(0., 1.), vec2(1., 0.), x0.x > x0.y);
+    var x12 = x0.xyxy + C.xxzz;
+    x12.x = x12.x - i1.x;
+    x12.y = x12.y - i1.y;
+    i = mod289_2(i); // Avoid truncation effects in permutation
+    let p = permute3(permute3(i.y + vec3(0., i1.y, 1.)) + i.x + vec3(0., i1.x, 1.));
+    var m = max(0.5 - vec3(dot(x0, x0), dot(x12.xy, x12.xy), dot(x12.zw, x12.zw)), vec3(0.));
+    m *= m;
+    m *= m;
+    let x = 2. * fract(p * C.www) - 1.;
+    let h = abs(x) - 0.5;
+    let ox = floor(x + 0.5);
+    let a0 = x - ox;
+    m *= 1.79284291400159 - 0.85373472095314 * (a0 * a0 + h * h);
+    let g = vec3(a0.x * x0.x + h.x * x0.y, a0.yz * x12.xz + h.yz * x12.yw);
+    return 130. * dot(m, g);
+}
+
+// Hash of the integer lattice point i.
+fn lattice_hash(i: vec2<f32>) -> u32 {
+    return hash(hash(u32(i32(i.x))) ^ u32(i32(i.y)));
+}
+
+fn valueNoise2(p: vec2<f32>) -> f32 {
+    let i = floor(p);
+    let u = fade2(fract(p));
+    let a = f32(lattice_hash(i)) / 4294967295.0 * 2.0 - 1.0;
+    let b = f32(lattice_hash(i + vec2(1.0, 0.0))) / 4294967295.0 * 2.0 - 1.0;
+    let c = f32(lattice_hash(i + vec2(0.0, 1.0))) / 4294967295.0 * 2.0 - 1.0;
+    let d = f32(lattice_hash(i + vec2(1.0, 1.0))) / 4294967295.0 * 2.0 - 1.0;
+    return mix(mix(a, b, u.x), mix(c, d, u.x), u.y);
+}
+
+// Distance to the closest feature point (F1), one random point per lattice cell, mapped from
+// roughly [0, 1] to [-1, 1].
+fn worleyNoise2(p: vec2<f32>) -> f32 {
+    let i = floor(p);
+    let f = fract(p);
+    var min_distance = 8.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let cell = vec2<f32>(f32(x), f32(y));
+            let h = lattice_hash(i + cell);
+            let feature = cell + vec2<f32>(f32(h) / 4294967295.0, random_f32(h));
+            min_distance = min(min_distance, length(feature - f));
+        }
+    }
+    return 2.0 * min_distance - 1.0;
+}
+
 fn hash(value: u32) -> u32 {
     var state = value;
     state = state ^ 2747636419u;
@@ -216,6 +1651,23 @@ fn random_f32(value: u32) -> f32 {
     return f32(hash(value)) / 4294967295.0;
 }
 
+// Mixes the seed into a hash input. Seed 0 leaves the input unchanged.
+fn seeded(value: u32) -> u32 {
+    if globals.seed == 0u {
+        return value;
+    }
+    return value ^ hash(globals.seed);
+}
+
+// Moves the noise lattice to a seed dependent place within its period of 289.
+fn seed_noise_offset() -> vec2<f32> {
+    if globals.seed == 0u {
+        return vec2<f32>(0.0);
+    }
+    let seed_hash = hash(globals.seed);
+    return vec2<f32>(random_f32(seed_hash), random_f32(seed_hash + 1u)) * 289.0;
+}
+
 fn pcg_hash(input: u32) -> u32 {
     let state = input * 747796405u + 289133645u;
     let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
@@ -0,0 +1,621 @@
+// Line colour gradients with any number of stops, the built-in palettes and palette file import.
+//
+// Colours are linear RGB with straight alpha, like `line_color_start` and `line_color_end`. A
+// gradient is baked into a ramp of `COLOR_RAMP_SIZE` colours that the compute shader samples, so
+// the shader doesn't need to know how the stops are interpolated.
+//
+// Three palette formats are read, all of them sRGB:
+//
+// - GIMP palettes (.gpl): a `GIMP Palette` header and one `R G B [name]` line per colour.
+// - Adobe Swatch Exchange (.ase): RGB, CMYK and gray colour entries. Lab entries are skipped.
+// - Hex lists (any other extension): `RRGGBB` or `RRGGBBAA` colours with an optional `#`,
+//   separated by whitespace, commas or new lines. Lines starting with `;` are comments.
+
+use std::{path::Path, sync::Arc};
+
+use bevy::prelude::*;
+use bevy_egui::egui;
+
+// Must match the number of colours `GradientBuffer` uploads.
+pub const COLOR_RAMP_SIZE: usize = 256;
+
+// sRGB hex colours from dark to light.
+pub const PALETTES: [(&str, &[&str]); 8] = [
+    (
+        "Viridis",
+        &[
+            "440154", "482878", "3e4989", "31688e", "26828e", "1f9e89", "35b779", "6ece58",
+            "b5de2b", "fde725",
+        ],
+    ),
+    (
+        "Magma",
+        &[
+            "000004", "1c1044", "4f127b", "812581", "b5367a", "e55964", "fb8761", "fec287",
+            "fcfdbf",
+        ],
+    ),
+    (
+        "Inferno",
+        &[
+            "000004", "1f0c48", "550f6d", "88226a", "ba3655", "e35933", "f98e09", "f9cb35",
+            "fcffa4",
+        ],
+    ),
+    (
+        "Ocean",
+        &[
+            "012a4a", "013a63", "01497c", "2a6f97", "468faf", "89c2d9", "a9d6e5",
+        ],
+    ),
+    (
+        "Sunset",
+        &["0b1d51", "5b2a86", "b33f8c", "f0746e", "fbb36b", "fde9a5"],
+    ),
+    (
+        "Ember",
+        &["000000", "5c0a00", "b22200", "f25c05", "f9a41b", "fef3b0"],
+    ),
+    (
+        "Rainbow",
+        &["ff0000", "ffff00", "00ff00", "00ffff", "0000ff", "ff00ff"],
+    ),
+    ("Grayscale", &["000000", "ffffff"]),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Interpolation {
+    LinearRgb,
+    // Perceptually even, without the dark or grey middle of linear RGB blends
+    #[default]
+    Oklab,
+}
+
+impl Interpolation {
+    pub const ALL: [Interpolation; 2] = [Interpolation::LinearRgb, Interpolation::Oklab];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Interpolation::LinearRgb => "Linear RGB",
+            Interpolation::Oklab => "OKLab",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GradientStop {
+    // From 0 at the start of the line (or the weakest field) to 1
+    pub position: f32,
+    pub color: Vec4,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Gradient {
+    // Sorted by position, at least one
+    stops: Vec<GradientStop>,
+    interpolation: Interpolation,
+    ramp: Vec<Vec4>,
+}
+
+impl Gradient {
+    // Positions are clamped to [0, 1]. Without stops the gradient is transparent.
+    pub fn new(mut stops: Vec<GradientStop>, interpolation: Interpolation) -> Self {
+        for stop in &mut stops {
+            stop.position = stop.position.clamp(0.0, 1.0);
+        }
+        stops.sort_by(|a, b| a.position.total_cmp(&b.position));
+        if stops.is_empty() {
+            stops.push(GradientStop {
+                position: 0.0,
+                color: Vec4::ZERO,
+            });
+        }
+
+        let mut gradient = Self {
+            stops,
+            interpolation,
+            ramp: Vec::new(),
+        };
+        gradient.ramp = (0..COLOR_RAMP_SIZE)
+            .map(|i| gradient.evaluate(i as f32 / (COLOR_RAMP_SIZE - 1) as f32))
+            .collect();
+        gradient
+    }
+
+    // Spreads the colours evenly from 0 to 1.
+    pub fn from_colors(colors: &[Vec4], interpolation: Interpolation) -> Self {
+        let last = (colors.len().max(2) - 1) as f32;
+        let stops = colors
+            .iter()
+            .enumerate()
+            .map(|(i, &color)| GradientStop {
+                position: i as f32 / last,
+                color,
+            })
+            .collect();
+        Self::new(stops, interpolation)
+    }
+
+    pub fn stops(&self) -> &[GradientStop] {
+        &self.stops
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    pub fn ramp(&self) -> &[Vec4] {
+        &self.ramp
+    }
+
+    // The exact colour at `t`, constant before the first and after the last stop.
+    pub fn evaluate(&self, t: f32) -> Vec4 {
+        let next = self.stops.partition_point(|stop| stop.position <= t);
+        if next == 0 {
+            return self.stops[0].color;
+        }
+        if next == self.stops.len() {
+            return self.stops[next - 1].color;
+        }
+        let (a, b) = (self.stops[next - 1], self.stops[next]);
+        let f = (t - a.position) / (b.position - a.position);
+        match self.interpolation {
+            Interpolation::LinearRgb => a.color.lerp(b.color, f),
+            Interpolation::Oklab => {
+                let lab = linear_srgb_to_oklab(a.color.truncate())
+                    .lerp(linear_srgb_to_oklab(b.color.truncate()), f);
+                oklab_to_linear_srgb(lab)
+                    .clamp(Vec3::ZERO, Vec3::ONE)
+                    .extend(a.color.w + (b.color.w - a.color.w) * f)
+            }
+        }
+    }
+
+    // Mirrors `sample_color_ramp`.
+    pub fn sample(&self, f: f32) -> Vec4 {
+        let last = self.ramp.len() - 1;
+        let x = f.clamp(0.0, 1.0) * last as f32;
+        let i = (x as usize).min(last);
+        let j = (i + 1).min(last);
+        self.ramp[i].lerp(self.ramp[j], x - i as f32)
+    }
+
+    pub fn with_opacity(&self, opacity: f32) -> Self {
+        let stops = self
+            .stops
+            .iter()
+            .map(|stop| GradientStop {
+                color: stop.color.truncate().extend(opacity),
+                ..*stop
+            })
+            .collect();
+        Self::new(stops, self.interpolation)
+    }
+}
+
+pub fn linear_srgb_to_oklab(c: Vec3) -> Vec3 {
+    let l = 0.412_221_46 * c.x + 0.536_332_55 * c.y + 0.051_445_99 * c.z;
+    let m = 0.211_903_5 * c.x + 0.680_699_5 * c.y + 0.107_396_96 * c.z;
+    let s = 0.088_302_46 * c.x + 0.281_718_85 * c.y + 0.629_978_7 * c.z;
+    let (l, m, s) = (l.cbrt(), m.cbrt(), s.cbrt());
+    Vec3::new(
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    )
+}
+
+pub fn oklab_to_linear_srgb(c: Vec3) -> Vec3 {
+    let l = c.x + 0.396_337_78 * c.y + 0.215_803_76 * c.z;
+    let m = c.x - 0.105_561_346 * c.y - 0.063_854_17 * c.z;
+    let s = c.x - 0.089_484_18 * c.y - 1.291_485_5 * c.z;
+    let (l, m, s) = (l * l * l, m * m * m, s * s * s);
+    Vec3::new(
+        4.076_741_7 * l - 3.307_711_6 * m + 0.230_969_94 * s,
+        -1.268_438 * l + 2.609_757_4 * m - 0.341_319_38 * s,
+        -0.004_196_086_3 * l - 0.703_418_6 * m + 1.707_614_7 * s,
+    )
+}
+
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.040_45 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn srgb_color(r: f32, g: f32, b: f32) -> Vec4 {
+    Vec4::new(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), 1.0)
+}
+
+// The colours of a built-in palette, ignoring case.
+pub fn find_palette(name: &str) -> Option<Vec<Vec4>> {
+    let (_, colors) = PALETTES
+        .iter()
+        .find(|(palette, _)| palette.eq_ignore_ascii_case(name))?;
+    Some(
+        colors
+            .iter()
+            .map(|hex| parse_hex_color(hex).unwrap())
+            .collect(),
+    )
+}
+
+pub fn load_palette(path: &Path) -> Result<Vec<Vec4>, String> {
+    let error = |e: String| format!("failed to load {}: {e}", path.display());
+    let contents = std::fs::read(path).map_err(|e| error(e.to_string()))?;
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_ascii_lowercase());
+    let colors = match extension.as_ref().and_then(|extension| extension.to_str()) {
+        Some("gpl") => parse_gpl(&String::from_utf8_lossy(&contents)),
+        Some("ase") => parse_ase(&contents),
+        _ => parse_hex_palette(&String::from_utf8_lossy(&contents)),
+    }
+    .map_err(error)?;
+    if colors.is_empty() {
+        return Err(error("no colours".to_string()));
+    }
+    Ok(colors)
+}
+
+// A built-in palette by name, or else a palette file.
+pub fn load_palette_or_file(name_or_path: &str) -> Result<Vec<Vec4>, String> {
+    let path = Path::new(name_or_path);
+    match find_palette(name_or_path) {
+        Some(colors) => Ok(colors),
+        None if path.exists() => load_palette(path),
+        None => {
+            let names: Vec<String> = PALETTES
+                .iter()
+                .map(|(name, _)| name.to_lowercase())
+                .collect();
+            Err(format!(
+                "'{name_or_path}' is neither a palette file nor a built-in palette ({})",
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+pub fn parse_hex_color(hex: &str) -> Option<Vec4> {
+    let hex = hex.trim().trim_start_matches('#');
+    if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap() as f32 / 255.0;
+    let color = srgb_color(channel(0), channel(1), channel(2));
+    Some(if hex.len() == 8 {
+        color.truncate().extend(channel(3))
+    } else {
+        color
+    })
+}
+
+pub fn parse_hex_palette(contents: &str) -> Result<Vec<Vec4>, String> {
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with(';'))
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|token| !token.is_empty())
+        .map(|token| parse_hex_color(token).ok_or_else(|| format!("invalid hex colour '{token}'")))
+        .collect()
+}
+
+pub fn parse_gpl(contents: &str) -> Result<Vec<Vec4>, String> {
+    let mut lines = contents.lines().enumerate();
+    if lines
+        .next()
+        .is_none_or(|(_, header)| header.trim() != "GIMP Palette")
+    {
+        return Err("not a GIMP palette".to_string());
+    }
+
+    let mut colors = Vec::new();
+    for (i, line) in lines {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+        let rgb: Vec<f32> = line
+            .split_whitespace()
+            .take(3)
+            .map_while(|channel| channel.parse::<u8>().ok())
+            .map(|channel| channel as f32 / 255.0)
+            .collect();
+        if rgb.len() != 3 {
+            return Err(format!("line {}: expected R G B, found '{line}'", i + 1));
+        }
+        colors.push(srgb_color(rgb[0], rgb[1], rgb[2]));
+    }
+    Ok(colors)
+}
+
+// Big-endian: the magic `ASEF`, a u16 major and minor version, the u32 number of blocks and then
+// the blocks, each a u16 type and the u32 length of what follows.
+pub fn parse_ase(contents: &[u8]) -> Result<Vec<Vec4>, String> {
+    const COLOR_ENTRY: u16 = 0x0001;
+
+    if contents.len() < 12 || &contents[..4] != b"ASEF" {
+        return Err("not an Adobe Swatch Exchange file".to_string());
+    }
+    let truncated = || "truncated colour block".to_string();
+    let u16_at = |i: usize| -> Option<u16> {
+        Some(u16::from_be_bytes(
+            contents.get(i..i + 2)?.try_into().unwrap(),
+        ))
+    };
+    let u32_at = |i: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(
+            contents.get(i..i + 4)?.try_into().unwrap(),
+        ))
+    };
+
+    let num_blocks = u32_at(8).unwrap();
+    let mut colors = Vec::new();
+    let mut offset = 12;
+    for _ in 0..num_blocks {
+        let block_type = u16_at(offset).ok_or_else(truncated)?;
+        let length = u32_at(offset + 2).ok_or_else(truncated)? as usize;
+        let block = contents
+            .get(offset + 6..offset + 6 + length)
+            .ok_or_else(truncated)?;
+        offset += 6 + length;
+        if block_type != COLOR_ENTRY {
+            // Group start and end
+            continue;
+        }
+
+        // The UTF-16 name with its length in characters, the colour model and its values
+        let name_length =
+            2 * u16::from_be_bytes(block.get(..2).ok_or_else(truncated)?.try_into().unwrap())
+                as usize;
+        let model = block
+            .get(2 + name_length..6 + name_length)
+            .ok_or_else(truncated)?;
+        let values: Vec<f32> = block[6 + name_length..]
+            .chunks_exact(4)
+            .map(|value| f32::from_be_bytes(value.try_into().unwrap()))
+            .collect();
+        let value = |i: usize| values.get(i).copied().ok_or_else(truncated);
+        match model {
+            b"RGB " => colors.push(srgb_color(value(0)?, value(1)?, value(2)?)),
+            b"CMYK" => {
+                let k = 1.0 - value(3)?;
+                colors.push(srgb_color(
+                    (1.0 - value(0)?) * k,
+                    (1.0 - value(1)?) * k,
+                    (1.0 - value(2)?) * k,
+                ));
+            }
+            b"Gray" => colors.push(srgb_color(value(0)?, value(0)?, value(0)?)),
+            _ => {}
+        }
+    }
+    Ok(colors)
+}
+
+fn egui_color(color: Vec4) -> egui::Color32 {
+    egui::Rgba::from_rgb(color.x, color.y, color.z).into()
+}
+
+// Returns the edited gradient if it was changed.
+pub fn gradient_ui(ui: &mut egui::Ui, gradient: &Arc<Gradient>) -> Option<Gradient> {
+    let mut changed = false;
+    let mut stops = gradient.stops().to_vec();
+    let mut interpolation = gradient.interpolation();
+
+    // Preview of the ramp with the stops marked below it, opaque
+    let (rect, _) = ui.allocate_exact_size(
+        egui::vec2(ui.available_width().min(300.0), 20.0),
+        egui::Sense::hover(),
+    );
+    let ramp = gradient.ramp();
+    for (i, &color) in ramp.iter().enumerate() {
+        let x = |i: usize| rect.left() + rect.width() * i as f32 / ramp.len() as f32;
+        let slice = egui::Rect::from_x_y_ranges(x(i)..=x(i + 1), rect.y_range());
+        ui.painter().rect_filled(slice, 0.0, egui_color(color));
+    }
+    for stop in &stops {
+        let x = rect.left() + rect.width() * stop.position;
+        ui.painter().line_segment(
+            [
+                egui::pos2(x, rect.bottom() - 5.0),
+                egui::pos2(x, rect.bottom()),
+            ],
+            egui::Stroke::new(2.0, ui.visuals().strong_text_color()),
+        );
+    }
+
+    ui.horizontal(|ui| {
+        ui.label("Interpolation");
+        egui::ComboBox::from_id_source("gradient_interpolation")
+            .selected_text(interpolation.name())
+            .show_ui(ui, |ui| {
+                for option in Interpolation::ALL {
+                    changed |= ui
+                        .selectable_value(&mut interpolation, option, option.name())
+                        .changed();
+                }
+            });
+    });
+
+    let opacity = stops[0].color.w;
+    ui.horizontal(|ui| {
+        ui.label("Palette");
+        egui::ComboBox::from_id_source("gradient_palette")
+            .selected_text("Apply...")
+            .show_ui(ui, |ui| {
+                for (name, _) in PALETTES {
+                    if ui.selectable_label(false, name).clicked() {
+                        // Palettes are opaque, keep the opacity of the lines
+                        let colors = find_palette(name).unwrap();
+                        stops = Gradient::from_colors(&colors, interpolation)
+                            .with_opacity(opacity)
+                            .stops()
+                            .to_vec();
+                        changed = true;
+                    }
+                }
+            });
+        let mut new_opacity = opacity;
+        if ui
+            .add(
+                egui::DragValue::new(&mut new_opacity)
+                    .speed(0.01)
+                    .clamp_range(0.0..=1.0)
+                    .prefix("opacity: "),
+            )
+            .on_hover_text("Sets the opacity of every stop")
+            .changed()
+        {
+            for stop in &mut stops {
+                stop.color.w = new_opacity;
+            }
+            changed = true;
+        }
+        if ui.button("Reverse").clicked() {
+            for stop in &mut stops {
+                stop.position = 1.0 - stop.position;
+            }
+            changed = true;
+        }
+    });
+
+    let mut remove = None;
+    for (i, stop) in stops.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut stop.position)
+                        .speed(0.01)
+                        .clamp_range(0.0..=1.0),
+                )
+                .changed();
+            let mut rgba = stop.color.to_array();
+            if ui.color_edit_button_rgba_premultiplied(&mut rgba).changed() {
+                stop.color = Vec4::from_array(rgba);
+                changed = true;
+            }
+            if ui.button("Remove").clicked() {
+                remove = Some(i);
+            }
+        });
+    }
+    if let Some(i) = remove.filter(|_| stops.len() > 2) {
+        stops.remove(i);
+        changed = true;
+    }
+
+    if ui.button("Add stop").clicked() {
+        // In the middle of the widest gap, without changing the colours
+        let (position, _) = stops
+            .windows(2)
+            .map(|pair| {
+                let gap = pair[1].position - pair[0].position;
+                (pair[0].position + gap / 2.0, gap)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((0.5, 0.0));
+        stops.push(GradientStop {
+            position,
+            color: gradient.evaluate(position),
+        });
+        changed = true;
+    }
+
+    changed.then(|| Gradient::new(stops, interpolation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gradients_interpolate_between_stops() {
+        let red = Vec4::new(1.0, 0.0, 0.0, 1.0);
+        let blue = Vec4::new(0.0, 0.0, 1.0, 0.5);
+        let linear = Gradient::from_colors(&[red, blue], Interpolation::LinearRgb);
+        let oklab = Gradient::from_colors(&[red, blue], Interpolation::Oklab);
+
+        for gradient in [&linear, &oklab] {
+            assert!(gradient.sample(0.0).distance(red) < 1e-4);
+            assert!(gradient.sample(1.0).distance(blue) < 1e-4);
+            assert_eq!(gradient.sample(2.0), gradient.sample(1.0));
+            assert_eq!(gradient.ramp().len(), COLOR_RAMP_SIZE);
+        }
+        assert!(linear.sample(0.5).distance(Vec4::new(0.5, 0.0, 0.5, 0.75)) < 1e-4);
+        // OKLab blends the perceived lightness evenly, alpha is still blended linearly
+        let lightness = |color: Vec4| linear_srgb_to_oklab(color.truncate()).x;
+        let middle = oklab.sample(0.5);
+        let expected = (lightness(red) + lightness(blue)) / 2.0;
+        assert!((lightness(middle) - expected).abs() < 1e-3, "{middle}");
+        let black_to_white = [Vec4::W, Vec4::ONE];
+        let linear_gray = Gradient::from_colors(&black_to_white, Interpolation::LinearRgb);
+        let oklab_gray = Gradient::from_colors(&black_to_white, Interpolation::Oklab);
+        assert!(lightness(linear_gray.sample(0.5)) > 0.75);
+        assert!((lightness(oklab_gray.sample(0.5)) - 0.5).abs() < 1e-3);
+        assert!((middle.w - 0.75).abs() < 1e-4);
+
+        let white = Vec3::ONE;
+        let lab = linear_srgb_to_oklab(white);
+        assert!((lab - Vec3::X).abs().max_element() < 1e-3, "{lab}");
+        assert!((oklab_to_linear_srgb(lab) - white).abs().max_element() < 1e-3);
+
+        let stops = Gradient::new(
+            vec![
+                GradientStop {
+                    position: 1.0,
+                    color: blue,
+                },
+                GradientStop {
+                    position: 0.25,
+                    color: red,
+                },
+            ],
+            Interpolation::LinearRgb,
+        );
+        assert_eq!(stops.stops()[0].color, red);
+        assert_eq!(stops.evaluate(0.1), red);
+    }
+
+    #[test]
+    fn palettes_are_parsed() {
+        let hex = parse_hex_palette("; comment\n#ff0000 00ff00,\n0000ff80\n").unwrap();
+        assert_eq!(hex.len(), 3);
+        assert_eq!(hex[0], Vec4::new(1.0, 0.0, 0.0, 1.0));
+        assert_eq!(hex[2].w, 128.0 / 255.0);
+        assert!(parse_hex_palette("ff00").is_err());
+
+        let gpl = parse_gpl(
+            "GIMP Palette\nName: Test\nColumns: 2\n# comment\n255 255 255 White\n  0 0 0\n",
+        )
+        .unwrap();
+        assert_eq!(gpl, vec![Vec4::ONE, Vec4::new(0.0, 0.0, 0.0, 1.0)]);
+        assert!(parse_gpl("255 0 0").is_err());
+
+        let mut ase = b"ASEF\0\x01\0\0\0\0\0\x03".to_vec();
+        // An empty group around a named RGB and a gray colour
+        ase.extend(b"\xc0\x01\0\0\0\x02\0\0");
+        ase.extend(b"\0\x01\0\0\0\x18\0\x02\0A\0\0RGB ");
+        for value in [1.0f32, 0.0, 1.0] {
+            ase.extend(value.to_be_bytes());
+        }
+        ase.extend(b"\0\x02");
+        ase.extend(b"\0\x01\0\0\0\x0e\0\x01\0\0Gray");
+        ase.extend(0.0f32.to_be_bytes());
+        ase.extend(b"\0\x02");
+        let colors = parse_ase(&ase).unwrap();
+        assert_eq!(
+            colors,
+            vec![Vec4::new(1.0, 0.0, 1.0, 1.0), Vec4::new(0.0, 0.0, 0.0, 1.0)]
+        );
+
+        assert_eq!(find_palette("viridis").unwrap().len(), 10);
+    }
+}
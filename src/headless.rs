@@ -0,0 +1,472 @@
+// Headless batch renderer.
+//
+//     gpu_flow_fields render [OPTIONS] <PARAMS>...
+//
+// Reads `FlowFieldGlobals` from RON parameter files, traces all `max_iterations` with the CPU
+// tracer and writes one PNG (or SVG, HPGL, G-code) per parameter file. Doesn't need a display or
+// a GPU.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    cpu_tracer::*,
+    expression::FieldExpression,
+    gradients::{load_palette_or_file, Gradient, Interpolation},
+    images::load_rgba_image,
+    influences::{parse_influence, Influence},
+    obstacles::{parse_obstacle, Obstacle},
+    plotter::{export_plot, PlotFormat, PlotterSettings},
+    presets::load_preset,
+    rasterizer::Canvas,
+    seeding::load_density_map,
+    svg_export::export_svg,
+    vector_grid::load_vector_grid,
+    width_curve::{parse_width_curve, WidthCurve},
+    FlowFieldGlobals, FlowFieldInputs,
+};
+
+const USAGE: &str = "\
+Usage: gpu_flow_fields render [OPTIONS] <PARAMS>...
+
+Renders every RON parameter file (e.g. a saved preset) to an image without opening a window.
+
+Options:
+  -o, --output <PATH>      Output file (single parameter file only) or directory [default: .]
+      --format <FORMAT>    png, svg, hpgl or gcode [default: png]
+      --size <WxH>         Size of the canvas in logical pixels [default: 1280x720]
+      --scale <FACTOR>     Output pixels per logical pixel [default: 1]
+      --samples <N>        Samples per pixel along each axis [default: 3]
+      --seed <SEED>        Overrides the seed of every parameter file
+      --density-image <PATH>
+                           PNG for the image density seeding mode
+      --field-image <PATH> PNG for the image field modes
+      --color-image <PATH> PNG the lines take their colours from with image_color_mode
+      --vector-data <PATH> CSV or raw grid for the vector data field mode
+      --expression <TEXT>  vx = ..., vy = ... for the expression field mode
+      --influence <KIND,X,Y,STRENGTH,RADIUS>
+                           Adds an attractor, repulsor or vortex, may be repeated
+      --obstacle <SHAPE,BEHAVIOUR,X,Y,...>
+                           Adds a circle (RADIUS), box (WIDTH,HEIGHT) or polygon (X1,Y1,...)
+                           that lines stop at, steer around or are masked by, may be repeated
+      --gradient <PALETTE|PATH>
+                           Colours lines with a built-in palette (e.g. viridis) or a .gpl, .ase or
+                           hex palette file, at the opacity of line_color_start
+      --interpolation <MODE>
+                           rgb or oklab, how the gradient blends its colours [default: oklab]
+      --width-curve <X1,W1,X2,W2,...>
+                           Widths relative to line_width along the lines for the curve width
+                           mode, from X = 0 at the start to 1 at the end
+
+Plotter options (hpgl and gcode):
+      --paper <WxH>        Paper size in mm [default: 297x210]
+      --margin <MM>        Margin on every side in mm [default: 15]
+      --feed-rate <MM>     Drawing speed in mm/min [default: 3000]
+      --pen-up <CMD>       G-code to lift the pen [default: G0 Z2]
+      --pen-down <CMD>     G-code to lower the pen [default: G1 Z0 F1000]
+      --no-optimize        Plot the lines in trace order
+  -h, --help               Print this help";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Svg,
+    Plot(PlotFormat),
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Svg => "svg",
+            OutputFormat::Plot(PlotFormat::Hpgl) => "hpgl",
+            OutputFormat::Plot(PlotFormat::Gcode) => "gcode",
+        }
+    }
+}
+
+pub struct RenderOptions {
+    pub params: Vec<PathBuf>,
+    pub output: Option<PathBuf>,
+    pub format: OutputFormat,
+    pub width: u32,
+    pub height: u32,
+    pub scale: f32,
+    pub samples_per_axis: u32,
+    pub seed: Option<u32>,
+    pub density_image: Option<PathBuf>,
+    pub field_image: Option<PathBuf>,
+    pub color_image: Option<PathBuf>,
+    pub vector_data: Option<PathBuf>,
+    pub expression: Option<String>,
+    pub influences: Vec<Influence>,
+    pub obstacles: Vec<Obstacle>,
+    pub gradient: Option<Vec<bevy::math::Vec4>>,
+    pub interpolation: Interpolation,
+    pub width_curve: Option<WidthCurve>,
+    pub plotter: PlotterSettings,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            params: Vec::new(),
+            output: None,
+            format: OutputFormat::Png,
+            width: 1280,
+            height: 720,
+            scale: 1.0,
+            samples_per_axis: 3,
+            seed: None,
+            density_image: None,
+            field_image: None,
+            color_image: None,
+            vector_data: None,
+            expression: None,
+            influences: Vec::new(),
+            obstacles: Vec::new(),
+            gradient: None,
+            interpolation: Interpolation::default(),
+            width_curve: None,
+            plotter: PlotterSettings::default(),
+        }
+    }
+}
+
+// Entry point of the `render` subcommand. `args` excludes the program name and the subcommand.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let Some(options) = parse_args(args)? else {
+        println!("{USAGE}");
+        return Ok(());
+    };
+
+    let inputs = FlowFieldInputs {
+        density_map: match &options.density_image {
+            Some(path) => Some(Arc::new(load_density_map(path)?)),
+            None => None,
+        },
+        field_image: match &options.field_image {
+            Some(path) => Some(Arc::new(load_rgba_image(path)?)),
+            None => None,
+        },
+        vector_grid: match &options.vector_data {
+            Some(path) => Some(Arc::new(load_vector_grid(path)?)),
+            None => None,
+        },
+        field_expression: match &options.expression {
+            Some(source) => Some(Arc::new(
+                FieldExpression::parse(source).map_err(|e| format!("--expression: {e}"))?,
+            )),
+            None => None,
+        },
+        influences: Arc::new(options.influences.clone()),
+        obstacles: Arc::new(options.obstacles.clone()),
+        gradient: None,
+        color_image: match &options.color_image {
+            Some(path) => Some(Arc::new(load_rgba_image(path)?)),
+            None => None,
+        },
+        width_curve: Arc::new(options.width_curve.clone().unwrap_or_default()),
+    };
+
+    for params_path in &options.params {
+        let mut globals = load_globals(params_path)?;
+        globals.viewport_width = options.width as f32;
+        globals.viewport_height = options.height as f32;
+        if let Some(seed) = options.seed {
+            globals.seed = seed;
+        }
+        let inputs = FlowFieldInputs {
+            gradient: options.gradient.as_ref().map(|colors| {
+                Arc::new(
+                    Gradient::from_colors(colors, options.interpolation)
+                        .with_opacity(globals.line_color_start.w),
+                )
+            }),
+            ..inputs.clone()
+        };
+
+        let output_path = output_path(&options, params_path);
+        if let Some(parent) = output_path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("failed to create {}: {e}", parent.display()))?;
+        }
+        match options.format {
+            OutputFormat::Png => {
+                let canvas = render(&globals, &inputs, options.scale, options.samples_per_axis);
+                write_png(&canvas, globals.seed, &output_path)
+                    .map_err(|e| format!("failed to write {}: {e}", output_path.display()))?;
+            }
+            OutputFormat::Svg => export_svg(&globals, &inputs, &output_path)?,
+            OutputFormat::Plot(format) => {
+                let stats = export_plot(&globals, &inputs, &options.plotter, format, &output_path)?;
+                println!(
+                    "{} paths, {:.0} mm drawn, {:.0} mm pen-up travel ({:.0} mm unoptimized)",
+                    stats.num_paths,
+                    stats.pen_down_distance,
+                    stats.pen_up_distance,
+                    stats.unoptimized_pen_up_distance
+                );
+            }
+        }
+
+        println!("{} -> {}", params_path.display(), output_path.display());
+    }
+
+    Ok(())
+}
+
+fn parse_args(args: &[String]) -> Result<Option<RenderOptions>, String> {
+    let mut options = RenderOptions::default();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for {arg}\n\n{USAGE}"))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => options.output = Some(PathBuf::from(value()?)),
+            "--format" => {
+                options.format = match value()?.as_str() {
+                    "png" => OutputFormat::Png,
+                    "svg" => OutputFormat::Svg,
+                    "hpgl" => OutputFormat::Plot(PlotFormat::Hpgl),
+                    "gcode" => OutputFormat::Plot(PlotFormat::Gcode),
+                    format => {
+                        return Err(format!(
+                            "unknown format '{format}', expected png, svg, hpgl or gcode"
+                        ))
+                    }
+                }
+            }
+            "--size" => (options.width, options.height) = parse_size(value()?)?,
+            "--seed" => {
+                options.seed = Some(
+                    value()?
+                        .parse()
+                        .map_err(|_| "--seed must be a non-negative integer")?,
+                );
+            }
+            "--density-image" => options.density_image = Some(PathBuf::from(value()?)),
+            "--field-image" => options.field_image = Some(PathBuf::from(value()?)),
+            "--color-image" => options.color_image = Some(PathBuf::from(value()?)),
+            "--vector-data" => options.vector_data = Some(PathBuf::from(value()?)),
+            "--expression" => options.expression = Some(value()?.to_string()),
+            "--influence" => options.influences.push(parse_influence(value()?)?),
+            "--obstacle" => options.obstacles.push(parse_obstacle(value()?)?),
+            "--gradient" => options.gradient = Some(load_palette_or_file(value()?)?),
+            "--interpolation" => {
+                options.interpolation = match value()?.as_str() {
+                    "rgb" => Interpolation::LinearRgb,
+                    "oklab" => Interpolation::Oklab,
+                    mode => {
+                        return Err(format!(
+                            "unknown interpolation '{mode}', expected rgb or oklab"
+                        ))
+                    }
+                }
+            }
+            "--width-curve" => options.width_curve = Some(parse_width_curve(value()?)?),
+            "--paper" => {
+                let (width, height) = parse_size(value()?)?;
+                options.plotter.paper_width = width;
+                options.plotter.paper_height = height;
+            }
+            "--margin" => {
+                options.plotter.margin = value()?
+                    .parse()
+                    .ok()
+                    .filter(|margin: &f32| *margin >= 0.0)
+                    .ok_or("--margin must be a non-negative number")?;
+            }
+            "--feed-rate" => {
+                options.plotter.feed_rate = value()?
+                    .parse()
+                    .ok()
+                    .filter(|feed_rate: &f32| *feed_rate > 0.0)
+                    .ok_or("--feed-rate must be a positive number")?;
+            }
+            "--pen-up" => options.plotter.pen_up_command = value()?.clone(),
+            "--pen-down" => options.plotter.pen_down_command = value()?.clone(),
+            "--no-optimize" => options.plotter.optimize = false,
+            "--scale" => {
+                options.scale = value()?
+                    .parse()
+                    .ok()
+                    .filter(|scale: &f32| *scale > 0.0)
+                    .ok_or("--scale must be a positive number")?;
+            }
+            "--samples" => {
+                options.samples_per_axis = value()?
+                    .parse()
+                    .ok()
+                    .filter(|samples: &u32| *samples > 0)
+                    .ok_or("--samples must be a positive integer")?;
+            }
+            _ if arg.starts_with('-') => {
+                return Err(format!("unknown option {arg}\n\n{USAGE}"));
+            }
+            _ => options.params.push(PathBuf::from(arg)),
+        }
+    }
+
+    if options.params.is_empty() {
+        return Err(format!("no parameter files given\n\n{USAGE}"));
+    }
+
+    Ok(Some(options))
+}
+
+// Parses sizes like 1920x1080.
+fn parse_size<T: std::str::FromStr + Default + PartialOrd>(size: &str) -> Result<(T, T), String> {
+    size.split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .filter(|(w, h)| *w > T::default() && *h > T::default())
+        .ok_or_else(|| format!("invalid size '{size}', expected e.g. 1920x1080"))
+}
+
+pub fn load_globals(path: &Path) -> Result<FlowFieldGlobals, String> {
+    let globals = load_preset(path)?;
+
+    if globals.max_iterations < 2 {
+        return Err(format!(
+            "{}: max_iterations must be at least 2",
+            path.display()
+        ));
+    }
+
+    Ok(globals)
+}
+
+// A single parameter file may be written to an explicit file path, everything else goes into the
+// output directory named after the parameter file.
+fn output_path(options: &RenderOptions, params_path: &Path) -> PathBuf {
+    let file_name = Path::new(params_path.file_stem().unwrap_or_default())
+        .with_extension(options.format.extension());
+    match &options.output {
+        Some(output) if options.params.len() == 1 && output.extension().is_some() => output.clone(),
+        Some(output) => output.join(file_name),
+        None => file_name,
+    }
+}
+
+// The seed is stored in a text chunk so the image can be reproduced.
+fn write_png(canvas: &Canvas, seed: u32, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, canvas.width, canvas.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.add_text_chunk("seed".to_string(), seed.to_string())?;
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&canvas.resolve())?;
+    Ok(())
+}
+
+// Traces and rasterizes all lines. `globals.viewport_width/height` is the size of the canvas in
+// logical pixels and `scale` the number of output pixels per logical pixel.
+pub fn render(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    scale: f32,
+    samples_per_axis: u32,
+) -> Canvas {
+    let mut canvas = Canvas::new(
+        (globals.viewport_width * scale).round() as u32,
+        (globals.viewport_height * scale).round() as u32,
+        scale,
+        samples_per_axis,
+        globals.background_color,
+    );
+
+    for_each_line(globals, inputs, |_, vertices| {
+        canvas.draw_line(globals, vertices)
+    });
+
+    canvas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<RenderOptions>, String> {
+        parse_args(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parses_flags() {
+        let options = parse(&[
+            "a.ron",
+            "-o",
+            "out",
+            "--format",
+            "svg",
+            "--size",
+            "640x360",
+            "--scale",
+            "2",
+            "--seed",
+            "7",
+            "--no-optimize",
+            "b.ron",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            options.params,
+            [PathBuf::from("a.ron"), PathBuf::from("b.ron")]
+        );
+        assert_eq!(options.output, Some(PathBuf::from("out")));
+        assert!(options.format == OutputFormat::Svg);
+        assert_eq!((options.width, options.height), (640, 360));
+        assert_eq!(options.scale, 2.0);
+        assert_eq!(options.seed, Some(7));
+        assert!(!options.plotter.optimize);
+        // Untouched options keep their defaults
+        assert_eq!(options.samples_per_axis, 3);
+
+        assert!(parse(&["--help", "a.ron"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let error = |args: &[&str]| parse(args).err().unwrap();
+        assert!(error(&["a.ron", "--size"]).starts_with("missing value for --size"));
+        assert!(error(&[]).starts_with("no parameter files given"));
+        assert!(error(&["a.ron", "--bogus"]).starts_with("unknown option --bogus"));
+        assert!(error(&["a.ron", "--format", "jpg"]).starts_with("unknown format 'jpg'"));
+        assert!(error(&["a.ron", "--size", "0x10"]).starts_with("invalid size '0x10'"));
+        assert!(error(&["a.ron", "--size", "10"]).starts_with("invalid size '10'"));
+        assert_eq!(
+            error(&["a.ron", "--scale", "-1"]),
+            "--scale must be a positive number"
+        );
+        assert_eq!(
+            error(&["a.ron", "--seed", "x"]),
+            "--seed must be a non-negative integer"
+        );
+    }
+
+    #[test]
+    fn output_paths() {
+        let options = parse(&["presets/a.ron", "-o", "out/image.png"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            output_path(&options, Path::new("presets/a.ron")),
+            PathBuf::from("out/image.png")
+        );
+        // Several parameter files go into the output directory
+        let options = parse(&["a.ron", "b.ron", "-o", "out", "--format", "gcode"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            output_path(&options, Path::new("presets/b.ron")),
+            PathBuf::from("out/b.gcode")
+        );
+    }
+}
@@ -0,0 +1,167 @@
+// Images the flow field reads, loaded from PNG files.
+
+use std::{fs::File, io::BufReader, path::Path};
+
+use bevy::prelude::*;
+
+// The luminance of an image in [0, 1], row by row from the top.
+pub struct LumaImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<f32>,
+}
+
+// 8 bit RGBA, row by row from the top. Uploaded as is and unpacked by the compute shader.
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl RgbaImage {
+    // Mirrors `field_image_texel` in the compute shader, coordinates are clamped to the edge.
+    pub fn texel(&self, x: i32, y: i32) -> Vec4 {
+        let x = x.clamp(0, self.width as i32 - 1);
+        let y = y.clamp(0, self.height as i32 - 1);
+        unpack_rgba(self.pixels[(y * self.width as i32 + x) as usize])
+    }
+
+    // Mirrors `sample_field_image`. Bilinear filtering at world position `p`, with the image placed
+    // by `image_to_world`.
+    pub fn sample(&self, viewport: Vec2, p: Vec2) -> Vec4 {
+        let size = Vec2::new(self.width as f32, self.height as f32);
+        let p = world_to_image(viewport, size, p) * size - 0.5;
+        let p0 = p.floor();
+        let t = p - p0;
+        let (x, y) = (p0.x as i32, p0.y as i32);
+        let mix = |a: Vec4, b: Vec4, t: f32| a * (1.0 - t) + b * t;
+        let top = mix(self.texel(x, y), self.texel(x + 1, y), t.x);
+        let bottom = mix(self.texel(x, y + 1), self.texel(x + 1, y + 1), t.x);
+        mix(top, bottom, t.y)
+    }
+}
+
+// Mirrors WGSL's `unpack4x8unorm`.
+pub fn unpack_rgba(pixel: [u8; 4]) -> Vec4 {
+    Vec4::from_array(pixel.map(|c| c as f32 / 255.0))
+}
+
+// Mirrors `luma` in the compute shader. Rec. 709 luma of the encoded values, composited over
+// white so the transparent background of a logo counts as white.
+pub fn luma(color: Vec4) -> f32 {
+    let luma = 0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z;
+    luma * color.w + (1.0 - color.w)
+}
+
+// Grey and indexed images are expanded to RGBA.
+pub fn load_rgba_image(path: &Path) -> Result<RgbaImage, String> {
+    let error = |e: &dyn std::fmt::Display| format!("failed to load {}: {e}", path.display());
+
+    let file = File::open(path).map_err(|e| error(&e))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(|e| error(&e))?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|e| error(&e))?;
+
+    let channels = info.color_type.samples();
+    let pixels = buffer[..info.buffer_size()]
+        .chunks_exact(channels)
+        .map(|pixel| match *pixel {
+            [l] => [l, l, l, 255],
+            [l, a] => [l, l, l, a],
+            [r, g, b] => [r, g, b, 255],
+            [r, g, b, a] => [r, g, b, a],
+            _ => unreachable!("PNG pixels have 1 to 4 channels"),
+        })
+        .collect();
+
+    Ok(RgbaImage {
+        width: info.width,
+        height: info.height,
+        pixels,
+    })
+}
+
+pub fn load_luma_image(path: &Path) -> Result<LumaImage, String> {
+    let image = load_rgba_image(path)?;
+    Ok(LumaImage {
+        width: image.width,
+        height: image.height,
+        pixels: image.pixels.iter().map(|p| luma(unpack_rgba(*p))).collect(),
+    })
+}
+
+// Where an image covers the viewport: scaled to fit inside it and centred, like the viewport
+// itself around the origin. Returns the world position of image coordinates in [0, 1] x [0, 1],
+// y down.
+pub fn image_to_world(viewport: Vec2, image_size: Vec2, uv: Vec2) -> Vec2 {
+    let scale = (viewport.x / image_size.x).min(viewport.y / image_size.y);
+    Vec2::new(uv.x - 0.5, 0.5 - uv.y) * image_size * scale
+}
+
+// Mirrors `world_to_image` in the compute shader, the inverse of `image_to_world`.
+pub fn world_to_image(viewport: Vec2, image_size: Vec2, p: Vec2) -> Vec2 {
+    let scale = (viewport.x / image_size.x).min(viewport.y / image_size.y);
+    let p = p / (image_size * scale);
+    Vec2::new(p.x + 0.5, 0.5 - p.y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_luma_of_rgba() {
+        let path = std::env::temp_dir().join("gpu_flow_fields_loads_luma_of_rgba.png");
+        {
+            let file = File::create(&path).unwrap();
+            let mut encoder = png::Encoder::new(file, 3, 1);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().unwrap();
+            writer
+                .write_image_data(&[0, 0, 0, 255, 255, 255, 255, 255, 0, 0, 0, 0])
+                .unwrap();
+        }
+        let image = load_luma_image(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((image.width, image.height), (3, 1));
+        // Black, white, and transparent black over white
+        assert_eq!(image.pixels, [0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn image_fits_inside_viewport() {
+        let viewport = Vec2::new(400.0, 200.0);
+        let image = Vec2::new(50.0, 100.0);
+        assert_eq!(
+            image_to_world(viewport, image, Vec2::ZERO),
+            Vec2::new(-50.0, 100.0)
+        );
+        assert_eq!(
+            image_to_world(viewport, image, Vec2::ONE),
+            Vec2::new(50.0, -100.0)
+        );
+        let uv = Vec2::new(0.25, 0.8);
+        let p = image_to_world(viewport, image, uv);
+        assert!(world_to_image(viewport, image, p).abs_diff_eq(uv, 1e-6));
+    }
+
+    #[test]
+    fn sample_interpolates_texel_centres() {
+        let image = RgbaImage {
+            width: 2,
+            height: 1,
+            pixels: vec![[0, 0, 0, 255], [255, 255, 255, 255]],
+        };
+        // The image covers x in [-100, 100] of a 200 x 100 viewport, texel centres at -50 and 50
+        let viewport = Vec2::new(200.0, 100.0);
+        let sample = |x: f32| image.sample(viewport, Vec2::new(x, 10.0)).x;
+        assert_eq!(sample(-80.0), 0.0);
+        assert_eq!(sample(-50.0), 0.0);
+        assert_eq!(sample(0.0), 0.5);
+        assert_eq!(sample(80.0), 1.0);
+    }
+}
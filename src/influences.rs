@@ -0,0 +1,368 @@
+// Point influences placed on the canvas that are added to the field direction: attractors pull
+// lines in, repulsors push them away and vortices spin them around.
+//
+// Shift + click on the canvas adds an influence, dragging moves one and right clicking removes it.
+
+use std::sync::Arc;
+
+use bevy::{prelude::*, window::PrimaryWindow};
+use bevy_egui::{egui, EguiContexts};
+
+use crate::{FlowFieldGlobals, FlowFieldInputs};
+
+// Distance in logical pixels within which the centre of an influence is picked by the mouse.
+const PICK_RADIUS: f32 = 12.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum InfluenceKind {
+    #[default]
+    Attractor,
+    Repulsor,
+    // Counterclockwise for positive strengths
+    Vortex,
+}
+
+impl InfluenceKind {
+    pub const ALL: [InfluenceKind; 3] = [
+        InfluenceKind::Attractor,
+        InfluenceKind::Repulsor,
+        InfluenceKind::Vortex,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InfluenceKind::Attractor => "Attractor",
+            InfluenceKind::Repulsor => "Repulsor",
+            InfluenceKind::Vortex => "Vortex",
+        }
+    }
+
+    fn color(self) -> egui::Color32 {
+        match self {
+            InfluenceKind::Attractor => egui::Color32::from_rgb(40, 120, 220),
+            InfluenceKind::Repulsor => egui::Color32::from_rgb(220, 60, 40),
+            InfluenceKind::Vortex => egui::Color32::from_rgb(40, 170, 80),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Influence {
+    pub kind: InfluenceKind,
+    // World position
+    pub position: Vec2,
+    // Length of the added vector at the centre, relative to the unit field direction
+    pub strength: f32,
+    // Distance in pixels at which the influence fades out
+    pub radius: f32,
+}
+
+impl Influence {
+    // Mirrors `influence_vector` in the compute shader. Falls off quadratically to zero at
+    // `radius`.
+    pub fn vector(&self, p: Vec2) -> Vec2 {
+        let d = self.position - p;
+        let distance = d.length();
+        if distance >= self.radius || distance == 0.0 {
+            return Vec2::ZERO;
+        }
+        let falloff = 1.0 - distance / self.radius;
+        let towards = d / distance;
+        let direction = match self.kind {
+            InfluenceKind::Attractor => towards,
+            InfluenceKind::Repulsor => -towards,
+            InfluenceKind::Vortex => Vec2::new(towards.y, -towards.x),
+        };
+        direction * self.strength * falloff * falloff
+    }
+}
+
+// Mirrors `apply_influences`. `direction` turned towards the sum of the influences at `p`, or left
+// as it is where they cancel it out.
+pub fn apply_influences(influences: &[Influence], direction: Vec2, p: Vec2) -> Vec2 {
+    let combined = influences
+        .iter()
+        .fold(direction, |sum, influence| sum + influence.vector(p));
+    if combined.length() > 0.0 {
+        combined.normalize()
+    } else {
+        direction
+    }
+}
+
+// Parses `KIND,X,Y,STRENGTH,RADIUS` with the kind by name, e.g. `vortex,0,0,2,150`.
+pub fn parse_influence(text: &str) -> Result<Influence, String> {
+    let error = || format!("expected KIND,X,Y,STRENGTH,RADIUS, found `{text}`");
+    let fields: Vec<&str> = text.split(',').map(str::trim).collect();
+    let [kind, values @ ..] = fields.as_slice() else {
+        return Err(error());
+    };
+    let kind = InfluenceKind::ALL
+        .into_iter()
+        .find(|k| k.name().eq_ignore_ascii_case(kind))
+        .ok_or_else(error)?;
+    let values: Vec<f32> = values
+        .iter()
+        .map(|v| v.parse().map_err(|_| error()))
+        .collect::<Result<_, _>>()?;
+    let [x, y, strength, radius] = values[..] else {
+        return Err(error());
+    };
+    Ok(Influence {
+        kind,
+        position: Vec2::new(x, y),
+        strength,
+        radius,
+    })
+}
+
+// Settings of new influences and the mouse state.
+#[derive(Resource)]
+pub struct InfluenceEditor {
+    pub kind: InfluenceKind,
+    pub strength: f32,
+    pub radius: f32,
+    // Draw the influences over the canvas
+    pub show: bool,
+    // Index of the influence being dragged
+    pub dragging: Option<usize>,
+}
+
+impl Default for InfluenceEditor {
+    fn default() -> Self {
+        Self {
+            kind: InfluenceKind::Attractor,
+            strength: 2.0,
+            radius: 150.0,
+            show: true,
+            dragging: None,
+        }
+    }
+}
+
+// The influence list in the settings window. Returns the edited influences if they changed.
+pub fn influences_ui(
+    ui: &mut egui::Ui,
+    editor: &mut InfluenceEditor,
+    influences: &[Influence],
+) -> Option<Vec<Influence>> {
+    let mut changed = false;
+
+    ui.horizontal(|ui| {
+        ui.label("New");
+        egui::ComboBox::from_id_source("influence_kind")
+            .selected_text(editor.kind.name())
+            .show_ui(ui, |ui| {
+                for option in InfluenceKind::ALL {
+                    ui.selectable_value(&mut editor.kind, option, option.name());
+                }
+            });
+        ui.add(
+            egui::DragValue::new(&mut editor.strength)
+                .speed(0.05)
+                .prefix("strength:"),
+        );
+        ui.add(
+            egui::DragValue::new(&mut editor.radius)
+                .speed(1.0)
+                .clamp_range(1.0..=10000.0)
+                .prefix("radius:"),
+        );
+    })
+    .response
+    .on_hover_text("Shift + click on the canvas to add, drag to move, right click to remove");
+
+    let mut remove = None;
+    let mut influences = influences.to_vec();
+    for (i, influence) in influences.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            let mut kind = influence.kind;
+            egui::ComboBox::from_id_source(("influence", i))
+                .selected_text(kind.name())
+                .show_ui(ui, |ui| {
+                    for option in InfluenceKind::ALL {
+                        ui.selectable_value(&mut kind, option, option.name());
+                    }
+                });
+            changed |= kind != influence.kind;
+            influence.kind = kind;
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut influence.strength)
+                        .speed(0.05)
+                        .prefix("strength:"),
+                )
+                .changed();
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut influence.radius)
+                        .speed(1.0)
+                        .clamp_range(1.0..=10000.0)
+                        .prefix("radius:"),
+                )
+                .changed();
+            if ui.button("Remove").clicked() {
+                remove = Some(i);
+            }
+        });
+    }
+    if let Some(i) = remove {
+        influences.remove(i);
+        changed = true;
+    }
+
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut editor.show, "Show");
+        if ui.button("Clear").clicked() && !influences.is_empty() {
+            influences.clear();
+            changed = true;
+        }
+    });
+
+    if !changed {
+        return None;
+    }
+    editor.dragging = None;
+    Some(influences)
+}
+
+// Adds, drags and removes influences with the mouse, and draws them over the canvas.
+pub fn edit_influences(
+    mut contexts: EguiContexts,
+    mouse: Res<Input<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    mut editor: ResMut<InfluenceEditor>,
+    mut inputs: ResMut<FlowFieldInputs>,
+    mut globals: ResMut<FlowFieldGlobals>,
+) {
+    let (Ok(window), Ok((camera, camera_transform))) = (windows.get_single(), cameras.get_single())
+    else {
+        return;
+    };
+    let ctx = contexts.ctx_mut();
+
+    if editor.show {
+        let painter = ctx.layer_painter(egui::LayerId::background());
+        for influence in inputs.influences.iter() {
+            let Some(centre) =
+                camera.world_to_viewport(camera_transform, influence.position.extend(0.0))
+            else {
+                continue;
+            };
+            let centre = egui::pos2(centre.x, centre.y);
+            let stroke = egui::Stroke::new(1.0, influence.kind.color());
+            painter.circle_stroke(centre, influence.radius, stroke);
+            painter.circle_filled(centre, 4.0, influence.kind.color());
+        }
+    }
+
+    if !mouse.pressed(MouseButton::Left) {
+        editor.dragging = None;
+    }
+    let over_ui = ctx.wants_pointer_input() || ctx.is_pointer_over_area();
+    if over_ui && editor.dragging.is_none() {
+        return;
+    }
+    let Some(cursor) = window
+        .cursor_position()
+        .and_then(|cursor| camera.viewport_to_world_2d(camera_transform, cursor))
+    else {
+        return;
+    };
+
+    let picked = inputs
+        .influences
+        .iter()
+        .enumerate()
+        .map(|(i, influence)| (i, influence.position.distance(cursor)))
+        .filter(|(_, distance)| *distance <= PICK_RADIUS)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(i, _)| i);
+
+    let shift = ctx.input(|input| input.modifiers.shift);
+    let mut influences = None;
+    if mouse.just_pressed(MouseButton::Left) {
+        if shift {
+            let mut list = (*inputs.influences).clone();
+            list.push(Influence {
+                kind: editor.kind,
+                position: cursor,
+                strength: editor.strength,
+                radius: editor.radius,
+            });
+            editor.dragging = Some(list.len() - 1);
+            influences = Some(list);
+        } else {
+            editor.dragging = picked;
+        }
+    } else if let Some(i) = editor.dragging {
+        if inputs
+            .influences
+            .get(i)
+            .is_some_and(|influence| influence.position != cursor)
+        {
+            let mut list = (*inputs.influences).clone();
+            list[i].position = cursor;
+            influences = Some(list);
+        }
+    } else if mouse.just_pressed(MouseButton::Right) {
+        if let Some(i) = picked {
+            let mut list = (*inputs.influences).clone();
+            list.remove(i);
+            influences = Some(list);
+        }
+    }
+
+    if let Some(list) = influences {
+        inputs.influences = Arc::new(list);
+        globals.should_reset = 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn influences_fall_off_to_radius() {
+        let influence = |kind| Influence {
+            kind,
+            position: Vec2::ZERO,
+            strength: 2.0,
+            radius: 100.0,
+        };
+        let p = Vec2::new(50.0, 0.0);
+        assert_eq!(
+            influence(InfluenceKind::Attractor).vector(p),
+            Vec2::new(-0.5, 0.0)
+        );
+        assert_eq!(
+            influence(InfluenceKind::Repulsor).vector(p),
+            Vec2::new(0.5, 0.0)
+        );
+        assert_eq!(
+            influence(InfluenceKind::Vortex).vector(p),
+            Vec2::new(0.0, 0.5)
+        );
+        assert_eq!(
+            influence(InfluenceKind::Attractor).vector(Vec2::new(0.0, 100.0)),
+            Vec2::ZERO
+        );
+
+        // The field direction is turned towards the attractor
+        let direction = apply_influences(&[influence(InfluenceKind::Attractor)], Vec2::Y, p);
+        assert!(direction.abs_diff_eq(Vec2::new(-0.5, 1.0).normalize(), 1e-6));
+
+        assert_eq!(
+            parse_influence("vortex, 1, 2, 3, 4").unwrap(),
+            Influence {
+                kind: InfluenceKind::Vortex,
+                position: Vec2::new(1.0, 2.0),
+                strength: 3.0,
+                radius: 4.0,
+            }
+        );
+        assert!(parse_influence("vortex,1,2,3").is_err());
+    }
+}
@@ -22,12 +22,31 @@ use bevy::{
     window::{WindowResized, WindowResolution},
 };
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc};
 
 mod compute;
+mod cpu_tracer;
+mod evenly_spaced;
+mod expression;
+mod gradients;
+mod headless;
+mod images;
+mod influences;
+mod noise;
+mod obstacles;
+mod plotter;
+mod presets;
+mod rasterizer;
 mod render;
+mod seeding;
+mod svg_export;
 mod utilities;
+mod vector_grid;
+mod width_curve;
 
 use compute::*;
+use plotter::*;
 use render::*;
 
 const FLOW_FIELD_RENDER_GRAPH: &str = "flow_field_graph";
@@ -36,10 +55,21 @@ const FLOW_FIELD_COMPUTE_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3935433275);
 const FLOW_FIELD_RENDER_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 2388554825);
+const FLOW_FIELD_COMPACT_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 1706623941);
 
 const WORK_GROUP_SIZE: u32 = 16;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("render") {
+        if let Err(e) = headless::run(&args[2..]) {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     App::new()
         .add_plugins((
             DefaultPlugins,
@@ -71,8 +101,42 @@ impl Plugin for FlowFieldPlugin {
             "flow_field_render.wgsl",
             Shader::from_wgsl
         );
+        load_internal_asset!(
+            app,
+            FLOW_FIELD_COMPACT_SHADER,
+            "flow_field_compact.wgsl",
+            Shader::from_wgsl
+        );
 
-        app.add_plugins(EguiPlugin).add_systems(Update, update_ui);
+        app.add_plugins(EguiPlugin)
+            .init_resource::<ExportSettings>()
+            .init_resource::<ImportSettings>()
+            .init_resource::<ExpressionEditor>()
+            .init_resource::<influences::InfluenceEditor>()
+            .init_resource::<presets::PresetBrowser>()
+            .init_resource::<FlowFieldInputs>()
+            .init_resource::<CpuLineMesh>()
+            .init_resource::<CpuLineMeshTask>()
+            .init_resource::<LineSeeds>()
+            .add_plugins((
+                ExtractResourcePlugin::<FlowFieldInputs>::default(),
+                ExtractResourcePlugin::<CpuLineMesh>::default(),
+                ExtractResourcePlugin::<LineSeeds>::default(),
+            ))
+            .add_systems(
+                Update,
+                (
+                    update_ui,
+                    influences::edit_influences,
+                    presets::update_presets_ui,
+                    (
+                        update_cpu_line_mesh,
+                        update_line_seeds,
+                        expression::update_expression_shader,
+                    ),
+                )
+                    .chain(),
+            );
 
         app.insert_resource(FlowFieldStopwatch(Stopwatch::new()))
             .init_resource::<ShouldUpdateFlowField>()
@@ -110,20 +174,50 @@ impl Plugin for FlowFieldPlugin {
             .init_resource::<FlowFieldComputeState>()
             .init_resource::<CurrentIterationCount>()
             .init_resource::<FlowFieldLineMeshBuffers>()
+            .init_resource::<LineSeedBuffer>()
+            .init_resource::<DensityMapBuffer>()
+            .init_resource::<FieldImageBuffer>()
+            .init_resource::<VectorGridBuffer>()
+            .init_resource::<InfluenceBuffer>()
+            .init_resource::<ObstacleBuffers>()
+            .init_resource::<GradientBuffer>()
+            .init_resource::<ColorImageTexture>()
+            .init_resource::<WidthCurveBuffer>()
             .init_resource::<FlowFieldComputeResources>()
             .init_resource::<FlowFieldComputeBindGroup>()
             .init_resource::<MSRenderTarget>()
             .init_resource::<FlowFieldRenderResources>()
-            .init_resource::<FlowFieldRenderBindGroup>();
+            .init_resource::<FlowFieldRenderBindGroup>()
+            .init_resource::<FlowFieldCompactResources>()
+            .init_resource::<FlowFieldCompactBindGroup>();
 
         render_app
             .add_systems(
                 Render,
-                (create_ms_render_target, create_line_mesh_buffers).in_set(RenderSet::Prepare),
+                (
+                    create_ms_render_target,
+                    create_line_mesh_buffers,
+                    create_line_seed_buffer,
+                    create_density_map_buffer,
+                    create_field_image_buffer,
+                    create_vector_grid_buffer,
+                    create_influence_buffer,
+                    create_obstacle_buffers,
+                    create_gradient_buffer,
+                    create_color_image_texture,
+                    create_width_curve_buffer,
+                    specialize_compute_pipelines,
+                )
+                    .in_set(RenderSet::Prepare),
             )
             .add_systems(
                 Render,
-                (queue_compute_bind_group, queue_render_bind_group).in_set(RenderSet::Queue),
+                (
+                    queue_compute_bind_group,
+                    queue_render_bind_group,
+                    queue_compact_bind_group,
+                )
+                    .in_set(RenderSet::Queue),
             );
 
         render_app
@@ -147,22 +241,37 @@ impl Plugin for FlowFieldPlugin {
     }
 }
 
-pub fn update_ui(mut contexts: EguiContexts, mut globals: ResMut<FlowFieldGlobals>) {
+#[allow(clippy::too_many_arguments)]
+pub fn update_ui(
+    mut contexts: EguiContexts,
+    mut globals: ResMut<FlowFieldGlobals>,
+    mut export_settings: ResMut<ExportSettings>,
+    mut import_settings: ResMut<ImportSettings>,
+    mut inputs: ResMut<FlowFieldInputs>,
+    mut expression_editor: ResMut<ExpressionEditor>,
+    mut influence_editor: ResMut<influences::InfluenceEditor>,
+    render_device: Res<RenderDevice>,
+) {
     egui::Window::new("Settings").show(contexts.ctx_mut(), |ui| {
         let mut should_reset = false;
 
         ui.horizontal(|ui| {
             ui.label("Number of lines");
+            let max_num_lines = max_num_lines(&globals, &render_device.limits()).clamp(1, 100000);
             if ui
                 .add(
                     egui::DragValue::new(&mut globals.num_lines)
                         .speed(1.0)
-                        .clamp_range(1..=100000),
+                        .clamp_range(1..=max_num_lines),
                 )
                 .changed()
             {
                 should_reset = true;
             }
+            if max_num_lines < 100000 {
+                ui.label(format!("at most {max_num_lines}"))
+                    .on_hover_text("The most lines the GPU's buffers fit with these joins and iterations");
+            }
         });
 
         ui.horizontal(|ui| {
@@ -179,6 +288,89 @@ pub fn update_ui(mut contexts: EguiContexts, mut globals: ResMut<FlowFieldGlobal
             }
         });
 
+        ui.horizontal(|ui| {
+            ui.label("Seeding");
+            let mut seeding_mode = cpu_tracer::SeedingMode::from_u32(globals.seeding_mode);
+            egui::ComboBox::from_id_source("seeding_mode")
+                .selected_text(seeding_mode.name())
+                .show_ui(ui, |ui| {
+                    for (i, option) in cpu_tracer::SeedingMode::ALL.into_iter().enumerate() {
+                        if ui
+                            .selectable_value(&mut seeding_mode, option, option.name())
+                            .changed()
+                        {
+                            globals.seeding_mode = i as u32;
+                            should_reset = true;
+                        }
+                    }
+                });
+        });
+
+        let mut bidirectional = globals.bidirectional == 1;
+        if ui
+            .checkbox(&mut bidirectional, "Bidirectional")
+            .on_hover_text("Trace each line backwards and forwards from its seed")
+            .changed()
+        {
+            globals.bidirectional = bidirectional as u32;
+            should_reset = true;
+        }
+
+        if cpu_tracer::SeedingMode::from_u32(globals.seeding_mode)
+            == cpu_tracer::SeedingMode::EvenlySpaced
+        {
+            ui.horizontal(|ui| {
+                ui.label("Separation");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut globals.line_separation)
+                            .speed(0.1)
+                            .clamp_range(0.5..=500.0),
+                    )
+                    .on_hover_text("Distance between lines in pixels")
+                    .changed()
+                {
+                    should_reset = true;
+                }
+                ui.label("Test ratio");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut globals.separation_test_ratio)
+                            .speed(0.01)
+                            .clamp_range(0.0..=1.0),
+                    )
+                    .on_hover_text("Lines stop this fraction of the separation away from other lines")
+                    .changed()
+                {
+                    should_reset = true;
+                }
+            });
+        }
+
+        if cpu_tracer::SeedingMode::from_u32(globals.seeding_mode)
+            == cpu_tracer::SeedingMode::ImageDensity
+        {
+            ui.horizontal(|ui| {
+                ui.label("Density image")
+                    .on_hover_text("PNG file, lines start more often where it's darker");
+                ui.text_edit_singleline(&mut import_settings.density_image_path);
+                if ui.button("Load").clicked() {
+                    let path = PathBuf::from(&import_settings.density_image_path);
+                    import_settings.status = match seeding::load_density_map(&path) {
+                        Ok(map) => {
+                            inputs.density_map = Some(Arc::new(map));
+                            should_reset = true;
+                            format!("Loaded {}", path.display())
+                        }
+                        Err(e) => e,
+                    };
+                }
+            });
+            if !import_settings.status.is_empty() {
+                ui.label(&import_settings.status);
+            }
+        }
+
         ui.horizontal(|ui| {
             ui.label("Iteration step size");
             if ui
@@ -189,6 +381,38 @@ pub fn update_ui(mut contexts: EguiContexts, mut globals: ResMut<FlowFieldGlobal
             }
         });
 
+        ui.horizontal(|ui| {
+            ui.label("Integrator");
+            let mut integrator = cpu_tracer::Integrator::from_u32(globals.integrator);
+            egui::ComboBox::from_id_source("integrator")
+                .selected_text(integrator.name())
+                .show_ui(ui, |ui| {
+                    for (i, option) in cpu_tracer::Integrator::ALL.into_iter().enumerate() {
+                        if ui
+                            .selectable_value(&mut integrator, option, option.name())
+                            .changed()
+                        {
+                            globals.integrator = i as u32;
+                            should_reset = true;
+                        }
+                    }
+                });
+            if integrator == cpu_tracer::Integrator::Rk45 {
+                ui.label("Tolerance");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut globals.integrator_tolerance)
+                            .speed(0.0001)
+                            .clamp_range(0.00001..=10.0),
+                    )
+                    .on_hover_text("Largest error per substep in pixels")
+                    .changed()
+                {
+                    should_reset = true;
+                }
+            }
+        });
+
         ui.horizontal(|ui| {
             ui.label("Line width");
             if ui
@@ -199,6 +423,156 @@ pub fn update_ui(mut contexts: EguiContexts, mut globals: ResMut<FlowFieldGlobal
             }
         });
 
+        ui.horizontal(|ui| {
+            ui.label("Width by");
+            let mut width_mode = cpu_tracer::WidthMode::from_u32(globals.width_mode);
+            egui::ComboBox::from_id_source("width_mode")
+                .selected_text(width_mode.name())
+                .show_ui(ui, |ui| {
+                    for (i, option) in cpu_tracer::WidthMode::ALL.into_iter().enumerate() {
+                        if ui
+                            .selectable_value(&mut width_mode, option, option.name())
+                            .changed()
+                        {
+                            globals.width_mode = i as u32;
+                            should_reset = true;
+                        }
+                    }
+                });
+        });
+
+        let width_mode = cpu_tracer::WidthMode::from_u32(globals.width_mode);
+        if matches!(
+            width_mode,
+            cpu_tracer::WidthMode::Taper
+                | cpu_tracer::WidthMode::SinePulse
+                | cpu_tracer::WidthMode::LineRandom
+                | cpu_tracer::WidthMode::FieldStrength
+        ) {
+            ui.horizontal(|ui| {
+                ui.label("Min width");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut globals.min_width)
+                            .speed(0.01)
+                            .clamp_range(0.0..=1.0),
+                    )
+                    .on_hover_text("Relative to the line width")
+                    .changed()
+                {
+                    should_reset = true;
+                }
+            });
+        }
+        match width_mode {
+            cpu_tracer::WidthMode::Taper => {
+                ui.horizontal(|ui| {
+                    ui.label("Taper length");
+                    if ui
+                        .add(
+                            egui::DragValue::new(&mut globals.width_taper)
+                                .speed(0.01)
+                                .clamp_range(0.0..=0.5),
+                        )
+                        .on_hover_text("Fraction of each line's own length tapered at each end")
+                        .changed()
+                    {
+                        should_reset = true;
+                    }
+                });
+            }
+            cpu_tracer::WidthMode::SinePulse => {
+                ui.horizontal(|ui| {
+                    ui.label("Pulses");
+                    if ui
+                        .add(
+                            egui::DragValue::new(&mut globals.width_pulses)
+                                .speed(0.05)
+                                .clamp_range(0.0..=100.0),
+                        )
+                        .changed()
+                    {
+                        should_reset = true;
+                    }
+                });
+            }
+            cpu_tracer::WidthMode::Curve => {
+                if let Some(curve) = width_curve::width_curve_ui(ui, &inputs.width_curve) {
+                    inputs.width_curve = Arc::new(curve);
+                    should_reset = true;
+                }
+            }
+            cpu_tracer::WidthMode::FieldStrength => {
+                ui.horizontal(|ui| {
+                    ui.label("Full width at");
+                    if ui
+                        .add(
+                            egui::DragValue::new(&mut globals.width_field_scale)
+                                .speed(0.01)
+                                .clamp_range(0.0..=1000.0),
+                        )
+                        .on_hover_text("Field magnitude lines are full width at")
+                        .changed()
+                    {
+                        should_reset = true;
+                    }
+                });
+            }
+            cpu_tracer::WidthMode::Constant | cpu_tracer::WidthMode::LineRandom => {}
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Joins");
+            let mut line_join = cpu_tracer::LineJoin::from_u32(globals.line_join);
+            egui::ComboBox::from_id_source("line_join")
+                .selected_text(line_join.name())
+                .show_ui(ui, |ui| {
+                    for (i, option) in cpu_tracer::LineJoin::ALL.into_iter().enumerate() {
+                        if ui
+                            .selectable_value(&mut line_join, option, option.name())
+                            .changed()
+                        {
+                            globals.line_join = i as u32;
+                            should_reset = true;
+                        }
+                    }
+                });
+            if line_join == cpu_tracer::LineJoin::Miter {
+                ui.label("Limit");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut globals.miter_limit)
+                            .speed(0.05)
+                            .clamp_range(1.0..=100.0),
+                    )
+                    .on_hover_text(
+                        "Longest a miter gets relative to the line width, longer ones are cut off like a bevel",
+                    )
+                    .changed()
+                {
+                    should_reset = true;
+                }
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Caps");
+            let mut line_cap = cpu_tracer::LineCap::from_u32(globals.line_cap);
+            egui::ComboBox::from_id_source("line_cap")
+                .selected_text(line_cap.name())
+                .show_ui(ui, |ui| {
+                    for (i, option) in cpu_tracer::LineCap::ALL.into_iter().enumerate() {
+                        if ui
+                            .selectable_value(&mut line_cap, option, option.name())
+                            .changed()
+                        {
+                            globals.line_cap = i as u32;
+                            should_reset = true;
+                        }
+                    }
+                });
+        });
+
         let mut rgba_start = [
             globals.line_color_start.x,
             globals.line_color_start.y,
@@ -211,24 +585,160 @@ pub fn update_ui(mut contexts: EguiContexts, mut globals: ResMut<FlowFieldGlobal
             globals.line_color_end.z,
             globals.line_color_end.w,
         ];
-        ui.horizontal(|ui| {
-            ui.label("Color start ");
+        if inputs.gradient.is_none() {
+            ui.horizontal(|ui| {
+                ui.label("Color start ");
+                if ui
+                    .color_edit_button_rgba_premultiplied(&mut rgba_start)
+                    .changed()
+                {
+                    should_reset = true;
+                }
+                ui.label("Color end ");
+                if ui
+                    .color_edit_button_rgba_premultiplied(&mut rgba_end)
+                    .changed()
+                {
+                    should_reset = true;
+                }
+            });
+        }
+        globals.line_color_start = Vec4::from_array(rgba_start);
+        globals.line_color_end = Vec4::from_array(rgba_end);
+
+        ui.collapsing("Gradient", |ui| {
+            let mut use_gradient = inputs.gradient.is_some();
             if ui
-                .color_edit_button_rgba_premultiplied(&mut rgba_start)
+                .checkbox(&mut use_gradient, "Multi-stop gradient")
+                .on_hover_text("Replaces the start and end colors")
                 .changed()
             {
+                // Starts out looking the same as the start and end colors
+                inputs.gradient = use_gradient.then(|| {
+                    Arc::new(gradients::Gradient::from_colors(
+                        &[globals.line_color_start, globals.line_color_end],
+                        gradients::Interpolation::LinearRgb,
+                    ))
+                });
                 should_reset = true;
             }
-            ui.label("Color end ");
-            if ui
-                .color_edit_button_rgba_premultiplied(&mut rgba_end)
-                .changed()
-            {
+            let Some(gradient) = inputs.gradient.clone() else {
+                return;
+            };
+            if let Some(gradient) = gradients::gradient_ui(ui, &gradient) {
+                inputs.gradient = Some(Arc::new(gradient));
                 should_reset = true;
             }
+            ui.horizontal(|ui| {
+                ui.label("Palette file")
+                    .on_hover_text(".gpl, .ase or a list of hex colors");
+                ui.text_edit_singleline(&mut import_settings.palette_path);
+                if ui.button("Load").clicked() {
+                    let path = PathBuf::from(&import_settings.palette_path);
+                    import_settings.status = match gradients::load_palette(&path) {
+                        Ok(colors) => {
+                            let status = format!("Loaded {}: {} colors", path.display(), colors.len());
+                            // Keeps the opacity of the lines like the built-in palettes
+                            let opacity = gradient.stops()[0].color.w;
+                            inputs.gradient = Some(Arc::new(
+                                gradients::Gradient::from_colors(&colors, gradient.interpolation())
+                                    .with_opacity(opacity),
+                            ));
+                            should_reset = true;
+                            status
+                        }
+                        Err(e) => e,
+                    };
+                }
+            });
+            if !import_settings.status.is_empty() {
+                ui.label(&import_settings.status);
+            }
         });
-        globals.line_color_start = Vec4::from_array(rgba_start);
-        globals.line_color_end = Vec4::from_array(rgba_end);
+
+        ui.horizontal(|ui| {
+            ui.label("Color by");
+            let mut color_mode = cpu_tracer::ColorMode::from_u32(globals.color_mode);
+            egui::ComboBox::from_id_source("color_mode")
+                .selected_text(color_mode.name())
+                .show_ui(ui, |ui| {
+                    for (i, option) in cpu_tracer::ColorMode::ALL.into_iter().enumerate() {
+                        if ui
+                            .selectable_value(&mut color_mode, option, option.name())
+                            .changed()
+                        {
+                            globals.color_mode = i as u32;
+                            should_reset = true;
+                        }
+                    }
+                });
+        })
+        .response
+        .on_hover_text(
+            "Vector magnitude needs loaded vector data, image luminance a loaded field image",
+        );
+
+        if cpu_tracer::ColorMode::from_u32(globals.color_mode) == cpu_tracer::ColorMode::Curvature
+        {
+            ui.horizontal(|ui| {
+                ui.label("Curvature radius");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut globals.color_curvature_radius)
+                            .speed(0.5)
+                            .clamp_range(0.0..=10000.0),
+                    )
+                    .on_hover_text("Lines bending this tightly, in pixels, get the end color")
+                    .changed()
+                {
+                    should_reset = true;
+                }
+            });
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Image colors");
+            let mut image_color_mode =
+                cpu_tracer::ImageColorMode::from_u32(globals.image_color_mode);
+            egui::ComboBox::from_id_source("image_color_mode")
+                .selected_text(image_color_mode.name())
+                .show_ui(ui, |ui| {
+                    for (i, option) in cpu_tracer::ImageColorMode::ALL.into_iter().enumerate() {
+                        if ui
+                            .selectable_value(&mut image_color_mode, option, option.name())
+                            .changed()
+                        {
+                            globals.image_color_mode = i as u32;
+                            should_reset = true;
+                        }
+                    }
+                });
+        })
+        .response
+        .on_hover_text("Takes the color of the lines from an image, keeping their opacity");
+
+        if cpu_tracer::ImageColorMode::from_u32(globals.image_color_mode)
+            != cpu_tracer::ImageColorMode::Off
+        {
+            ui.horizontal(|ui| {
+                ui.label("Color image").on_hover_text("PNG file placed like the field image");
+                ui.text_edit_singleline(&mut import_settings.color_image_path);
+                if ui.button("Load").clicked() {
+                    let path = PathBuf::from(&import_settings.color_image_path);
+                    import_settings.status = match images::load_rgba_image(&path) {
+                        Ok(image) => {
+                            inputs.color_image = Some(Arc::new(image));
+                            should_reset = true;
+                            format!("Loaded {}", path.display())
+                        }
+                        Err(e) => e,
+                    };
+                }
+            });
+            if !import_settings.status.is_empty() {
+                ui.label(&import_settings.status);
+            }
+        }
 
         let mut rgba_background = [
             globals.background_color.x,
@@ -296,6 +806,316 @@ pub fn update_ui(mut contexts: EguiContexts, mut globals: ResMut<FlowFieldGlobal
             }
         });
 
+        ui.horizontal(|ui| {
+            ui.label("Field");
+            let mut field_mode = cpu_tracer::FieldMode::from_u32(globals.field_mode);
+            egui::ComboBox::from_id_source("field_mode")
+                .selected_text(field_mode.name())
+                .show_ui(ui, |ui| {
+                    for (i, option) in cpu_tracer::FieldMode::ALL.into_iter().enumerate() {
+                        if ui
+                            .selectable_value(&mut field_mode, option, option.name())
+                            .changed()
+                        {
+                            globals.field_mode = i as u32;
+                            should_reset = true;
+                        }
+                    }
+                });
+        })
+        .response
+        .on_hover_text(
+            "Curl noise follows the curl of the noise, which doesn't bunch lines into sinks and \
+             sources. Domain warp moves where the curl is sampled after it's taken, so with warp \
+             layers the field isn't divergence-free any more and lines can bunch up again",
+        );
+
+        let field_mode = cpu_tracer::FieldMode::from_u32(globals.field_mode);
+        if matches!(
+            field_mode,
+            cpu_tracer::FieldMode::ImageLuminance
+                | cpu_tracer::FieldMode::ImageContour
+                | cpu_tracer::FieldMode::ImageVector
+        ) || cpu_tracer::ColorMode::from_u32(globals.color_mode)
+            == cpu_tracer::ColorMode::ImageLuminance
+        {
+            ui.horizontal(|ui| {
+                ui.label("Field image")
+                    .on_hover_text("PNG file, flat areas follow the noise");
+                ui.text_edit_singleline(&mut import_settings.field_image_path);
+                if ui.button("Load").clicked() {
+                    let path = PathBuf::from(&import_settings.field_image_path);
+                    import_settings.status = match images::load_rgba_image(&path) {
+                        Ok(image) => {
+                            inputs.field_image = Some(Arc::new(image));
+                            should_reset = true;
+                            format!("Loaded {}", path.display())
+                        }
+                        Err(e) => e,
+                    };
+                }
+            });
+            if field_mode == cpu_tracer::FieldMode::ImageContour {
+                ui.horizontal(|ui| {
+                    ui.label("Gradient step");
+                    if ui
+                        .add(
+                            egui::DragValue::new(&mut globals.image_gradient_step)
+                                .speed(0.1)
+                                .clamp_range(0.1..=100.0),
+                        )
+                        .on_hover_text("Distance in pixels the luminance gradient is measured across")
+                        .changed()
+                    {
+                        should_reset = true;
+                    }
+                });
+            }
+            if !import_settings.status.is_empty() {
+                ui.label(&import_settings.status);
+            }
+        }
+
+        if field_mode == cpu_tracer::FieldMode::Expression {
+            ui.label("Expression").on_hover_text(
+                "vx = ..., vy = ... in x, y (world position) and t (iteration), e.g. \
+                 vx = sin(y*0.01), vy = cos(x*0.01). Other names are local variables. \
+                 noise(x, y) is the field's noise, perlin, simplex, value and worley the base noises.",
+            );
+            let changed = ui
+                .add(
+                    egui::TextEdit::multiline(&mut expression_editor.source)
+                        .code_editor()
+                        .desired_rows(3),
+                )
+                .changed();
+            // The default expression is compiled the first time the mode is selected
+            if changed || (inputs.field_expression.is_none() && expression_editor.error.is_empty())
+            {
+                // Invalid input keeps the last valid expression
+                match expression::FieldExpression::parse(&expression_editor.source) {
+                    Ok(expression) => {
+                        inputs.field_expression = Some(Arc::new(expression));
+                        expression_editor.error.clear();
+                        should_reset = true;
+                    }
+                    Err(e) => expression_editor.error = e,
+                }
+            }
+            if !expression_editor.error.is_empty() {
+                ui.colored_label(egui::Color32::RED, &expression_editor.error);
+            }
+        }
+
+        if field_mode == cpu_tracer::FieldMode::VectorGrid {
+            ui.horizontal(|ui| {
+                ui.label("Vector data")
+                    .on_hover_text("CSV with lat, lon, u and v columns, or raw float32 grid");
+                ui.text_edit_singleline(&mut import_settings.vector_grid_path);
+                if ui.button("Load").clicked() {
+                    let path = PathBuf::from(&import_settings.vector_grid_path);
+                    import_settings.status = match vector_grid::load_vector_grid(&path) {
+                        Ok(grid) => {
+                            let status = format!(
+                                "Loaded {}: {} x {} vectors",
+                                path.display(),
+                                grid.width,
+                                grid.height
+                            );
+                            inputs.vector_grid = Some(Arc::new(grid));
+                            should_reset = true;
+                            status
+                        }
+                        Err(e) => e,
+                    };
+                }
+            });
+            if !import_settings.status.is_empty() {
+                ui.label(&import_settings.status);
+            }
+        }
+
+        ui.collapsing("Influences", |ui| {
+            if let Some(influences) =
+                influences::influences_ui(ui, &mut influence_editor, &inputs.influences)
+            {
+                inputs.influences = Arc::new(influences);
+                should_reset = true;
+            }
+        });
+
+        ui.collapsing("Obstacles", |ui| {
+            if let Some(obstacles) = obstacles::obstacles_ui(ui, &inputs.obstacles) {
+                inputs.obstacles = Arc::new(obstacles);
+                should_reset = true;
+            }
+        });
+
+        ui.collapsing("Termination", |ui| {
+            let mut stop_outside_bounds = globals.stop_outside_bounds == 1;
+            if ui
+                .checkbox(&mut stop_outside_bounds, "Stop outside bounds")
+                .on_hover_text("Lines end once they leave the viewport and the seed padding")
+                .changed()
+            {
+                globals.stop_outside_bounds = stop_outside_bounds as u32;
+                should_reset = true;
+            }
+            let mut stop_in_masks = globals.stop_in_masks == 1;
+            if ui
+                .checkbox(&mut stop_in_masks, "Stop in masks")
+                .on_hover_text("Lines end on entering a mask obstacle instead of continuing hidden")
+                .changed()
+            {
+                globals.stop_in_masks = stop_in_masks as u32;
+                should_reset = true;
+            }
+            ui.horizontal(|ui| {
+                ui.label("Min field magnitude");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut globals.min_field_magnitude)
+                            .speed(0.001)
+                            .clamp_range(0.0..=f32::MAX),
+                    )
+                    .on_hover_text(
+                        "Lines end where the vector the field follows is shorter than this. \
+                         Noise angles always have length 1.",
+                    )
+                    .changed()
+                {
+                    should_reset = true;
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Collision distance");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut globals.collision_distance)
+                            .speed(0.1)
+                            .clamp_range(0.0..=1000.0),
+                    )
+                    .on_hover_text(
+                        "Lines end on entering a grid cell of this size another line passed through. 0 disables collisions.",
+                    )
+                    .changed()
+                {
+                    should_reset = true;
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Length variation");
+                if ui
+                    .add(egui::Slider::new(&mut globals.length_variation, 0.0..=1.0))
+                    .on_hover_text("Shortens every line by a random fraction of the max iterations up to this")
+                    .changed()
+                {
+                    should_reset = true;
+                }
+            });
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Noise");
+            let mut noise_type = noise::NoiseType::from_u32(globals.noise_type);
+            egui::ComboBox::from_id_source("noise_type")
+                .selected_text(noise_type.name())
+                .show_ui(ui, |ui| {
+                    for (i, option) in noise::NoiseType::ALL.into_iter().enumerate() {
+                        if ui
+                            .selectable_value(&mut noise_type, option, option.name())
+                            .changed()
+                        {
+                            globals.noise_type = i as u32;
+                            should_reset = true;
+                        }
+                    }
+                });
+            let mut fractal_mode = noise::FractalMode::from_u32(globals.noise_fractal_mode);
+            egui::ComboBox::from_id_source("noise_fractal_mode")
+                .selected_text(fractal_mode.name())
+                .show_ui(ui, |ui| {
+                    for (i, option) in noise::FractalMode::ALL.into_iter().enumerate() {
+                        if ui
+                            .selectable_value(&mut fractal_mode, option, option.name())
+                            .changed()
+                        {
+                            globals.noise_fractal_mode = i as u32;
+                            should_reset = true;
+                        }
+                    }
+                });
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Octaves");
+            if ui
+                .add(egui::DragValue::new(&mut globals.noise_octaves).speed(0.05).clamp_range(1..=8))
+                .changed()
+            {
+                should_reset = true;
+            }
+            ui.label("Lacunarity").on_hover_text("Frequency multiplier per octave");
+            if ui
+                .add(egui::DragValue::new(&mut globals.noise_lacunarity).speed(0.01).clamp_range(1.0..=4.0))
+                .changed()
+            {
+                should_reset = true;
+            }
+            ui.label("Gain").on_hover_text("Amplitude multiplier per octave");
+            if ui
+                .add(egui::DragValue::new(&mut globals.noise_gain).speed(0.01).clamp_range(0.0..=1.0))
+                .changed()
+            {
+                should_reset = true;
+            }
+        });
+
+        egui::CollapsingHeader::new("Domain warp").show(ui, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Layers").on_hover_text("f(p + g(p + h(p))) has two layers");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut globals.num_warp_layers)
+                            .speed(0.05)
+                            .clamp_range(0..=MAX_WARP_LAYERS as u32),
+                    )
+                    .changed()
+                {
+                    should_reset = true;
+                }
+            });
+
+            let num_warp_layers = globals.num_warp_layers as usize;
+            for (i, layer) in globals.warp_layers[..num_warp_layers].iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("Layer {}", i + 1));
+                    if ui
+                        .add(
+                            egui::DragValue::new(&mut layer.strength)
+                                .speed(0.01)
+                                .clamp_range(0.0..=20.0)
+                                .prefix("strength:"),
+                        )
+                        .changed()
+                    {
+                        should_reset = true;
+                    }
+                    if ui
+                        .add(
+                            egui::DragValue::new(&mut layer.scale)
+                                .speed(0.01)
+                                .clamp_range(0.0..=20.0)
+                                .prefix("scale:"),
+                        )
+                        .changed()
+                    {
+                        should_reset = true;
+                    }
+                });
+            }
+        });
+
         ui.horizontal(|ui| {
             ui.label("Field offset");
             if ui
@@ -321,6 +1141,20 @@ pub fn update_ui(mut contexts: EguiContexts, mut globals: ResMut<FlowFieldGlobal
             }
         });
 
+        ui.horizontal(|ui| {
+            ui.label("Seed").on_hover_text("Moves the line start positions and the noise. 0 is unseeded.");
+            if ui
+                .add(egui::DragValue::new(&mut globals.seed).speed(1.0))
+                .changed()
+            {
+                should_reset = true;
+            }
+            if ui.button("Randomize seed").clicked() {
+                globals.seed = random_seed();
+                should_reset = true;
+            }
+        });
+
         ui.horizontal(|ui| {
             if ui.button("Reset").clicked() {
                 should_reset = true;
@@ -331,6 +1165,124 @@ pub fn update_ui(mut contexts: EguiContexts, mut globals: ResMut<FlowFieldGlobal
             // globals.paused = 1;
         });
 
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            ui.label("SVG path");
+            ui.text_edit_singleline(&mut export_settings.svg_path);
+            if ui.button("Export SVG").clicked() {
+                let path = PathBuf::from(&export_settings.svg_path);
+                export_settings.status = match svg_export::export_svg(&globals, &inputs, &path) {
+                    Ok(()) => format!("Exported {}", path.display()),
+                    Err(e) => e,
+                };
+            }
+        });
+
+        egui::CollapsingHeader::new("Plotter").show(ui, |ui| {
+            let plotter = &mut export_settings.plotter;
+            ui.horizontal(|ui| {
+                ui.label("Paper (mm)");
+                ui.add(
+                    egui::DragValue::new(&mut plotter.paper_width)
+                        .speed(1.0)
+                        .clamp_range(1.0..=5000.0)
+                        .prefix("w:"),
+                );
+                ui.add(
+                    egui::DragValue::new(&mut plotter.paper_height)
+                        .speed(1.0)
+                        .clamp_range(1.0..=5000.0)
+                        .prefix("h:"),
+                );
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Margin (mm)");
+                ui.add(
+                    egui::DragValue::new(&mut plotter.margin)
+                        .speed(0.5)
+                        .clamp_range(0.0..=1000.0),
+                );
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Feed rate (mm/min)");
+                ui.add(
+                    egui::DragValue::new(&mut plotter.feed_rate)
+                        .speed(10.0)
+                        .clamp_range(1.0..=100000.0),
+                );
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Pen up");
+                ui.text_edit_singleline(&mut plotter.pen_up_command)
+                    .on_hover_text("G-code only, HPGL always uses PU");
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Pen down");
+                ui.text_edit_singleline(&mut plotter.pen_down_command)
+                    .on_hover_text("G-code only, HPGL always uses PD");
+            });
+
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut plotter.optimize, "Optimize travel");
+                ui.label("Merge distance (mm)");
+                ui.add(
+                    egui::DragValue::new(&mut plotter.merge_distance)
+                        .speed(0.01)
+                        .clamp_range(0.0..=10.0),
+                )
+                .on_hover_text("Paths whose ends are closer than this are joined without lifting the pen");
+            });
+
+            ui.horizontal(|ui| {
+                ui.label("Plot path");
+                ui.text_edit_singleline(&mut export_settings.plot_path);
+            });
+
+            ui.horizontal(|ui| {
+                for (label, format) in [
+                    ("Export HPGL", PlotFormat::Hpgl),
+                    ("Export G-code", PlotFormat::Gcode),
+                ] {
+                    if ui.button(label).clicked() {
+                        let path = PathBuf::from(&export_settings.plot_path);
+                        export_settings.status =
+                            match export_plot(
+                                &globals,
+                                &inputs,
+                                &export_settings.plotter,
+                                format,
+                                &path,
+                            ) {
+                                Ok(stats) => format!(
+                                    "Exported {}: {} paths, {:.0} mm pen-up travel ({:.0} mm unoptimized)",
+                                    path.display(),
+                                    stats.num_paths,
+                                    stats.pen_up_distance,
+                                    stats.unoptimized_pen_up_distance
+                                ),
+                                Err(e) => e,
+                            };
+                    }
+                }
+            });
+        });
+
+        if !export_settings.status.is_empty() {
+            ui.label(&export_settings.status);
+        }
+
+        // Changing the joins or iterations can make the lines too large for the GPU's buffers
+        let max_num_lines = max_num_lines(&globals, &render_device.limits()).max(1);
+        if globals.num_lines > max_num_lines {
+            globals.num_lines = max_num_lines;
+            should_reset = true;
+        }
+
         if should_reset {
             globals.should_reset = 1;
         } else {
@@ -339,6 +1291,79 @@ pub fn update_ui(mut contexts: EguiContexts, mut globals: ResMut<FlowFieldGlobal
     });
 }
 
+// A non-zero seed derived from the current time.
+pub fn random_seed() -> u32 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    cpu_tracer::hash(nanos as u32 ^ (nanos >> 32) as u32).max(1)
+}
+
+// Exports trace the lines again on the CPU, which gives the same geometry as the compute shader.
+#[derive(Resource)]
+pub struct ExportSettings {
+    pub svg_path: String,
+    // HPGL or G-code, depending on the button used
+    pub plot_path: String,
+    pub plotter: PlotterSettings,
+    // Result of the last export, shown in the settings window
+    pub status: String,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            svg_path: "flow_field.svg".to_string(),
+            plot_path: "flow_field.plt".to_string(),
+            plotter: PlotterSettings::default(),
+            status: String::new(),
+        }
+    }
+}
+
+// Files the flow field inputs are loaded from.
+#[derive(Resource)]
+pub struct ImportSettings {
+    pub density_image_path: String,
+    pub field_image_path: String,
+    pub vector_grid_path: String,
+    pub palette_path: String,
+    pub color_image_path: String,
+    // Result of the last import, shown in the settings window
+    pub status: String,
+}
+
+impl Default for ImportSettings {
+    fn default() -> Self {
+        Self {
+            density_image_path: "density.png".to_string(),
+            field_image_path: "field.png".to_string(),
+            vector_grid_path: "wind.csv".to_string(),
+            palette_path: "palette.gpl".to_string(),
+            color_image_path: "photo.png".to_string(),
+            status: String::new(),
+        }
+    }
+}
+
+// Text of the field expression, see expression::FieldExpression.
+#[derive(Resource)]
+pub struct ExpressionEditor {
+    pub source: String,
+    // Parse error of `source`, shown below it
+    pub error: String,
+}
+
+impl Default for ExpressionEditor {
+    fn default() -> Self {
+        Self {
+            source: "vx = sin(y*0.01)\nvy = cos(x*0.01)".to_string(),
+            error: String::new(),
+        }
+    }
+}
+
 #[derive(Resource, Clone, ExtractResource)]
 pub struct WindowSize {
     pub width: u32,
@@ -388,12 +1413,18 @@ impl Default for FlowFieldCameraBundle {
     }
 }
 
-#[derive(Resource, ExtractResource, ShaderType, Clone, Copy)]
+// Fields that only make sense for the running app are skipped when (de)serializing parameter files.
+#[derive(Resource, ExtractResource, ShaderType, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
 pub struct FlowFieldGlobals {
     // The flow field state will reset if set to 1
+    #[serde(skip)]
     pub should_reset: u32,
+    #[serde(skip)]
     pub paused: u32,
+    #[serde(skip)]
     pub viewport_width: f32,
+    #[serde(skip)]
     pub viewport_height: f32,
     pub num_lines: u32,
     // Needs to be > 2 because the init stage does 2 iterations
@@ -416,6 +1447,86 @@ pub struct FlowFieldGlobals {
     pub noise_scale: f32,
     pub field_offset_x: f32,
     pub field_offset_y: f32,
+    // Mixed into the line start positions and the noise lattice.
+    // 0 gives the same output as before seeds existed.
+    pub seed: u32,
+    // See noise::NoiseType
+    pub noise_type: u32,
+    // Fractal Brownian motion. A single octave is the plain noise.
+    pub noise_octaves: u32,
+    // Frequency multiplier per octave
+    pub noise_lacunarity: f32,
+    // Amplitude multiplier per octave
+    pub noise_gain: f32,
+    // See noise::FractalMode
+    pub noise_fractal_mode: u32,
+    // See cpu_tracer::FieldMode
+    pub field_mode: u32,
+    // Number of `warp_layers` the noise position is run through, 0 disables domain warping
+    pub num_warp_layers: u32,
+    pub warp_layers: [WarpLayer; MAX_WARP_LAYERS],
+    // See cpu_tracer::Integrator
+    pub integrator: u32,
+    // Largest error per substep of the adaptive integrator, in pixels
+    pub integrator_tolerance: f32,
+    // See cpu_tracer::SeedingMode
+    pub seeding_mode: u32,
+    // Distance between evenly spaced lines in pixels
+    pub line_separation: f32,
+    // Evenly spaced lines stop this fraction of `line_separation` away from other lines
+    pub separation_test_ratio: f32,
+    // Distance in pixels between the image samples whose difference is the luminance gradient of
+    // cpu_tracer::FieldMode::ImageContour. Larger steps smooth out fine detail.
+    pub image_gradient_step: f32,
+    // See cpu_tracer::ColorMode
+    pub color_mode: u32,
+    // Lines end once they leave the viewport padded like the seeds if 1
+    pub stop_outside_bounds: u32,
+    // Lines end where cpu_tracer::field_magnitude is below this, 0 disables
+    pub min_field_magnitude: f32,
+    // Lines end on entering a mask obstacle instead of continuing hidden if 1
+    pub stop_in_masks: u32,
+    // Lines end on entering a cell of a grid this size another line passed through, 0 disables
+    pub collision_distance: f32,
+    // Every line is shortened by a random fraction of max_iterations up to this
+    pub length_variation: f32,
+    // Lines grow backwards and forwards from a seed in their middle if 1, see
+    // cpu_tracer::trace_step
+    pub bidirectional: u32,
+    // See cpu_tracer::ColorMode::Curvature
+    pub color_curvature_radius: f32,
+    // See cpu_tracer::ImageColorMode
+    pub image_color_mode: u32,
+    // See cpu_tracer::WidthMode
+    pub width_mode: u32,
+    // The width of the thinnest joints relative to line_width
+    pub min_width: f32,
+    // See cpu_tracer::WidthMode::Taper
+    pub width_taper: f32,
+    // See cpu_tracer::WidthMode::SinePulse
+    pub width_pulses: f32,
+    // See cpu_tracer::WidthMode::FieldStrength
+    pub width_field_scale: f32,
+    // See cpu_tracer::LineJoin
+    pub line_join: u32,
+    // See cpu_tracer::LineCap
+    pub line_cap: u32,
+    // Longest a miter join gets relative to the line width, longer ones are cut off like a bevel
+    pub miter_limit: f32,
+}
+
+pub const MAX_WARP_LAYERS: usize = 4;
+
+// One layer of domain warping, see noise::warp.
+#[derive(ShaderType, Clone, Copy, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct WarpLayer {
+    // Distance the position is moved, in noise space
+    pub strength: f32,
+    // Frequency of the warping noise relative to the field noise
+    pub scale: f32,
+    // Keeps the layers from warping along the same noise
+    pub offset: Vec2,
 }
 
 impl Default for FlowFieldGlobals {
@@ -439,10 +1550,77 @@ impl Default for FlowFieldGlobals {
             noise_scale: 0.005,
             field_offset_x: 0.0,
             field_offset_y: 0.0,
+            seed: 0,
+            noise_type: 0,
+            noise_octaves: 1,
+            noise_lacunarity: 2.0,
+            noise_gain: 0.5,
+            noise_fractal_mode: 0,
+            field_mode: 0,
+            num_warp_layers: 0,
+            // Apart from each other and from the y components at WARP_COMPONENT_OFFSET
+            warp_layers: [
+                Vec2::new(0.0, 0.0),
+                Vec2::new(13.7, 31.9),
+                Vec2::new(47.3, 7.1),
+                Vec2::new(23.9, 59.3),
+            ]
+            .map(|offset| WarpLayer {
+                strength: 1.0,
+                scale: 1.0,
+                offset,
+            }),
+            integrator: 0,
+            integrator_tolerance: 0.01,
+            seeding_mode: 0,
+            line_separation: 10.0,
+            separation_test_ratio: 0.5,
+            image_gradient_step: 2.0,
+            color_mode: 0,
+            stop_outside_bounds: 0,
+            min_field_magnitude: 0.0,
+            stop_in_masks: 0,
+            collision_distance: 0.0,
+            length_variation: 0.0,
+            bidirectional: 0,
+            color_curvature_radius: 50.0,
+            image_color_mode: 0,
+            width_mode: 0,
+            min_width: 0.2,
+            width_taper: 0.2,
+            width_pulses: 3.0,
+            width_field_scale: 1.0,
+            line_join: 0,
+            line_cap: 0,
+            miter_limit: 4.0,
         }
     }
 }
 
+// Data the flow field reads besides the globals, loaded from files instead of being part of the
+// uniform. The compute shader gets it through storage buffers, the CPU tracer reads it directly.
+#[derive(Resource, Clone, ExtractResource, Default)]
+pub struct FlowFieldInputs {
+    // See cpu_tracer::SeedingMode::ImageDensity
+    pub density_map: Option<Arc<seeding::DensityMap>>,
+    // Sampled by the image modes of cpu_tracer::FieldMode
+    pub field_image: Option<Arc<images::RgbaImage>>,
+    // See cpu_tracer::FieldMode::VectorGrid
+    pub vector_grid: Option<Arc<vector_grid::VectorGrid>>,
+    // See cpu_tracer::FieldMode::Expression, also compiled into the compute shader
+    pub field_expression: Option<Arc<expression::FieldExpression>>,
+    // Added to the field direction, see influences::apply_influences
+    pub influences: Arc<Vec<influences::Influence>>,
+    // Shapes lines stop at, steer around or are hidden inside, see obstacles::Obstacle
+    pub obstacles: Arc<Vec<obstacles::Obstacle>>,
+    // Replaces the blend from line_color_start to line_color_end, see gradients::Gradient
+    pub gradient: Option<Arc<gradients::Gradient>>,
+    // See cpu_tracer::ImageColorMode
+    pub color_image: Option<Arc<images::RgbaImage>>,
+    // See cpu_tracer::WidthMode::Curve
+    pub width_curve: Arc<width_curve::WidthCurve>,
+}
+
 #[derive(Resource, Clone, ExtractResource, Default)]
 pub struct ShouldUpdateFlowField(pub bool);
 
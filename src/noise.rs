@@ -0,0 +1,375 @@
+// CPU versions of the noise functions in flow_field_compute.wgsl, see cpu_tracer.rs.
+//
+// The compute shader only contains the noise type and fractal mode selected through shader defs,
+// the CPU tracer picks them at runtime from `FlowFieldGlobals`.
+
+use bevy::{prelude::*, render::render_resource::ShaderDefVal};
+
+use crate::{cpu_tracer::*, FlowFieldGlobals, MAX_WARP_LAYERS};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum NoiseType {
+    #[default]
+    Perlin,
+    Simplex,
+    Value,
+    Worley,
+}
+
+impl NoiseType {
+    pub const ALL: [NoiseType; 4] = [
+        NoiseType::Perlin,
+        NoiseType::Simplex,
+        NoiseType::Value,
+        NoiseType::Worley,
+    ];
+
+    // Unknown values fall back to Perlin noise.
+    pub fn from_u32(value: u32) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NoiseType::Perlin => "Perlin",
+            NoiseType::Simplex => "Simplex",
+            NoiseType::Value => "Value",
+            NoiseType::Worley => "Worley",
+        }
+    }
+
+    pub fn shader_def(self) -> ShaderDefVal {
+        match self {
+            NoiseType::Perlin => "NOISE_PERLIN",
+            NoiseType::Simplex => "NOISE_SIMPLEX",
+            NoiseType::Value => "NOISE_VALUE",
+            NoiseType::Worley => "NOISE_WORLEY",
+        }
+        .into()
+    }
+}
+
+// How the octaves of fractal Brownian motion are combined.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub enum FractalMode {
+    #[default]
+    Fbm,
+    // 1 - |noise| squared, giving sharp ridges
+    Ridged,
+    // |noise|, giving creases
+    Turbulence,
+}
+
+impl FractalMode {
+    pub const ALL: [FractalMode; 3] = [
+        FractalMode::Fbm,
+        FractalMode::Ridged,
+        FractalMode::Turbulence,
+    ];
+
+    pub fn from_u32(value: u32) -> Self {
+        Self::ALL.get(value as usize).copied().unwrap_or_default()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FractalMode::Fbm => "fBm",
+            FractalMode::Ridged => "Ridged",
+            FractalMode::Turbulence => "Turbulence",
+        }
+    }
+
+    pub fn shader_def(self) -> Option<ShaderDefVal> {
+        match self {
+            FractalMode::Fbm => None,
+            FractalMode::Ridged => Some("FRACTAL_RIDGED".into()),
+            FractalMode::Turbulence => Some("FRACTAL_TURBULENCE".into()),
+        }
+    }
+}
+
+// Must match `WARP_COMPONENT_OFFSET` in the compute shader. Decorrelates the x and y of a warp.
+pub const WARP_COMPONENT_OFFSET: Vec2 = Vec2::new(5.2, 1.3);
+
+// Domain warping in the style of f(p + g(p + h(p))): every layer displaces `p` by noise sampled at
+// the position displaced by the previous layers.
+pub fn warp(globals: &FlowFieldGlobals, p: Vec2) -> Vec2 {
+    let mut displacement = Vec2::ZERO;
+    for layer in &globals.warp_layers[..(globals.num_warp_layers as usize).min(MAX_WARP_LAYERS)] {
+        let q = (p + displacement) * layer.scale + layer.offset;
+        displacement = layer.strength
+            * Vec2::new(
+                fractal_noise(globals, q),
+                fractal_noise(globals, q + WARP_COMPONENT_OFFSET),
+            );
+    }
+    p + displacement
+}
+
+pub fn base_noise(noise_type: NoiseType, p: Vec2) -> f32 {
+    match noise_type {
+        NoiseType::Perlin => perlin_noise_2(p),
+        NoiseType::Simplex => simplex_noise_2(p),
+        NoiseType::Value => value_noise_2(p),
+        NoiseType::Worley => worley_noise_2(p),
+    }
+}
+
+// Sum of `noise_octaves` octaves, normalized by the total amplitude. A single octave gives the
+// base noise unchanged.
+pub fn fractal_noise(globals: &FlowFieldGlobals, p: Vec2) -> f32 {
+    let noise_type = NoiseType::from_u32(globals.noise_type);
+    let fractal_mode = FractalMode::from_u32(globals.noise_fractal_mode);
+
+    let mut sum = 0.0;
+    let mut total_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for _ in 0..globals.noise_octaves.max(1) {
+        let mut n = base_noise(noise_type, p * frequency);
+        match fractal_mode {
+            FractalMode::Fbm => {}
+            FractalMode::Ridged => {
+                n = 1.0 - n.abs();
+                n *= n;
+            }
+            FractalMode::Turbulence => n = n.abs(),
+        }
+        sum += amplitude * n;
+        total_amplitude += amplitude;
+        amplitude *= globals.noise_gain;
+        frequency *= globals.noise_lacunarity;
+    }
+    sum / total_amplitude
+}
+
+// MIT License. © Stefan Gustavson, Munrocket
+//
+fn permute4(x: Vec4) -> Vec4 {
+    ((x * 34.0 + 1.0) * x) % Vec4::splat(289.0)
+}
+
+pub fn fade2(t: Vec2) -> Vec2 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+// WGSL `mix`, which is not guaranteed to round the same way as `lerp`.
+pub fn mix(x: f32, y: f32, a: f32) -> f32 {
+    x * (1.0 - a) + y * a
+}
+
+pub fn perlin_noise_2(p: Vec2) -> f32 {
+    let p_xyxy = Vec4::new(p.x, p.y, p.x, p.y);
+    let mut pi = p_xyxy.floor() + Vec4::new(0.0, 0.0, 1.0, 1.0);
+    let pf = p_xyxy.fract() - Vec4::new(0.0, 0.0, 1.0, 1.0);
+    pi %= Vec4::splat(289.0); // To avoid truncation effects in permutation
+    let ix = Vec4::new(pi.x, pi.z, pi.x, pi.z);
+    let iy = Vec4::new(pi.y, pi.y, pi.w, pi.w);
+    let fx = Vec4::new(pf.x, pf.z, pf.x, pf.z);
+    let fy = Vec4::new(pf.y, pf.y, pf.w, pf.w);
+    let i = permute4(permute4(ix) + iy);
+    let mut gx = 2.0 * (i * 0.024_390_244).fract() - 1.0; // 1/41 = 0.024...
+    let gy = gx.abs() - 0.5;
+    let tx = (gx + 0.5).floor();
+    gx -= tx;
+    let mut g00 = Vec2::new(gx.x, gy.x);
+    let mut g10 = Vec2::new(gx.y, gy.y);
+    let mut g01 = Vec2::new(gx.z, gy.z);
+    let mut g11 = Vec2::new(gx.w, gy.w);
+    let norm = 1.792_842_9
+        - 0.853_734_7 * Vec4::new(g00.dot(g00), g01.dot(g01), g10.dot(g10), g11.dot(g11));
+    g00 *= norm.x;
+    g01 *= norm.y;
+    g10 *= norm.z;
+    g11 *= norm.w;
+    let n00 = g00.dot(Vec2::new(fx.x, fy.x));
+    let n10 = g10.dot(Vec2::new(fx.y, fy.y));
+    let n01 = g01.dot(Vec2::new(fx.z, fy.z));
+    let n11 = g11.dot(Vec2::new(fx.w, fy.w));
+    let fade_xy = fade2(Vec2::new(pf.x, pf.y));
+    let n_x = Vec2::new(mix(n00, n10, fade_xy.x), mix(n01, n11, fade_xy.x));
+    let n_xy = mix(n_x.x, n_x.y, fade_xy.y);
+    2.3 * n_xy
+}
+
+// MIT License. © Ian McEwan, Stefan Gustavson, Munrocket
+//
+fn mod289_2(x: Vec2) -> Vec2 {
+    x - (x * (1.0 / 289.0)).floor() * 289.0
+}
+
+fn mod289_3(x: Vec3) -> Vec3 {
+    x - (x * (1.0 / 289.0)).floor() * 289.0
+}
+
+fn permute3(x: Vec3) -> Vec3 {
+    mod289_3(((x * 34.0) + 1.0) * x)
+}
+
+pub fn simplex_noise_2(v: Vec2) -> f32 {
+    let c = Vec4::new(
+        0.211_324_87,  // (3.0-sqrt(3.0))/6.0
+        0.366_025_42,  // 0.5*(sqrt(3.0)-1.0)
+        -0.577_350_26, // -1.0 + 2.0 * C.x
+        0.024_390_243, // 1.0 / 41.0
+    );
+    let mut i = (v + v.dot(Vec2::splat(c.y))).floor();
+    let x0 = v - i + i.dot(Vec2::splat(c.x));
+    let i1 = if x0.x > x0.y {
+        Vec2::new(1.0, 0.0)
+    } else {
+        Vec2::new(0.0, 1.0)
+    };
+    let mut x12 = Vec4::new(x0.x, x0.y, x0.x, x0.y) + Vec4::new(c.x, c.x, c.z, c.z);
+    x12.x -= i1.x;
+    x12.y -= i1.y;
+    i = mod289_2(i);
+    let p = permute3(permute3(i.y + Vec3::new(0.0, i1.y, 1.0)) + i.x + Vec3::new(0.0, i1.x, 1.0));
+    let x12_xy = Vec2::new(x12.x, x12.y);
+    let x12_zw = Vec2::new(x12.z, x12.w);
+    let mut m =
+        (0.5 - Vec3::new(x0.dot(x0), x12_xy.dot(x12_xy), x12_zw.dot(x12_zw))).max(Vec3::ZERO);
+    m *= m;
+    m *= m;
+    let x = 2.0 * (p * c.w).fract() - 1.0;
+    let h = x.abs() - 0.5;
+    let ox = (x + 0.5).floor();
+    let a0 = x - ox;
+    m *= 1.792_842_9 - 0.853_734_7 * (a0 * a0 + h * h);
+    let g = Vec3::new(
+        a0.x * x0.x + h.x * x0.y,
+        a0.y * x12.x + h.y * x12.y,
+        a0.z * x12.z + h.z * x12.w,
+    );
+    130.0 * m.dot(g)
+}
+
+// Hash of the integer lattice point `i`.
+fn lattice_hash(i: Vec2) -> u32 {
+    hash(hash(i.x as i32 as u32) ^ i.y as i32 as u32)
+}
+
+pub fn value_noise_2(p: Vec2) -> f32 {
+    let i = p.floor();
+    let u = fade2(p.fract());
+    let value = |offset: Vec2| lattice_hash(i + offset) as f32 / 4294967295.0 * 2.0 - 1.0;
+    let a = value(Vec2::new(0.0, 0.0));
+    let b = value(Vec2::new(1.0, 0.0));
+    let c = value(Vec2::new(0.0, 1.0));
+    let d = value(Vec2::new(1.0, 1.0));
+    mix(mix(a, b, u.x), mix(c, d, u.x), u.y)
+}
+
+// Distance to the closest feature point (F1), one random point per lattice cell, mapped from
+// roughly [0, 1] to [-1, 1].
+pub fn worley_noise_2(p: Vec2) -> f32 {
+    let i = p.floor();
+    let f = p.fract();
+    let mut min_distance: f32 = 8.0;
+    for y in -1..=1 {
+        for x in -1..=1 {
+            let cell = Vec2::new(x as f32, y as f32);
+            let h = lattice_hash(i + cell);
+            let feature = cell + Vec2::new(h as f32 / 4294967295.0, random_f32(h));
+            min_distance = min_distance.min((feature - f).length());
+        }
+    }
+    2.0 * min_distance - 1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perlin_noise_is_pinned() {
+        let samples = [
+            (Vec2::new(0.0, 0.0), 0.0),
+            (Vec2::new(0.5, 0.5), -0.491_532_6),
+            (Vec2::new(1.25, -3.5), -0.016_670_6),
+            (Vec2::new(-17.3, 42.1), 0.093_516_0),
+        ];
+        for (p, expected) in samples {
+            let n = perlin_noise_2(p);
+            assert!((n - expected).abs() < 1e-5, "perlin_noise_2({p}) = {n}");
+        }
+    }
+
+    #[test]
+    fn single_octave_is_base_noise() {
+        let globals = FlowFieldGlobals::default();
+        for p in [
+            Vec2::new(0.5, 0.5),
+            Vec2::new(1.25, -3.5),
+            Vec2::new(-17.3, 42.1),
+        ] {
+            assert_eq!(fractal_noise(&globals, p), perlin_noise_2(p));
+        }
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        for noise_type in NoiseType::ALL {
+            for fractal_mode in FractalMode::ALL {
+                let globals = FlowFieldGlobals {
+                    noise_type: noise_type as u32,
+                    noise_fractal_mode: fractal_mode as u32,
+                    noise_octaves: 4,
+                    ..default()
+                };
+                for i in 0..1000 {
+                    let p = Vec2::new(random_f32(2 * i), random_f32(2 * i + 1)) * 200.0 - 100.0;
+                    let n = fractal_noise(&globals, p);
+                    assert!(
+                        (-1.1..=1.1).contains(&n),
+                        "{noise_type:?} {fractal_mode:?} at {p}: {n}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn warp_layers() {
+        let mut globals = FlowFieldGlobals::default();
+        let p = Vec2::new(0.3, -1.7);
+        assert_eq!(warp(&globals, p), p);
+
+        globals.num_warp_layers = 1;
+        let layer = globals.warp_layers[0];
+        let q = p * layer.scale + layer.offset;
+        let expected = p + layer.strength
+            * Vec2::new(
+                fractal_noise(&globals, q),
+                fractal_noise(&globals, q + WARP_COMPONENT_OFFSET),
+            );
+        assert_eq!(warp(&globals, p), expected);
+
+        globals.warp_layers[0].strength = 0.0;
+        assert_eq!(warp(&globals, p), p);
+    }
+
+    #[test]
+    fn default_warp_layers_sample_different_noise() {
+        let globals = FlowFieldGlobals::default();
+        // Where each layer samples the noise for x and y relative to its position. Noise features
+        // are about 1 apart, so these don't follow the same noise.
+        let samples: Vec<Vec2> = globals
+            .warp_layers
+            .iter()
+            .flat_map(|layer| [layer.offset, layer.offset + WARP_COMPONENT_OFFSET])
+            .collect();
+        for (i, a) in samples.iter().enumerate() {
+            for b in &samples[i + 1..] {
+                assert!(a.distance(*b) > 2.0, "{a} and {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn value_noise_interpolates_lattice() {
+        let p = Vec2::new(3.0, -2.0);
+        let expected = lattice_hash(p) as f32 / 4294967295.0 * 2.0 - 1.0;
+        assert_eq!(value_noise_2(p), expected);
+    }
+}
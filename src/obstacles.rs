@@ -0,0 +1,364 @@
+// Obstacles and masks: shapes given by signed distance functions that lines stop at, flow around
+// or aren't drawn inside.
+//
+// Lines are steered around an obstacle by removing the part of their direction that points into
+// it, along the gradient of its distance, within `margin` of its surface.
+
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy_egui::egui;
+
+// Must match `OBSTACLE_GRADIENT_STEP` in the compute shader. Distance in pixels across which the
+// gradient of the signed distance is taken.
+pub const OBSTACLE_GRADIENT_STEP: f32 = 0.5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Shape {
+    #[default]
+    Circle,
+    Box,
+    Polygon,
+}
+
+impl Shape {
+    pub const ALL: [Shape; 3] = [Shape::Circle, Shape::Box, Shape::Polygon];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Shape::Circle => "Circle",
+            Shape::Box => "Box",
+            Shape::Polygon => "Polygon",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ObstacleBehaviour {
+    // Lines end at the last joint before entering
+    #[default]
+    Stop,
+    // Lines flow around
+    Steer,
+    // Lines continue through but aren't drawn inside
+    Mask,
+}
+
+impl ObstacleBehaviour {
+    pub const ALL: [ObstacleBehaviour; 3] = [
+        ObstacleBehaviour::Stop,
+        ObstacleBehaviour::Steer,
+        ObstacleBehaviour::Mask,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ObstacleBehaviour::Stop => "Stop",
+            ObstacleBehaviour::Steer => "Steer",
+            ObstacleBehaviour::Mask => "Mask",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Obstacle {
+    pub shape: Shape,
+    pub behaviour: ObstacleBehaviour,
+    // World position
+    pub centre: Vec2,
+    // The radius of circles in x, half the width and height of boxes
+    pub size: Vec2,
+    // Corners of polygons relative to `centre`
+    pub points: Vec<Vec2>,
+    // Grows the shape by this distance, rounding its corners
+    pub rounding: f32,
+    // Distance from the surface at which steered lines start turning
+    pub margin: f32,
+}
+
+impl Obstacle {
+    pub fn new(shape: Shape, behaviour: ObstacleBehaviour, centre: Vec2) -> Self {
+        Self {
+            shape,
+            behaviour,
+            centre,
+            size: Vec2::splat(60.0),
+            points: match shape {
+                Shape::Polygon => (0..5)
+                    .map(|i| {
+                        let angle = std::f32::consts::TAU * i as f32 / 5.0;
+                        80.0 * Vec2::new(-angle.sin(), angle.cos())
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            },
+            rounding: 0.0,
+            margin: 20.0,
+        }
+    }
+
+    // Mirrors `obstacle_distance` in the compute shader. Negative inside.
+    pub fn distance(&self, p: Vec2) -> f32 {
+        let p = p - self.centre;
+        let distance = match self.shape {
+            Shape::Circle => p.length() - self.size.x,
+            Shape::Box => {
+                let q = p.abs() - self.size;
+                q.max(Vec2::ZERO).length() + q.x.max(q.y).min(0.0)
+            }
+            Shape::Polygon => polygon_distance(&self.points, p),
+        };
+        distance - self.rounding
+    }
+
+    // Mirrors `obstacle_gradient`, pointing away from the shape. Not normalized.
+    pub fn gradient(&self, p: Vec2) -> Vec2 {
+        let dx = Vec2::new(OBSTACLE_GRADIENT_STEP, 0.0);
+        let dy = Vec2::new(0.0, OBSTACLE_GRADIENT_STEP);
+        Vec2::new(
+            self.distance(p + dx) - self.distance(p - dx),
+            self.distance(p + dy) - self.distance(p - dy),
+        )
+    }
+}
+
+// Mirrors `polygon_distance`. Signed distance to a closed polygon, after Inigo Quilez.
+pub fn polygon_distance(points: &[Vec2], p: Vec2) -> f32 {
+    let Some(&first) = points.first() else {
+        return f32::MAX;
+    };
+    let mut d = (p - first).length_squared();
+    let mut s = 1.0;
+    let mut j = points.len() - 1;
+    for i in 0..points.len() {
+        let e = points[j] - points[i];
+        let w = p - points[i];
+        let b = w - e * (w.dot(e) / e.dot(e).max(f32::MIN_POSITIVE)).clamp(0.0, 1.0);
+        d = d.min(b.dot(b));
+        // Crossings of a ray towards +x flip the sign
+        let c = [p.y >= points[i].y, p.y < points[j].y, e.x * w.y > e.y * w.x];
+        if c == [true; 3] || c == [false; 3] {
+            s = -s;
+        }
+        j = i;
+    }
+    s * d.sqrt()
+}
+
+// Mirrors `steer_around_obstacles`. Turns `direction` along the surface of the steering obstacles
+// it points into, fully at the surface and not at all `margin` away. Inside it also points out.
+pub fn steer_around_obstacles(obstacles: &[Obstacle], direction: Vec2, p: Vec2) -> Vec2 {
+    let mut steered = direction;
+    for obstacle in obstacles {
+        if obstacle.behaviour != ObstacleBehaviour::Steer {
+            continue;
+        }
+        let d = obstacle.distance(p);
+        if d >= obstacle.margin {
+            continue;
+        }
+        let n = obstacle.gradient(p).normalize_or_zero();
+        let w = (1.0 - d / obstacle.margin.max(f32::MIN_POSITIVE)).clamp(0.0, 1.0);
+        let inward = steered.dot(n);
+        if inward < 0.0 {
+            steered -= n * inward * w;
+        }
+        if d < 0.0 {
+            steered += n;
+        }
+    }
+    if steered.length() > 0.0 {
+        steered.normalize()
+    } else {
+        direction
+    }
+}
+
+// Mirrors `is_inside_obstacle`.
+pub fn is_inside_obstacle(obstacles: &[Obstacle], behaviour: ObstacleBehaviour, p: Vec2) -> bool {
+    obstacles
+        .iter()
+        .any(|obstacle| obstacle.behaviour == behaviour && obstacle.distance(p) < 0.0)
+}
+
+// Parses `SHAPE,BEHAVIOUR,X,Y,...` with the shape and behaviour by name. Circles are followed by
+// the radius, boxes by the width and height and polygons by the corners relative to X,Y, e.g.
+// `circle,steer,0,0,100` or `polygon,mask,0,0,-50,-50,50,-50,0,50`.
+pub fn parse_obstacle(text: &str) -> Result<Obstacle, String> {
+    let error = || format!("expected SHAPE,BEHAVIOUR,X,Y,..., found `{text}`");
+    let fields: Vec<&str> = text.split(',').map(str::trim).collect();
+    let [shape, behaviour, values @ ..] = fields.as_slice() else {
+        return Err(error());
+    };
+    let shape = Shape::ALL
+        .into_iter()
+        .find(|s| s.name().eq_ignore_ascii_case(shape))
+        .ok_or_else(error)?;
+    let behaviour = ObstacleBehaviour::ALL
+        .into_iter()
+        .find(|b| b.name().eq_ignore_ascii_case(behaviour))
+        .ok_or_else(error)?;
+    let values: Vec<f32> = values
+        .iter()
+        .map(|v| v.parse().map_err(|_| error()))
+        .collect::<Result<_, _>>()?;
+
+    let mut obstacle = Obstacle::new(shape, behaviour, Vec2::ZERO);
+    match (shape, values.as_slice()) {
+        (Shape::Circle, &[x, y, radius]) => {
+            obstacle.centre = Vec2::new(x, y);
+            obstacle.size = Vec2::splat(radius);
+        }
+        (Shape::Box, &[x, y, width, height]) => {
+            obstacle.centre = Vec2::new(x, y);
+            obstacle.size = Vec2::new(width, height) / 2.0;
+        }
+        (Shape::Polygon, [x, y, corners @ ..]) if corners.len() >= 6 && corners.len() % 2 == 0 => {
+            obstacle.centre = Vec2::new(*x, *y);
+            obstacle.points = corners.chunks_exact(2).map(Vec2::from_slice).collect();
+        }
+        _ => {
+            return Err(format!(
+                "{text}: circles need X,Y,RADIUS, boxes X,Y,WIDTH,HEIGHT and polygons X,Y and at least 3 corners"
+            ))
+        }
+    }
+    Ok(obstacle)
+}
+
+// The obstacle list in the settings window. Returns the edited obstacles if they changed.
+pub fn obstacles_ui(ui: &mut egui::Ui, obstacles: &Arc<Vec<Obstacle>>) -> Option<Vec<Obstacle>> {
+    let mut changed = false;
+    let mut obstacles = (**obstacles).clone();
+
+    ui.horizontal(|ui| {
+        for shape in Shape::ALL {
+            if ui
+                .button(format!("Add {}", shape.name().to_lowercase()))
+                .clicked()
+            {
+                obstacles.push(Obstacle::new(shape, ObstacleBehaviour::Stop, Vec2::ZERO));
+                changed = true;
+            }
+        }
+    });
+
+    let mut remove = None;
+    for (i, obstacle) in obstacles.iter_mut().enumerate() {
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label(obstacle.shape.name());
+            let mut behaviour = obstacle.behaviour;
+            egui::ComboBox::from_id_source(("obstacle_behaviour", i))
+                .selected_text(behaviour.name())
+                .show_ui(ui, |ui| {
+                    for option in ObstacleBehaviour::ALL {
+                        ui.selectable_value(&mut behaviour, option, option.name());
+                    }
+                });
+            changed |= behaviour != obstacle.behaviour;
+            obstacle.behaviour = behaviour;
+            if ui.button("Remove").clicked() {
+                remove = Some(i);
+            }
+        });
+        ui.horizontal(|ui| {
+            let mut drag = |ui: &mut egui::Ui, value: &mut f32, prefix: &str| {
+                changed |= ui
+                    .add(egui::DragValue::new(value).speed(1.0).prefix(prefix))
+                    .changed();
+            };
+            drag(ui, &mut obstacle.centre.x, "x:");
+            drag(ui, &mut obstacle.centre.y, "y:");
+            match obstacle.shape {
+                Shape::Circle => drag(ui, &mut obstacle.size.x, "radius:"),
+                Shape::Box => {
+                    drag(ui, &mut obstacle.size.x, "half width:");
+                    drag(ui, &mut obstacle.size.y, "half height:");
+                }
+                Shape::Polygon => {}
+            }
+            drag(ui, &mut obstacle.rounding, "rounding:");
+            if obstacle.behaviour == ObstacleBehaviour::Steer {
+                drag(ui, &mut obstacle.margin, "margin:");
+            }
+        });
+        if obstacle.shape == Shape::Polygon {
+            ui.collapsing(format!("Corners ({})", obstacle.points.len()), |ui| {
+                for point in &mut obstacle.points {
+                    ui.horizontal(|ui| {
+                        changed |= ui
+                            .add(egui::DragValue::new(&mut point.x).speed(1.0).prefix("x:"))
+                            .changed();
+                        changed |= ui
+                            .add(egui::DragValue::new(&mut point.y).speed(1.0).prefix("y:"))
+                            .changed();
+                    });
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Add corner").clicked() {
+                        let last = obstacle.points.last().copied().unwrap_or_default();
+                        obstacle.points.push(last + Vec2::new(20.0, 0.0));
+                        changed = true;
+                    }
+                    if obstacle.points.len() > 3 && ui.button("Remove corner").clicked() {
+                        obstacle.points.pop();
+                        changed = true;
+                    }
+                });
+            });
+        }
+        obstacle.size = obstacle.size.max(Vec2::ZERO);
+        obstacle.margin = obstacle.margin.max(0.0);
+    }
+    if let Some(i) = remove {
+        obstacles.remove(i);
+        changed = true;
+    }
+
+    changed.then_some(obstacles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapes_have_signed_distances() {
+        let circle = parse_obstacle("circle,stop,10,0,5").unwrap();
+        assert_eq!(circle.distance(Vec2::new(20.0, 0.0)), 5.0);
+        assert_eq!(circle.distance(Vec2::new(10.0, 0.0)), -5.0);
+
+        let square = parse_obstacle("box,mask,0,0,20,20").unwrap();
+        assert_eq!(square.distance(Vec2::new(0.0, 15.0)), 5.0);
+        assert_eq!(square.distance(Vec2::new(13.0, 14.0)), 5.0);
+        assert_eq!(square.distance(Vec2::new(2.0, 0.0)), -8.0);
+
+        let triangle = parse_obstacle("polygon,steer,0,0,-10,-10,10,-10,-10,10").unwrap();
+        assert_eq!(triangle.distance(Vec2::new(0.0, -15.0)), 5.0);
+        assert_eq!(triangle.distance(Vec2::new(-8.0, -8.0)), -2.0);
+        assert!(triangle.distance(Vec2::new(6.0, 6.0)) > 0.0);
+
+        assert!(parse_obstacle("polygon,steer,0,0,1,1,2,2").is_err());
+        assert!(parse_obstacle("circle,bounce,0,0,1").is_err());
+    }
+
+    #[test]
+    fn steering_follows_surface() {
+        let mut circle = parse_obstacle("circle,steer,0,0,10").unwrap();
+        circle.margin = 10.0;
+        let obstacles = [circle];
+        // Heading at the circle from the right, at the surface and halfway into the margin
+        let at_surface =
+            steer_around_obstacles(&obstacles, Vec2::new(-1.0, 0.1), Vec2::new(10.0, 0.0));
+        assert!(at_surface.x.abs() < 1e-3 && at_surface.y > 0.99);
+        let halfway =
+            steer_around_obstacles(&obstacles, Vec2::new(-1.0, 1.0), Vec2::new(15.0, 0.0));
+        assert!(halfway.x < 0.0 && halfway.x > -std::f32::consts::FRAC_1_SQRT_2);
+        // Moving away isn't changed
+        assert_eq!(
+            steer_around_obstacles(&obstacles, Vec2::X, Vec2::new(15.0, 0.0)),
+            Vec2::X
+        );
+    }
+}
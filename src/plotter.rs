@@ -0,0 +1,527 @@
+// Pen plotter export (HPGL and G-code).
+//
+// The traced lines are clipped to the viewport, scaled to fit the paper and reordered to minimise
+// pen-up travel: paths are visited greedily by nearest endpoint, reversed when their end is closer
+// than their start, and joined without lifting the pen when they touch.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use bevy::prelude::*;
+
+use crate::{cpu_tracer::*, FlowFieldGlobals, FlowFieldInputs};
+
+// HPGL plotter units per millimetre
+const HPGL_UNITS_PER_MM: f32 = 40.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PlotFormat {
+    Hpgl,
+    Gcode,
+}
+
+// All lengths are in millimetres.
+#[derive(Clone)]
+pub struct PlotterSettings {
+    pub paper_width: f32,
+    pub paper_height: f32,
+    pub margin: f32,
+    // Drawing speed in mm/min. Travel moves use rapid positioning.
+    pub feed_rate: f32,
+    pub pen_up_command: String,
+    pub pen_down_command: String,
+    // Paths whose ends are closer than this are joined without lifting the pen.
+    pub merge_distance: f32,
+    pub optimize: bool,
+}
+
+impl Default for PlotterSettings {
+    fn default() -> Self {
+        // A4 landscape on a GRBL plotter with the pen on the Z axis.
+        Self {
+            paper_width: 297.0,
+            paper_height: 210.0,
+            margin: 15.0,
+            feed_rate: 3000.0,
+            pen_up_command: "G0 Z2".to_string(),
+            pen_down_command: "G1 Z0 F1000".to_string(),
+            merge_distance: 0.1,
+            optimize: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PlotStats {
+    pub num_paths: usize,
+    pub pen_down_distance: f32,
+    pub pen_up_distance: f32,
+    // Pen-up travel of the paths in trace order, before optimising
+    pub unoptimized_pen_up_distance: f32,
+}
+
+pub fn export_plot(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    settings: &PlotterSettings,
+    format: PlotFormat,
+    path: &Path,
+) -> Result<PlotStats, String> {
+    let paths = plot_paths(globals, inputs, settings);
+    let unoptimized_pen_up_distance = pen_up_distance(&paths);
+    let paths = if settings.optimize {
+        optimize_paths(paths, settings.merge_distance)
+    } else {
+        paths
+    };
+
+    let file =
+        File::create(path).map_err(|e| format!("failed to create {}: {e}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    match format {
+        PlotFormat::Hpgl => write_hpgl(&paths, globals.seed, &mut out),
+        PlotFormat::Gcode => write_gcode(&paths, settings, globals.seed, &mut out),
+    }
+    .and_then(|_| out.flush())
+    .map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+
+    Ok(PlotStats {
+        num_paths: paths.len(),
+        pen_down_distance: paths.iter().map(|p| polyline_length(p)).sum(),
+        pen_up_distance: pen_up_distance(&paths),
+        unoptimized_pen_up_distance,
+    })
+}
+
+// Traces all lines and returns the parts inside the viewport in paper coordinates (mm, origin in
+// the bottom left corner of the paper, y pointing up).
+pub fn plot_paths(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    settings: &PlotterSettings,
+) -> Vec<Vec<Vec2>> {
+    let half_viewport = Vec2::new(globals.viewport_width, globals.viewport_height) / 2.0;
+    let to_paper = world_to_paper(globals, settings);
+
+    let mut paths = Vec::new();
+    for_each_line(globals, inputs, |_, vertices| {
+        let layout = LineLayout::of_vertices(globals, vertices.len());
+        for points in drawn_polylines(layout.joint_vertices(vertices)) {
+            for piece in clip_polyline(&points, -half_viewport, half_viewport) {
+                paths.push(piece.into_iter().map(&to_paper).collect());
+            }
+        }
+    });
+    paths
+}
+
+// The viewport scaled to fit inside the margins and centred on the paper. Both have y pointing up.
+fn world_to_paper(globals: &FlowFieldGlobals, settings: &PlotterSettings) -> impl Fn(Vec2) -> Vec2 {
+    let viewport = Vec2::new(globals.viewport_width, globals.viewport_height);
+    let drawable = (Vec2::new(settings.paper_width, settings.paper_height) - 2.0 * settings.margin)
+        .max(Vec2::ZERO);
+    let scale = (drawable / viewport).min_element();
+    let origin = Vec2::new(settings.paper_width, settings.paper_height) / 2.0;
+    move |p| origin + p * scale
+}
+
+// Splits a polyline into the pieces inside the rectangle.
+pub fn clip_polyline(points: &[Vec2], min: Vec2, max: Vec2) -> Vec<Vec<Vec2>> {
+    let mut pieces: Vec<Vec<Vec2>> = Vec::new();
+    let mut current: Vec<Vec2> = Vec::new();
+
+    for segment in points.windows(2) {
+        match clip_segment(segment[0], segment[1], min, max) {
+            Some((a, b)) => {
+                if current.last() != Some(&a) {
+                    if current.len() > 1 {
+                        pieces.push(std::mem::take(&mut current));
+                    }
+                    current.clear();
+                    current.push(a);
+                }
+                current.push(b);
+            }
+            None => {
+                if current.len() > 1 {
+                    pieces.push(std::mem::take(&mut current));
+                }
+                current.clear();
+            }
+        }
+    }
+    if current.len() > 1 {
+        pieces.push(current);
+    }
+
+    pieces
+}
+
+// Liang-Barsky
+fn clip_segment(a: Vec2, b: Vec2, min: Vec2, max: Vec2) -> Option<(Vec2, Vec2)> {
+    let d = b - a;
+    let mut t0: f32 = 0.0;
+    let mut t1: f32 = 1.0;
+
+    for (p, q) in [
+        (-d.x, a.x - min.x),
+        (d.x, max.x - a.x),
+        (-d.y, a.y - min.y),
+        (d.y, max.y - a.y),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else {
+            let t = q / p;
+            if p < 0.0 {
+                t0 = t0.max(t);
+            } else {
+                t1 = t1.min(t);
+            }
+        }
+    }
+
+    // Only touching the rectangle in a corner leaves a single point
+    if t0 > t1 || (t0 == t1 && d != Vec2::ZERO) {
+        return None;
+    }
+    let clipped_a = if t0 > 0.0 { a + d * t0 } else { a };
+    let clipped_b = if t1 < 1.0 { a + d * t1 } else { b };
+    Some((clipped_a, clipped_b))
+}
+
+// Greedy nearest neighbour ordering starting from the origin. Every path can be drawn in either
+// direction, and a path starting where the previous one ended is appended to it.
+pub fn optimize_paths(paths: Vec<Vec<Vec2>>, merge_distance: f32) -> Vec<Vec<Vec2>> {
+    let mut grid = EndpointGrid::new(&paths);
+    let mut visited = vec![false; paths.len()];
+    let mut paths: Vec<Option<Vec<Vec2>>> = paths.into_iter().map(Some).collect();
+    let mut optimized: Vec<Vec<Vec2>> = Vec::new();
+    let mut pen = Vec2::ZERO;
+
+    while let Some((index, reversed, distance)) = grid.nearest(pen, &visited) {
+        visited[index] = true;
+        let mut path = paths[index].take().unwrap();
+        if reversed {
+            path.reverse();
+        }
+        pen = *path.last().unwrap();
+
+        match optimized.last_mut() {
+            Some(previous) if distance <= merge_distance => {
+                let skip = usize::from(distance == 0.0);
+                previous.extend_from_slice(&path[skip..]);
+            }
+            _ => optimized.push(path),
+        }
+    }
+
+    optimized
+}
+
+// Uniform grid over the endpoints of all paths, for nearest endpoint queries.
+struct EndpointGrid {
+    min: Vec2,
+    cell_size: f32,
+    width: i32,
+    height: i32,
+    // Endpoint `2 * i` is the start of path `i` and `2 * i + 1` its end.
+    cells: Vec<Vec<u32>>,
+    endpoints: Vec<Vec2>,
+}
+
+impl EndpointGrid {
+    fn new(paths: &[Vec<Vec2>]) -> Self {
+        let endpoints: Vec<Vec2> = paths.iter().flat_map(|p| [p[0], p[p.len() - 1]]).collect();
+        let min = endpoints.iter().copied().fold(Vec2::MAX, Vec2::min);
+        let max = endpoints.iter().copied().fold(Vec2::MIN, Vec2::max);
+        let size = (max - min).max(Vec2::splat(1e-3));
+
+        // Roughly two endpoints per cell
+        let cell_size = (size.x * size.y / endpoints.len().max(1) as f32 * 2.0)
+            .sqrt()
+            .max(1e-3);
+        let width = (size.x / cell_size) as i32 + 1;
+        let height = (size.y / cell_size) as i32 + 1;
+
+        let mut grid = Self {
+            min,
+            cell_size,
+            width,
+            height,
+            cells: vec![Vec::new(); (width * height) as usize],
+            endpoints,
+        };
+        for (i, p) in grid.endpoints.iter().enumerate() {
+            let (x, y) = grid.cell(*p);
+            grid.cells[(y * width + x) as usize].push(i as u32);
+        }
+        grid
+    }
+
+    fn cell(&self, p: Vec2) -> (i32, i32) {
+        let c = ((p - self.min) / self.cell_size).floor();
+        (
+            (c.x as i32).clamp(0, self.width - 1),
+            (c.y as i32).clamp(0, self.height - 1),
+        )
+    }
+
+    // Returns the closest unvisited path, whether it should be drawn reversed and the distance to
+    // its nearest endpoint. Searches rings of cells around `p` until no closer endpoint can exist.
+    fn nearest(&mut self, p: Vec2, visited: &[bool]) -> Option<(usize, bool, f32)> {
+        let (cx, cy) = self.cell(p);
+        // `p` may lie outside the grid, so rings are counted from the clamped cell.
+        let outside = (self.min - p)
+            .max(p - (self.min + Vec2::new(self.width as f32, self.height as f32) * self.cell_size))
+            .max(Vec2::ZERO)
+            .length();
+        let max_ring = self.width.max(self.height);
+        let mut best: Option<(u32, f32)> = None;
+
+        for ring in 0..=max_ring {
+            if let Some((_, distance)) = best {
+                if distance <= outside.max((ring - 1) as f32 * self.cell_size) {
+                    break;
+                }
+            }
+            for y in cy - ring..=cy + ring {
+                if y < 0 || y >= self.height {
+                    continue;
+                }
+                for x in cx - ring..=cx + ring {
+                    if x < 0 || x >= self.width {
+                        continue;
+                    }
+                    if (y - cy).abs() != ring && (x - cx).abs() != ring {
+                        continue;
+                    }
+                    let cell = &mut self.cells[(y * self.width + x) as usize];
+                    cell.retain(|&e| !visited[e as usize / 2]);
+                    for &e in cell.iter() {
+                        let distance = self.endpoints[e as usize].distance(p);
+                        if best.is_none_or(|(_, d)| distance < d) {
+                            best = Some((e, distance));
+                        }
+                    }
+                }
+            }
+        }
+
+        best.map(|(e, distance)| (e as usize / 2, e % 2 == 1, distance))
+    }
+}
+
+fn polyline_length(points: &[Vec2]) -> f32 {
+    points.windows(2).map(|s| s[0].distance(s[1])).sum()
+}
+
+// Travel from the origin to the first path and between all paths.
+pub fn pen_up_distance(paths: &[Vec<Vec2>]) -> f32 {
+    let mut pen = Vec2::ZERO;
+    let mut distance = 0.0;
+    for path in paths {
+        distance += pen.distance(path[0]);
+        pen = path[path.len() - 1];
+    }
+    distance
+}
+
+pub fn write_hpgl(paths: &[Vec<Vec2>], seed: u32, out: &mut impl Write) -> io::Result<()> {
+    let units = |p: Vec2| (p * HPGL_UNITS_PER_MM).round().as_ivec2();
+
+    writeln!(out, "IN;")?;
+    writeln!(out, "CO\"flow field plot, seed {seed}\";")?;
+    writeln!(out, "SP1;")?;
+    for path in paths {
+        let start = units(path[0]);
+        writeln!(out, "PU{},{};", start.x, start.y)?;
+        write!(out, "PD")?;
+        for (i, p) in path[1..].iter().enumerate() {
+            let p = units(*p);
+            let separator = if i == 0 { "" } else { "," };
+            write!(out, "{separator}{},{}", p.x, p.y)?;
+        }
+        writeln!(out, ";")?;
+    }
+    writeln!(out, "PU;SP0;")
+}
+
+pub fn write_gcode(
+    paths: &[Vec<Vec2>],
+    settings: &PlotterSettings,
+    seed: u32,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(out, "; flow field plot, {} paths", paths.len())?;
+    writeln!(out, "; seed: {seed}")?;
+    writeln!(out, "G21 ; millimetres")?;
+    writeln!(out, "G90 ; absolute positioning")?;
+    writeln!(out, "{}", settings.pen_up_command)?;
+    for path in paths {
+        writeln!(out, "G0 X{:.3} Y{:.3}", path[0].x, path[0].y)?;
+        writeln!(out, "{}", settings.pen_down_command)?;
+        for p in &path[1..] {
+            writeln!(out, "G1 X{:.3} Y{:.3} F{}", p.x, p.y, settings.feed_rate)?;
+        }
+        writeln!(out, "{}", settings.pen_up_command)?;
+    }
+    writeln!(out, "G0 X0 Y0")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(points: &[(f32, f32)]) -> Vec<Vec2> {
+        points.iter().map(|&(x, y)| Vec2::new(x, y)).collect()
+    }
+
+    #[test]
+    fn clips_polylines_to_rectangle() {
+        let (min, max) = (Vec2::splat(-10.0), Vec2::splat(10.0));
+        let clip = |points: &[(f32, f32)]| clip_polyline(&v(points), min, max);
+
+        let inside = [(-5.0, -5.0), (5.0, -5.0), (5.0, 5.0)];
+        assert_eq!(clip(&inside), [v(&inside)]);
+        // Leaving and coming back in splits the line
+        assert_eq!(
+            clip(&[(0.0, 0.0), (20.0, 0.0), (20.0, 5.0), (0.0, 5.0)]),
+            [v(&[(0.0, 0.0), (10.0, 0.0)]), v(&[(10.0, 5.0), (0.0, 5.0)])]
+        );
+        // Crossing the whole rectangle from outside
+        assert_eq!(
+            clip(&[(-20.0, 0.0), (20.0, 0.0)]),
+            [v(&[(-10.0, 0.0), (10.0, 0.0)])]
+        );
+        // Parallel to an edge outside of it, and missing a corner
+        assert!(clip(&[(-20.0, 15.0), (20.0, 15.0)]).is_empty());
+        assert!(clip(&[(5.0, 20.0), (20.0, 5.0)]).is_empty());
+        // Along an edge is inside
+        assert_eq!(
+            clip(&[(-20.0, 10.0), (20.0, 10.0)]),
+            [v(&[(-10.0, 10.0), (10.0, 10.0)])]
+        );
+        // Only touching a corner leaves a single point, which isn't a line
+        assert!(clip(&[(0.0, 20.0), (20.0, 0.0)]).is_empty());
+    }
+
+    #[test]
+    fn optimizes_travel() {
+        let paths = vec![
+            v(&[(10.0, 0.0), (20.0, 0.0)]),
+            // Closer to the pen with its end, so it's drawn reversed
+            v(&[(5.0, 5.0), (1.0, 0.0)]),
+            // Starts where the first one ends
+            v(&[(20.0, 0.0), (20.0, 10.0)]),
+            // Starts close to where the previous one ends
+            v(&[(20.05, 10.0), (30.0, 10.0)]),
+        ];
+        assert_eq!(
+            optimize_paths(paths, 0.1),
+            [
+                v(&[(1.0, 0.0), (5.0, 5.0)]),
+                v(&[
+                    (10.0, 0.0),
+                    (20.0, 0.0),
+                    (20.0, 10.0),
+                    (20.05, 10.0),
+                    (30.0, 10.0)
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn grid_finds_nearest_endpoint() {
+        // A deterministic scatter of short paths
+        let paths: Vec<Vec<Vec2>> = (0..200)
+            .map(|i| {
+                let p = Vec2::new((i * 37 % 101) as f32, (i * 61 % 53) as f32 * 2.0);
+                vec![p, p + Vec2::new((i % 7) as f32, 3.0)]
+            })
+            .collect();
+        let mut grid = EndpointGrid::new(&paths);
+        let mut visited = vec![false; paths.len()];
+        for i in 0..150 {
+            visited[i * 7 % paths.len()] = true;
+        }
+        // Inside the grid and far outside of it
+        for p in [
+            Vec2::new(50.0, 50.0),
+            Vec2::new(3.0, 97.0),
+            Vec2::new(-200.0, 40.0),
+            Vec2::new(500.0, 500.0),
+        ] {
+            let expected = paths
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !visited[*i])
+                .flat_map(|(_, path)| [path[0].distance(p), path[1].distance(p)])
+                .fold(f32::MAX, f32::min);
+            let (index, reversed, distance) = grid.nearest(p, &visited).unwrap();
+            assert!(!visited[index]);
+            assert_eq!(distance, expected);
+            assert_eq!(paths[index][usize::from(reversed)].distance(p), distance);
+        }
+        assert!(grid.nearest(Vec2::ZERO, &[true; 200]).is_none());
+    }
+
+    #[test]
+    fn fits_viewport_on_paper() {
+        let globals = FlowFieldGlobals {
+            viewport_width: 200.0,
+            viewport_height: 100.0,
+            ..default()
+        };
+        // 267 x 180 mm inside the margins, so the width is what limits the scale
+        let to_paper = world_to_paper(&globals, &PlotterSettings::default());
+        assert_eq!(to_paper(Vec2::ZERO), Vec2::new(148.5, 105.0));
+        // The top left corner of the viewport stays at the top
+        assert_eq!(to_paper(Vec2::new(-100.0, 50.0)), Vec2::new(15.0, 171.75));
+    }
+
+    #[test]
+    fn writes_plotter_commands() {
+        let paths = vec![
+            v(&[(1.0, 2.0), (3.0, 4.5), (5.0, 6.0)]),
+            v(&[(0.5, 0.0), (0.0, 0.025)]),
+        ];
+
+        let mut hpgl = Vec::new();
+        write_hpgl(&paths, 3, &mut hpgl).unwrap();
+        // 40 units per millimetre, pen up to the start of each path and down through the rest
+        assert_eq!(
+            String::from_utf8(hpgl).unwrap(),
+            "IN;\nCO\"flow field plot, seed 3\";\nSP1;\nPU40,80;\nPD120,180,200,240;\nPU20,0;\nPD0,1;\nPU;SP0;\n"
+        );
+
+        let settings = PlotterSettings {
+            feed_rate: 1200.0,
+            ..default()
+        };
+        let mut gcode = Vec::new();
+        write_gcode(&paths[..1], &settings, 3, &mut gcode).unwrap();
+        let gcode = String::from_utf8(gcode).unwrap();
+        let lines: Vec<&str> = gcode.lines().collect();
+        assert_eq!(
+            lines[2..],
+            [
+                "G21 ; millimetres",
+                "G90 ; absolute positioning",
+                "G0 Z2",
+                "G0 X1.000 Y2.000",
+                "G1 Z0 F1000",
+                "G1 X3.000 Y4.500 F1200",
+                "G1 X5.000 Y6.000 F1200",
+                "G0 Z2",
+                "G0 X0 Y0",
+            ]
+        );
+    }
+}
@@ -0,0 +1,264 @@
+// Saving and loading `FlowFieldGlobals` as versioned RON presets, and the preset browser window.
+
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use serde::{Deserialize, Serialize};
+
+use crate::FlowFieldGlobals;
+
+// Bump when a change to `FlowFieldGlobals` needs old presets to be migrated in `load_preset`.
+pub const PRESET_VERSION: u32 = 1;
+
+pub const PRESET_EXTENSION: &str = "ron";
+
+#[derive(Serialize, Deserialize)]
+pub struct Preset {
+    pub version: u32,
+    pub globals: FlowFieldGlobals,
+}
+
+// Only the version of a preset file, to tell presets apart from plain `FlowFieldGlobals` which
+// have no version (0).
+#[derive(Deserialize)]
+struct PresetVersion {
+    #[serde(default)]
+    version: u32,
+}
+
+pub fn save_preset(globals: &FlowFieldGlobals, path: &Path) -> Result<(), String> {
+    let preset = Preset {
+        version: PRESET_VERSION,
+        globals: *globals,
+    };
+    let contents = ron::ser::to_string_pretty(&preset, ron::ser::PrettyConfig::default())
+        .map_err(|e| format!("failed to serialize preset: {e}"))?;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("failed to create {}: {e}", parent.display()))?;
+    }
+    std::fs::write(path, contents).map_err(|e| format!("failed to write {}: {e}", path.display()))
+}
+
+// Also accepts plain `FlowFieldGlobals` without the version header, as written by hand for the
+// headless renderer.
+pub fn load_preset(path: &Path) -> Result<FlowFieldGlobals, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+
+    let parse_error =
+        |e: ron::error::SpannedError| format!("failed to parse {}: {e}", path.display());
+
+    match ron::from_str::<PresetVersion>(&contents).map_err(parse_error)?.version {
+        0 => ron::from_str::<FlowFieldGlobals>(&contents).map_err(parse_error),
+        version if version > PRESET_VERSION => Err(format!(
+            "{} was saved by a newer version (preset version {version}, supported up to {PRESET_VERSION})",
+            path.display(),
+        )),
+        _ => Ok(ron::from_str::<Preset>(&contents).map_err(parse_error)?.globals),
+    }
+}
+
+// Replaces everything except the runtime-only state of `globals` and resets the flow field.
+pub fn apply_preset(globals: &mut FlowFieldGlobals, preset: FlowFieldGlobals) {
+    *globals = FlowFieldGlobals {
+        should_reset: 1,
+        paused: globals.paused,
+        viewport_width: globals.viewport_width,
+        viewport_height: globals.viewport_height,
+        ..preset
+    };
+}
+
+#[derive(Resource)]
+pub struct PresetBrowser {
+    pub dir: PathBuf,
+    // Name of the preset to save, without extension
+    pub name: String,
+    pub entries: Vec<PathBuf>,
+    pub needs_refresh: bool,
+    // Result of the last save or load
+    pub status: String,
+}
+
+impl Default for PresetBrowser {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("presets"),
+            name: "preset".to_string(),
+            entries: Vec::new(),
+            needs_refresh: true,
+            status: String::new(),
+        }
+    }
+}
+
+impl PresetBrowser {
+    fn refresh(&mut self) {
+        self.entries = std::fs::read_dir(&self.dir)
+            .map(|dir| {
+                dir.filter_map(|entry| Some(entry.ok()?.path()))
+                    .filter(|path| {
+                        path.extension().and_then(|e| e.to_str()) == Some(PRESET_EXTENSION)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.entries.sort();
+        self.needs_refresh = false;
+    }
+}
+
+// Runs after `update_ui` so the reset requested by loading a preset isn't cleared again.
+pub fn update_presets_ui(
+    mut contexts: EguiContexts,
+    mut globals: ResMut<FlowFieldGlobals>,
+    mut browser: ResMut<PresetBrowser>,
+) {
+    if browser.needs_refresh {
+        browser.refresh();
+    }
+
+    egui::Window::new("Presets").show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            ui.label("Name");
+            ui.text_edit_singleline(&mut browser.name);
+            if ui.button("Save").clicked() {
+                let path = browser
+                    .dir
+                    .join(&browser.name)
+                    .with_extension(PRESET_EXTENSION);
+                browser.status = match save_preset(&globals, &path) {
+                    Ok(()) => format!("Saved {}", path.display()),
+                    Err(e) => e,
+                };
+                browser.needs_refresh = true;
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label(format!("{}/", browser.dir.display()));
+            if ui.button("Refresh").clicked() {
+                browser.needs_refresh = true;
+            }
+        });
+
+        let mut load = None;
+        egui::ScrollArea::vertical()
+            .max_height(200.0)
+            .show(ui, |ui| {
+                if browser.entries.is_empty() {
+                    ui.label("No presets");
+                }
+                for path in &browser.entries {
+                    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                    if ui.button(name).on_hover_text("Load").clicked() {
+                        load = Some(path.clone());
+                    }
+                }
+            });
+
+        if let Some(path) = load {
+            browser.status = match load_preset(&path) {
+                Ok(preset) => {
+                    apply_preset(&mut globals, preset);
+                    if let Some(name) = path.file_stem() {
+                        browser.name = name.to_string_lossy().into_owned();
+                    }
+                    format!("Loaded {}", path.display())
+                }
+                Err(e) => e,
+            };
+        }
+
+        if !browser.status.is_empty() {
+            ui.label(&browser.status);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("gpu_flow_fields_{name}.ron"))
+    }
+
+    // Globals don't implement PartialEq, the serialized fields are what presets keep
+    fn serialized(globals: &FlowFieldGlobals) -> String {
+        ron::to_string(globals).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let globals = FlowFieldGlobals {
+            num_lines: 123,
+            step_size: 2.5,
+            seed: 42,
+            line_color_start: Vec4::new(0.1, 0.2, 0.3, 0.4),
+            ..default()
+        };
+        let path = temp_path("round_trips");
+        save_preset(&globals, &path).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let loaded = load_preset(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(contents.contains(&format!("version: {PRESET_VERSION}")));
+        assert_eq!(serialized(&loaded.unwrap()), serialized(&globals));
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let path = temp_path("rejects_newer_versions");
+        let version = PRESET_VERSION + 1;
+        std::fs::write(&path, format!("(version: {version}, globals: ())")).unwrap();
+        let error = load_preset(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(error.contains("newer version"), "{error}");
+    }
+
+    #[test]
+    fn loads_plain_globals() {
+        let path = temp_path("loads_plain_globals");
+        std::fs::write(&path, "(num_lines: 7, step_size: 3.0)").unwrap();
+        let loaded = load_preset(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let expected = FlowFieldGlobals {
+            num_lines: 7,
+            step_size: 3.0,
+            ..default()
+        };
+        assert_eq!(serialized(&loaded.unwrap()), serialized(&expected));
+    }
+
+    #[test]
+    fn applying_keeps_runtime_state() {
+        let mut globals = FlowFieldGlobals {
+            paused: 1,
+            viewport_width: 640.0,
+            viewport_height: 360.0,
+            ..default()
+        };
+        let preset = FlowFieldGlobals {
+            num_lines: 9,
+            viewport_width: 1.0,
+            viewport_height: 1.0,
+            ..default()
+        };
+        apply_preset(&mut globals, preset);
+
+        assert_eq!(globals.num_lines, 9);
+        assert_eq!(globals.should_reset, 1);
+        assert_eq!(globals.paused, 1);
+        assert_eq!(
+            (globals.viewport_width, globals.viewport_height),
+            (640.0, 360.0)
+        );
+    }
+}
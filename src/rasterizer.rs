@@ -0,0 +1,226 @@
+// Software rasterizer for the line mesh produced by the CPU tracer.
+//
+// Mimics `FlowFieldRenderNode`: triangles are alpha blended into a multisampled target (with the
+// same blend state as the render pipeline) which is then resolved by averaging the samples.
+
+use bevy::prelude::*;
+
+use crate::{
+    cpu_tracer::{skip_stopped_line_segments, write_line_indices, LineLayout, LineVertex},
+    FlowFieldGlobals,
+};
+
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    // Number of pixels per world unit
+    pub scale: f32,
+    // Each pixel holds samples_per_axis * samples_per_axis samples
+    pub samples_per_axis: u32,
+    // Linear RGBA, row major, y pointing down
+    samples: Vec<Vec4>,
+}
+
+impl Canvas {
+    pub fn new(
+        width: u32,
+        height: u32,
+        scale: f32,
+        samples_per_axis: u32,
+        background_color: Vec4,
+    ) -> Self {
+        let num_samples =
+            (width * samples_per_axis) as usize * (height * samples_per_axis) as usize;
+        Self {
+            width,
+            height,
+            scale,
+            samples_per_axis,
+            samples: vec![background_color; num_samples],
+        }
+    }
+
+    // Draws one line in the layout written by `trace_line`, in the same order as the index buffer.
+    pub fn draw_line(&mut self, globals: &FlowFieldGlobals, vertices: &[LineVertex]) {
+        let layout = LineLayout::of_vertices(globals, vertices.len());
+        let mut indices = vec![0; layout.num_indices() as usize];
+        write_line_indices(layout, 0, &mut indices);
+        skip_stopped_line_segments(layout, 0, &mut indices, vertices);
+        for triangle in indices.chunks_exact(3) {
+            self.draw_triangle(
+                &vertices[triangle[0] as usize],
+                &vertices[triangle[1] as usize],
+                &vertices[triangle[2] as usize],
+            );
+        }
+    }
+
+    pub fn draw_triangle(&mut self, v0: &LineVertex, v1: &LineVertex, v2: &LineVertex) {
+        let mut p0 = self.world_to_sample(v0.position.truncate().truncate());
+        let mut p1 = self.world_to_sample(v1.position.truncate().truncate());
+        let p2 = self.world_to_sample(v2.position.truncate().truncate());
+        let (mut c0, mut c1, c2) = (v0.color, v1.color, v2.color);
+
+        let mut area = edge(p0, p1, p2);
+        if area == 0.0 {
+            return;
+        }
+        // Front faces aren't culled, so make the winding consistent instead.
+        if area < 0.0 {
+            std::mem::swap(&mut p0, &mut p1);
+            std::mem::swap(&mut c0, &mut c1);
+            area = -area;
+        }
+
+        let samples_width = self.width * self.samples_per_axis;
+        let samples_height = self.height * self.samples_per_axis;
+        let min = p0.min(p1).min(p2).floor().max(Vec2::ZERO);
+        let max = p0
+            .max(p1)
+            .max(p2)
+            .ceil()
+            .min(Vec2::new(samples_width as f32, samples_height as f32));
+        if min.x >= max.x || min.y >= max.y {
+            return;
+        }
+
+        for y in min.y as u32..max.y as u32 {
+            for x in min.x as u32..max.x as u32 {
+                let p = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+                let w0 = edge(p1, p2, p);
+                let w1 = edge(p2, p0, p);
+                let w2 = edge(p0, p1, p);
+                if !(covers(w0, p1, p2) && covers(w1, p2, p0) && covers(w2, p0, p1)) {
+                    continue;
+                }
+
+                let src = (c0 * w0 + c1 * w1 + c2 * w2) / area;
+                let dst = &mut self.samples[(y * samples_width + x) as usize];
+                *dst = blend(src, *dst);
+            }
+        }
+    }
+
+    // Averages the samples of each pixel and encodes them as 8 bit sRGB, like the resolve into the
+    // `Rgba8UnormSrgb` view target.
+    pub fn resolve(&self) -> Vec<u8> {
+        let s = self.samples_per_axis as usize;
+        let samples_width = self.width as usize * s;
+        let mut pixels = Vec::with_capacity(self.width as usize * self.height as usize * 4);
+
+        for y in 0..self.height as usize {
+            for x in 0..self.width as usize {
+                let mut sum = Vec4::ZERO;
+                for sy in 0..s {
+                    let row = (y * s + sy) * samples_width;
+                    for sx in 0..s {
+                        sum += self.samples[row + x * s + sx];
+                    }
+                }
+                let c = sum / (s * s) as f32;
+                pixels.extend_from_slice(&[
+                    linear_to_srgb_u8(c.x),
+                    linear_to_srgb_u8(c.y),
+                    linear_to_srgb_u8(c.z),
+                    (c.w.clamp(0.0, 1.0) * 255.0).round() as u8,
+                ]);
+            }
+        }
+
+        pixels
+    }
+
+    // World space has its origin in the middle of the canvas and y pointing up, like the default
+    // 2d camera.
+    fn world_to_sample(&self, p: Vec2) -> Vec2 {
+        let s = self.samples_per_axis as f32;
+        Vec2::new(
+            (p.x * self.scale + self.width as f32 / 2.0) * s,
+            (self.height as f32 / 2.0 - p.y * self.scale) * s,
+        )
+    }
+}
+
+fn edge(a: Vec2, b: Vec2, p: Vec2) -> f32 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
+
+// Top-left fill rule, so samples on an edge shared by two triangles are only blended once.
+fn covers(w: f32, a: Vec2, b: Vec2) -> bool {
+    if w != 0.0 {
+        return w > 0.0;
+    }
+    let d = b - a;
+    (d.y == 0.0 && d.x > 0.0) || d.y < 0.0
+}
+
+// BlendState::ALPHA_BLENDING
+fn blend(src: Vec4, dst: Vec4) -> Vec4 {
+    let a = src.w;
+    let rgb = src.truncate() * a + dst.truncate() * (1.0 - a);
+    rgb.extend(a + dst.w * (1.0 - a))
+}
+
+pub fn linear_to_srgb_u8(c: f32) -> u8 {
+    let c = c.clamp(0.0, 1.0);
+    let srgb = if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0).round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Vec4 = Vec4::new(1.0, 0.0, 0.0, 0.5);
+
+    // A horizontal line of width 2 along y = 0, as the two joints `trace_line` would write.
+    fn line(start: f32, end: f32) -> Vec<LineVertex> {
+        [start, end]
+            .iter()
+            .flat_map(|&x| {
+                [1.0, -1.0].map(|y| LineVertex {
+                    position: Vec4::new(x, y, 0.0, 0.0),
+                    color: RED,
+                })
+            })
+            .collect()
+    }
+
+    fn pixel(canvas: &Canvas, x: u32, y: u32) -> [u8; 4] {
+        let i = 4 * (y * canvas.width + x) as usize;
+        canvas.resolve()[i..i + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn covers_and_blends_line() {
+        // World x in [-5, 5] and y in [5, -5], pixel (5, 4) covers x in [0, 1] and y in [1, 0]
+        let mut canvas = Canvas::new(10, 10, 1.0, 2, Vec4::new(0.0, 0.0, 0.0, 1.0));
+        let globals = FlowFieldGlobals::default();
+        canvas.draw_line(&globals, &line(-2.5, 3.0));
+
+        let half_red = linear_to_srgb_u8(0.5);
+        assert_eq!(pixel(&canvas, 5, 4), [half_red, 0, 0, 255]);
+        assert_eq!(pixel(&canvas, 5, 5), [half_red, 0, 0, 255]);
+        // Outside the line
+        assert_eq!(pixel(&canvas, 5, 3), [0, 0, 0, 255]);
+        assert_eq!(pixel(&canvas, 8, 4), [0, 0, 0, 255]);
+        // Half of the samples of the pixel at x in [-3, -2]
+        assert_eq!(pixel(&canvas, 2, 4), [linear_to_srgb_u8(0.25), 0, 0, 255]);
+
+        // Blends over what's already there, the shared diagonal of the two triangles only once
+        canvas.draw_line(&globals, &line(-2.5, 3.0));
+        assert_eq!(pixel(&canvas, 5, 4), [linear_to_srgb_u8(0.75), 0, 0, 255]);
+    }
+
+    #[test]
+    fn blends_onto_transparent_background() {
+        let mut canvas = Canvas::new(4, 4, 1.0, 1, Vec4::ZERO);
+        canvas.draw_line(&FlowFieldGlobals::default(), &line(-2.0, 2.0));
+        assert_eq!(pixel(&canvas, 1, 1), [linear_to_srgb_u8(0.5), 0, 0, 128]);
+        assert_eq!(pixel(&canvas, 1, 3), [0, 0, 0, 0]);
+    }
+}
@@ -1,4 +1,4 @@
-use std::mem::size_of;
+use std::{mem::size_of, sync::atomic::Ordering};
 
 use bevy::{
     ecs::query::QueryItem,
@@ -19,7 +19,7 @@ use wgpu::{
 use crate::{
     compute::{FlowFieldComputeResources, FlowFieldLineMeshBuffers},
     utilities::*,
-    FlowFieldGlobals, WindowSize, FLOW_FIELD_RENDER_SHADER,
+    FlowFieldGlobals, WindowSize, FLOW_FIELD_COMPACT_SHADER, FLOW_FIELD_RENDER_SHADER,
 };
 
 pub struct FlowFieldRenderNode;
@@ -61,6 +61,23 @@ impl ViewNode for FlowFieldRenderNode {
             // read_buffer_f32(&vertex_buffer, render_context.render_device(), &queue);
             // read_buffer_u32(&index_buffer, render_context.render_device(), &queue);
 
+            // Only after the compute node or a new CPU mesh changed the index buffer
+            let compacted = &mesh_buffers.compacted;
+            if !compacted.load(Ordering::Relaxed)
+                && compact_indices(render_context, world, mesh_buffers)
+            {
+                compacted.store(true, Ordering::Relaxed);
+            }
+            let compacted = compacted
+                .load(Ordering::Relaxed)
+                .then(|| {
+                    mesh_buffers
+                        .compacted_index_buffer
+                        .clone()
+                        .zip(mesh_buffers.draw_args_buffer.clone())
+                })
+                .flatten();
+
             let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
                 label: Some("flow_field_render_pass"),
                 color_attachments: &[Some(RenderPassColorAttachment {
@@ -79,19 +96,229 @@ impl ViewNode for FlowFieldRenderNode {
                 depth_stencil_attachment: None,
             });
 
-            let num_indices = 6 * globals.num_lines * (globals.max_iterations - 1);
-
             pass.set_render_pipeline(&pipeline);
             pass.set_bind_group(0, &bind_group, &[view_uniform_offset.offset]);
             pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-            pass.set_index_buffer(index_buffer.slice(..), 0, IndexFormat::Uint32);
-            pass.draw_indexed(0..num_indices, 0, 0..1);
+            // Draws every triangle until the compaction pipelines are compiled
+            if let Some((compacted_index_buffer, draw_args_buffer)) = &compacted {
+                pass.set_index_buffer(compacted_index_buffer.slice(..), 0, IndexFormat::Uint32);
+                pass.draw_indexed_indirect(draw_args_buffer, 0);
+            } else {
+                pass.set_index_buffer(index_buffer.slice(..), 0, IndexFormat::Uint32);
+                pass.draw_indexed(0..mesh_buffers.num_indices, 0, 0..1);
+            }
         }
 
         Ok(())
     }
 }
 
+// Runs flow_field_compact.wgsl on the index buffer, if the pipelines are ready.
+fn compact_indices(
+    render_context: &mut RenderContext,
+    world: &World,
+    mesh_buffers: &FlowFieldLineMeshBuffers,
+) -> bool {
+    let compact_resources = world.resource::<FlowFieldCompactResources>();
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let (
+        Some(count_pipeline),
+        Some(scan_blocks_pipeline),
+        Some(scan_block_totals_pipeline),
+        Some(compact_pipeline),
+        Some(bind_group),
+    ) = (
+        pipeline_cache.get_compute_pipeline(compact_resources.count_pipeline_id),
+        pipeline_cache.get_compute_pipeline(compact_resources.scan_blocks_pipeline_id),
+        pipeline_cache.get_compute_pipeline(compact_resources.scan_block_totals_pipeline_id),
+        pipeline_cache.get_compute_pipeline(compact_resources.compact_pipeline_id),
+        &world.resource::<FlowFieldCompactBindGroup>().0,
+    )
+    else {
+        return false;
+    };
+
+    let num_chunks = num_compact_chunks(mesh_buffers.num_indices);
+    let num_workgroups = num_chunks.div_ceil(COMPACT_WORK_GROUP_SIZE);
+    let mut pass = render_context
+        .command_encoder()
+        .begin_compute_pass(&ComputePassDescriptor {
+            label: Some("flow_field_compact_pass"),
+        });
+    pass.set_bind_group(0, bind_group, &[]);
+    pass.set_pipeline(count_pipeline);
+    pass.dispatch_workgroups(num_workgroups, 1, 1);
+    pass.set_pipeline(scan_blocks_pipeline);
+    pass.dispatch_workgroups(num_compact_blocks(mesh_buffers.num_indices), 1, 1);
+    pass.set_pipeline(scan_block_totals_pipeline);
+    pass.dispatch_workgroups(1, 1, 1);
+    pass.set_pipeline(compact_pipeline);
+    pass.dispatch_workgroups(num_workgroups, 1, 1);
+
+    true
+}
+
+// Must match CHUNK_TRIANGLES in flow_field_compact.wgsl
+const COMPACT_CHUNK_TRIANGLES: u32 = 64;
+// Must match SCAN_BLOCK in flow_field_compact.wgsl
+const COMPACT_SCAN_BLOCK: u32 = 256;
+// Must match the workgroup size of count_chunks and compact_chunks
+const COMPACT_WORK_GROUP_SIZE: u32 = 64;
+
+// The number of chunks flow_field_compact.wgsl splits num_indices indices into.
+pub fn num_compact_chunks(num_indices: u32) -> u32 {
+    (num_indices / 3).div_ceil(COMPACT_CHUNK_TRIANGLES)
+}
+
+// The number of blocks of chunks flow_field_compact.wgsl sums separately.
+pub fn num_compact_blocks(num_indices: u32) -> u32 {
+    num_compact_chunks(num_indices).div_ceil(COMPACT_SCAN_BLOCK)
+}
+
+#[derive(ShaderType, Clone, Copy)]
+struct CompactParams {
+    num_triangles: u32,
+}
+
+#[derive(Resource)]
+pub struct FlowFieldCompactResources {
+    pub count_pipeline_id: CachedComputePipelineId,
+    pub scan_blocks_pipeline_id: CachedComputePipelineId,
+    pub scan_block_totals_pipeline_id: CachedComputePipelineId,
+    pub compact_pipeline_id: CachedComputePipelineId,
+    pub bind_group_layout: BindGroupLayout,
+}
+
+impl FromWorld for FlowFieldCompactResources {
+    fn from_world(world: &mut World) -> Self {
+        let pipeline_cache = world.resource::<PipelineCache>();
+
+        let storage_entry = |binding, read_only| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty: BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout =
+            world
+                .resource::<RenderDevice>()
+                .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    label: None,
+                    entries: &[
+                        // Params
+                        BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: ShaderStages::COMPUTE,
+                            ty: BindingType::Buffer {
+                                ty: BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        // Index buffer
+                        storage_entry(1, true),
+                        // Compacted index buffer
+                        storage_entry(2, false),
+                        // Chunk offsets
+                        storage_entry(3, false),
+                        // Block offsets
+                        storage_entry(4, false),
+                        // Draw arguments
+                        storage_entry(5, false),
+                    ],
+                });
+
+        let queue_pipeline = |entry_point: &'static str| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(Cow::from(format!("flow_field_{entry_point}_pipeline"))),
+                layout: vec![bind_group_layout.clone()],
+                push_constant_ranges: vec![],
+                shader: FLOW_FIELD_COMPACT_SHADER.typed(),
+                shader_defs: vec![],
+                entry_point: Cow::from(entry_point),
+            })
+        };
+
+        Self {
+            count_pipeline_id: queue_pipeline("count_chunks"),
+            scan_blocks_pipeline_id: queue_pipeline("scan_blocks"),
+            scan_block_totals_pipeline_id: queue_pipeline("scan_block_totals"),
+            compact_pipeline_id: queue_pipeline("compact_chunks"),
+            bind_group_layout,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct FlowFieldCompactBindGroup(pub Option<BindGroup>);
+
+pub fn queue_compact_bind_group(
+    mut compact_bind_group: ResMut<FlowFieldCompactBindGroup>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    compact_resources: Res<FlowFieldCompactResources>,
+    mesh_buffers: Res<FlowFieldLineMeshBuffers>,
+) {
+    let (
+        Some(index_buffer),
+        Some(compacted_index_buffer),
+        Some(chunk_offset_buffer),
+        Some(block_offset_buffer),
+        Some(draw_args_buffer),
+    ) = (
+        &mesh_buffers.index_buffer,
+        &mesh_buffers.compacted_index_buffer,
+        &mesh_buffers.chunk_offset_buffer,
+        &mesh_buffers.block_offset_buffer,
+        &mesh_buffers.draw_args_buffer,
+    )
+    else {
+        return;
+    };
+
+    let params = CompactParams {
+        num_triangles: mesh_buffers.num_indices / 3,
+    };
+    let params_buffer = struct_to_buffer(params, &render_device, &render_queue);
+    let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+        label: None,
+        layout: &compact_resources.bind_group_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: params_buffer.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: index_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: compacted_index_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: chunk_offset_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: block_offset_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: draw_args_buffer.as_entire_binding(),
+            },
+        ],
+    });
+
+    *compact_bind_group = FlowFieldCompactBindGroup(Some(bind_group));
+}
+
 impl FromWorld for FlowFieldRenderNode {
     fn from_world(_world: &mut World) -> Self {
         Self
@@ -0,0 +1,406 @@
+// Start positions generated on the CPU and uploaded to the seed buffer that `init` reads.
+//
+// Seeds are in [0, 1] x [0, 1] over the padded viewport the other seeding modes use, so `init`
+// maps them the same way as its random positions. They are generated in pixels first so the
+// distances between them don't depend on the aspect ratio.
+
+use bevy::prelude::*;
+
+use std::path::Path;
+
+use crate::{cpu_tracer::*, images::*, FlowFieldGlobals};
+
+// Candidates around every active point in Bridson's algorithm
+pub const POISSON_DISK_ATTEMPTS: u32 = 30;
+// Bridson's algorithm fills a bit more than one point per this many disk radii squared. Used to
+// pick the radius for `num_lines` points.
+pub const POISSON_DISK_DENSITY: f32 = 0.6;
+// Candidates per point in Mitchell's best-candidate algorithm
+pub const BEST_CANDIDATE_SAMPLES: u32 = 12;
+
+// Seeds for the modes that are generated on the CPU, empty for the others.
+pub fn generate_seeds(globals: &FlowFieldGlobals) -> Vec<Vec2> {
+    let size = Vec2::new(
+        globals.viewport_width + SEED_PADDING,
+        globals.viewport_height + SEED_PADDING,
+    );
+    let mut rng = HashRng::new(globals);
+    let seeds = match SeedingMode::from_u32(globals.seeding_mode) {
+        SeedingMode::PoissonDisk => poisson_disk(size, globals.num_lines, &mut rng),
+        SeedingMode::BlueNoise => best_candidate(size, globals.num_lines, &mut rng),
+        _ => return Vec::new(),
+    };
+    seeds.into_iter().map(|p| p / size).collect()
+}
+
+// The columns and rows of the grid seeding modes, with cells as close to square as possible.
+pub fn grid_size(globals: &FlowFieldGlobals) -> UVec2 {
+    let aspect = (globals.viewport_width + SEED_PADDING) / (globals.viewport_height + SEED_PADDING);
+    let columns = ((globals.num_lines as f32 * aspect).sqrt().ceil() as u32).max(1);
+    let rows = globals.num_lines.div_ceil(columns).max(1);
+    UVec2::new(columns, rows)
+}
+
+// The darkness of an image as a probability density for line start positions. Read by
+// `seed_position` on both sides, which picks a row by its share of the total density and then a
+// pixel within the row.
+pub struct DensityMap {
+    pub width: u32,
+    pub height: u32,
+    // The cumulative distribution over the rows, followed by the cumulative distribution within
+    // every row. Each ends at 1.
+    pub cdf: Vec<f32>,
+}
+
+impl DensityMap {
+    // None if the image has no dark pixels, so there's nothing to sample.
+    pub fn new(image: &LumaImage) -> Option<Self> {
+        let (width, height) = (image.width as usize, image.height as usize);
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut pixel_cdf = Vec::with_capacity(width * height);
+        let mut total = 0.0;
+        for row in image.pixels.chunks_exact(width) {
+            let mut row_total = 0.0;
+            let row_start = pixel_cdf.len();
+            for luma in row {
+                row_total += (1.0 - luma.clamp(0.0, 1.0)) as f64;
+                pixel_cdf.push(row_total);
+            }
+            if row_total > 0.0 {
+                pixel_cdf[row_start..]
+                    .iter_mut()
+                    .for_each(|c| *c /= row_total);
+            }
+            total += row_total;
+            row_cdf.push(total);
+        }
+        if total <= 0.0 {
+            return None;
+        }
+
+        let cdf = row_cdf
+            .iter()
+            .map(|c| c / total)
+            .chain(pixel_cdf)
+            .map(|c| c as f32)
+            .collect();
+        Some(Self {
+            width: image.width,
+            height: image.height,
+            cdf,
+        })
+    }
+
+    // Mirrors `sample_density_map` in the compute shader. Maps two uniform random numbers to a
+    // position in the image, in [0, 1] x [0, 1] with y down.
+    pub fn sample(&self, random: Vec2) -> Vec2 {
+        let row = cdf_index(&self.cdf, 0, self.height, random.y);
+        let row_offset = self.height + row * self.width;
+        let column = cdf_index(&self.cdf, row_offset, self.width, random.x);
+        Vec2::new(
+            cdf_position(&self.cdf, row_offset, self.width, column, random.x),
+            cdf_position(&self.cdf, 0, self.height, row, random.y),
+        )
+    }
+}
+
+pub fn load_density_map(path: &Path) -> Result<DensityMap, String> {
+    DensityMap::new(&load_luma_image(path)?)
+        .ok_or_else(|| format!("{} has no dark pixels to seed lines at", path.display()))
+}
+
+// The first of the `n` entries of `cdf` starting at `offset` that is larger than `u`.
+fn cdf_index(cdf: &[f32], offset: u32, n: u32, u: f32) -> u32 {
+    let (mut low, mut high) = (0, n);
+    while low < high {
+        let middle = (low + high) / 2;
+        if cdf[(offset + middle) as usize] <= u {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    low.min(n - 1)
+}
+
+// Where `u` falls within entry `i`, in [0, 1] over all `n` entries. Keeps the sample continuous
+// instead of snapping it to the pixel.
+fn cdf_position(cdf: &[f32], offset: u32, n: u32, i: u32, u: f32) -> f32 {
+    let low = if i > 0 {
+        cdf[(offset + i - 1) as usize]
+    } else {
+        0.0
+    };
+    let high = cdf[(offset + i) as usize];
+    let t = if high > low {
+        ((u - low) / (high - low)).clamp(0.0, 1.0)
+    } else {
+        0.5
+    };
+    (i as f32 + t) / n as f32
+}
+
+// The same hashes as the shader, drawn in sequence.
+pub struct HashRng<'a> {
+    globals: &'a FlowFieldGlobals,
+    counter: u32,
+}
+
+impl<'a> HashRng<'a> {
+    pub fn new(globals: &'a FlowFieldGlobals) -> Self {
+        Self {
+            globals,
+            counter: 0,
+        }
+    }
+
+    fn next_f32(&mut self) -> f32 {
+        self.counter = self.counter.wrapping_add(1);
+        random_f32(seeded(self.globals, self.counter))
+    }
+
+    fn next_vec2(&mut self) -> Vec2 {
+        Vec2::new(self.next_f32(), self.next_f32())
+    }
+}
+
+// Points bucketed into square cells for nearest neighbour queries.
+struct PointGrid {
+    cell_size: f32,
+    width: i32,
+    height: i32,
+    cells: Vec<Vec<Vec2>>,
+}
+
+impl PointGrid {
+    fn new(size: Vec2, cell_size: f32) -> Self {
+        let width = (size.x / cell_size).ceil().max(1.0) as i32;
+        let height = (size.y / cell_size).ceil().max(1.0) as i32;
+        Self {
+            cell_size,
+            width,
+            height,
+            cells: vec![Vec::new(); (width * height) as usize],
+        }
+    }
+
+    fn cell(&self, p: Vec2) -> IVec2 {
+        (p / self.cell_size)
+            .floor()
+            .as_ivec2()
+            .clamp(IVec2::ZERO, IVec2::new(self.width - 1, self.height - 1))
+    }
+
+    fn insert(&mut self, p: Vec2) {
+        let c = self.cell(p);
+        self.cells[(c.y * self.width + c.x) as usize].push(p);
+    }
+
+    fn ring(&self, center: IVec2, ring: i32) -> impl Iterator<Item = &Vec2> + '_ {
+        (center.y - ring..=center.y + ring)
+            .flat_map(move |y| (center.x - ring..=center.x + ring).map(move |x| IVec2::new(x, y)))
+            .filter(move |c| (c.x - center.x).abs() == ring || (c.y - center.y).abs() == ring)
+            .filter(|c| c.x >= 0 && c.y >= 0 && c.x < self.width && c.y < self.height)
+            .flat_map(|c| &self.cells[(c.y * self.width + c.x) as usize])
+    }
+
+    // Distance from `p` to the closest point, searching rings of cells outwards until no closer
+    // point can be left.
+    fn nearest_distance(&self, p: Vec2) -> f32 {
+        let center = self.cell(p);
+        let mut nearest = f32::MAX;
+        for ring in 0..self.width.max(self.height) {
+            if nearest <= (ring - 1).max(0) as f32 * self.cell_size {
+                break;
+            }
+            for q in self.ring(center, ring) {
+                nearest = nearest.min(q.distance(p));
+            }
+        }
+        nearest
+    }
+}
+
+// Bridson, "Fast Poisson Disk Sampling in Arbitrary Dimensions" (2007), with the radius picked so
+// the rectangle fills with about `num_points` points. Extra points are dropped at random, which
+// keeps the spacing even. If there are too few the remaining lines start at random positions.
+pub fn poisson_disk(size: Vec2, num_points: u32, rng: &mut HashRng) -> Vec<Vec2> {
+    if num_points == 0 {
+        return Vec::new();
+    }
+    let radius = (POISSON_DISK_DENSITY * size.x * size.y / num_points as f32).sqrt();
+    // A cell of radius / sqrt(2) holds at most one point, so the two rings around a cell cover
+    // everything closer than the radius.
+    let mut grid = PointGrid::new(size, radius / std::f32::consts::SQRT_2);
+
+    let mut points = vec![rng.next_vec2() * size];
+    grid.insert(points[0]);
+    let mut active = vec![0];
+    while !active.is_empty() {
+        let active_index = ((rng.next_f32() * active.len() as f32) as usize).min(active.len() - 1);
+        let center = points[active[active_index]];
+
+        let mut found = false;
+        for _ in 0..POISSON_DISK_ATTEMPTS {
+            let angle = rng.next_f32() * std::f32::consts::TAU;
+            let distance = radius * (1.0 + rng.next_f32());
+            let candidate = center + Vec2::from_angle(angle) * distance;
+            if candidate.cmplt(Vec2::ZERO).any() || candidate.cmpge(size).any() {
+                continue;
+            }
+            let c = grid.cell(candidate);
+            if (0..=2)
+                .flat_map(|ring| grid.ring(c, ring))
+                .all(|q| q.distance(candidate) >= radius)
+            {
+                grid.insert(candidate);
+                active.push(points.len());
+                points.push(candidate);
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            active.swap_remove(active_index);
+        }
+    }
+
+    // Partial Fisher-Yates shuffle, keeping the first `num_points`.
+    let num_kept = points.len().min(num_points as usize);
+    for i in 0..num_kept {
+        let j =
+            i + ((rng.next_f32() * (points.len() - i) as f32) as usize).min(points.len() - i - 1);
+        points.swap(i, j);
+    }
+    points.truncate(num_kept);
+    points
+}
+
+// Mitchell's best-candidate algorithm: every point is the candidate farthest from the points so
+// far. Gives exactly `num_points` points with a blue noise spectrum.
+pub fn best_candidate(size: Vec2, num_points: u32, rng: &mut HashRng) -> Vec<Vec2> {
+    let cell_size = (size.x * size.y / num_points.max(1) as f32).sqrt();
+    let mut grid = PointGrid::new(size, cell_size);
+
+    let mut points = Vec::with_capacity(num_points as usize);
+    for _ in 0..num_points {
+        let mut best = Vec2::ZERO;
+        let mut best_distance = -1.0;
+        for _ in 0..BEST_CANDIDATE_SAMPLES {
+            let candidate = rng.next_vec2() * size;
+            let distance = grid.nearest_distance(candidate);
+            if distance > best_distance {
+                best = candidate;
+                best_distance = distance;
+            }
+        }
+        grid.insert(best);
+        points.push(best);
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn min_distance(points: &[Vec2]) -> f32 {
+        let mut min = f32::MAX;
+        for (i, p) in points.iter().enumerate() {
+            for q in &points[i + 1..] {
+                min = min.min(p.distance(*q));
+            }
+        }
+        min
+    }
+
+    #[test]
+    fn poisson_disk_keeps_radius() {
+        let globals = FlowFieldGlobals::default();
+        let size = Vec2::new(300.0, 200.0);
+        let points = poisson_disk(size, 500, &mut HashRng::new(&globals));
+        // Density is only approximate, but the count shouldn't be far off.
+        assert!(points.len() > 400, "{} points", points.len());
+        let radius = (POISSON_DISK_DENSITY * size.x * size.y / 500.0).sqrt();
+        assert!(min_distance(&points) >= radius * 0.999);
+        assert!(points
+            .iter()
+            .all(|p| p.cmpge(Vec2::ZERO).all() && p.cmplt(size).all()));
+    }
+
+    #[test]
+    fn best_candidate_is_spread_out() {
+        let globals = FlowFieldGlobals::default();
+        let size = Vec2::new(300.0, 200.0);
+        let blue = best_candidate(size, 500, &mut HashRng::new(&globals));
+        assert_eq!(blue.len(), 500);
+
+        let mut rng = HashRng::new(&globals);
+        let white: Vec<Vec2> = (0..500).map(|_| rng.next_vec2() * size).collect();
+        assert!(min_distance(&blue) > 4.0 * min_distance(&white));
+    }
+
+    #[test]
+    fn nearest_distance_matches_brute_force() {
+        let globals = FlowFieldGlobals::default();
+        let size = Vec2::new(100.0, 50.0);
+        let mut rng = HashRng::new(&globals);
+        let mut grid = PointGrid::new(size, 7.0);
+        let points: Vec<Vec2> = (0..40).map(|_| rng.next_vec2() * size).collect();
+        for p in &points {
+            grid.insert(*p);
+        }
+        for _ in 0..100 {
+            let p = rng.next_vec2() * size;
+            let expected = points
+                .iter()
+                .map(|q| q.distance(p))
+                .fold(f32::MAX, f32::min);
+            assert_eq!(grid.nearest_distance(p), expected);
+        }
+    }
+
+    #[test]
+    fn density_map_samples_dark_pixels() {
+        // A dark pixel, a white one and a grey one with half the density of the dark one
+        let image = LumaImage {
+            width: 3,
+            height: 2,
+            pixels: vec![0.0, 1.0, 0.5, 1.0, 1.0, 1.0],
+        };
+        let map = DensityMap::new(&image).unwrap();
+        let globals = FlowFieldGlobals::default();
+        let mut rng = HashRng::new(&globals);
+        let mut counts = [0; 3];
+        for _ in 0..3000 {
+            let p = map.sample(rng.next_vec2());
+            assert!(p.y >= 0.0 && p.y <= 0.5, "{p}");
+            counts[((p.x * 3.0) as usize).min(2)] += 1;
+        }
+        assert_eq!(counts[1], 0);
+        assert!(
+            (counts[0] as f32 / counts[2] as f32 - 2.0).abs() < 0.2,
+            "{counts:?}"
+        );
+
+        let white = LumaImage {
+            width: 1,
+            height: 1,
+            pixels: vec![1.0],
+        };
+        assert!(DensityMap::new(&white).is_none());
+    }
+
+    #[test]
+    fn grid_size_fits_all_lines() {
+        for num_lines in [1, 7, 100, 40000] {
+            let globals = FlowFieldGlobals {
+                num_lines,
+                ..default()
+            };
+            let grid = grid_size(&globals);
+            assert!(grid.x * grid.y >= num_lines);
+            assert!(grid.x * (grid.y - 1) < num_lines);
+        }
+    }
+}
@@ -0,0 +1,472 @@
+// SVG export of the traced lines.
+//
+// Lines of a single colour become a single `<path>` through the centres of their joints. Lines whose
+// colour changes along them are split into pieces from the middle of one segment to the middle of
+// the next, each with a gradient between the colours at its ends and joint, so the colours follow
+// the path like on the canvas. The pieces meet with butt ends and their caps are filled shapes.
+// Lines are stroked at `line_width`, widths varying along them (see cpu_tracer::WidthMode) are not
+// exported. Joins are left to the SVG renderer, which cuts miters past the limit off with a bevel
+// like the canvas does. Lines without joins are exported with bevels, the closest SVG has.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use bevy::prelude::*;
+
+use crate::{cpu_tracer::*, rasterizer::linear_to_srgb_u8, FlowFieldGlobals, FlowFieldInputs};
+
+pub fn export_svg(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    path: &Path,
+) -> Result<(), String> {
+    let file =
+        File::create(path).map_err(|e| format!("failed to create {}: {e}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    write_svg(globals, inputs, &mut out)
+        .and_then(|_| out.flush())
+        .map_err(|e| format!("failed to write {}: {e}", path.display()))
+}
+
+// Traces all lines on the CPU and writes them as an SVG document the size of the viewport.
+pub fn write_svg(
+    globals: &FlowFieldGlobals,
+    inputs: &FlowFieldInputs,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let width = globals.viewport_width;
+    let height = globals.viewport_height;
+
+    writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    )?;
+    writeln!(out, "<desc>flow field, seed {}</desc>", globals.seed)?;
+    writeln!(
+        out,
+        r#"<rect width="100%" height="100%" fill="{}" fill-opacity="{}"/>"#,
+        svg_color(globals.background_color),
+        globals.background_color.w
+    )?;
+    let line_join = match LineJoin::from_u32(globals.line_join) {
+        LineJoin::None | LineJoin::Bevel => "bevel",
+        LineJoin::Miter => "miter",
+        LineJoin::Round => "round",
+    };
+    let line_cap = match LineCap::from_u32(globals.line_cap) {
+        LineCap::Butt => "butt",
+        LineCap::Square => "square",
+        LineCap::Round => "round",
+    };
+    writeln!(
+        out,
+        r#"<g fill="none" stroke-width="{}" stroke-linejoin="{line_join}" stroke-linecap="{line_cap}" stroke-miterlimit="{}">"#,
+        globals.line_width,
+        globals.miter_limit.max(1.0)
+    )?;
+
+    let mut result = Ok(());
+    for_each_line(globals, inputs, |line_index, vertices| {
+        if result.is_ok() {
+            let layout = LineLayout::of_vertices(globals, vertices.len());
+            result = write_line(globals, line_index, layout.joint_vertices(vertices), out);
+        }
+    });
+    result?;
+
+    writeln!(out, "</g>")?;
+    writeln!(out, "</svg>")
+}
+
+fn write_line(
+    globals: &FlowFieldGlobals,
+    line_index: u32,
+    vertices: &[LineVertex],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let joints: Vec<Vec2> = joint_positions(vertices)
+        .map(|p| to_svg_space(globals, p))
+        .collect();
+    let color = |joint: usize| vertices[2 * joint].color;
+    // One subpath or run of pieces per part of the line that isn't masked
+    let parts = drawn_joints(vertices);
+    let Some(&first) = parts.first().and_then(|part| part.first()) else {
+        return Ok(());
+    };
+
+    if parts
+        .iter()
+        .flatten()
+        .all(|&joint| color(joint) == color(first))
+    {
+        write!(out, r#"<path {} d=""#, solid_stroke(color(first)))?;
+        for (i, part) in parts.iter().enumerate() {
+            let separator = if i == 0 { "" } else { " " };
+            let p = joints[part[0]];
+            write!(out, "{separator}M{:.2} {:.2}", p.x, p.y)?;
+            for &joint in &part[1..] {
+                write!(out, " L{:.2} {:.2}", joints[joint].x, joints[joint].y)?;
+            }
+        }
+        return writeln!(out, r#""/>"#);
+    }
+
+    for (part_index, part) in parts.iter().enumerate() {
+        let points: Vec<Vec2> = part.iter().map(|&joint| joints[joint]).collect();
+        let colors: Vec<Vec4> = part.iter().map(|&joint| color(joint)).collect();
+        let n = points.len();
+        // Segments are interpolated linearly, so halfway along them is the mean colour
+        let middle = |k: usize| {
+            (
+                (points[k] + points[k + 1]) / 2.0,
+                (colors[k] + colors[k + 1]) / 2.0,
+            )
+        };
+        for k in 0..n {
+            let mut piece = Vec::with_capacity(3);
+            if k > 0 {
+                piece.push(middle(k - 1));
+            }
+            piece.push((points[k], colors[k]));
+            if k < n - 1 {
+                piece.push(middle(k));
+            }
+            let id = format!("l{line_index}_{part_index}_{k}");
+            write_piece(&id, &piece, out)?;
+        }
+
+        if LineCap::from_u32(globals.line_cap) != LineCap::Butt {
+            let half_width = globals.line_width / 2.0;
+            write_cap(globals, points[0], points[1], half_width, colors[0], out)?;
+            write_cap(
+                globals,
+                points[n - 1],
+                points[n - 2],
+                half_width,
+                colors[n - 1],
+                out,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// A piece of a line through the given points, with a gradient from the colour of the first to the
+// last that passes through the colour of the one in between.
+fn write_piece(id: &str, piece: &[(Vec2, Vec4)], out: &mut impl Write) -> io::Result<()> {
+    let (start, start_color) = piece[0];
+    let end = piece[piece.len() - 1].0;
+    let along = end - start;
+
+    let stroke = if piece.iter().all(|(_, color)| *color == start_color) {
+        solid_stroke(start_color)
+    } else if along.length_squared() == 0.0 {
+        // Lines that turn right around, where there is nothing to spread the gradient over
+        solid_stroke(piece[piece.len() / 2].1)
+    } else {
+        write!(
+            out,
+            r#"<linearGradient id="{id}" gradientUnits="userSpaceOnUse" x1="{:.2}" y1="{:.2}" x2="{:.2}" y2="{:.2}">"#,
+            start.x, start.y, end.x, end.y
+        )?;
+        for &(p, color) in piece {
+            let offset = ((p - start).dot(along) / along.length_squared()).clamp(0.0, 1.0);
+            write!(
+                out,
+                r#"<stop offset="{offset:.3}" stop-color="{}" stop-opacity="{}"/>"#,
+                svg_color(color),
+                color.w
+            )?;
+        }
+        writeln!(out, "</linearGradient>")?;
+        format!(r#"stroke="url(#{id})""#)
+    };
+
+    write!(
+        out,
+        r#"<path {stroke} stroke-linecap="butt" d="M{:.2} {:.2}"#,
+        start.x, start.y
+    )?;
+    for (p, _) in &piece[1..] {
+        write!(out, " L{:.2} {:.2}", p.x, p.y)?;
+    }
+    writeln!(out, r#""/>"#)
+}
+
+// The square or round cap of a line that ends at `end`, pointing away from `neighbour`.
+fn write_cap(
+    globals: &FlowFieldGlobals,
+    end: Vec2,
+    neighbour: Vec2,
+    half_width: f32,
+    color: Vec4,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let outwards = (end - neighbour).normalize_or_zero() * half_width;
+    if outwards == Vec2::ZERO {
+        return Ok(());
+    }
+    let normal = outwards.perp();
+    let (a, b) = (end + normal, end - normal);
+    write!(
+        out,
+        r#"<path fill="{}" fill-opacity="{}" stroke="none" d="M{:.2} {:.2} "#,
+        svg_color(color),
+        color.w,
+        a.x,
+        a.y
+    )?;
+    match LineCap::from_u32(globals.line_cap) {
+        LineCap::Round => {
+            // Half a circle from a to b around the outside
+            let sweep = u32::from(normal.perp_dot(outwards) > 0.0);
+            write!(
+                out,
+                "A{half_width:.2} {half_width:.2} 0 0 {sweep} {:.2} {:.2}",
+                b.x, b.y
+            )?;
+        }
+        _ => {
+            let (c, d) = (a + outwards, b + outwards);
+            write!(
+                out,
+                "L{:.2} {:.2} L{:.2} {:.2} L{:.2} {:.2}",
+                c.x, c.y, d.x, d.y, b.x, b.y
+            )?;
+        }
+    }
+    writeln!(out, r#" Z"/>"#)
+}
+
+fn solid_stroke(color: Vec4) -> String {
+    format!(
+        r#"stroke="{}" stroke-opacity="{}""#,
+        svg_color(color),
+        color.w
+    )
+}
+
+// World space has its origin in the middle of the viewport and y pointing up.
+fn to_svg_space(globals: &FlowFieldGlobals, p: Vec2) -> Vec2 {
+    Vec2::new(
+        p.x + globals.viewport_width / 2.0,
+        globals.viewport_height / 2.0 - p.y,
+    )
+}
+
+// Line colours are linear, SVG colours are sRGB.
+fn svg_color(color: Vec4) -> String {
+    format!(
+        "#{:02x}{:02x}{:02x}",
+        linear_to_srgb_u8(color.x),
+        linear_to_srgb_u8(color.y),
+        linear_to_srgb_u8(color.z)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joint(p: Vec2, color: Vec4) -> [LineVertex; 2] {
+        [1.0, -1.0].map(|y| LineVertex {
+            position: (p + Vec2::new(0.0, y)).extend(0.0).extend(0.0),
+            color,
+        })
+    }
+
+    fn svg_of_line(globals: &FlowFieldGlobals, joints: &[[LineVertex; 2]]) -> String {
+        let mut out = Vec::new();
+        write_line(globals, 0, joints.concat().as_slice(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // The elements with the given tag, e.g. "<path ", without any parsing of their contents.
+    fn elements<'a>(svg: &'a str, tag: &str) -> Vec<&'a str> {
+        svg.match_indices(tag)
+            .map(|(i, _)| &svg[i..i + svg[i..].find('>').unwrap()])
+            .collect()
+    }
+
+    fn attribute<'a>(element: &'a str, name: &str) -> &'a str {
+        let start = element.find(&format!(" {name}=\"")).unwrap() + name.len() + 3;
+        &element[start..start + element[start..].find('"').unwrap()]
+    }
+
+    fn numbers(text: &str) -> Vec<f32> {
+        text.split(|c: char| c != '.' && c != '-' && !c.is_ascii_digit())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().unwrap())
+            .collect()
+    }
+
+    const RED: Vec4 = Vec4::new(1.0, 0.0, 0.0, 1.0);
+    const BLUE: Vec4 = Vec4::new(0.0, 0.0, 1.0, 0.5);
+
+    #[test]
+    fn single_colour_line_is_one_path() {
+        let globals = FlowFieldGlobals {
+            viewport_width: 100.0,
+            viewport_height: 100.0,
+            ..default()
+        };
+        let svg = svg_of_line(
+            &globals,
+            &[
+                joint(Vec2::new(0.0, 0.0), RED),
+                joint(Vec2::new(10.0, 0.0), RED),
+                joint(Vec2::new(10.0, 10.0), RED),
+            ],
+        );
+        let paths = elements(&svg, "<path ");
+        assert_eq!(paths.len(), 1);
+        assert_eq!(attribute(paths[0], "stroke"), "#ff0000");
+        // In SVG space, y pointing down from the top left corner
+        assert_eq!(
+            numbers(attribute(paths[0], "d")),
+            [50.0, 50.0, 60.0, 50.0, 60.0, 40.0]
+        );
+        assert!(!svg.contains("linearGradient"));
+    }
+
+    #[test]
+    fn gradients_follow_looping_line() {
+        let globals = FlowFieldGlobals {
+            viewport_width: 100.0,
+            viewport_height: 100.0,
+            line_cap: LineCap::Round as u32,
+            ..default()
+        };
+        let corners = [
+            (0.0, 0.0),
+            (20.0, 0.0),
+            (20.0, 20.0),
+            (0.0, 20.0),
+            (0.0, 0.0),
+        ];
+        let mut joints: Vec<_> = corners
+            .iter()
+            .enumerate()
+            .map(|(k, &(x, y))| joint(Vec2::new(x, y), RED.lerp(BLUE, k as f32 / 4.0)))
+            .collect();
+        // The line stopped after coming back to its start
+        let mut stopped = joints[4];
+        stopped[0].position.w = LINE_STOPPED;
+        stopped[0].color = Vec4::ONE;
+        joints.push(stopped);
+
+        let svg = svg_of_line(&globals, &joints);
+        let gradients = elements(&svg, "<linearGradient ");
+        let pieces: Vec<Vec<f32>> = elements(&svg, "<path ")
+            .iter()
+            .filter(|path| attribute(path, "stroke").starts_with("url"))
+            .map(|path| numbers(attribute(path, "d")))
+            .collect();
+        assert_eq!(gradients.len(), 5);
+        assert_eq!(pieces.len(), 5);
+        for (gradient, piece) in gradients.iter().zip(&pieces) {
+            // From one end of the piece to the other, never collapsed to a point
+            let ends = numbers(&format!(
+                "{} {} {} {}",
+                attribute(gradient, "x1"),
+                attribute(gradient, "y1"),
+                attribute(gradient, "x2"),
+                attribute(gradient, "y2")
+            ));
+            assert_eq!(ends[..2], piece[..2]);
+            assert_eq!(ends[2..], piece[piece.len() - 2..]);
+            assert_ne!(ends[..2], ends[2..]);
+        }
+        // The pieces meet halfway along the segments
+        for k in 1..pieces.len() {
+            assert_eq!(pieces[k - 1][pieces[k - 1].len() - 2..], pieces[k][..2]);
+        }
+        assert_eq!(pieces[1][..2], [60.0, 50.0]);
+
+        // The colours at the ends are those of the first and last joint that were drawn
+        let stops = elements(&svg, "<stop ");
+        assert_eq!(attribute(stops[0], "stop-color"), "#ff0000");
+        assert_eq!(attribute(stops[stops.len() - 1], "stop-color"), "#0000ff");
+        assert_eq!(attribute(stops[stops.len() - 1], "stop-opacity"), "0.5");
+
+        // Half circles at both ends
+        let caps: Vec<&str> = elements(&svg, "<path ")
+            .into_iter()
+            .filter(|path| attribute(path, "stroke") == "none")
+            .collect();
+        assert_eq!(caps.len(), 2);
+        assert!(caps.iter().all(|cap| attribute(cap, "d").contains('A')));
+    }
+
+    #[test]
+    fn multi_stop_gradients_keep_joint_colours() {
+        let globals = FlowFieldGlobals {
+            viewport_width: 200.0,
+            viewport_height: 100.0,
+            num_lines: 6,
+            max_iterations: 30,
+            step_size: 3.0,
+            color_mode: ColorMode::FieldAngle as u32,
+            ..default()
+        };
+        let green = Vec4::new(0.0, 1.0, 0.0, 1.0);
+        let inputs = FlowFieldInputs {
+            gradient: Some(std::sync::Arc::new(
+                crate::gradients::Gradient::from_colors(
+                    &[RED, green, BLUE],
+                    crate::gradients::Interpolation::Oklab,
+                ),
+            )),
+            ..default()
+        };
+        let mut out = Vec::new();
+        write_svg(&globals, &inputs, &mut out).unwrap();
+        let svg = String::from_utf8(out).unwrap();
+
+        // The colour each piece has at its joint, which is its first stop at the start of a part
+        // and the one in between otherwise
+        let stops_by_id: std::collections::HashMap<&str, Vec<&str>> =
+            elements(&svg, "<linearGradient ")
+                .into_iter()
+                .zip(svg.split("</linearGradient>"))
+                .map(|(gradient, contents)| {
+                    let stops = elements(contents, "<stop ");
+                    let colors = stops.iter().map(|stop| attribute(stop, "stop-color"));
+                    (attribute(gradient, "id"), colors.collect())
+                })
+                .collect();
+        let mut pieces = elements(&svg, "<path ")
+            .into_iter()
+            .filter(|path| attribute(path, "stroke") != "none");
+
+        let mut num_joints = 0;
+        for_each_line(&globals, &inputs, |_, vertices| {
+            let vertices =
+                LineLayout::of_vertices(&globals, vertices.len()).joint_vertices(vertices);
+            for part in drawn_joints(vertices) {
+                for (k, &joint) in part.iter().enumerate() {
+                    let piece = pieces.next().unwrap();
+                    let stroke = attribute(piece, "stroke");
+                    let color = match stroke.strip_prefix("url(#") {
+                        Some(id) => stops_by_id[id.trim_end_matches(')')][usize::from(k > 0)],
+                        None => stroke,
+                    };
+                    assert_eq!(color, svg_color(vertices[2 * joint].color));
+                    num_joints += 1;
+                }
+            }
+        });
+        assert!(num_joints > 20);
+        assert!(pieces.next().is_none());
+        // The ramp is sampled in between its ends as well, where green is the strongest channel
+        assert!(elements(&svg, "<stop ").iter().any(|stop| {
+            let channel = |i: usize| {
+                u8::from_str_radix(&attribute(stop, "stop-color")[i..i + 2], 16).unwrap()
+            };
+            channel(3) > channel(1).max(channel(5))
+        }));
+    }
+}
@@ -0,0 +1,292 @@
+// Vector data on a regular latitude/longitude grid, e.g. wind or ocean currents, for
+// cpu_tracer::FieldMode::VectorGrid.
+//
+// Two formats are read:
+//
+// - CSV with a header naming the `lat`, `lon`, `u` and `v` columns (in any order, other columns
+//   are ignored) and one row per grid point. Grid points without a row are treated as having no
+//   flow, like land in ocean current data.
+// - Raw little-endian binary: the magic `UVGR`, the u32 width and height of the grid, the f32
+//   bounds `lon_min lat_min lon_max lat_max`, and then `width * height` f32 (u, v) pairs row by
+//   row, starting with the southernmost row.
+//
+// NaN vectors are read as no flow. The grid is drawn like an equirectangular map, scaled to fit
+// inside the viewport.
+
+use std::path::Path;
+
+use bevy::prelude::*;
+
+use crate::images::world_to_image;
+
+pub const RAW_MAGIC: &[u8; 4] = b"UVGR";
+
+pub struct VectorGrid {
+    pub width: u32,
+    pub height: u32,
+    // Longitude and latitude of the first and last grid point
+    pub min: Vec2,
+    pub max: Vec2,
+    // Length of the longest vector, for colouring by magnitude
+    pub max_magnitude: f32,
+    // (u, v) row by row from the south
+    pub vectors: Vec<Vec2>,
+}
+
+impl VectorGrid {
+    pub fn new(width: u32, height: u32, min: Vec2, max: Vec2, mut vectors: Vec<Vec2>) -> Self {
+        for v in &mut vectors {
+            if !v.is_finite() {
+                *v = Vec2::ZERO;
+            }
+        }
+        let max_magnitude = vectors.iter().map(|v| v.length()).fold(0.0, f32::max);
+        Self {
+            width,
+            height,
+            min,
+            max,
+            max_magnitude,
+            vectors,
+        }
+    }
+
+    // Mirrors `vector_grid_point` in the compute shader, clamped to the edge.
+    pub fn point(&self, x: i32, y: i32) -> Vec2 {
+        let x = x.clamp(0, self.width as i32 - 1);
+        let y = y.clamp(0, self.height as i32 - 1);
+        self.vectors[(y * self.width as i32 + x) as usize]
+    }
+
+    // Mirrors `sample_vector_grid`. Bilinear interpolation at world position `p`.
+    pub fn sample(&self, viewport: Vec2, p: Vec2) -> Vec2 {
+        let size = self.max - self.min;
+        let uv = world_to_image(viewport, size, p);
+        // Grid points are at the corners of the area, not at the centres of cells like pixels
+        let p = Vec2::new(uv.x, 1.0 - uv.y)
+            * Vec2::new(self.width as f32 - 1.0, self.height as f32 - 1.0);
+        let p0 = p.floor();
+        let t = p - p0;
+        let (x, y) = (p0.x as i32, p0.y as i32);
+        let bottom = self.point(x, y) * (1.0 - t.x) + self.point(x + 1, y) * t.x;
+        let top = self.point(x, y + 1) * (1.0 - t.x) + self.point(x + 1, y + 1) * t.x;
+        bottom * (1.0 - t.y) + top * t.y
+    }
+}
+
+// CSV for `.csv` files, the raw format for everything else.
+pub fn load_vector_grid(path: &Path) -> Result<VectorGrid, String> {
+    let error = |e: String| format!("failed to load {}: {e}", path.display());
+    let contents = std::fs::read(path).map_err(|e| error(e.to_string()))?;
+    let is_csv = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    if is_csv {
+        parse_csv(&String::from_utf8_lossy(&contents)).map_err(error)
+    } else {
+        parse_raw(&contents).map_err(error)
+    }
+}
+
+pub fn parse_csv(contents: &str) -> Result<VectorGrid, String> {
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+
+    let (_, header) = lines.next().ok_or("no header")?;
+    let header: Vec<String> = header
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    let column = |names: &[&str]| {
+        header
+            .iter()
+            .position(|name| names.contains(&name.as_str()))
+            .ok_or_else(|| format!("no {} column", names[0]))
+    };
+    let columns = [
+        column(&["lon", "longitude"])?,
+        column(&["lat", "latitude"])?,
+        column(&["u"])?,
+        column(&["v"])?,
+    ];
+
+    let mut rows = Vec::new();
+    for (line_index, line) in lines {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let mut values = [0.0; 4];
+        for (value, column) in values.iter_mut().zip(columns) {
+            let field = fields
+                .get(column)
+                .ok_or_else(|| format!("line {}: missing column", line_index + 1))?;
+            // Empty and non-numeric values (e.g. NaN written as "nan" or "--") are no flow
+            *value = field.parse().unwrap_or(f32::NAN);
+        }
+        if !values[0].is_finite() || !values[1].is_finite() {
+            return Err(format!("line {}: invalid coordinates", line_index + 1));
+        }
+        rows.push(values);
+    }
+
+    let lons = grid_coordinates(rows.iter().map(|row| row[0]), "longitude")?;
+    let lats = grid_coordinates(rows.iter().map(|row| row[1]), "latitude")?;
+    let width = lons.len();
+    let mut vectors = vec![Vec2::ZERO; width * lats.len()];
+    for [lon, lat, u, v] in rows {
+        let x = lons
+            .binary_search_by(|c| c.partial_cmp(&lon).unwrap())
+            .unwrap();
+        let y = lats
+            .binary_search_by(|c| c.partial_cmp(&lat).unwrap())
+            .unwrap();
+        vectors[y * width + x] = Vec2::new(u, v);
+    }
+
+    Ok(VectorGrid::new(
+        width as u32,
+        lats.len() as u32,
+        Vec2::new(lons[0], lats[0]),
+        Vec2::new(lons[width - 1], lats[lats.len() - 1]),
+        vectors,
+    ))
+}
+
+// The sorted distinct values of one coordinate, which must be evenly spaced.
+fn grid_coordinates(values: impl Iterator<Item = f32>, name: &str) -> Result<Vec<f32>, String> {
+    let mut coordinates: Vec<f32> = values.collect();
+    coordinates.sort_by(f32::total_cmp);
+    coordinates.dedup();
+    if coordinates.len() < 2 {
+        return Err(format!("the grid needs at least two {name}s"));
+    }
+
+    let spacing =
+        (coordinates[coordinates.len() - 1] - coordinates[0]) / (coordinates.len() - 1) as f32;
+    for (i, c) in coordinates.iter().enumerate() {
+        if (c - (coordinates[0] + i as f32 * spacing)).abs() > 0.01 * spacing {
+            return Err(format!("{name} {c} is not on a regular grid"));
+        }
+    }
+    Ok(coordinates)
+}
+
+pub fn parse_raw(contents: &[u8]) -> Result<VectorGrid, String> {
+    let header_size = 4 + 2 * 4 + 4 * 4;
+    if contents.len() < header_size || &contents[..4] != RAW_MAGIC {
+        return Err("not a CSV or raw vector grid file".to_string());
+    }
+    let word = |i: usize| <[u8; 4]>::try_from(&contents[4 + 4 * i..8 + 4 * i]).unwrap();
+    let (width, height) = (u32::from_le_bytes(word(0)), u32::from_le_bytes(word(1)));
+    let [lon_min, lat_min, lon_max, lat_max] = [2, 3, 4, 5].map(|i| f32::from_le_bytes(word(i)));
+    if width < 2 || height < 2 {
+        return Err(format!(
+            "the grid needs at least 2 x 2 points, not {width} x {height}"
+        ));
+    }
+    if !(lon_max > lon_min && lat_max > lat_min) {
+        return Err("the bounds must have lon_min < lon_max and lat_min < lat_max".to_string());
+    }
+
+    let data = &contents[header_size..];
+    let num_vectors = width as usize * height as usize;
+    if data.len() != 8 * num_vectors {
+        return Err(format!(
+            "expected {} bytes of vectors for {width} x {height} points, found {}",
+            8 * num_vectors,
+            data.len()
+        ));
+    }
+    let vectors = data
+        .chunks_exact(8)
+        .map(|v| {
+            Vec2::new(
+                f32::from_le_bytes(v[..4].try_into().unwrap()),
+                f32::from_le_bytes(v[4..].try_into().unwrap()),
+            )
+        })
+        .collect();
+
+    Ok(VectorGrid::new(
+        width,
+        height,
+        Vec2::new(lon_min, lat_min),
+        Vec2::new(lon_max, lat_max),
+        vectors,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fills_grid() {
+        let csv = "\
+# wind
+time,latitude,longitude,u,v
+0,10,0,1,0
+0,10,1,2,0
+0,10,2,nan,0
+0,11,0,0,1
+0,11,2,0,3
+";
+        let grid = parse_csv(csv).unwrap();
+        assert_eq!((grid.width, grid.height), (3, 2));
+        assert_eq!(
+            (grid.min, grid.max),
+            (Vec2::new(0.0, 10.0), Vec2::new(2.0, 11.0))
+        );
+        assert_eq!(
+            grid.vectors,
+            [
+                Vec2::new(1.0, 0.0),
+                Vec2::new(2.0, 0.0),
+                Vec2::ZERO,
+                Vec2::new(0.0, 1.0),
+                Vec2::ZERO,
+                Vec2::new(0.0, 3.0),
+            ]
+        );
+        assert_eq!(grid.max_magnitude, 3.0);
+
+        assert!(parse_csv("lat,lon,u,v\n0,0,1,1\n0,1,1,1\n0,3,1,1\n1,0,1,1\n").is_err());
+    }
+
+    #[test]
+    fn raw_matches_csv() {
+        let mut raw = RAW_MAGIC.to_vec();
+        raw.extend(2u32.to_le_bytes());
+        raw.extend(2u32.to_le_bytes());
+        for value in [
+            0.0f32, 10.0, 1.0, 11.0, 1.0, 0.0, 2.0, 0.0, 0.0, 1.0, 0.0, 3.0,
+        ] {
+            raw.extend(value.to_le_bytes());
+        }
+        let grid = parse_raw(&raw).unwrap();
+        let csv = parse_csv("lon,lat,u,v\n0,10,1,0\n1,10,2,0\n0,11,0,1\n1,11,0,3\n").unwrap();
+        assert_eq!((grid.width, grid.height), (csv.width, csv.height));
+        assert_eq!((grid.min, grid.max), (csv.min, csv.max));
+        assert_eq!(grid.vectors, csv.vectors);
+
+        assert!(parse_raw(&raw[..raw.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn sample_interpolates_between_points() {
+        let grid = VectorGrid::new(
+            2,
+            2,
+            Vec2::ZERO,
+            Vec2::ONE,
+            vec![Vec2::X, Vec2::X, Vec2::Y, Vec2::Y * 3.0],
+        );
+        // The grid covers [-50, 50] x [-50, 50] of a 200 x 100 viewport, south at the bottom
+        let viewport = Vec2::new(200.0, 100.0);
+        assert_eq!(grid.sample(viewport, Vec2::new(-50.0, -50.0)), Vec2::X);
+        assert_eq!(grid.sample(viewport, Vec2::new(50.0, 50.0)), Vec2::Y * 3.0);
+        assert_eq!(grid.sample(viewport, Vec2::ZERO), Vec2::new(0.5, 1.0));
+        // Clamped outside
+        assert_eq!(grid.sample(viewport, Vec2::new(-90.0, -90.0)), Vec2::X);
+    }
+}
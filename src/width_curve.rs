@@ -0,0 +1,195 @@
+// A user-drawn width profile along the lines for cpu_tracer::WidthMode::Curve.
+//
+// The curve goes through points of (position along the line, width relative to `line_width`) and
+// is baked into `WIDTH_CURVE_SIZE` evenly spaced widths that the compute shader samples, like the
+// colour ramp of a gradient.
+
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy_egui::egui;
+
+// Must match the number of widths `WidthCurveBuffer` uploads.
+pub const WIDTH_CURVE_SIZE: usize = 256;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct WidthCurve {
+    // Sorted by x, at least one
+    points: Vec<Vec2>,
+    samples: Vec<f32>,
+}
+
+impl Default for WidthCurve {
+    // A brush stroke that swells quickly and thins out slowly
+    fn default() -> Self {
+        Self::new(vec![
+            Vec2::new(0.0, 0.1),
+            Vec2::new(0.2, 1.0),
+            Vec2::new(1.0, 0.1),
+        ])
+    }
+}
+
+impl WidthCurve {
+    // Positions are clamped to [0, 1] and widths to at least 0. Without points the width is 1.
+    pub fn new(mut points: Vec<Vec2>) -> Self {
+        for point in &mut points {
+            *point = point.max(Vec2::ZERO);
+            point.x = point.x.min(1.0);
+        }
+        points.sort_by(|a, b| a.x.total_cmp(&b.x));
+        if points.is_empty() {
+            points.push(Vec2::new(0.0, 1.0));
+        }
+
+        let mut curve = Self {
+            points,
+            samples: Vec::new(),
+        };
+        curve.samples = (0..WIDTH_CURVE_SIZE)
+            .map(|i| curve.evaluate(i as f32 / (WIDTH_CURVE_SIZE - 1) as f32))
+            .collect();
+        curve
+    }
+
+    pub fn points(&self) -> &[Vec2] {
+        &self.points
+    }
+
+    pub fn samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    // Eases between the points, so the curve is flat at every point. Constant before the first
+    // and after the last point.
+    pub fn evaluate(&self, t: f32) -> f32 {
+        let next = self.points.partition_point(|point| point.x <= t);
+        if next == 0 {
+            return self.points[0].y;
+        }
+        if next == self.points.len() {
+            return self.points[next - 1].y;
+        }
+        let (a, b) = (self.points[next - 1], self.points[next]);
+        let f = (t - a.x) / (b.x - a.x);
+        a.y + (b.y - a.y) * f * f * (3.0 - 2.0 * f)
+    }
+
+    // Mirrors `sample_width_curve`.
+    pub fn sample(&self, t: f32) -> f32 {
+        let last = self.samples.len() - 1;
+        let x = t.clamp(0.0, 1.0) * last as f32;
+        let i = (x as usize).min(last);
+        let j = (i + 1).min(last);
+        self.samples[i] + (self.samples[j] - self.samples[i]) * (x - i as f32)
+    }
+}
+
+// Parses `X1,W1,X2,W2,...`.
+pub fn parse_width_curve(text: &str) -> Result<WidthCurve, String> {
+    let error = || format!("invalid width curve '{text}', expected X1,W1,X2,W2,...");
+    let values = text
+        .split(',')
+        .map(|value| value.trim().parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()
+        .map_err(|_| error())?;
+    if values.len() < 2 || values.len() % 2 != 0 {
+        return Err(error());
+    }
+    let points = values
+        .chunks_exact(2)
+        .map(|point| Vec2::new(point[0], point[1]))
+        .collect();
+    Ok(WidthCurve::new(points))
+}
+
+// Returns the edited curve if it was changed.
+pub fn width_curve_ui(ui: &mut egui::Ui, curve: &Arc<WidthCurve>) -> Option<WidthCurve> {
+    let mut changed = false;
+    let mut points = curve.points().to_vec();
+
+    // Preview of the stroke, widths above 1 are cut off
+    let (rect, _) = ui.allocate_exact_size(
+        egui::vec2(ui.available_width().min(300.0), 30.0),
+        egui::Sense::hover(),
+    );
+    let samples = curve.samples();
+    let outline: Vec<egui::Pos2> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, width)| {
+            let x = rect.left() + rect.width() * i as f32 / (samples.len() - 1) as f32;
+            egui::pos2(x, rect.center().y - rect.height() / 2.0 * width.min(1.0))
+        })
+        .collect();
+    let mirrored = outline
+        .iter()
+        .rev()
+        .map(|p| egui::pos2(p.x, 2.0 * rect.center().y - p.y));
+    ui.painter().add(egui::Shape::line(
+        outline.iter().copied().chain(mirrored).collect(),
+        egui::Stroke::new(1.0, ui.visuals().strong_text_color()),
+    ));
+
+    let mut remove = None;
+    for (i, point) in points.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut point.x)
+                        .speed(0.01)
+                        .clamp_range(0.0..=1.0)
+                        .prefix("at: "),
+                )
+                .changed();
+            changed |= ui
+                .add(
+                    egui::DragValue::new(&mut point.y)
+                        .speed(0.01)
+                        .clamp_range(0.0..=10.0)
+                        .prefix("width: "),
+                )
+                .changed();
+            if ui.button("Remove").clicked() {
+                remove = Some(i);
+            }
+        });
+    }
+    if let Some(i) = remove.filter(|_| points.len() > 2) {
+        points.remove(i);
+        changed = true;
+    }
+
+    if ui.button("Add point").clicked() {
+        // In the middle of the widest gap, without changing the curve there
+        let x = points
+            .windows(2)
+            .map(|pair| (pair[0].x + pair[1].x) / 2.0)
+            .zip(points.windows(2).map(|pair| pair[1].x - pair[0].x))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map_or(0.5, |(x, _)| x);
+        points.push(Vec2::new(x, curve.evaluate(x)));
+        changed = true;
+    }
+
+    changed.then(|| WidthCurve::new(points))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves_ease_between_points() {
+        let curve = parse_width_curve("1, 0.5, 0, 0").unwrap();
+        assert_eq!(curve.points(), &[Vec2::ZERO, Vec2::new(1.0, 0.5)]);
+        assert_eq!(curve.sample(0.0), 0.0);
+        assert_eq!(curve.sample(1.0), 0.5);
+        assert!((curve.sample(0.5) - 0.25).abs() < 1e-3);
+        // Flat at the points
+        assert!(curve.sample(0.02) < 0.01);
+
+        assert!(parse_width_curve("0,1,1").is_err());
+        assert!(parse_width_curve("0,wide").is_err());
+    }
+}